@@ -5,12 +5,27 @@ extern crate mbms_traits;
 extern crate monome;
 extern crate smallvec;
 extern crate musical_scales;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
+use std::cell::Cell;
 use std::cmp;
 use std::fmt;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
 use std::{thread, time};
+#[cfg(feature = "osc")]
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "osc")]
+use std::sync::atomic::AtomicBool;
 
 use audio_clock::*;
 use musical_scales::*;
@@ -22,10 +37,131 @@ use smallvec::SmallVec;
 
 /// Maximum number of steps in the sequencer, in sixteenth.
 const MAX_STEPS: usize = 128;
+/// Maximum number of bars in the sequencer.
+const MAX_BARS: usize = MAX_STEPS / 16;
+/// Number of keys of the control row that show the octave or the bars, from the left arrow,
+/// stopping before the scale and shift keys.
+const INDICATOR_KEYS: usize = 6;
 /// Initial number of steps in the sequencer, in sixteenth.
 const INITIAL_STEPS: usize = 32;
+/// Default duration of the trigger sent on each step, in seconds.
+const DEFAULT_TRIGGER_DURATION: f32 = 0.01;
+/// Minimum duration of the trigger sent on each step, in seconds.
+const MIN_TRIGGER_DURATION: f32 = 0.001;
+/// Time the gate is low between two steps in gate mode, in seconds, so that envelopes retrigger.
+const REGATE_DURATION: f32 = 0.002;
+/// Number of debug events that can be queued by the audio thread before being dropped.
+#[cfg(feature = "debug-events")]
+const DEBUG_QUEUE_SIZE: usize = 64;
+/// Number of MIDI messages that can be queued by the audio thread for the main thread.
+const MIDI_QUEUE_SIZE: usize = 256;
+/// Number of MIDI clock messages per beat.
+const MIDI_CLOCK_PPQN: usize = 24;
+/// How often the OSC thread checks if it has to stop, in milliseconds.
+#[cfg(feature = "osc")]
+const OSC_POLL_INTERVAL: u64 = 100;
+/// Number of changes that can wait for the next bar, enough for a whole pattern.
+const QUANTIZE_QUEUE_SIZE: usize = 2048;
+/// Number of messages of an edit that can wait for the end of the edit, enough for a whole pattern.
+const BATCH_QUEUE_SIZE: usize = 2048;
+/// MIDI note number of a pitch at 0V, that is C0.
+const MIDI_NOTE_AT_ZERO_VOLT: f32 = 12.;
+/// Maximum amount of swing, in fraction of the maximum delay of odd sixteenth.
+const MAX_SWING: f32 = 0.75;
+/// Version of the format of the patterns saved on disk.
+const PATTERN_VERSION: u32 = 1;
+/// Version of the format of the projects saved on disk.
+const PROJECT_VERSION: u32 = 1;
+/// Seed of the random playback direction, the same on both sides.
+const DIRECTION_SEED: u32 = 0x6d6d6d73;
+/// Minimum tempo, in beats per minute.
+const MIN_TEMPO: f32 = 20.;
+/// Maximum tempo, in beats per minute.
+const MAX_TEMPO: f32 = 300.;
 /// Number of notes that can be represented, in semitones.
 const MAX_NOTES: usize = 128;
+/// Maximum number of notes that can be played at the same time on a step.
+const MAX_POLYPHONY: usize = 4;
+/// Maximum velocity of a step, this is the default.
+const MAX_VELOCITY: u8 = 127;
+/// Maximum probability of a step to fire, in percent, this is the default.
+const MAX_PROBABILITY: u8 = 100;
+/// Speeds at which a track can go, relative to the clock.
+const CLOCK_RATIOS: [f32; 5] = [0.25, 0.5, 1., 2., 3.];
+/// Maximum number of tracks.
+const MAX_TRACKS: usize = 4;
+/// Number of grid refreshes during which cleared steps are lit.
+const CLEAR_FLASH_FRAMES: usize = 8;
+/// Maximum glide time, in seconds.
+const MAX_GLIDE_TIME: f32 = 2.;
+/// Maximum time constant of the smoothing of the pitch CV, in seconds.
+const MAX_PITCH_SMOOTHING: f32 = 0.05;
+/// Default, minimum and maximum frequency of A4, in Hz.
+const DEFAULT_TUNING: f32 = 440.;
+const MIN_TUNING: f32 = 400.;
+const MAX_TUNING: f32 = 480.;
+/// Maximum number of triggers in a step.
+const MAX_RATCHET: u8 = 4;
+/// Number of triggers of a track that can be repeating at the same time.
+const ECHO_QUEUE_SIZE: usize = 16;
+/// Level under which the repeats of a trigger stop, relative to the trigger.
+const MIN_ECHO_LEVEL: f32 = 0.1;
+/// Shortest and longest time between the repeats of a trigger, in steps.
+const MIN_DELAY_TIME: f32 = 1.;
+const MAX_DELAY_TIME: f32 = 16.;
+/// Maximum level of a repeat relative to the one before.
+const MAX_FEEDBACK: f32 = 0.9;
+/// Number of patterns that can be arranged in a song.
+const BANK_SIZE: usize = 8;
+/// Time without edit after which the pattern is saved, in milliseconds.
+const AUTOSAVE_DELAY: u64 = 2000;
+/// Time an arrow key is held before its move repeats, in milliseconds.
+const MOVE_REPEAT_DELAY: u64 = 400;
+/// Time between the repeats of the move of a held arrow key, in milliseconds.
+const MOVE_REPEAT_INTERVAL: u64 = 80;
+/// Number of intervals between taps averaged to find the tempo.
+const TAP_TEMPO_INTERVALS: usize = 4;
+/// Time after which a tap starts a new tempo, in milliseconds, 30 BPM.
+const TAP_TEMPO_TIMEOUT: u64 = 2000;
+/// Maximum timing offset of a step, in eighth of a sixteenth, earlier or later.
+const MAX_NUDGE: i8 = 3;
+/// Seed of the random number generator used to decide if a step fires.
+const PROBABILITY_SEED: u32 = 0x70726f62;
+/// Seed of the random number generator used to make random patterns.
+const RANDOMIZE_SEED: u32 = 0x72616e64;
+/// Seed of the random number generator used to mutate the patterns, in random walk.
+const WALK_SEED: u32 = 0x77616c6b;
+/// Seed of the random delays of the steps, when humanizing.
+const HUMANIZE_SEED: u32 = 0x68756d61;
+/// Longest delay of a step when fully humanized, in sixteenth.
+const MAX_HUMANIZE_DELAY: f32 = 0.2;
+/// How long the metronome is lit on each beat, in beats.
+const METRONOME_FLASH: f32 = 0.25;
+/// The most loops a step condition can count.
+const MAX_CONDITION_LOOPS: u8 = 8;
+/// The analog outputs of the Bela.
+const ANALOG_OUT_CHANNELS: usize = 8;
+/// Volts at the analog inputs of the Bela when they read 1.0.
+const ANALOG_IN_MAX_VOLTS: f32 = 4.096;
+/// How much closer to another degree the transpose CV has to be to change the transposition, in
+/// octaves, a quarter of a semitone.
+const TRANSPOSE_CV_HYSTERESIS: f32 = 1. / 48.;
+/// The finest and the coarsest resolutions of the steps, in steps per beat.
+const MIN_STEPS_PER_BEAT: f32 = 1.;
+const MAX_STEPS_PER_BEAT: f32 = 8.;
+/// The longest count in before the playback starts, in bars.
+const MAX_COUNT_IN_BARS: usize = 4;
+/// The highest pitch of a custom scale, in volts: MIDI note 127.
+const MAX_SCALE_CV: f32 = (127. - MIDI_NOTE_AT_ZERO_VOLT) / 12.;
+/// The fewest notes a custom scale can have.
+pub const MIN_SCALE_NOTES: usize = 2;
+/// The most notes a custom scale can have, one per row of the tallest virtual grid.
+pub const MAX_SCALE_NOTES: usize = 128;
+
+/// The scale the sequencer starts with, unless another one is picked when building it.
+fn default_scale() -> Scale {
+    Scale::new(PitchClass::B, ScaleType::Minor)
+}
 
 pub fn clamp<T: PartialOrd>(input: T, min: T, max: T) -> T {
     debug_assert!(min <= max, "min must be less than or equal to max");
@@ -38,626 +174,10334 @@ pub fn clamp<T: PartialOrd>(input: T, min: T, max: T) -> T {
     }
 }
 
+/// The scale types that can be picked, in the order in which they are displayed.
+const SCALE_TYPES: [ScaleType; 7] = [
+    ScaleType::Chromatic,
+    ScaleType::Major,
+    ScaleType::Minor,
+    ScaleType::MinorMelodic,
+    ScaleType::MinorHarmonic,
+    ScaleType::MajorPentatonic,
+    ScaleType::MinorPentatonic,
+];
 
-#[derive(Debug)]
-enum Message {
-    Tick((usize, usize)),
-    Scale(Scale),
-    Resize(usize),
-    Clear,
-    Start,
-    Stop,
-    TempoChange(f32),
+/// A scale that isn't one of `musical_scales`, for example a subset of 19-EDO or a maqam: the
+/// offsets of its notes above the root, in cents, repeated every period, an octave by default. The
+/// notes go from the root in the lowest octave up to MIDI note 127, MIDI tracks play the closest
+/// semitone.
+#[derive(Debug, Clone)]
+pub struct CustomScale {
+    root: PitchClass,
+    cents: Vec<f32>,
+    period: f32,
+    note_count: usize,
+    // The chromatic scale on the root, that `scale()` returns for the scale.
+    chromatic: Scale,
 }
 
-pub struct MMMSRenderer {
-    clock_updater: ClockUpdater,
-    clock_consumer: ClockConsumer,
-    receiver: Receiver<Message>,
-    tempo: f32,
-    steps: SmallVec<[Option<Pitch>; 64]>,
-    scale: Scale,
-    trigger_port: BelaPort,
-    pitch_port: BelaPort,
-    prev_pitch: f32
+impl PartialEq for CustomScale {
+    fn eq(&self, other: &CustomScale) -> bool {
+        self.root == other.root && self.cents == other.cents && self.period == other.period &&
+            self.note_count == other.note_count
+    }
 }
 
-impl MMMSRenderer {
-    fn new(
-        width: usize,
-        height: usize,
-        clock_updater: ClockUpdater,
-        clock_consumer: ClockConsumer,
-        receiver: Receiver<Message>,
-        trigger_port: BelaPort,
-        pitch_port: BelaPort
-    ) -> MMMSRenderer {
-        let mut steps = SmallVec::<[Option<Pitch>; 64]>::new();
-        steps.resize(INITIAL_STEPS, None);
-        let scale = Scale::new(PitchClass::B, ScaleType::Minor);
-        MMMSRenderer {
-            receiver,
-            clock_updater,
-            clock_consumer,
-            tempo: 0.,
-            trigger_port,
-            pitch_port,
-            steps,
-            scale,
-            prev_pitch: 0.0
+impl CustomScale {
+    /// A scale repeating every octave. `cents` starts with the root, 0, and increases, staying
+    /// below 1200.
+    pub fn new(root: PitchClass, cents: &[f32]) -> Result<CustomScale, MmmsError> {
+        CustomScale::with_period(root, cents, 1200.)
+    }
+    /// A scale repeating every `period` cents, for example 1901.96 for a tritave. Fails if the
+    /// period isn't a positive number, if the offsets don't start at 0, don't increase or don't fit
+    /// in the period, or if there are fewer than `MIN_SCALE_NOTES` or more than `MAX_SCALE_NOTES`
+    /// notes up to MIDI note 127. When there are fewer notes than rows on the grid, the rows below
+    /// the lowest note are empty.
+    pub fn with_period(root: PitchClass, cents: &[f32], period: f32) -> Result<CustomScale, MmmsError> {
+        let increasing = cents.windows(2).all(|pair| pair[0] < pair[1]);
+        if !period.is_finite() || period <= 0. || cents.first() != Some(&0.) || !increasing ||
+            cents[cents.len() - 1] >= period {
+            return Err(MmmsError::InvalidScale);
+        }
+        let mut scale = CustomScale {
+            root,
+            cents: cents.to_vec(),
+            period,
+            note_count: 0,
+            chromatic: Scale::new(root, ScaleType::Chromatic),
+        };
+        while scale.note_count <= MAX_SCALE_NOTES && scale.cv(scale.note_count) <= MAX_SCALE_CV {
+            scale.note_count += 1;
+        }
+        if scale.note_count < MIN_SCALE_NOTES || scale.note_count > MAX_SCALE_NOTES {
+            return Err(MmmsError::InvalidScale);
         }
+        Ok(scale)
     }
-    fn press(&mut self, x: usize, y: usize) {
-        self.steps[x] = Some(self.scale.idx_to_pitch(self.scale.note_count() - 1 - y).unwrap())
+    /// The number of notes, from the lowest to the highest.
+    pub fn note_count(&self) -> usize {
+        self.note_count
     }
-    fn set_tempo(&mut self, new_tempo: f32) {
-        self.tempo = new_tempo;
+    /// The number of notes in a period.
+    pub fn octave_note_count(&self) -> usize {
+        self.cents.len()
     }
-    fn set_scale(&mut self, scale: Scale) {
-        for i in self.steps.iter_mut() {
-            *i = None;
+    pub fn fundamental(&self) -> PitchClass {
+        self.root
+    }
+    /// The offsets of the notes above the root, in cents.
+    pub fn cents(&self) -> &[f32] {
+        &self.cents
+    }
+    /// The interval after which the notes repeat, in cents.
+    pub fn period(&self) -> f32 {
+        self.period
+    }
+    // The pitch of note `idx`, in volts, without checking that it's in range.
+    fn cv(&self, idx: usize) -> f32 {
+        let count = self.cents.len();
+        let cents = (idx / count) as f32 * self.period + self.cents[idx % count];
+        (semitones_above_c(self.root) as f32 * 100. + cents) / 1200.
+    }
+}
+
+/// The notes the rows of a track play, from the lowest: a scale of `musical_scales`, or a custom
+/// one.
+#[derive(Debug, Clone)]
+pub enum TrackScale {
+    Standard(Scale),
+    Custom(CustomScale),
+}
+
+impl TrackScale {
+    pub fn note_count(&self) -> usize {
+        match *self {
+            TrackScale::Standard(ref scale) => scale.note_count(),
+            TrackScale::Custom(ref scale) => scale.note_count(),
         }
-        self.scale = scale;
     }
-    fn resize(&mut self, new_size: usize) {
-        self.steps.resize(new_size, None);
+    /// The number of notes in an octave, or in the period of a custom scale.
+    pub fn octave_note_count(&self) -> usize {
+        match *self {
+            TrackScale::Standard(ref scale) => scale.octave_note_count(),
+            TrackScale::Custom(ref scale) => scale.octave_note_count(),
+        }
     }
-    fn clear(&mut self) {
-        for i in self.steps.iter_mut() {
-            *i = None;
+    pub fn fundamental(&self) -> PitchClass {
+        match *self {
+            TrackScale::Standard(ref scale) => scale.fundamental(),
+            TrackScale::Custom(ref scale) => scale.fundamental(),
         }
     }
-    fn print_seq(&self) {
-        for step in self.steps.iter() {
-            if step.is_some() {
-                print!("{}\t", step.clone().unwrap());
-            } else {
-                print!("  \t");
+    /// The type of a scale of `musical_scales`, `None` for a custom scale.
+    pub fn scale_type(&self) -> Option<ScaleType> {
+        match *self {
+            TrackScale::Standard(ref scale) => Some(scale.scale_type()),
+            TrackScale::Custom(_) => None,
+        }
+    }
+    /// The pitch of note `idx`, the lowest being 0, in volts, one volt per octave, 0V being C0.
+    /// `None` if there is no such note.
+    pub fn idx_to_cv(&self, idx: usize) -> Option<f32> {
+        match *self {
+            TrackScale::Standard(ref scale) => scale.idx_to_pitch(idx).ok().map(|pitch| pitch.to_cv()),
+            TrackScale::Custom(ref scale) if idx < scale.note_count() => Some(scale.cv(idx)),
+            TrackScale::Custom(_) => None,
+        }
+    }
+    /// The pitch of the note of the scale closest to `input_volts`, in volts, one volt per octave,
+    /// 0V being C0. Below and above the scale, this is its lowest and highest note. Halfway between
+    /// two notes, this is the lower one.
+    pub fn quantize_cv(&self, input_volts: f32) -> f32 {
+        self.idx_to_cv(self.nearest_idx(input_volts)).unwrap_or(0.)
+    }
+    // The index of the note closest to `input_volts`, as in `quantize_cv`.
+    fn nearest_idx(&self, input_volts: f32) -> usize {
+        let mut closest = (0, self.idx_to_cv(0).unwrap_or(0.));
+        for idx in 1..self.note_count() {
+            let cv = match self.idx_to_cv(idx) {
+                Some(cv) => cv,
+                None => break,
+            };
+            if (cv - input_volts).abs() < (closest.1 - input_volts).abs() {
+                closest = (idx, cv);
             }
         }
-        println!("");
+        closest.0
     }
-}
-
-impl InstrumentRenderer for MMMSRenderer {
-    fn render(&mut self, context: &mut Context) {
-        match self.receiver.try_recv() {
-            Ok(msg) => match msg {
-                Message::Tick((x, y)) => {
-                    self.press(x, y);
-                }
-                Message::Start => {}
-                Message::Stop => {}
-                Message::Resize(new_size) => {
-                    self.resize(new_size)
-                }
-                Message::Clear => {
-                    self.clear();
-                }
-                Message::TempoChange(tempo) => {
-                    self.set_tempo(tempo);
-                }
-                Message::Scale(scale) => {
-                    self.set_scale(scale);
-                }
-            },
-            Err(err) => match err {
-                std::sync::mpsc::TryRecvError::Empty => {}
-                std::sync::mpsc::TryRecvError::Disconnected => {
-                    println!("disconnected");
-                }
-            },
+    // The degree of note `idx`, only the tonic for a custom scale.
+    fn idx_to_degree(&self, idx: usize) -> Option<Degrees> {
+        match *self {
+            TrackScale::Standard(ref scale) => scale.idx_to_degree(idx).ok(),
+            TrackScale::Custom(ref scale) if idx % scale.octave_note_count() == 0 => Some(Degrees::Tonic),
+            TrackScale::Custom(_) => None,
         }
-
-        let frames = context.audio_frames();
-        let analog_period = 1. / context.analog_sample_rate();
-        let digital_period = 1. / context.digital_sample_rate();
-        let beat = self.clock_consumer.beat();
-        let sixteenth = beat * 4.;
-        let trigger_duration = 0.01; // 10ms
-
-        match self.trigger_port {
-            BelaPort::AnalogOut(n) => {
-                let mut sixteenth = beat * 4.;
-                let analog_channels = context.analog_out_channels();
-                let analog_frames = context.analog_frames();
-                let analog_out = context.analog_out();
-                for i in 0..analog_frames {
-                    let integer_sixteenth = sixteenth as usize % self.steps.len();
-                    let pitch = &self.steps[integer_sixteenth];
-                    if pitch.is_some() && sixteenth.fract() < trigger_duration {
-                        println!("playing {}", pitch.clone().unwrap());
-                        analog_out[i * analog_channels + n] = 1.0;
-                    } else {
-                        analog_out[i * analog_channels + n] = 0.0;
-                    }
-                    sixteenth += analog_period;
-                }
+    }
+    /// This scale, or the chromatic scale on the root of a custom scale.
+    pub fn as_scale(&self) -> &Scale {
+        match *self {
+            TrackScale::Standard(ref scale) => scale,
+            TrackScale::Custom(ref scale) => &scale.chromatic,
+        }
+    }
+    // The pitch of note `idx`, the closest semitone for a custom scale.
+    fn idx_to_pitch(&self, idx: usize) -> Option<Pitch> {
+        let cv = self.idx_to_cv(idx)?;
+        let scale = self.as_scale();
+        let distance = |idx: &usize| (scale.idx_to_pitch(*idx).unwrap().to_cv() - cv).abs();
+        let nearest = match *self {
+            TrackScale::Standard(_) => idx,
+            TrackScale::Custom(_) => (0..scale.note_count())
+                .min_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap())?,
+        };
+        scale.idx_to_pitch(nearest).ok()
+    }
+    // The scale the picker starts on: this one, or the chromatic scale on the root of a custom
+    // scale.
+    fn standard(&self) -> Scale {
+        self.as_scale().clone()
+    }
+    // Whether both scales have the same notes.
+    fn same_as(&self, other: &TrackScale) -> bool {
+        match (self, other) {
+            (&TrackScale::Standard(ref a), &TrackScale::Standard(ref b)) => {
+                a.fundamental() == b.fundamental() && a.scale_type() == b.scale_type()
             }
-            BelaPort::Digital(n) => {
-                let digital_frames = context.digital_frames();
-                let mut sixteenth = beat * 4.;
-                for frame in 0..digital_frames {
-                    let integer_sixteenth = sixteenth as usize % self.steps.len();
-                    let pitch = &self.steps[integer_sixteenth];
-                    if pitch.is_some() && sixteenth.fract() < trigger_duration {
-                        println!("playing {}", pitch.clone().unwrap());
-                        context.digital_write_once(frame, n, 1);
-                    } else {
-                        context.digital_write_once(frame, n, 0);
-                    }
-                    sixteenth += digital_period;
-                }
+            (&TrackScale::Custom(ref a), &TrackScale::Custom(ref b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for TrackScale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackScale::Standard(ref scale) => {
+                write!(f, "{:?} {:?}", scale.fundamental(), scale.scale_type())
             }
-            _ => {
-                panic!("wrong ports.");
+            TrackScale::Custom(ref scale) => {
+                write!(f, "{:?} custom, {} notes per period", scale.fundamental(),
+                       scale.octave_note_count())
             }
         }
-        if let BelaPort::AnalogOut(channel) = self.pitch_port {
-            let analog_channels = context.analog_out_channels();
-            let analog_frames = context.analog_frames();
-            let analog_out = context.analog_out();
-            let mut sixteenth = beat * 4.;
-            for i in 0..analog_frames {
-                let integer_sixteenth = sixteenth as usize % self.steps.len();
-                let pitch = &self.steps[integer_sixteenth];
-
-                // divide by ten to map to the bela range:
-                // 0 -> 1.0 is 0 -> 5v in bela, with then an analog gain of two
-                if pitch.is_some() {
-                    let value = pitch.clone().unwrap().to_cv() / 10.0;
-                    assert!(value <= 1.0);
-                    self.prev_pitch = value;
-                    analog_out[i * analog_channels + channel] = value;
-                } else {
-                    analog_out[i * analog_channels + channel] = self.prev_pitch
-                }
-                sixteenth += analog_period;
+    }
+}
+
+impl From<Scale> for TrackScale {
+    fn from(scale: Scale) -> TrackScale {
+        TrackScale::Standard(scale)
+    }
+}
+
+impl From<CustomScale> for TrackScale {
+    fn from(scale: CustomScale) -> TrackScale {
+        TrackScale::Custom(scale)
+    }
+}
+
+/// The position of `degree` in a scale, from 0 for the tonic to 6 for the seventh.
+fn degree_index(degree: Degrees) -> usize {
+    match degree {
+        Degrees::Tonic => 0,
+        Degrees::Supertonic => 1,
+        Degrees::Mediant => 2,
+        Degrees::Subdominant => 3,
+        Degrees::Dominant => 4,
+        Degrees::Submediant => 5,
+        Degrees::Leading => 6,
+    }
+}
+
+/// The row in `to` whose pitch is the closest to the pitch of `row` in `from`, the lower one on a
+/// tie. Rows are numbered from the top, like in the grid.
+fn nearest_row(from: &TrackScale, to: &TrackScale, row: usize) -> usize {
+    let cv = match from.idx_to_cv(from.note_count() - 1 - row) {
+        Some(cv) => cv,
+        None => return row,
+    };
+    let mut nearest = 0;
+    let mut distance = std::f32::MAX;
+    for idx in 0..to.note_count() {
+        if let Some(pitch) = to.idx_to_cv(idx) {
+            let d = (pitch - cv).abs();
+            if d < distance {
+                distance = d;
+                nearest = idx;
             }
-        } else {
-            panic!("wtf.");
         }
+    }
+    to.note_count() - 1 - nearest
+}
 
-        self.clock_updater.increment(frames);
+/// The pitch class `semitones` above C.
+fn pitch_class(semitones: usize) -> PitchClass {
+    // Walk the circle of fifths: seven fifths up is one semitone up.
+    let mut pitch = PitchClass::C;
+    for _ in 0..(semitones % 12) * 7 % 12 {
+        pitch = pitch.fifth();
     }
+    pitch
 }
 
-pub struct MMMS {
-    tempo: f32,
-    width: usize,
-    height: usize,
-    sender: Sender<Message>,
-    audio_clock: ClockConsumer,
-    state_tracker: GridStateTracker,
-    virtual_grid: VirtualGrid,
-    picking_scale: bool
+/// The number of semitones between C and `pitch`.
+fn semitones_above_c(pitch: PitchClass) -> usize {
+    let mut current = PitchClass::C;
+    for fifths in 0..12 {
+        if current == pitch {
+            return fifths * 7 % 12;
+        }
+        current = current.fifth();
+    }
+    0
 }
 
-impl MMMS {
-    pub fn new(
-        ports: (BelaPort, BelaPort),
-        width: usize,
-        height: usize,
-        tempo: f32,
-    ) -> (MMMS, MMMSRenderer) {
-        let (sender, receiver) = channel::<Message>();
+/// Compute a bar of sixteenth containing a euclidian rythm of `pulses` in `steps`, repeated
+/// until the end of the bar.
+fn euclid_bar(pulses: usize, steps: usize) -> [bool; 16] {
+    let steps = clamp(steps, 1, 16);
+    let pulses = clamp(pulses, 0, steps);
+    let mut pattern = [0 as u8; 16];
+    euclidian_rythm(&mut pattern[..steps], pulses).unwrap();
+    let mut bar = [false; 16];
+    for i in 0..16 {
+        bar[i] = pattern[i % steps] != 0;
+    }
+    bar
+}
 
-        let (clock_updater, clock_consumer) = audio_clock(tempo, 44100);
+/// On which loops of the pattern a step plays, like the trig conditions of the Elektron machines.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// On all the loops.
+    Always,
+    /// On loop `a` of every `b` loops, from 1: `Every(1, 2)` plays on the first loop and every
+    /// other loop after that. `b` is at most 8.
+    Every(u8, u8),
+    /// Only during a fill.
+    Fill,
+    /// Except during a fill.
+    NotFill,
+}
 
-        let (trigger_port, pitch_port) = ports;
+impl Default for Condition {
+    fn default() -> Condition {
+        Condition::Always
+    }
+}
 
-        match pitch_port {
-            BelaPort::AnalogOut(_) => {
-            }
-            _ => {
-                panic!("Cannot render CV on GPIO.");
+impl Condition {
+    // The condition after this one, when changing it from the grid.
+    fn next(self) -> Condition {
+        match self {
+            Condition::Always => Condition::Every(1, 2),
+            Condition::Every(1, 2) => Condition::Every(2, 2),
+            Condition::Every(2, 2) => Condition::Every(1, 4),
+            Condition::Every(a, 4) if a < 4 => Condition::Every(a + 1, 4),
+            Condition::Every(..) => Condition::Fill,
+            Condition::Fill => Condition::NotFill,
+            Condition::NotFill => Condition::Always,
+        }
+    }
+    // The same condition, with loops that exist.
+    fn clamped(self) -> Condition {
+        match self {
+            Condition::Every(a, b) => {
+                let b = clamp(b, 1, MAX_CONDITION_LOOPS);
+                Condition::Every(clamp(a, 1, b), b)
             }
+            condition => condition,
+        }
+    }
+    // Whether a step plays during loop `current`, from 0, being a fill or not.
+    fn met(self, current: usize, fill: bool) -> bool {
+        match self {
+            Condition::Always => true,
+            Condition::Every(a, b) => current % b as usize == a as usize - 1,
+            Condition::Fill => fill,
+            Condition::NotFill => !fill,
         }
+    }
+}
 
-        let virtual_grid = VirtualGrid::new();
+/// What a step plays: nothing programmed, a rest that cuts the previous note instead of letting
+/// it ring, or notes, at least one.
+#[derive(Debug, Clone, PartialEq)]
+enum StepKind<N> {
+    Empty,
+    Rest,
+    Note(SmallVec<[N; MAX_POLYPHONY]>),
+}
 
-        let renderer = MMMSRenderer::new(
-            16,
-            8,
-            clock_updater,
-            clock_consumer.clone(),
-            receiver,
-            trigger_port,
-            pitch_port);
-        let state_tracker = GridStateTracker::new(16, 8);
+/// A step of the sequence. The notes are rows in the control side, and pitches in the renderer.
+#[derive(Debug, Clone)]
+struct Step<N> {
+    kind: StepKind<N>,
+    velocity: u8,
+    // chance for the step to fire when played, in percent
+    probability: u8,
+    // number of evenly spaced triggers in the step
+    ratchet: u8,
+    // also fires the accent gate
+    accent: bool,
+    // holds the note of the previous step instead of playing its own
+    tie: bool,
+    // timing offset, in eighth of a sixteenth, negative is earlier
+    nudge: i8,
+    // the loops on which the step plays
+    condition: Condition,
+}
 
-        let grid = vec![0 as u8; 128];
-        (
-            MMMS {
-                tempo: 120.,
-                width,
-                height,
-                sender,
-                audio_clock: clock_consumer,
-                state_tracker,
-                virtual_grid,
-                picking_scale: false
-            },
-            renderer,
-        )
+impl<N: PartialEq> Step<N> {
+    fn new() -> Step<N> {
+        Step {
+            kind: StepKind::Empty,
+            velocity: MAX_VELOCITY,
+            probability: MAX_PROBABILITY,
+            ratchet: 1,
+            accent: false,
+            tie: false,
+            nudge: 0,
+            condition: Condition::Always,
+        }
     }
-    fn scale_picker(&self, current_scale: Scale, grid: &mut [u8]) {
-        assert!(grid.len() == 7 * 16);
-        let mut pitch = PitchClass::C;
-        // fundamental picker
-        for i in 0..3 {
-            for j in 0..4 {
-                grid[i * 16 + j] = if pitch == current_scale.fundamental() { 15 } else { 8 };
-                pitch = pitch.fifth();
+    // The notes of the step, none for an empty step or a rest.
+    fn notes(&self) -> &[N] {
+        match self.kind {
+            StepKind::Note(ref notes) => notes,
+            StepKind::Empty | StepKind::Rest => &[],
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.notes().is_empty()
+    }
+    fn is_rest(&self) -> bool {
+        match self.kind {
+            StepKind::Rest => true,
+            _ => false,
+        }
+    }
+    // Make a step without notes a rest, or empty. A step with notes stays as it is.
+    fn set_rest(&mut self, rest: bool) {
+        match self.kind {
+            StepKind::Empty if rest => self.kind = StepKind::Rest,
+            StepKind::Rest if !rest => self.kind = StepKind::Empty,
+            _ => {}
+        }
+    }
+    // Add a note if it's not present and there is room for it, remove it otherwise. A step with a
+    // note isn't a rest, a step whose last note is removed is empty.
+    fn toggle(&mut self, note: N) {
+        let mut notes = match mem::replace(&mut self.kind, StepKind::Empty) {
+            StepKind::Note(notes) => notes,
+            StepKind::Empty | StepKind::Rest => SmallVec::new(),
+        };
+        match notes.iter().position(|n| *n == note) {
+            Some(i) => {
+                notes.remove(i);
+            }
+            None => {
+                if notes.len() < MAX_POLYPHONY {
+                    notes.push(note);
+                }
             }
         }
-
-        // Scale picker
-        let scales : [ScaleType; 7] = [
-            ScaleType::Chromatic,
-            ScaleType::Major,
-            ScaleType::Minor,
-            ScaleType::MinorMelodic,
-            ScaleType::MinorHarmonic,
-            ScaleType::MajorPentatonic,
-            ScaleType::MinorPentatonic,
-        ];
-
-        // 4 + 1 of padding for the fundamental picker
-        let mut h_offset = 5;
-        let mut itv = SmallVec::<[u8; 12]>::new();
-        for scale in scales.iter() {
-            Scale::type_to_intervals(scale, &mut itv);
-            // draw it on the right hand side. Only the seven first notes.
-            let note_count_clamped = clamp(itv.len(), 0, 7);
-            for i in 0..note_count_clamped {
-                let steps2luminosity = [
-                    5, // 1 semitone
-                    9, // 2 semitones
-                    11, // 3 semitones
-                    13 // 4 semitonees
-                ];
-                let lum_modifier = if *scale == current_scale.scale_type() {
-                    2
-                } else {
-                    0
-                };
-                grid[i * 16 + h_offset] = lum_modifier + steps2luminosity[(itv[i] - 1) as usize];
+        self.kind = if notes.is_empty() { StepKind::Empty } else { StepKind::Note(notes) };
+    }
+    // Remove the notes, and the rest: nothing is programmed.
+    fn clear_notes(&mut self) {
+        self.kind = StepKind::Empty;
+    }
+    // Replace each note by `f` of it. Notes that end up on the same one are merged.
+    fn map_notes<F: Fn(&N) -> N>(&mut self, f: F) {
+        if let StepKind::Note(ref mut notes) = self.kind {
+            let old = mem::replace(notes, SmallVec::new());
+            for note in old.iter() {
+                let note = f(note);
+                if !notes.contains(&note) {
+                    notes.push(note);
+                }
             }
-            h_offset += 1;
         }
     }
 }
 
-#[derive(Clone, PartialEq)]
-enum MMMSIntent {
-    Nothing,
-    Tick,
+/// Reflect `note` across `pivot`, staying in the `count` notes of the scale.
+fn reflect(note: u8, pivot: u8, count: usize) -> u8 {
+    clamp(2 * pivot as isize - note as isize, 0, count as isize - 1) as u8
 }
 
-#[derive(Debug, Copy, Clone)]
-enum MMMSAction {
-    Nothing,
-    Tick((usize, usize)),
-    Move((isize, isize)),
-    Clear,
-    ToggleScale,
-    Resize(usize), // number is the number of bars
-}
+/// Number of frames per second of the clock.
+const CLOCK_RATE: u32 = 44100;
 
-struct GridStateTracker {
-    buttons: Vec<MMMSIntent>,
-    width: usize,
-    height: usize,
+/// A clock, that replaces the current one when the tempo changes.
+struct Clock {
+    updater: ClockUpdater,
+    consumer: ClockConsumer,
 }
 
-impl GridStateTracker {
-    fn new(width: usize, height: usize) -> GridStateTracker {
-        GridStateTracker {
-            width,
-            height,
-            buttons: vec![MMMSIntent::Nothing; width * height],
-        }
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Clock {{ beat: {} }}", self.consumer.beat())
     }
+}
 
-    fn shift_down(&self) -> bool {
-      self.buttons[Self::idx(self.width, 15, 0)] != MMMSIntent::Nothing
+/// Whether the metronome is lit at `beat`, at the beginning of each beat, and if it's the first
+/// beat of a bar.
+fn metronome_flash(beat: f32) -> Option<bool> {
+    if beat.fract() >= METRONOME_FLASH {
+        return None;
     }
-    fn scale_down(&self) -> bool {
-      self.buttons[Self::idx(self.width, 14, 0)] != MMMSIntent::Nothing
+    Some(beat as usize % 4 == 0)
+}
+
+/// The number of keys lit on a row of `width` keys to represent `tempo`.
+fn tempo_bar_length(tempo: f32, width: usize) -> usize {
+    1 + ((tempo - MIN_TEMPO) / (MAX_TEMPO - MIN_TEMPO) * (width - 1) as f32).round() as usize
+}
+
+/// The keys lit on a row of `width` keys to show a transposition of `degrees`, from the key right
+/// of the middle, that is no transposition, to the left or to the right.
+fn transpose_bar(degrees: i32, width: usize) -> (usize, usize) {
+    let middle = (width / 2) as i32;
+    let end = clamp(middle + degrees, 0, width as i32 - 1) as usize;
+    let middle = middle as usize;
+    if end < middle {
+        (end, middle)
+    } else {
+        (middle, end)
     }
+}
 
-    fn down(&mut self, x: usize, y: usize) {
-        if y == 0 {
-            // control row, rightmost part, does nothing for now, the last one is shift, and the
-            // one before that is the scale change button
-            if x == 15 || x == 14 {
-                self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Tick;
-            } else {
-                self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Nothing;
-            }
-        } else {
-            self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Tick;
+// The number of frames of `beats` at `tempo`.
+fn beats_to_frames(beats: f32, tempo: f32) -> usize {
+    (beats * 60. / tempo * CLOCK_RATE as f32) as usize
+}
+
+// The position of `frame` of a block, in steps, when the block starts at step `start` and a frame
+// lasts `increment` steps. This is computed from the start of the block for each frame instead of
+// being accumulated, so that it doesn't drift from the clock, even after a long time, when a frame
+// is shorter than the precision of an `f32` that far.
+fn frame_sixteenth(start: f32, frame: usize, increment: f32) -> f32 {
+    (start as f64 + frame as f64 * increment as f64) as f32
+}
+
+// Messages for a single track.
+#[derive(Debug)]
+enum TrackMessage {
+    Tick((usize, usize)),
+    Velocity((usize, u8)),
+    Probability((usize, u8)),
+    Ratchet((usize, u8)),
+    Accent((usize, bool)),
+    Tie((usize, bool)),
+    Rest((usize, bool)),
+    Euclid { start: usize, pulses: usize, steps: usize, y: usize },
+    Scale(TrackScale),
+    Resize(usize),
+    Clear,
+    ClearBar(usize),
+    Glide(f32),
+    PitchSmoothing(f32),
+    ClockRatio(f32),
+    Rotate(isize),
+    Invert(usize),
+    Reverse,
+    Nudge((usize, i8)),
+    Condition((usize, Condition)),
+}
+
+impl TrackMessage {
+    // Whether this changes the pattern, that is saved, rather than how the track plays it.
+    fn changes_pattern(&self) -> bool {
+        match *self {
+            TrackMessage::Glide(_) | TrackMessage::PitchSmoothing(_) => false,
+            TrackMessage::ClockRatio(_) => false,
+            _ => true,
         }
     }
-    fn up(&mut self, x: usize, y: usize) -> MMMSAction {
-        if y == 0 {
-            self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Nothing;
-            if !self.shift_down() {
+}
+
+/// A change of `track` that waits for the next bar when quantizing, see `Message::Quantized`.
+#[derive(Debug)]
+struct QuantizedChange {
+    track: usize,
+    message: TrackMessage,
+}
+
+#[derive(Debug)]
+enum Message {
+    Track(usize, TrackMessage),
+    Start,
+    Stop,
+    TempoChange(f32, Clock),
+    // go back to the first step on a clock starting from 0, at the next sixteenth
+    Reset(Clock),
+    Transpose(i32),
+    TriggerDuration(f32),
+    Swing(f32),
+    Humanize(f32),
+    Direction(Direction),
+    Dice(Dice),
+    Reroll,
+    // the next loop of each track is a fill
+    Fill,
+    CountIn(usize),
+    Tuning(f32),
+    GateMode(GateMode),
+    NotePriority(MonoNotePriority),
+    Legato(bool),
+    StepsPerBeat(f32),
+    // play a note with this pitch once on a track, outside of the pattern
+    PreviewNote(usize, f32),
+    LoopRegion(usize, usize),
+    Quantize(bool),
+    // a change of the structure of a track, applied at the next bar when quantizing
+    Quantized(usize, TrackMessage),
+    // a change of a track applied when its loop starts again, when playing
+    Looped(usize, TrackMessage),
+    // track, row of the grid, muted
+    MuteRow(usize, usize, bool),
+    // the messages between the two are applied together, so that an edit is never half played
+    BeginBatch,
+    EndBatch,
+}
+
+/// Duration of a sixteenth at `tempo`, in seconds.
+fn sixteenth_duration(tempo: f32) -> f32 {
+    60. / tempo / 4.
+}
+
+/// Clamp a trigger duration between 1ms and the length of a sixteenth at `tempo`.
+fn clamp_trigger_duration(duration: f32, tempo: f32) -> f32 {
+    let max = sixteenth_duration(tempo);
+    clamp(duration, MIN_TRIGGER_DURATION.min(max), max)
+}
+
+/// Map a position in sixteenth to the step being played and the position in this step, in
+/// sixteenth. Odd steps are delayed by `swing` times two thirds of a sixteenth, so that a swing of
+/// 0.5 has a triplet feel. Until the delay has elapsed, the previous step continues.
+fn swing_position(sixteenth: f32, swing: f32) -> (usize, f32) {
+    let step = sixteenth as usize;
+    let fract = sixteenth.fract();
+    if step % 2 == 1 {
+        let delay = swing * 2. / 3.;
+        if fract < delay {
+            return (step - 1, 1. + fract);
+        }
+        return (step, fract - delay);
+    }
+    (step, fract)
+}
+
+/// Whether row `y` is muted in `muted_rows`, that has one bit per row.
+fn row_muted(muted_rows: u128, y: usize) -> bool {
+    y < 128 && muted_rows & (1 << y) != 0
+}
+
+/// The speed in `CLOCK_RATIOS` that is the closest to `ratio`.
+fn clamp_clock_ratio(ratio: f32) -> f32 {
+    let mut closest = 1.;
+    for r in CLOCK_RATIOS.iter() {
+        if (r - ratio).abs() < (closest - ratio).abs() {
+            closest = *r;
+        }
+    }
+    closest
+}
+
+/// The delay of step `step`, in sixteenth, between 0 and `humanize` times `MAX_HUMANIZE_DELAY`. It
+/// only depends on `seed` and the step, so that it's stable for the whole step.
+fn humanize_delay(seed: u32, step: usize, humanize: f32) -> f32 {
+    if humanize <= 0. {
+        return 0.;
+    }
+    let unit = hash(seed, step as u32) as f32 / u32::max_value() as f32;
+    unit * humanize * MAX_HUMANIZE_DELAY
+}
+
+/// When step `step` starts, in sixteenth, swung and delayed by its `humanize_delay`. The delays are
+/// shorter than a step, so a step never starts after the next one.
+fn humanize_onset(step: usize, swing: f32, seed: u32, humanize: f32) -> f32 {
+    let swing_delay = if step % 2 == 1 { swing * 2. / 3. } else { 0. };
+    step as f32 + swing_delay + humanize_delay(seed, step, humanize)
+}
+
+/// Same as `swing_position`, with each step moved by `delay` of it, in sixteenth. The steps must
+/// stay in order, and not move further than half the way to the previous or the next one. Before
+/// the first step starts, it's at its beginning.
+fn delayed_position<F: Fn(usize) -> f32>(sixteenth: f32, swing: f32, delay: F) -> (usize, f32) {
+    let (step, position) = swing_position(sixteenth, swing);
+    let start = sixteenth - position;
+    // the next step can start early, the previous one can end late
+    let next = start + step_length(step, swing) + delay(step + 1);
+    if sixteenth >= next {
+        return (step + 1, sixteenth - next);
+    }
+    let current = start + delay(step);
+    if sixteenth >= current {
+        return (step, sixteenth - current);
+    }
+    if step == 0 {
+        return (0, 0.);
+    }
+    let previous = start - step_length(step - 1, swing) + delay(step - 1);
+    (step - 1, sixteenth - previous)
+}
+
+/// Length of step `step`, in sixteenth, once swung.
+fn step_length(step: usize, swing: f32) -> f32 {
+    let delay = swing * 2. / 3.;
+    if step % 2 == 0 {
+        1. + delay
+    } else {
+        1. - delay
+    }
+}
+
+/// The order in which the steps are played.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    /// Forward then reverse, without repeating the first and last steps.
+    PingPong,
+    Random,
+}
+
+/// When the steps with a probability decide whether they fire.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dice {
+    /// Each time they are played.
+    EveryStep,
+    /// Once, and the pattern loops with the same decisions, until they are rolled again on demand,
+    /// or every this many loops. 0 only rolls them again on demand.
+    Frozen(usize),
+}
+
+/// The chord a grid key adds in chord mode, in the scale of the track, from the key up.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChordType {
+    /// The root, the third and the fifth.
+    Triad,
+    /// A triad and the seventh.
+    Seventh,
+    /// The root, the fourth and the fifth.
+    Sus,
+}
+
+impl ChordType {
+    // The degrees of the notes of the chord, counted in notes of the scale from the root.
+    fn degrees(&self) -> &'static [usize] {
+        match *self {
+            ChordType::Triad => &[0, 2, 4],
+            ChordType::Seventh => &[0, 2, 4, 6],
+            ChordType::Sus => &[0, 3, 4],
+        }
+    }
+    // Triad, seventh, sus, then no chord.
+    fn next(chord: Option<ChordType>) -> Option<ChordType> {
+        match chord {
+            None => Some(ChordType::Triad),
+            Some(ChordType::Triad) => Some(ChordType::Seventh),
+            Some(ChordType::Seventh) => Some(ChordType::Sus),
+            Some(ChordType::Sus) => None,
+        }
+    }
+}
+
+impl Direction {
+    fn next(self) -> Direction {
+        match self {
+            Direction::Forward => Direction::Reverse,
+            Direction::Reverse => Direction::PingPong,
+            Direction::PingPong => Direction::Random,
+            Direction::Random => Direction::Forward,
+        }
+    }
+}
+
+/// Hash an integer, to get random numbers that only depend on a seed and a position.
+fn hash(seed: u32, value: u32) -> u32 {
+    let mut x = seed ^ value;
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+/// A source of random numbers.
+trait Rng {
+    fn next_u32(&mut self) -> u32;
+    // True `percent` percent of the time.
+    fn chance(&mut self, percent: u8) -> bool {
+        self.next_u32() % (MAX_PROBABILITY as u32) < percent as u32
+    }
+    // A number between 0 and `n`, excluded.
+    fn below(&mut self, n: usize) -> usize {
+        self.next_u32() as usize % n
+    }
+    // A number between 0 and 1, excluded.
+    fn unit(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// A xorshift random number generator, cheap enough for the audio thread, and deterministic for
+/// a given seed.
+#[derive(Debug, Clone)]
+struct XorShift {
+    state: u32,
+}
+
+impl XorShift {
+    fn new(seed: u32) -> XorShift {
+        // zero is the only state xorshift can't get out of
+        XorShift { state: if seed == 0 { 1 } else { seed } }
+    }
+}
+
+impl Rng for XorShift {
+    fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+}
+
+/// Map the number of sixteenth elapsed to the step played, in a pattern of `len` steps. In random
+/// mode, the step only depends on `seed` and `raw`, so that it's stable for the whole sixteenth.
+fn direction_step(direction: Direction, seed: u32, raw: usize, len: usize) -> usize {
+    match direction {
+        Direction::Forward => raw % len,
+        Direction::Reverse => len - 1 - raw % len,
+        Direction::PingPong => {
+            if len == 1 {
+                return 0;
+            }
+            let period = 2 * (len - 1);
+            let position = raw % period;
+            if position < len { position } else { period - position }
+        }
+        Direction::Random => hash(seed, raw as u32) as usize % len,
+    }
+}
+
+/// The steps played in a pattern of `len` steps, when looping from `start` to `end`, excluded. The
+/// region is clamped to the pattern, all the steps are played when it's empty.
+fn loop_region(start: usize, end: usize, len: usize) -> (usize, usize) {
+    let end = cmp::min(end, len);
+    if start >= end {
+        return (0, len);
+    }
+    (start, end)
+}
+
+/// Rotate `steps` by `amount` steps, to the right when positive, wrapping around.
+fn rotate<T>(steps: &mut [T], amount: isize) {
+    if steps.is_empty() {
+        return;
+    }
+    let amount = amount.rem_euclid(steps.len() as isize) as usize;
+    steps.rotate_right(amount);
+}
+
+/// The pitch of note `index` of `scale` in volts, moved by `transpose` degrees. Notes that would be
+/// out of the scale stay on its lowest or highest note.
+fn transposed_pitch(scale: &TrackScale, index: u8, transpose: i32) -> f32 {
+    scale.idx_to_cv(transposed_idx(scale, index, transpose)).unwrap()
+}
+
+/// The index of note `index` of `scale` moved by `transpose` degrees, as in `transposed_pitch`.
+fn transposed_idx(scale: &TrackScale, index: u8, transpose: i32) -> usize {
+    let last = scale.note_count() as i32 - 1;
+    clamp(index as i32 + transpose, 0, last) as usize
+}
+
+/// The MIDI note number of a pitch, the closest one for pitches between two semitones. CV is one
+/// volt per octave.
+fn pitch_to_midi(pitch: f32) -> u8 {
+    clamp((pitch * 12. + MIDI_NOTE_AT_ZERO_VOLT).round(), 0., 127.) as u8
+}
+
+/// A note of a pattern exported as MIDI, see `MMMSRenderer::to_midi_events`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MidiEvent {
+    NoteOn { track: usize, note: u8, velocity: u8 },
+    NoteOff { track: usize, note: u8 },
+}
+
+/// The frequency of a pitch, in Hz, A4 being `a4` Hz.
+fn pitch_frequency(pitch: f32, a4: f32) -> f32 {
+    let note = pitch * 12. + MIDI_NOTE_AT_ZERO_VOLT;
+    a4 * (2. as f32).powf((note - 69.) / 12.)
+}
+
+/// Where the sequencer outputs the notes.
+pub enum Output {
+    /// A trigger on an analog or digital port, and the pitch as CV on an analog port.
+    CvGate(BelaPort, BelaPort),
+    /// MIDI messages on channel 1. The renderer can't do IO, so the messages are passed to this
+    /// function on the main thread, in `main_thread_work`.
+    Midi(Box<dyn FnMut(&[u8]) + Send>),
+}
+
+impl From<(BelaPort, BelaPort)> for Output {
+    fn from(ports: (BelaPort, BelaPort)) -> Output {
+        Output::CvGate(ports.0, ports.1)
+    }
+}
+
+/// The renderer side of the MIDI output: the notes currently on, sent to the main thread.
+struct MidiOutput {
+    sender: SyncSender<[u8; 3]>,
+    sounding: SmallVec<[u8; MAX_POLYPHONY]>,
+    // the step and the retrigger in this step last played
+    last_step: Option<(usize, usize)>,
+}
+
+impl MidiOutput {
+    fn note_on(&mut self, note: u8, velocity: u8) {
+        // a note on with a velocity of zero is a note off
+        let velocity = clamp(velocity, 1, MAX_VELOCITY);
+        if self.sender.try_send([0x90, note, velocity]).is_ok() {
+            self.sounding.push(note);
+        }
+    }
+    fn notes_off(&mut self) {
+        while let Some(&note) = self.sounding.last() {
+            if self.sender.try_send([0x80, note, 0]).is_err() {
+                return;
+            }
+            self.sounding.pop();
+        }
+    }
+}
+
+/// MIDI clock and transport messages, following the clock of the renderer. They are queued for the
+/// main thread like the notes.
+struct MidiClock {
+    sender: SyncSender<u8>,
+    // the next clock message to send, counted from the first beat
+    pulse: usize,
+    // a transport message that hasn't been queued yet
+    transport: Option<u8>,
+}
+
+impl MidiClock {
+    fn new(sender: SyncSender<u8>) -> MidiClock {
+        MidiClock {
+            sender,
+            pulse: 0,
+            // the renderer starts playing
+            transport: Some(0xfa),
+        }
+    }
+    // Start from the first beat, continue from elsewhere, or stop.
+    fn set_playing(&mut self, playing: bool, beat: f32) {
+        self.transport = Some(match (playing, beat == 0.) {
+            (false, _) => 0xfc,
+            (true, true) => 0xfa,
+            (true, false) => 0xfb,
+        });
+    }
+    // Queue the transport message if there is one, then the clock messages up to `beat` when
+    // playing. What can't be queued is retried on the next block.
+    fn process(&mut self, beat: f32, playing: bool) {
+        if let Some(message) = self.transport {
+            if self.sender.try_send(message).is_err() {
+                return;
+            }
+            self.transport = None;
+        }
+        if !playing {
+            return;
+        }
+        let pulses = beat * MIDI_CLOCK_PPQN as f32;
+        // the clock has been moved back
+        if self.pulse as f32 > pulses + 1. {
+            self.pulse = pulses.ceil() as usize;
+        }
+        while self.pulse as f32 <= pulses {
+            if self.sender.try_send(0xf8).is_err() {
+                return;
+            }
+            self.pulse += 1;
+        }
+    }
+}
+
+/// How often a note is repeated while the note repeat keys are held.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RepeatRate {
+    /// Every eighth note.
+    Eighth,
+    /// Three times per beat.
+    EighthTriplet,
+    /// Every sixteenth note, the default.
+    Sixteenth,
+    /// Six times per beat.
+    SixteenthTriplet,
+}
+
+impl RepeatRate {
+    // The time between two repeats, in beats.
+    fn beats(&self) -> f32 {
+        match *self {
+            RepeatRate::Eighth => 1. / 2.,
+            RepeatRate::EighthTriplet => 1. / 3.,
+            RepeatRate::Sixteenth => 1. / 4.,
+            RepeatRate::SixteenthTriplet => 1. / 6.,
+        }
+    }
+}
+
+/// An arrow key held on the control row, whose move repeats after a while.
+struct HeldMove {
+    x: usize,
+    mouve: (isize, isize),
+    since: time::Instant,
+    // the moves already repeated
+    repeats: usize,
+}
+
+impl HeldMove {
+    // The number of repeats there should have been at `now`.
+    fn due(&self, now: time::Instant) -> usize {
+        let held = now.duration_since(self.since);
+        let delay = time::Duration::from_millis(MOVE_REPEAT_DELAY);
+        if held < delay {
+            return 0;
+        }
+        let repeated = held - delay;
+        let interval = MOVE_REPEAT_INTERVAL as u128;
+        1 + (repeated.as_millis() / interval) as usize
+    }
+}
+
+/// When to repeat the selected note, on the main thread, following the clock.
+struct NoteRepeat {
+    rate: RepeatRate,
+    // the beat of the next repeat, while repeating
+    next: Option<f32>,
+}
+
+impl NoteRepeat {
+    fn new() -> NoteRepeat {
+        NoteRepeat { rate: RepeatRate::Sixteenth, next: None }
+    }
+    // The beats of the repeats up to `beat`, since the last call. The first one is on the first
+    // subdivision at or after `beat`, so that the repeats are on the clock.
+    fn repeats(&mut self, beat: f32) -> Vec<f32> {
+        let rate = self.rate.beats();
+        let mut next = match self.next {
+            Some(next) => next,
+            None => (beat / rate).ceil() * rate,
+        };
+        let mut repeats = Vec::new();
+        while next <= beat {
+            repeats.push(next);
+            next += rate;
+        }
+        self.next = Some(next);
+        repeats
+    }
+    fn stop(&mut self) {
+        self.next = None;
+    }
+}
+
+/// Where `MMMSRenderer` writes the triggers, the pitch CV and the velocity of a block, and reads
+/// the external clock. The Bela `Context` is one, `MockSink` records what is written, to run the
+/// renderer off the device.
+pub trait AudioSink {
+    /// Number of audio frames in the block, that the clock advances by.
+    fn audio_frames(&self) -> usize;
+    fn analog_sample_rate(&self) -> f32;
+    fn analog_frames(&self) -> usize;
+    fn analog_out_channels(&self) -> usize;
+    /// The analog outputs of the block, interleaved, from 0.0 to 1.0.
+    fn analog_out(&mut self) -> &mut [f32];
+    fn digital_sample_rate(&self) -> f32;
+    fn digital_frames(&self) -> usize;
+    /// Set digital pin `channel`, that is an output, at `frame` and until the end of the block.
+    fn digital_write_once(&mut self, frame: usize, channel: usize, value: u32);
+    /// Whether digital pin `channel`, that is an input, is high at `frame`.
+    fn digital_read(&self, frame: usize, channel: usize) -> bool;
+    fn analog_in_channels(&self) -> usize;
+    /// The analog inputs of the block, interleaved, from 0.0 to 1.0.
+    fn analog_in(&self) -> &[f32];
+}
+
+impl AudioSink for Context {
+    fn audio_frames(&self) -> usize {
+        Context::audio_frames(self)
+    }
+    fn analog_sample_rate(&self) -> f32 {
+        Context::analog_sample_rate(self)
+    }
+    fn analog_frames(&self) -> usize {
+        Context::analog_frames(self)
+    }
+    fn analog_out_channels(&self) -> usize {
+        Context::analog_out_channels(self)
+    }
+    fn analog_out(&mut self) -> &mut [f32] {
+        Context::analog_out(self)
+    }
+    fn digital_sample_rate(&self) -> f32 {
+        Context::digital_sample_rate(self)
+    }
+    fn digital_frames(&self) -> usize {
+        Context::digital_frames(self)
+    }
+    fn digital_write_once(&mut self, frame: usize, channel: usize, value: u32) {
+        Context::digital_write_once(self, frame, channel, value)
+    }
+    fn digital_read(&self, frame: usize, channel: usize) -> bool {
+        Context::digital_read(self, frame, channel)
+    }
+    fn analog_in_channels(&self) -> usize {
+        Context::analog_in_channels(self)
+    }
+    fn analog_in(&self) -> &[f32] {
+        Context::analog_in(self)
+    }
+}
+
+/// An `AudioSink` in memory, with the rates and the number of channels of a Bela: the analog
+/// channels run at half the audio rate. Each block overwrites what the previous one wrote.
+pub struct MockSink {
+    /// Number of audio and digital frames in a block.
+    pub frames: usize,
+    /// Audio and digital sample rate.
+    pub sample_rate: f32,
+    pub analog_out_channels: usize,
+    /// The analog outputs of the last block, interleaved.
+    pub analog_out: Vec<f32>,
+    /// One bit per digital pin for each frame of the last block, set when the pin is high.
+    pub digital_out: Vec<u16>,
+    /// Same as `digital_out`, for the pins read as inputs, written by the caller.
+    pub digital_in: Vec<u16>,
+    pub analog_in_channels: usize,
+    /// The analog inputs of the next block, interleaved, written by the caller.
+    pub analog_in: Vec<f32>,
+}
+
+impl MockSink {
+    /// Blocks of `frames` audio frames at 44.1kHz, with eight analog outputs and inputs.
+    pub fn new(frames: usize) -> MockSink {
+        MockSink {
+            frames,
+            sample_rate: 44100.,
+            analog_out_channels: 8,
+            analog_out: vec![0.; frames / 2 * 8],
+            digital_out: vec![0; frames],
+            digital_in: vec![0; frames],
+            analog_in_channels: 8,
+            analog_in: vec![0.; frames / 2 * 8],
+        }
+    }
+    /// The value of analog output `channel` at analog `frame` of the last block.
+    pub fn analog(&self, frame: usize, channel: usize) -> f32 {
+        self.analog_out[frame * self.analog_out_channels + channel]
+    }
+    /// Whether digital pin `channel` was high at `frame` of the last block.
+    pub fn digital(&self, frame: usize, channel: usize) -> bool {
+        self.digital_out[frame] & (1 << channel) != 0
+    }
+}
+
+impl AudioSink for MockSink {
+    fn audio_frames(&self) -> usize {
+        self.frames
+    }
+    fn analog_sample_rate(&self) -> f32 {
+        self.sample_rate / 2.
+    }
+    fn analog_frames(&self) -> usize {
+        self.frames / 2
+    }
+    fn analog_out_channels(&self) -> usize {
+        self.analog_out_channels
+    }
+    fn analog_out(&mut self) -> &mut [f32] {
+        &mut self.analog_out
+    }
+    fn digital_sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+    fn digital_frames(&self) -> usize {
+        self.frames
+    }
+    fn digital_write_once(&mut self, frame: usize, channel: usize, value: u32) {
+        for bits in self.digital_out[frame..].iter_mut() {
+            if value != 0 {
+                *bits |= 1 << channel;
+            } else {
+                *bits &= !(1 << channel);
+            }
+        }
+    }
+    fn digital_read(&self, frame: usize, channel: usize) -> bool {
+        self.digital_in[frame] & (1 << channel) != 0
+    }
+    fn analog_in_channels(&self) -> usize {
+        self.analog_in_channels
+    }
+    fn analog_in(&self) -> &[f32] {
+        &self.analog_in
+    }
+}
+
+/// Errors when configuring the outputs of the sequencer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MmmsError {
+    /// The trigger port is neither an analog output nor a digital pin.
+    InvalidTriggerPort,
+    /// The pitch port is not an analog output.
+    InvalidPitchPort,
+    /// The velocity port is not an analog output.
+    InvalidVelocityPort,
+    /// The accent port is neither an analog output nor a digital pin.
+    InvalidAccentPort,
+    /// The sync port is neither an analog output nor a digital pin.
+    InvalidSyncPort,
+    /// The transpose CV is not on an analog input.
+    InvalidTransposePort,
+    /// There is no track with this index.
+    InvalidTrack,
+    /// There must be between one and four tracks.
+    InvalidTrackCount,
+    /// The external clock is not on a digital pin.
+    InvalidClockPort,
+    /// The grid is neither 8 nor 16 keys wide and high.
+    InvalidGridSize,
+    /// The offsets of a custom scale don't increase from 0 within its period, its period isn't
+    /// positive, or it has too few or too many notes.
+    InvalidScale,
+    /// A control map doesn't have one key for scale and one for shift, at the same place with
+    /// shift held, or it is wider than the grid.
+    InvalidControlMap,
+    /// There is no step with this index in the pattern.
+    InvalidStep,
+    /// The OSC port can't be listened on, for example because it's taken.
+    #[cfg(feature = "osc")]
+    OscUnavailable(io::ErrorKind),
+}
+
+impl fmt::Display for MmmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MmmsError::InvalidTriggerPort => {
+                write!(f, "the trigger must be on an analog output or a digital pin")
+            }
+            MmmsError::InvalidPitchPort => write!(f, "the pitch must be on an analog output"),
+            MmmsError::InvalidVelocityPort => write!(f, "the velocity must be on an analog output"),
+            MmmsError::InvalidAccentPort => {
+                write!(f, "the accent must be on an analog output or a digital pin")
+            }
+            MmmsError::InvalidSyncPort => {
+                write!(f, "the sync pulse must be on an analog output or a digital pin")
+            }
+            MmmsError::InvalidTransposePort => {
+                write!(f, "the transpose CV must be on an analog input")
+            }
+            MmmsError::InvalidTrack => write!(f, "there is no such track"),
+            MmmsError::InvalidClockPort => write!(f, "the external clock must be on a digital pin"),
+            MmmsError::InvalidGridSize => write!(f, "the grid must be 8 or 16 keys wide and high"),
+            MmmsError::InvalidScale => write!(
+                f,
+                "the offsets of a custom scale must increase from 0 within a positive period, \
+                 for {} to {} notes",
+                MIN_SCALE_NOTES,
+                MAX_SCALE_NOTES
+            ),
+            MmmsError::InvalidControlMap => {
+                write!(f, "the control map must fit the grid, with a scale and a shift key")
+            }
+            MmmsError::InvalidTrackCount => {
+                write!(f, "there must be between one and {} tracks", MAX_TRACKS)
+            }
+            MmmsError::InvalidStep => write!(f, "there is no such step"),
+            #[cfg(feature = "osc")]
+            MmmsError::OscUnavailable(kind) => {
+                write!(f, "could not listen for OSC messages: {}", io::Error::from(kind))
+            }
+        }
+    }
+}
+
+impl std::error::Error for MmmsError {}
+
+/// The channel of an analog output, or an error if `port` isn't one.
+fn analog_out_channel(port: BelaPort, error: MmmsError) -> Result<usize, MmmsError> {
+    match port {
+        BelaPort::AnalogOut(channel) => Ok(channel),
+        _ => Err(error),
+    }
+}
+
+/// The port of a gate, or an error if `port` is neither an analog output nor a digital pin.
+fn gate_port(port: BelaPort, error: MmmsError) -> Result<TriggerPort, MmmsError> {
+    match port {
+        BelaPort::AnalogOut(n) => Ok(TriggerPort::Analog(n)),
+        BelaPort::Digital(n) => Ok(TriggerPort::Digital(n)),
+        _ => Err(error),
+    }
+}
+
+/// How the pitch CV, in octaves, maps to an analog output, that goes from 0.0 to 1.0. On a Bela,
+/// 1.0 is 5V, or 10V with an analog gain of two, that is the default, at one volt per octave.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CvRange {
+    /// Volts between a note and the same note an octave higher.
+    pub volts_per_octave: f32,
+    /// Volts when the analog output is at 1.0.
+    pub max_volts: f32,
+}
+
+impl CvRange {
+    // The value of the analog output for `octaves` above the lowest note, the pitches that are out
+    // of the range are clamped.
+    fn normalize(&self, octaves: f32) -> f32 {
+        self.output(octaves * self.volts_per_octave)
+    }
+    // The value of the analog output for `volts`, clamped.
+    fn output(&self, volts: f32) -> f32 {
+        clamp(volts / self.max_volts, 0., 1.)
+    }
+}
+
+impl Default for CvRange {
+    fn default() -> CvRange {
+        CvRange { volts_per_octave: 1., max_volts: 10. }
+    }
+}
+
+/// The brightness of the keys of the grid, from 0 (off) to 15, depending on what they show.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Palette {
+    /// The rows of the tonics.
+    pub tonic: u8,
+    /// The rows of the dominants.
+    pub dominant: u8,
+    /// The rows of the leading tones.
+    pub leading: u8,
+    /// A note.
+    pub note: u8,
+    /// A note, on a step played earlier or later.
+    pub nudged: u8,
+    /// A note, on an accented step.
+    pub accent: u8,
+    /// A note continuing from a step on the left, on a tied step.
+    pub held: u8,
+    /// A rest.
+    pub rest: u8,
+    /// The column of the step being played.
+    pub playhead: u8,
+    /// The metronome, on each beat.
+    pub beat: u8,
+    /// The metronome, on the first beat of each bar.
+    pub bar: u8,
+    /// The rows of each degree, from the tonic to the seventh, in `ViewMode::Degrees`.
+    pub degrees: [u8; 7],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            tonic: 10,
+            dominant: 6,
+            leading: 4,
+            note: 12,
+            nudged: 13,
+            accent: 15,
+            held: 7,
+            rest: 2,
+            playhead: 4,
+            beat: 8,
+            bar: 15,
+            degrees: [10, 1, 5, 3, 6, 8, 4],
+        }
+    }
+}
+
+/// Which rows are lit when they have no notes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ViewMode {
+    /// The tonics, the dominants and the leading tones, to find the octaves.
+    Functions,
+    /// All the degrees, each in its own brightness, to find the intervals.
+    Degrees,
+}
+
+/// How long the trigger output is high for each note.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum GateMode {
+    /// A trigger of the duration set with `MMMS`, the default.
+    Trigger,
+    /// A gate that lasts the whole step, and goes low just before the next one.
+    Gate,
+    /// A gate that lasts this percentage of the step, from 1 to 100, whatever the tempo. It still
+    /// goes low just before the next step.
+    Length(f32),
+}
+
+/// Repeats of the triggers of a track, on its trigger output, for dub style delays.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TriggerDelay {
+    /// The time between a trigger and its first repeat, and between the repeats, from 1 to 16
+    /// steps of the track: 3 is a dotted eighth with sixteenth steps.
+    pub delay_time: f32,
+    /// The level of each repeat relative to the one before, from 0 to 0.9. The repeats stop under
+    /// a tenth of the trigger: 0.3 repeats once, 0.5 three times. The level of the repeats is
+    /// only heard on the analog outputs, the digital pins are always high.
+    pub feedback: f32,
+    /// The repeats also play the pitch of their note on the pitch CV.
+    pub pitch: bool,
+}
+
+/// How the pitch CV follows the pitch.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PitchTrackingMode {
+    /// The CV goes up by `CvRange::volts_per_octave` each octave.
+    VoltPerOctave,
+    /// The CV is proportional to the frequency, one volt being this frequency in Hz. The CV doubles
+    /// each octave.
+    HzPerVolt(f32),
+}
+
+/// Which note of a step with several notes the pitch CV plays.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MonoNotePriority {
+    /// The lowest note, the default, as a bass line would.
+    Lowest,
+    /// The highest note.
+    Highest,
+    /// The note added to the step last.
+    Last,
+}
+
+impl MonoNotePriority {
+    // The note of `notes` played, in the order in which they were added to the step.
+    fn note<'a, I: Iterator<Item = &'a u8>>(&self, notes: I) -> Option<&'a u8> {
+        match *self {
+            MonoNotePriority::Lowest => notes.min(),
+            MonoNotePriority::Highest => notes.max(),
+            MonoNotePriority::Last => notes.last(),
+        }
+    }
+}
+
+/// What drives the sequencer.
+pub enum ClockSource {
+    /// The clock of the sequencer, at the tempo set with `MMMS::set_tempo`.
+    Internal,
+    /// Rising edges on a digital pin, each edge is a sixteenth.
+    External(BelaPort),
+}
+
+/// Follows rising edges on a digital input. The position in a sixteenth is estimated from the
+/// time between the last two edges, and stops at the end of the sixteenth if the next edge is
+/// late.
+struct ExternalClock {
+    channel: usize,
+    previous: bool,
+    edges: usize,
+    // in frames
+    since_edge: usize,
+    period: Option<usize>,
+    sample_rate: f32,
+}
+
+impl ExternalClock {
+    fn new(channel: usize) -> ExternalClock {
+        ExternalClock {
+            channel,
+            previous: false,
+            edges: 0,
+            since_edge: 0,
+            period: None,
+            sample_rate: 44100.,
+        }
+    }
+    // Look for rising edges in the digital input of this block.
+    fn process<S: AudioSink>(&mut self, context: &S) {
+        self.sample_rate = context.digital_sample_rate();
+        for frame in 0..context.digital_frames() {
+            let high = context.digital_read(frame, self.channel);
+            self.input(high);
+        }
+    }
+    // A frame of the digital input.
+    fn input(&mut self, high: bool) {
+        self.since_edge += 1;
+        if high && !self.previous {
+            if self.edges > 0 {
+                self.period = Some(self.since_edge);
+            }
+            self.edges += 1;
+            self.since_edge = 0;
+        }
+        self.previous = high;
+    }
+    // The position in sixteenth, the first edge being the start of the first sixteenth.
+    fn sixteenth(&self) -> f32 {
+        if self.edges == 0 {
+            return 0.;
+        }
+        let fract = match self.period {
+            Some(period) => (self.since_edge as f32 / period as f32).min(0.999),
+            None => 0.,
+        };
+        (self.edges - 1) as f32 + fract
+    }
+    // The duration of a sixteenth, in seconds, if it's known.
+    fn sixteenth_duration(&self) -> Option<f32> {
+        self.period.map(|period| period as f32 / self.sample_rate)
+    }
+}
+
+/// Where the trigger is output, once validated.
+#[derive(Debug, Copy, Clone)]
+enum TriggerPort {
+    Analog(usize),
+    Digital(usize),
+}
+
+enum RendererOutput {
+    CvGate { trigger: TriggerPort, pitch_channel: usize },
+    Midi(MidiOutput),
+}
+
+/// Events sent by the audio thread, printed on the main thread, for debugging purposes.
+#[cfg(feature = "debug-events")]
+#[derive(Debug)]
+enum DebugEvent {
+    // track, step
+    Step(usize, usize),
+    Disconnected,
+}
+
+impl fmt::Debug for MMMSRenderer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MMMSRenderer")
+            .field("tempo", &self.tempo)
+            .field("steps_per_beat", &self.steps_per_beat)
+            .field("playing", &self.playing)
+            .field("beat", &self.clock_consumer.beat())
+            .field("tracks", &self.tracks)
+            .finish()
+    }
+}
+
+// The repeats of a trigger: the step of the track at which the first one starts, its level, and
+// the pitch CV they play, if they do, see `TriggerDelay`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Echo {
+    at: f32,
+    level: f32,
+    pitch: Option<f32>,
+}
+
+// When a step starts, see `Track::onset`, with what it depends on.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Onset {
+    raw: usize,
+    nudge: i8,
+    swing: f32,
+    humanize: f32,
+    at: f32,
+}
+
+/// How many onsets a track remembers: the step playing, and the ones before and after it.
+const ONSET_CACHE_SIZE: usize = 3;
+
+/// A sequence of steps, and where it's played.
+struct Track {
+    // the notes are indices in `scale`, the lowest note being 0. Room for the longest pattern, so
+    // that resizing doesn't allocate on the audio thread
+    steps: SmallVec<[Step<u8>; MAX_STEPS]>,
+    scale: TrackScale,
+    output: RendererOutput,
+    // analog output channel of the velocity
+    velocity_channel: Option<usize>,
+    // gate of the accented steps
+    accent_port: Option<TriggerPort>,
+    // added to the velocity of the accented steps
+    accent_velocity_boost: u8,
+    // the repeats of the triggers, the ones that are playing or waiting to, and the last step that
+    // has been repeated
+    trigger_delay: Option<TriggerDelay>,
+    echoes: [Option<Echo>; ECHO_QUEUE_SIZE],
+    echoed: Option<usize>,
+    prev_pitch: f32,
+    // the pitch CV, going towards `prev_pitch` by `glide_increment` each frame
+    pitch_out: f32,
+    glide_increment: f32,
+    // time to go from a pitch to the next, in seconds, 0 to jump
+    glide_time: f32,
+    // time constant of the lowpass filter after the glide, in seconds, 0 to leave the pitch CV as
+    // is, and its last output
+    pitch_smoothing: f32,
+    smoothed_pitch: f32,
+    // the pitch of a note played outside of the pattern, and the frames played of it
+    preview: Option<(f32, usize)>,
+    prev_velocity: f32,
+    // speed of the track relative to the clock
+    clock_ratio: f32,
+    // the onsets of the last steps played, at `raw % ONSET_CACHE_SIZE`, that are needed on
+    // every frame
+    onsets: Cell<[Option<Onset>; ONSET_CACHE_SIZE]>,
+    // the last two steps played, and whether they fired
+    rolls: [Option<(usize, bool)>; 2],
+    // the last two steps played, and whether a note was sounding, fired or tied
+    held: [Option<(usize, bool)>; 2],
+    // the steps played, from `loop_start` to `loop_end`, excluded
+    loop_start: usize,
+    loop_end: usize,
+    // the rows of the grid that don't play, one bit per row
+    muted_rows: u128,
+    // with frozen dice, a number below `MAX_PROBABILITY` per step, that fires if it's below its
+    // probability, and the loop during which they have been rolled, `None` to roll them again
+    dice: [u8; MAX_STEPS],
+    dice_loop: Option<usize>,
+    // a fill has been asked for, and the loop that is a fill once it has started
+    fill_armed: bool,
+    fill_loop: Option<usize>,
+    // the changes waiting for the next loop, allocated upfront, and the loop of the last block
+    looped: Vec<TrackMessage>,
+    current_loop: usize,
+    // the sixteenth from which the pattern plays from its first step, moved when the changes
+    // waiting for the next loop are applied, shared with the control side
+    origin: Arc<AtomicUsize>,
+    #[cfg(feature = "debug-events")]
+    debug_last_step: usize
+}
+
+impl Track {
+    fn new(output: RendererOutput, scale: TrackScale) -> Track {
+        let mut steps = SmallVec::<[Step<u8>; MAX_STEPS]>::new();
+        steps.resize(INITIAL_STEPS, Step::new());
+        Track {
+            steps,
+            scale,
+            output,
+            velocity_channel: None,
+            accent_port: None,
+            accent_velocity_boost: 0,
+            trigger_delay: None,
+            echoes: [None; ECHO_QUEUE_SIZE],
+            echoed: None,
+            prev_pitch: 0.0,
+            pitch_out: 0.0,
+            glide_increment: 0.0,
+            glide_time: 0.0,
+            pitch_smoothing: 0.0,
+            smoothed_pitch: 0.0,
+            preview: None,
+            prev_velocity: 0.0,
+            clock_ratio: 1.0,
+            onsets: Cell::new([None; ONSET_CACHE_SIZE]),
+            rolls: [None, None],
+            held: [None, None],
+            loop_start: 0,
+            loop_end: MAX_STEPS,
+            muted_rows: 0,
+            dice: [0; MAX_STEPS],
+            dice_loop: None,
+            fill_armed: false,
+            fill_loop: None,
+            looped: Vec::with_capacity(QUANTIZE_QUEUE_SIZE),
+            current_loop: 0,
+            origin: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "debug-events")]
+            debug_last_step: 0
+        }
+    }
+    fn handle_message(&mut self, msg: TrackMessage) {
+        match msg {
+            TrackMessage::Tick((x, y)) => {
+                self.press(x, y);
+            }
+            TrackMessage::Velocity((x, velocity)) => {
+                self.set_velocity(x, velocity);
+            }
+            TrackMessage::Probability((x, probability)) => {
+                self.set_probability(x, probability);
+            }
+            TrackMessage::Ratchet((x, ratchet)) => {
+                self.set_ratchet(x, ratchet);
+            }
+            TrackMessage::Accent((x, accent)) => {
+                self.steps[x].accent = accent;
+            }
+            TrackMessage::Tie((x, tie)) => {
+                self.steps[x].tie = tie;
+            }
+            TrackMessage::Rest((x, rest)) => {
+                self.steps[x].set_rest(rest);
+            }
+            TrackMessage::Euclid { start, pulses, steps, y } => {
+                self.euclid(start, pulses, steps, y);
+            }
+            TrackMessage::Resize(new_size) => {
+                self.resize(new_size)
+            }
+            TrackMessage::Clear => {
+                self.clear();
+            }
+            TrackMessage::ClearBar(bar) => {
+                self.clear_bar(bar);
+            }
+            TrackMessage::Glide(glide_time) => {
+                self.set_glide(glide_time);
+            }
+            TrackMessage::PitchSmoothing(time) => {
+                self.set_pitch_smoothing(time);
+            }
+            TrackMessage::ClockRatio(ratio) => {
+                self.set_clock_ratio(ratio);
+            }
+            TrackMessage::Scale(scale) => {
+                self.set_scale(scale);
+            }
+            TrackMessage::Rotate(amount) => {
+                rotate(&mut self.steps, amount);
+            }
+            TrackMessage::Invert(y) => {
+                self.invert(y);
+            }
+            TrackMessage::Reverse => {
+                self.steps.reverse();
+            }
+            TrackMessage::Nudge((x, nudge)) => {
+                self.steps[x].nudge = clamp(nudge, -MAX_NUDGE, MAX_NUDGE);
+            }
+            TrackMessage::Condition((x, condition)) => {
+                self.steps[x].condition = condition.clamped();
+            }
+        }
+    }
+    fn press(&mut self, x: usize, y: usize) {
+        let index = self.scale.note_count() - 1 - y;
+        self.steps[x].toggle(index as u8);
+    }
+    fn mute_row(&mut self, y: usize, muted: bool) {
+        if y < 128 {
+            if muted {
+                self.muted_rows |= 1 << y;
+            } else {
+                self.muted_rows &= !(1 << y);
+            }
+        }
+    }
+    // Whether `note`, an index in the scale, is on a muted row.
+    fn muted(&self, note: u8) -> bool {
+        row_muted(self.muted_rows, self.scale.note_count() - 1 - note as usize)
+    }
+    fn invert(&mut self, y: usize) {
+        let count = self.scale.note_count();
+        let pivot = (count - 1 - y) as u8;
+        for step in self.steps.iter_mut() {
+            step.map_notes(|note| reflect(*note, pivot, count));
+        }
+    }
+    fn set_velocity(&mut self, x: usize, velocity: u8) {
+        self.steps[x].velocity = clamp(velocity, 0, MAX_VELOCITY);
+    }
+    // The velocity step `index` is played at, raised when it's accented.
+    fn output_velocity(&self, index: usize) -> u8 {
+        let step = &self.steps[index];
+        if !step.accent {
+            return step.velocity;
+        }
+        cmp::min(step.velocity as usize + self.accent_velocity_boost as usize,
+                 MAX_VELOCITY as usize) as u8
+    }
+    fn set_probability(&mut self, x: usize, probability: u8) {
+        self.steps[x].probability = clamp(probability, 0, MAX_PROBABILITY);
+    }
+    fn set_ratchet(&mut self, x: usize, ratchet: u8) {
+        self.steps[x].ratchet = clamp(ratchet, 1, MAX_RATCHET);
+    }
+    fn euclid(&mut self, start: usize, pulses: usize, steps: usize, y: usize) {
+        let bar = euclid_bar(pulses, steps);
+        for i in 0..16 {
+            if start + i >= self.steps.len() {
+                break;
+            }
+            self.steps[start + i].clear_notes();
+            if bar[i] {
+                self.press(start + i, y);
+            }
+        }
+    }
+    fn set_glide(&mut self, glide_time: f32) {
+        self.glide_time = clamp(glide_time, 0.0, MAX_GLIDE_TIME);
+    }
+    fn set_pitch_smoothing(&mut self, time: f32) {
+        self.pitch_smoothing = clamp(time, 0.0, MAX_PITCH_SMOOTHING);
+    }
+    fn set_clock_ratio(&mut self, ratio: f32) {
+        self.clock_ratio = clamp_clock_ratio(ratio);
+        // the steps are counted differently, forget the rolls
+        self.rolls = [None, None];
+        self.held = [None, None];
+        self.restart(0);
+    }
+    fn set_loop_region(&mut self, start: usize, end: usize) {
+        self.loop_start = start;
+        self.loop_end = end;
+        // the steps played change, forget the rolls
+        self.rolls = [None, None];
+        self.held = [None, None];
+    }
+    fn set_scale(&mut self, scale: TrackScale) {
+        for i in self.steps.iter_mut() {
+            i.clear_notes();
+        }
+        self.scale = scale;
+    }
+    // Anything longer than the longest pattern is cut, so that it stays inline.
+    fn resize(&mut self, new_size: usize) {
+        self.steps.resize(clamp(new_size, 1, MAX_STEPS), Step::new());
+    }
+    fn clear(&mut self) {
+        for i in self.steps.iter_mut() {
+            *i = Step::new();
+        }
+    }
+    fn clear_bar(&mut self, bar: usize) {
+        for i in self.steps.iter_mut().skip(bar * 16).take(16) {
+            *i = Step::new();
+        }
+    }
+    fn print_seq(&self) {
+        for step in self.steps.iter() {
+            match step.notes().iter().min() {
+                Some(index) => {
+                    print!("{:.3}\t", transposed_pitch(&self.scale, *index, 0));
+                }
+                None => {
+                    print!("  \t");
+                }
+            }
+        }
+        println!("");
+    }
+    // When the step played at `raw` starts, in sixteenth, moved by its nudge. A step doesn't move
+    // further than half the way to the previous or the next one, so that they stay in order.
+    fn onset(&self, raw: usize, direction: Direction, swing: f32, humanize: f32) -> f32 {
+        let nudge = self.steps[self.step_at(raw, direction)].nudge;
+        let mut onsets = self.onsets.get();
+        let slot = raw % ONSET_CACHE_SIZE;
+        if let Some(cached) = onsets[slot] {
+            if (cached.raw, cached.nudge, cached.swing, cached.humanize) ==
+                (raw, nudge, swing, humanize) {
+                return cached.at;
+            }
+        }
+        let onset = |raw| humanize_onset(raw, swing, HUMANIZE_SEED, humanize);
+        let start = onset(raw);
+        let at = if nudge == 0 {
+            start
+        } else {
+            let earliest = if raw > 0 { (onset(raw - 1) + start) / 2. } else { start - 0.5 };
+            let latest = (start + onset(raw + 1)) / 2.;
+            clamp(start + nudge as f32 / 8., earliest + 0.001, latest - 0.001)
+        };
+        onsets[slot] = Some(Onset { raw, nudge, swing, humanize, at });
+        self.onsets.set(onsets);
+        at
+    }
+    // The step played after `raw` sixteenth, in the loop region.
+    fn step_at(&self, raw: usize, direction: Direction) -> usize {
+        let (start, end) = loop_region(self.loop_start, self.loop_end, self.steps.len());
+        start + direction_step(direction, DIRECTION_SEED, raw, end - start)
+    }
+    // Whether the step played at `raw` has notes and passes its probability roll. The roll happens
+    // once per step played, and is remembered for this step and the previous one, since both can
+    // be in the same block. With frozen dice, the rolls of all the steps are made at once, when
+    // the loop of `raw` is `loops` loops after the previous ones, or after `reroll`.
+    fn fires(&mut self, raw: usize, direction: Direction, dice: Dice, rng: &mut XorShift) -> bool {
+        for roll in self.rolls.iter() {
+            if let Some((step, fires)) = *roll {
+                if step == raw {
+                    return fires;
+                }
+            }
+        }
+        let current = self.loop_at(raw);
+        if self.fill_armed {
+            self.fill_armed = false;
+            self.fill_loop = Some(current + 1);
+        }
+        if let Dice::Frozen(loops) = dice {
+            let stale = match self.dice_loop {
+                Some(rolled) => loops > 0 && current >= rolled + loops,
+                None => true,
+            };
+            if stale {
+                for roll in self.dice.iter_mut() {
+                    *roll = rng.below(MAX_PROBABILITY as usize) as u8;
+                }
+            }
+            // the transport restarted from the beginning, the rolls are kept
+            if stale || self.dice_loop.map_or(false, |rolled| current < rolled) {
+                self.dice_loop = Some(current);
+            }
+        }
+        let index = self.step_at(raw, direction);
+        let step = &self.steps[index];
+        let muted = step.notes().iter().all(|note| self.muted(*note));
+        let fill = self.fill_loop == Some(current);
+        let fires = match step.probability {
+            _ if step.is_empty() || step.tie || muted => false,
+            _ if !step.condition.met(current, fill) => false,
+            0 => false,
+            p if p >= MAX_PROBABILITY => true,
+            p if dice != Dice::EveryStep => self.dice[index] < p,
+            p => rng.chance(p),
+        };
+        self.rolls = [self.rolls[1], Some((raw, fires))];
+        fires
+    }
+    // The loop of the pattern during which the step at `raw` is played, from 0.
+    fn loop_at(&self, raw: usize) -> usize {
+        let (start, end) = loop_region(self.loop_start, self.loop_end, self.steps.len());
+        raw / (end - start)
+    }
+    // Wait for the next loop to apply `msg`. A full queue is applied now, so that the changes stay
+    // in order.
+    fn queue_looped(&mut self, msg: TrackMessage) {
+        if self.looped.len() == self.looped.capacity() {
+            self.apply_looped();
+        }
+        self.looped.push(msg);
+    }
+    // Apply the changes waiting for the next loop, in order.
+    fn apply_looped(&mut self) {
+        let mut looped = mem::replace(&mut self.looped, Vec::new());
+        for msg in looped.drain(..) {
+            self.handle_message(msg);
+        }
+        self.looped = looped;
+    }
+    // Apply the changes waiting for the next loop if a loop starts at `sixteenth`, of the clock,
+    // or when stopped. When playing, the new pattern starts from its first step, wherever the
+    // clock is in it.
+    fn loop_wrap_at(&mut self, sixteenth: f32, playing: bool) {
+        let current = self.loop_at(self.local(sixteenth) as usize);
+        if current != self.current_loop || !playing {
+            if playing && !self.looped.is_empty() {
+                let (start, end) = loop_region(self.loop_start, self.loop_end, self.steps.len());
+                let origin = self.origin.load(Ordering::Relaxed) + current * (end - start);
+                // a fill of the loop that starts stays one
+                self.fill_loop = self.fill_loop.filter(|fill| *fill == current).map(|_| 0);
+                self.restart(origin);
+            }
+            self.apply_looped();
+        }
+        self.current_loop = self.loop_at(self.local(sixteenth) as usize);
+    }
+    // Play the first step of the pattern at sixteenth `origin` of the clock.
+    fn restart(&mut self, origin: usize) {
+        self.origin.store(origin, Ordering::Relaxed);
+        // the steps are counted from there, forget what has been remembered about them
+        self.rolls = [None, None];
+        self.held = [None, None];
+        self.onsets.set([None; ONSET_CACHE_SIZE]);
+    }
+    // The sixteenth of the pattern played at `sixteenth`, of the clock, see `restart`.
+    fn local(&self, sixteenth: f32) -> f32 {
+        sixteenth - self.origin.load(Ordering::Relaxed) as f32
+    }
+    // Whether the step played at `raw` is a rest.
+    fn rests(&self, raw: usize, direction: Direction) -> bool {
+        self.steps[self.step_at(raw, direction)].is_rest()
+    }
+    // Repeat the trigger of the step played at `raw`, that starts at `sixteenth`, with the pitch
+    // CV `pitch`, once per step played. The trigger doesn't repeat if too many triggers are
+    // already repeating.
+    fn schedule_echo(&mut self, raw: usize, sixteenth: f32, pitch: f32) {
+        let delay = match self.trigger_delay {
+            Some(delay) if self.echoed != Some(raw) && delay.feedback >= MIN_ECHO_LEVEL => delay,
+            _ => {
+                return;
+            }
+        };
+        self.echoed = Some(raw);
+        if let Some(slot) = self.echoes.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(Echo {
+                at: sixteenth + delay.delay_time,
+                level: delay.feedback,
+                pitch: if delay.pitch { Some(pitch) } else { None },
+            });
+        }
+    }
+    // The level and the pitch CV of the repeat playing at `sixteenth`, if any, a repeat lasting
+    // `window` steps.
+    fn echo_at(&self, sixteenth: f32, window: f32) -> Option<(f32, Option<f32>)> {
+        let delay = match self.trigger_delay {
+            Some(delay) => delay,
+            None => {
+                return None;
+            }
+        };
+        self.echoes.iter().filter_map(|echo| *echo).filter_map(|echo| {
+            if sixteenth < echo.at {
+                return None;
+            }
+            let repeat = ((sixteenth - echo.at) / delay.delay_time) as i32;
+            let position = sixteenth - echo.at - repeat as f32 * delay.delay_time;
+            let level = echo.level * delay.feedback.powi(repeat);
+            if position < window && level >= MIN_ECHO_LEVEL {
+                Some((level, echo.pitch))
+            } else {
+                None
+            }
+        }).next()
+    }
+    // Forget the triggers that have finished repeating at `sixteenth`.
+    fn expire_echoes(&mut self, sixteenth: f32) {
+        let delay = match self.trigger_delay {
+            Some(delay) => delay,
+            None => {
+                return;
+            }
+        };
+        for slot in self.echoes.iter_mut() {
+            if let Some(echo) = *slot {
+                let repeat = ((sixteenth - echo.at) / delay.delay_time).floor();
+                let level = echo.level * delay.feedback.powi(repeat as i32);
+                if repeat >= 1. && level < MIN_ECHO_LEVEL {
+                    *slot = None;
+                }
+            }
+        }
+    }
+    // Stop all the repeats, when the playback stops or the clock starts over.
+    fn clear_echoes(&mut self) {
+        self.echoes = [None; ECHO_QUEUE_SIZE];
+        self.echoed = None;
+    }
+    // Set the pitch CV to `value`, without gliding.
+    fn jump(&mut self, value: f32) {
+        self.prev_pitch = value;
+        self.pitch_out = value;
+    }
+    // Whether the step played at `raw` is tied to the previous one.
+    fn tied(&self, raw: usize, direction: Direction) -> bool {
+        self.steps[self.step_at(raw, direction)].tie
+    }
+    // Whether a note sounds during the step played at `raw`: it fires, or it's tied to a step during
+    // which a note sounds. Tied steps don't fire.
+    fn sounds(&mut self, raw: usize, direction: Direction, dice: Dice, rng: &mut XorShift) -> bool {
+        let mut previous = None;
+        for held in self.held.iter() {
+            if let Some((step, sounds)) = *held {
+                if step == raw {
+                    return sounds;
+                }
+                if step + 1 == raw {
+                    previous = Some(sounds);
+                }
+            }
+        }
+        let sounds = if self.tied(raw, direction) {
+            match previous {
+                Some(sounds) => sounds,
+                // started on a tied step, only look at the step before
+                None => raw > 0 && self.fires(raw - 1, direction, dice, rng),
+            }
+        } else {
+            self.fires(raw, direction, dice, rng)
+        };
+        self.held = [self.held[1], Some((raw, sounds))];
+        sounds
+    }
+    // The next value of the pitch CV, `period` seconds later, when `target` is played, or when
+    // nothing is if it's `None`. A new pitch is reached linearly in `glide_time`.
+    fn glide(&mut self, target: Option<f32>, period: f32) -> f32 {
+        if let Some(value) = target {
+            if value != self.prev_pitch {
+                self.prev_pitch = value;
+                let frames = self.glide_time / period;
+                if frames < 1. {
+                    self.pitch_out = value;
+                }
+                self.glide_increment = (value - self.pitch_out).abs() / frames.max(1.);
+            }
+        }
+        let distance = self.prev_pitch - self.pitch_out;
+        if distance.abs() <= self.glide_increment {
+            self.pitch_out = self.prev_pitch;
+        } else {
+            self.pitch_out += self.glide_increment * distance.signum();
+        }
+        self.pitch_out
+    }
+    // The next value of the pitch CV going through a one pole lowpass filter, `period` seconds
+    // later, so that the jumps don't click. `value` is returned as is without smoothing.
+    fn smooth(&mut self, value: f32, period: f32) -> f32 {
+        if self.pitch_smoothing == 0. {
+            self.smoothed_pitch = value;
+        } else {
+            let coefficient = 1. - (-period / self.pitch_smoothing).exp();
+            self.smoothed_pitch += (value - self.smoothed_pitch) * coefficient;
+        }
+        self.smoothed_pitch
+    }
+    // The number of triggers of the step played at `raw`. A tied step has no trigger of its own.
+    fn ratchets(&self, raw: usize, direction: Direction) -> usize {
+        let step = &self.steps[self.step_at(raw, direction)];
+        if step.tie {
+            1
+        } else {
+            step.ratchet as usize
+        }
+    }
+    // The retrigger of the step played at `raw` that `position` falls in, the position in this
+    // retrigger and the duration of its trigger, in sixteenth. Retriggers are evenly spaced, and
+    // their trigger lasts at most half of their length, so that they stay distinct and the last one
+    // ends before the next step, whatever the tempo. `trigger_duration` is in sixteenth.
+    fn ratchet_at(&self, raw: usize, position: f32, direction: Direction, swing: f32,
+                  trigger_duration: f32) -> (usize, f32, f32) {
+        let ratchet = self.ratchets(raw, direction);
+        if ratchet <= 1 {
+            return (0, position, trigger_duration);
+        }
+        let length = step_length(raw, swing) / ratchet as f32;
+        let index = clamp((position / length) as usize, 0, ratchet - 1);
+        (index, position - index as f32 * length, trigger_duration.min(length / 2.))
+    }
+}
+
+// The size of the pattern and how many steps have notes, rather than all the steps.
+impl fmt::Debug for Track {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let active = self.steps.iter().filter(|step| !step.is_empty()).count();
+        f.debug_struct("Track")
+            .field("steps", &self.steps.len())
+            .field("active_steps", &active)
+            .field("scale", &format_args!("{}", self.scale))
+            .field("loop", &(self.loop_start..cmp::min(self.loop_end, self.steps.len())))
+            .finish()
+    }
+}
+
+pub struct MMMSRenderer {
+    clock_updater: ClockUpdater,
+    clock_consumer: ClockConsumer,
+    receiver: Receiver<Message>,
+    tempo: f32,
+    tracks: SmallVec<[Track; MAX_TRACKS]>,
+    playing: bool,
+    trigger_duration: f32,
+    swing: f32,
+    // amount of random delay of the steps, from 0 to 1
+    humanize: f32,
+    direction: Direction,
+    // decides if the steps with a probability fire
+    rng: XorShift,
+    dice: Dice,
+    // when set, the clock follows it instead of running at `tempo`
+    external_clock: Option<ExternalClock>,
+    // the tempo of `clock_consumer`, set by the control side, that is `tempo` unless the clock
+    // follows an external one
+    clock_tempo: f32,
+    // in scale degrees, applied to all the notes played
+    transpose: i32,
+    cv_range: CvRange,
+    pitch_tracking: PitchTrackingMode,
+    // frequency of A4, in Hz
+    tuning_a4: f32,
+    gate_mode: GateMode,
+    note_priority: MonoNotePriority,
+    // value of the analog gates when high, from 0 to 1
+    trigger_level: f32,
+    // the analog frames the analog gates take to go up or down, 0 for an edge, and the last value
+    // of each analog output
+    trigger_edge_ramp: usize,
+    analog_levels: [f32; ANALOG_OUT_CHANNELS],
+    // a trigger at the beginning of each loop of the first track
+    sync_port: Option<TriggerPort>,
+    // the analog input of the transpose CV, and the transposition it is at, in scale degrees,
+    // added to `transpose`
+    transpose_cv_channel: Option<usize>,
+    cv_transpose: i32,
+    // the clock starting from 0 to switch to, and the sixteenth of the current clock at which to
+    // switch
+    reset: Option<(f32, Clock)>,
+    // the bars of clicks when the playback starts, and the frames of the count in already played,
+    // `None` once the pattern plays
+    count_in_bars: usize,
+    count_in: Option<usize>,
+    // the gate isn't retriggered between consecutive notes
+    legato: bool,
+    // the resolution of the steps, at a clock ratio of 1, four for sixteenths
+    steps_per_beat: f32,
+    // the changes wait for the next bar in `quantized`, allocated upfront
+    quantize: bool,
+    quantized: Vec<QuantizedChange>,
+    // the bar of the last block rendered
+    bar: usize,
+    // the messages of an edit wait for its end in `batch`, allocated upfront. An edit that doesn't
+    // fit is dropped whole at its end, and counted for the control side
+    batching: bool,
+    batch: Vec<Message>,
+    batch_overflow: bool,
+    dropped_batches: Arc<AtomicUsize>,
+    // false once the control side is gone, the last pattern received plays on
+    control_connected: bool,
+    // the blocks rendered late or with another size than the first one, read by the control side
+    overruns: Arc<AtomicUsize>,
+    block_frames: Option<usize>,
+    midi_clock: Option<MidiClock>,
+    #[cfg(feature = "debug-events")]
+    debug_sender: SyncSender<DebugEvent>
+}
+
+impl MMMSRenderer {
+    fn new(
+        tempo: f32,
+        clock_updater: ClockUpdater,
+        clock_consumer: ClockConsumer,
+        receiver: Receiver<Message>,
+        tracks: SmallVec<[Track; MAX_TRACKS]>,
+        #[cfg(feature = "debug-events")]
+        debug_sender: SyncSender<DebugEvent>
+    ) -> MMMSRenderer {
+        MMMSRenderer {
+            receiver,
+            clock_updater,
+            clock_consumer,
+            tempo,
+            tracks,
+            playing: true,
+            trigger_duration: DEFAULT_TRIGGER_DURATION,
+            swing: 0.0,
+            humanize: 0.0,
+            direction: Direction::Forward,
+            rng: XorShift::new(PROBABILITY_SEED),
+            dice: Dice::EveryStep,
+            external_clock: None,
+            clock_tempo: tempo,
+            transpose: 0,
+            cv_range: CvRange::default(),
+            pitch_tracking: PitchTrackingMode::VoltPerOctave,
+            tuning_a4: DEFAULT_TUNING,
+            gate_mode: GateMode::Trigger,
+            note_priority: MonoNotePriority::Lowest,
+            trigger_level: 1.0,
+            trigger_edge_ramp: 0,
+            analog_levels: [0.; ANALOG_OUT_CHANNELS],
+            sync_port: None,
+            transpose_cv_channel: None,
+            cv_transpose: 0,
+            reset: None,
+            count_in_bars: 0,
+            count_in: None,
+            legato: false,
+            steps_per_beat: 4.,
+            quantize: false,
+            quantized: Vec::with_capacity(QUANTIZE_QUEUE_SIZE),
+            bar: 0,
+            batching: false,
+            batch: Vec::with_capacity(BATCH_QUEUE_SIZE),
+            batch_overflow: false,
+            dropped_batches: Arc::new(AtomicUsize::new(0)),
+            control_connected: true,
+            overruns: Arc::new(AtomicUsize::new(0)),
+            block_frames: None,
+            midi_clock: None,
+            #[cfg(feature = "debug-events")]
+            debug_sender
+        }
+    }
+    /// The tempo, in beats per minute.
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+    /// Whether the control side, `MMMS`, still exists. Once it is gone, the renderer plays the
+    /// last pattern it received, until a new pair is created.
+    pub fn control_connected(&self) -> bool {
+        self.control_connected
+    }
+    /// The number of steps of `track`. Panics if there is no such track.
+    pub fn steps_count(&self, track: usize) -> usize {
+        self.tracks[track].steps.len()
+    }
+    /// Snap `input_volts`, in volts per octave, to the closest note of the current scale of
+    /// `track`, for example to quantize an external CV. Panics if there is no such track.
+    pub fn quantize_cv(&self, track: usize, input_volts: f32) -> f32 {
+        self.tracks[track].scale.quantize_cv(input_volts)
+    }
+    /// The notes of one loop of each track, as MIDI note on and note off events, with the beat at
+    /// which they happen from the start of the loop, in order. The steps are played in the
+    /// current direction, swung and nudged, with their retriggers and the length of the gates, and
+    /// the tied steps hold the note. Every step with notes fires, whatever its probability, and
+    /// the muted rows are left out.
+    pub fn to_midi_events(&self) -> Vec<(f64, MidiEvent)> {
+        let mut events = Vec::new();
+        for (index, track) in self.tracks.iter().enumerate() {
+            let (start, end) = loop_region(track.loop_start, track.loop_end, track.steps.len());
+            let ratio = self.steps_per_beat as f64 * track.clock_ratio as f64;
+            let beat = |sixteenth: f32| sixteenth as f64 / ratio;
+            for raw in 0..end - start {
+                let step_index = track.step_at(raw, self.direction);
+                let step = &track.steps[step_index];
+                if step.tie || step.is_empty() {
+                    continue;
+                }
+                let mut next = raw + 1;
+                while next < end - start && track.tied(next, self.direction) {
+                    next += 1;
+                }
+                let onset = track.onset(raw, self.direction, self.swing, 0.);
+                let ratchets = track.ratchets(raw, self.direction);
+                let length = step_length(raw, self.swing) / ratchets as f32;
+                for retrigger in 0..ratchets {
+                    let position = retrigger as f32 * length;
+                    let (_, _, gate) = self.ratchet_at(index, raw, position);
+                    let off = if retrigger + 1 == ratchets && next > raw + 1 {
+                        track.onset(next, self.direction, self.swing, 0.)
+                    } else {
+                        onset + position + gate
+                    };
+                    for note in step.notes().iter().filter(|note| !track.muted(**note)) {
+                        let transpose = self.transpose + self.cv_transpose;
+                        let pitch = transposed_pitch(&track.scale, *note, transpose);
+                        let note = pitch_to_midi(pitch);
+                        let velocity = clamp(track.output_velocity(step_index), 1, MAX_VELOCITY);
+                        let on = MidiEvent::NoteOn { track: index, note, velocity };
+                        events.push((beat(onset + position), on));
+                        events.push((beat(off), MidiEvent::NoteOff { track: index, note }));
+                    }
+                }
+            }
+        }
+        // a note ends before the same note starts again
+        events.sort_by(|a, b| {
+            let on = |event: &MidiEvent| match *event {
+                MidiEvent::NoteOff { .. } => 0,
+                MidiEvent::NoteOn { .. } => 1,
+            };
+            a.0.partial_cmp(&b.0).unwrap().then(on(&a.1).cmp(&on(&b.1)))
+        });
+        events
+    }
+    /// The step of `track` being played. Panics if there is no such track.
+    pub fn playhead(&self, track: usize) -> usize {
+        let track = &self.tracks[track];
+        let sixteenth = self.clock_consumer.beat() * self.steps_per_beat * track.clock_ratio;
+        track.step_at(track.local(sixteenth) as usize, self.direction)
+    }
+    /// The scale of `track`, the chromatic scale on its root for a custom scale. Panics if there
+    /// is no such track.
+    pub fn scale(&self, track: usize) -> &Scale {
+        self.tracks[track].scale.as_scale()
+    }
+    /// The scale of `track`, standard or custom. Panics if there is no such track.
+    pub fn track_scale(&self, track: usize) -> &TrackScale {
+        &self.tracks[track].scale
+    }
+    /// The steps of `track`, and the lowest pitch of each of them, transposed, `None` when a step
+    /// has no notes. The pitches of a custom scale are the closest semitones. Panics if there is
+    /// no such track.
+    pub fn steps<'a>(&'a self, track: usize) -> impl Iterator<Item = (usize, Option<Pitch>)> + 'a {
+        let scale = &self.tracks[track].scale;
+        self.lowest_notes(track).map(move |(x, index)| {
+            (x, index.and_then(|index| scale.idx_to_pitch(index)))
+        })
+    }
+    /// The steps of `track`, and the lowest pitch of each of them in volts, transposed, `None`
+    /// when a step has no notes. Panics if there is no such track.
+    pub fn steps_cv<'a>(&'a self, track: usize) -> impl Iterator<Item = (usize, Option<f32>)> + 'a {
+        let scale = &self.tracks[track].scale;
+        self.lowest_notes(track).map(move |(x, index)| {
+            (x, index.and_then(|index| scale.idx_to_cv(index)))
+        })
+    }
+    // The steps of `track`, and the index of the lowest note of each of them, transposed.
+    fn lowest_notes<'a>(
+        &'a self,
+        track: usize,
+    ) -> impl Iterator<Item = (usize, Option<usize>)> + 'a {
+        let track = &self.tracks[track];
+        let transpose = self.transpose + self.cv_transpose;
+        track.steps.iter().enumerate().map(move |(x, step)| {
+            let lowest = step.notes().iter().min();
+            (x, lowest.map(|index| transposed_idx(&track.scale, *index, transpose)))
+        })
+    }
+    /// The lowest pitch of step `index` of `track` in volts, before the transposition. `None` when
+    /// the step has no notes, or when there is no such track or step.
+    pub fn get_step(&self, track: usize, index: usize) -> Option<f32> {
+        let track = self.tracks.get(track)?;
+        let note = track.steps.get(index)?.notes().iter().min()?;
+        track.scale.idx_to_cv(*note as usize)
+    }
+    /// Make step `index` of `track` play the note of its scale closest to `pitch`, in volts, or
+    /// nothing when `None`, instead of its notes. This only changes what is played, use
+    /// `MMMS::set_step` to also change the grid.
+    pub fn set_step(&mut self, track: usize, index: usize, pitch: Option<f32>)
+                    -> Result<(), MmmsError> {
+        let track = self.tracks.get_mut(track).ok_or(MmmsError::InvalidTrack)?;
+        if index >= track.steps.len() {
+            return Err(MmmsError::InvalidStep);
+        }
+        let note = pitch.map(|pitch| track.scale.nearest_idx(pitch) as u8);
+        let step = &mut track.steps[index];
+        step.clear_notes();
+        if let Some(note) = note {
+            step.toggle(note);
+        }
+        Ok(())
+    }
+    /// Output the velocity of each step of `track` on `port`, that must be an analog output.
+    pub fn set_velocity_port(&mut self, track: usize, port: BelaPort) -> Result<(), MmmsError> {
+        let channel = analog_out_channel(port, MmmsError::InvalidVelocityPort)?;
+        match self.tracks.get_mut(track) {
+            Some(track) => {
+                track.velocity_channel = Some(channel);
+                Ok(())
+            }
+            None => Err(MmmsError::InvalidTrack),
+        }
+    }
+    /// Output a gate on `port` with the accented steps of `track`, on an analog output or a digital
+    /// pin. This works for all tracks, including the ones that output MIDI.
+    pub fn set_accent_port(&mut self, track: usize, port: BelaPort) -> Result<(), MmmsError> {
+        let port = gate_port(port, MmmsError::InvalidAccentPort)?;
+        match self.tracks.get_mut(track) {
+            Some(track) => {
+                track.accent_port = Some(port);
+                Ok(())
+            }
+            None => Err(MmmsError::InvalidTrack),
+        }
+    }
+    /// Add `boost` to the velocity of the accented steps of `track`, on its velocity output and
+    /// in MIDI, clamped to 127. The accent gate fires as well.
+    pub fn set_accent_velocity_boost(&mut self, track: usize, boost: u8) -> Result<(), MmmsError> {
+        match self.tracks.get_mut(track) {
+            Some(track) => {
+                track.accent_velocity_boost = boost;
+                Ok(())
+            }
+            None => Err(MmmsError::InvalidTrack),
+        }
+    }
+    /// Repeat the triggers of `track` on its trigger output, `None` to stop repeating them. The
+    /// delay time and the feedback are clamped to their ranges. MIDI tracks don't repeat.
+    pub fn set_trigger_delay(&mut self, track: usize, delay: Option<TriggerDelay>)
+                             -> Result<(), MmmsError> {
+        let delay = delay.map(|delay| TriggerDelay {
+            delay_time: clamp(delay.delay_time, MIN_DELAY_TIME, MAX_DELAY_TIME),
+            feedback: clamp(delay.feedback, 0., MAX_FEEDBACK),
+            pitch: delay.pitch,
+        });
+        match self.tracks.get_mut(track) {
+            Some(track) => {
+                track.trigger_delay = delay;
+                track.clear_echoes();
+                Ok(())
+            }
+            None => Err(MmmsError::InvalidTrack),
+        }
+    }
+    /// Output a trigger on `port` at the beginning of each loop of the first track, an analog
+    /// output or a digital pin, to reset other modules.
+    pub fn set_sync_port(&mut self, port: BelaPort) -> Result<(), MmmsError> {
+        self.sync_port = Some(gate_port(port, MmmsError::InvalidSyncPort)?);
+        Ok(())
+    }
+    /// Transpose all the tracks by the CV on `port`, an analog input, for example the pitch CV of
+    /// a keyboard, in the volts per octave of the CV range. The CV is quantized to the degrees of
+    /// the scale of the first track, and adds to the transposition set with `MMMS`.
+    pub fn set_transpose_cv_port(&mut self, port: BelaPort) -> Result<(), MmmsError> {
+        match port {
+            BelaPort::AnalogIn(channel) => {
+                self.transpose_cv_channel = Some(channel);
+                Ok(())
+            }
+            _ => Err(MmmsError::InvalidTransposePort),
+        }
+    }
+    /// How the pitch CV of all the tracks maps to the analog outputs.
+    pub fn set_cv_range(&mut self, range: CvRange) {
+        self.cv_range = range;
+    }
+    /// Whether the pitch CV of all the tracks is in volts per octave, or in Hz per volt.
+    pub fn set_pitch_tracking(&mut self, mode: PitchTrackingMode) {
+        self.pitch_tracking = mode;
+    }
+    /// Tune the pitch CV of all the tracks to A4 at `a4` Hz, between 400 and 480Hz. MIDI is
+    /// unaffected.
+    pub fn set_tuning(&mut self, a4: f32) {
+        self.tuning_a4 = clamp(a4, MIN_TUNING, MAX_TUNING);
+    }
+    /// The value of the gates on the analog outputs when they are high, between 0 and 1, the full
+    /// range of the outputs by default. The digital pins are always high.
+    pub fn set_trigger_level(&mut self, level: f32) {
+        self.trigger_level = clamp(level, 0.0, 1.0);
+    }
+    /// The number of analog frames the gates on the analog outputs take to go up or down, in a
+    /// straight line, so that they don't alias. 0, the default, switches them at once. The digital
+    /// pins switch at once.
+    pub fn set_trigger_edge_ramp(&mut self, frames: usize) {
+        self.trigger_edge_ramp = frames;
+    }
+    /// Output triggers, or gates that last as long as the steps or a part of them, on all the
+    /// tracks.
+    pub fn set_gate_mode(&mut self, mode: GateMode) {
+        self.gate_mode = match mode {
+            GateMode::Length(gate_length_pct) => GateMode::Length(clamp(gate_length_pct, 1., 100.)),
+            mode => mode,
+        };
+    }
+    /// Which note the pitch CV of the tracks plays when a step has several. MIDI plays them all.
+    pub fn set_note_priority(&mut self, priority: MonoNotePriority) {
+        self.note_priority = priority;
+    }
+    /// Click on each beat for `bars` bars when the playback starts, up to four, before playing the
+    /// patterns. The clicks are on the sync port when there is one, and on the gates of the tracks
+    /// otherwise. 0 starts right away.
+    pub fn set_count_in(&mut self, bars: usize) {
+        self.count_in_bars = cmp::min(bars, MAX_COUNT_IN_BARS);
+    }
+    /// In legato, the gate stays high from a note to the note of the next step, only the pitch
+    /// changes, gliding if a glide time is set. The other notes don't glide.
+    pub fn set_legato(&mut self, legato: bool) {
+        self.legato = legato;
+    }
+    /// The number of steps per beat at a clock ratio of 1, between 1 and 8: 4 for sixteenths, the
+    /// default, 3 for eighth triplets, 2 for eighths. The bars stay four beats long.
+    pub fn set_steps_per_beat(&mut self, steps: f32) {
+        self.steps_per_beat = clamp(steps, MIN_STEPS_PER_BEAT, MAX_STEPS_PER_BEAT);
+        // the steps are counted differently
+        for track in self.tracks.iter_mut() {
+            track.restart(0);
+        }
+    }
+    /// Apply the changes of the structure of the patterns, like a new scale, a new length or a
+    /// pattern loaded from the bank, at the next bar, instead of immediately.
+    pub fn set_quantize(&mut self, quantize: bool) {
+        self.quantize = quantize;
+        if !quantize {
+            self.apply_quantized();
+        }
+    }
+    /// Follow the internal clock, or rising edges on a digital pin. With an external clock, tempo
+    /// changes are ignored, and the sequencer doesn't advance if the edges stop.
+    pub fn set_clock_source(&mut self, source: ClockSource) -> Result<(), MmmsError> {
+        self.external_clock = match source {
+            ClockSource::Internal => None,
+            ClockSource::External(BelaPort::Digital(channel)) => Some(ExternalClock::new(channel)),
+            ClockSource::External(_) => {
+                return Err(MmmsError::InvalidClockPort);
+            }
+        };
+        Ok(())
+    }
+    fn set_tempo(&mut self, new_tempo: f32) {
+        self.tempo = new_tempo;
+        // a trigger can't be longer than a step
+        let trigger_duration = self.trigger_duration;
+        self.set_trigger_duration(trigger_duration);
+    }
+    // Continue on `clock`, running at `tempo`, from the current position.
+    fn set_clock(&mut self, mut clock: Clock, tempo: f32) {
+        let beat = self.clock_consumer.beat();
+        let late = beat - clock.consumer.beat();
+        if late > 0. {
+            clock.updater.increment(beats_to_frames(late, tempo));
+        }
+        self.clock_tempo = tempo;
+        self.clock_updater = clock.updater;
+        self.clock_consumer = clock.consumer;
+    }
+    // Play `pitch` once on `track` for the duration of a trigger, ending the note played before if
+    // there is one.
+    fn preview(&mut self, track: usize, pitch: f32) {
+        let track = &mut self.tracks[track];
+        if let (Some((previous, elapsed)), &mut RendererOutput::Midi(ref mut midi)) =
+            (track.preview, &mut track.output) {
+            if elapsed > 0 {
+                let _ = midi.sender.try_send([0x80, pitch_to_midi(previous), 0]);
+            }
+        }
+        track.preview = Some((pitch, 0));
+    }
+    // Go back to the first step on `clock` at the next sixteenth, right away when stopped.
+    fn reset(&mut self, clock: Clock) {
+        let sixteenth = self.clock_consumer.beat() * self.steps_per_beat;
+        let at = if self.playing { sixteenth.ceil() } else { sixteenth };
+        self.reset = Some((at, clock));
+        self.apply_reset();
+    }
+    // Follow the transpose CV, averaged over the block. The transposition only changes when the CV
+    // is clearly closer to another degree, so that noise doesn't make it flutter.
+    fn read_transpose_cv<S: AudioSink>(&mut self, context: &S) {
+        let channel = match self.transpose_cv_channel {
+            Some(channel) => channel,
+            None => return,
+        };
+        let (channels, frames) = (context.analog_in_channels(), context.analog_frames());
+        if channel >= channels || frames == 0 {
+            return;
+        }
+        let input = context.analog_in();
+        let sum: f32 = (0..frames).map(|i| input[i * channels + channel]).sum();
+        let octaves = sum / frames as f32 * ANALOG_IN_MAX_VOLTS / self.cv_range.volts_per_octave;
+        let scale = &self.tracks[0].scale;
+        let root = scale.idx_to_cv(0).unwrap_or(0.);
+        let distance = |degree: i32| {
+            let degree = clamp(degree, 0, scale.note_count() as i32 - 1) as usize;
+            (scale.idx_to_cv(degree).unwrap_or(0.) - root - octaves).abs()
+        };
+        let nearest = scale.nearest_idx(root + octaves) as i32;
+        if distance(self.cv_transpose) - distance(nearest) > TRANSPOSE_CV_HYSTERESIS {
+            self.cv_transpose = nearest;
+        }
+    }
+    // Switch to the clock of the reset once the sixteenth of the reset is reached, advanced by
+    // what has been played since.
+    fn apply_reset(&mut self) {
+        let sixteenth = self.clock_consumer.beat() * self.steps_per_beat;
+        match self.reset {
+            Some((at, _)) if sixteenth >= at => {}
+            _ => {
+                return;
+            }
+        }
+        if let Some((at, mut clock)) = self.reset.take() {
+            let late = (sixteenth - at) / self.steps_per_beat;
+            clock.updater.increment(beats_to_frames(late, self.clock_tempo));
+            self.clock_updater = clock.updater;
+            self.clock_consumer = clock.consumer;
+            for track in self.tracks.iter_mut() {
+                track.restart(0);
+            }
+        }
+        for track in self.tracks.iter_mut() {
+            track.clear_echoes();
+        }
+    }
+    fn set_trigger_duration(&mut self, duration: f32) {
+        self.trigger_duration = clamp_trigger_duration(duration, self.tempo);
+    }
+    fn set_swing(&mut self, swing: f32) {
+        self.swing = clamp(swing, 0.0, MAX_SWING);
+    }
+    fn set_humanize(&mut self, humanize: f32) {
+        self.humanize = clamp(humanize, 0.0, 1.0);
+    }
+    // The step being played at `sixteenth` on `track`, and the position in this step, swung,
+    // humanized and nudged.
+    fn position(&self, track: usize, sixteenth: f32) -> (usize, f32) {
+        let track = &self.tracks[track];
+        delayed_position(sixteenth, self.swing, |raw| {
+            let swung = humanize_onset(raw, self.swing, HUMANIZE_SEED, 0.);
+            track.onset(raw, self.direction, self.swing, self.humanize) - swung
+        })
+    }
+    fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+    }
+    /// When the steps with a probability decide whether they fire.
+    pub fn set_dice(&mut self, dice: Dice) {
+        self.dice = dice;
+        self.reroll();
+    }
+    // Roll the frozen dice of all the tracks again, for the next step played.
+    fn reroll(&mut self) {
+        for track in self.tracks.iter_mut() {
+            track.dice_loop = None;
+        }
+    }
+    fn set_playing(&mut self, playing: bool) {
+        if playing != self.playing {
+            if let Some(ref mut clock) = self.midi_clock {
+                clock.set_playing(playing, self.clock_consumer.beat());
+            }
+            self.count_in = if playing && self.count_in_bars > 0 { Some(0) } else { None };
+            for track in self.tracks.iter_mut() {
+                track.clear_echoes();
+            }
+        }
+        self.playing = playing;
+    }
+    // Only play the steps from `start` to `end`, excluded, on all the tracks. The region is
+    // clamped to the length of each track, an empty region plays the whole pattern.
+    fn set_loop_region(&mut self, start: usize, end: usize) {
+        for track in self.tracks.iter_mut() {
+            track.set_loop_region(start, end);
+        }
+    }
+    // Signal to the main thread that a new step is playing on a track. This never blocks nor
+    // allocates, the event is dropped if the queue is full.
+    #[cfg(feature = "debug-events")]
+    fn debug_step(&mut self, sixteenth: f32) {
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            let raw = track.local(sixteenth * track.clock_ratio) as usize;
+            let step = track.step_at(raw, self.direction);
+            if self.playing && step != track.debug_last_step && !track.steps[step].is_empty() {
+                let _ = self.debug_sender.try_send(DebugEvent::Step(index, step));
+            }
+            track.debug_last_step = step;
+        }
+    }
+}
+
+impl InstrumentRenderer for MMMSRenderer {
+    fn render(&mut self, context: &mut Context) {
+        self.render_to(context);
+    }
+}
+
+impl MMMSRenderer {
+    /// Render a block to `sink`, the Bela `Context` on the device, `MockSink` elsewhere.
+    pub fn render_to<S: AudioSink>(&mut self, context: &mut S) {
+        let start = time::Instant::now();
+        self.render_block(context);
+        self.count_overrun(context.audio_frames(), start.elapsed());
+    }
+    // Count the block of `frames` frames rendered in `elapsed` if it took longer than it lasts, or
+    // if it doesn't have the size of the first block, without blocking.
+    fn count_overrun(&mut self, frames: usize, elapsed: time::Duration) {
+        let expected = *self.block_frames.get_or_insert(frames);
+        let budget = time::Duration::from_secs_f64(frames as f64 / CLOCK_RATE as f64);
+        if frames != expected || elapsed > budget {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    fn render_block<S: AudioSink>(&mut self, context: &mut S) {
+        self.receive();
+        let counting_in = self.count_in.is_some();
+
+        let frames = context.audio_frames();
+        // The clock shared with the control side follows the external clock, when there is one.
+        if let Some(ref mut external) = self.external_clock {
+            external.process(context);
+            let late = external.sixteenth() / 4. - self.clock_consumer.beat();
+            if self.playing && late > 0. {
+                self.clock_updater.increment(beats_to_frames(late, self.clock_tempo));
+            }
+        }
+        self.apply_reset();
+        self.read_transpose_cv(context);
+        let beat = self.clock_consumer.beat();
+        self.quantize_at(beat);
+        for track in self.tracks.iter_mut() {
+            track.loop_wrap_at(beat * self.steps_per_beat * track.clock_ratio, self.playing);
+        }
+        if let Some(ref mut clock) = self.midi_clock {
+            clock.process(beat, self.playing);
+        }
+
+        #[cfg(feature = "debug-events")]
+        self.debug_step(beat * self.steps_per_beat);
+
+        for track in 0..self.tracks.len() {
+            if let Some(port) = self.tracks[track].accent_port {
+                self.render_gate(track, context, beat, port, true);
+            }
+            match self.tracks[track].output {
+                RendererOutput::CvGate { .. } => {
+                    self.render_cv(track, context, beat);
+                }
+                RendererOutput::Midi(_) => {
+                    let period = 1. / context.digital_sample_rate();
+                    self.render_midi(track, frames, period, beat);
+                }
+            }
+            self.render_preview(track, context);
+        }
+        if let Some(port) = self.sync_port {
+            if counting_in {
+                self.render_count_in(context, port);
+            } else {
+                self.render_sync(context, beat, port);
+            }
+        }
+
+        // Don't advance the clock while stopped, so that playback resumes where it was paused,
+        // nor during the count in, so that the patterns start from where they are once it's over.
+        if counting_in {
+            self.advance_count_in(frames);
+        } else if self.playing && self.external_clock.is_none() {
+            self.clock_updater.increment(frames);
+        }
+    }
+    // Whether the notes are played: the transport is playing and the count in is over.
+    fn audible(&self) -> bool {
+        self.playing && self.count_in.is_none()
+    }
+    // Handle the messages sent by the main thread since the last block. An edit that is still
+    // being sent waits for the next block, the block is rendered with the pattern as it was before.
+    fn receive(&mut self) {
+        if !self.control_connected {
+            return;
+        }
+        loop {
+            match self.receiver.try_recv() {
+                Ok(msg) => {
+                    self.handle_message(msg);
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {
+                    return;
+                }
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    self.control_connected = false;
+                    #[cfg(feature = "debug-events")]
+                    let _ = self.debug_sender.try_send(DebugEvent::Disconnected);
+                    return;
+                }
+            }
+        }
+    }
+    fn handle_message(&mut self, msg: Message) {
+        match msg {
+            Message::BeginBatch => {
+                self.batching = true;
+            }
+            Message::EndBatch => {
+                self.batching = false;
+                if self.batch_overflow {
+                    self.drop_batch();
+                } else {
+                    self.apply_batch();
+                }
+            }
+            msg => {
+                if self.batching {
+                    self.defer(msg);
+                } else {
+                    self.apply_message(msg);
+                }
+            }
+        }
+    }
+    // Wait for the end of the edit to apply `msg`. When the batch is full, the edit is dropped at
+    // its end rather than half applied.
+    fn defer(&mut self, msg: Message) {
+        if self.batch.len() == self.batch.capacity() {
+            self.batch_overflow = true;
+            return;
+        }
+        self.batch.push(msg);
+    }
+    // Forget the edit that doesn't fit in the batch, and count it.
+    fn drop_batch(&mut self) {
+        self.batch.clear();
+        self.batch_overflow = false;
+        self.dropped_batches.fetch_add(1, Ordering::Relaxed);
+    }
+    // Apply the messages of the edit, in order.
+    fn apply_batch(&mut self) {
+        let mut batch = mem::replace(&mut self.batch, Vec::new());
+        for msg in batch.drain(..) {
+            self.apply_message(msg);
+        }
+        self.batch = batch;
+    }
+    fn apply_message(&mut self, msg: Message) {
+        match msg {
+            Message::BeginBatch | Message::EndBatch => {}
+            Message::Track(track, message) => {
+                // the edits made after a change that waits for the next bar wait with it
+                let change = QuantizedChange { track, message };
+                if self.quantized.is_empty() {
+                    self.apply(change);
+                } else {
+                    self.queue(change);
+                }
+            }
+            Message::Quantized(track, message) => {
+                let change = QuantizedChange { track, message };
+                if !self.quantize || !self.playing {
+                    self.apply(change);
+                } else {
+                    self.queue(change);
+                }
+            }
+            Message::Looped(track, message) => {
+                match self.tracks.get_mut(track) {
+                    Some(track) if self.playing => track.queue_looped(message),
+                    _ => self.apply(QuantizedChange { track, message }),
+                }
+            }
+            Message::Quantize(quantize) => {
+                self.set_quantize(quantize);
+            }
+            Message::Start => {
+                self.set_playing(true);
+            }
+            Message::Stop => {
+                self.set_playing(false);
+            }
+            Message::TempoChange(tempo, clock) => {
+                // With an external clock, the new clock is still followed, so that the control side
+                // sees the position, but the tempo is ignored: the edges drive the playback.
+                if self.external_clock.is_none() {
+                    self.set_tempo(tempo);
+                }
+                self.set_clock(clock, tempo);
+            }
+            Message::Reset(clock) => {
+                self.reset(clock);
+            }
+            Message::TriggerDuration(duration) => {
+                self.set_trigger_duration(duration);
+            }
+            Message::Swing(swing) => {
+                self.set_swing(swing);
+            }
+            Message::Humanize(humanize) => {
+                self.set_humanize(humanize);
+            }
+            Message::Transpose(degrees) => {
+                self.transpose = degrees;
+            }
+            Message::Direction(direction) => {
+                self.set_direction(direction);
+            }
+            Message::Dice(dice) => {
+                self.set_dice(dice);
+            }
+            Message::Reroll => {
+                self.reroll();
+            }
+            Message::CountIn(bars) => {
+                self.set_count_in(bars);
+            }
+            Message::Fill => {
+                for track in self.tracks.iter_mut() {
+                    track.fill_armed = true;
+                }
+            }
+            Message::LoopRegion(start, end) => {
+                self.set_loop_region(start, end);
+            }
+            Message::Tuning(a4) => {
+                self.set_tuning(a4);
+            }
+            Message::GateMode(mode) => {
+                self.set_gate_mode(mode);
+            }
+            Message::NotePriority(priority) => {
+                self.set_note_priority(priority);
+            }
+            Message::Legato(legato) => {
+                self.set_legato(legato);
+            }
+            Message::StepsPerBeat(steps) => {
+                self.set_steps_per_beat(steps);
+            }
+            Message::PreviewNote(track, pitch) => {
+                self.preview(track, pitch);
+            }
+            Message::MuteRow(track, y, muted) => {
+                if let Some(track) = self.tracks.get_mut(track) {
+                    track.mute_row(y, muted);
+                }
+            }
+        }
+    }
+    // Apply `change` now, or after the changes of its track that wait for the next loop.
+    fn apply(&mut self, change: QuantizedChange) {
+        if let Some(track) = self.tracks.get_mut(change.track) {
+            if track.looped.is_empty() {
+                track.handle_message(change.message);
+            } else {
+                track.queue_looped(change.message);
+            }
+        }
+    }
+    // Wait for the next bar to apply `change`. A full queue is applied now, so that the changes
+    // stay in order.
+    fn queue(&mut self, change: QuantizedChange) {
+        if self.quantized.len() == self.quantized.capacity() {
+            self.apply_quantized();
+        }
+        self.quantized.push(change);
+    }
+    // Apply the changes waiting for the next bar, in order.
+    fn apply_quantized(&mut self) {
+        let mut quantized = mem::replace(&mut self.quantized, Vec::new());
+        for change in quantized.drain(..) {
+            self.apply(change);
+        }
+        self.quantized = quantized;
+    }
+    // Apply the changes waiting for the next bar if a bar starts at `beat`, or when stopped.
+    fn quantize_at(&mut self, beat: f32) {
+        let bar = (beat / 4.) as usize;
+        if bar != self.bar || !self.playing {
+            self.apply_quantized();
+        }
+        self.bar = bar;
+    }
+    // The value of the analog pitch output for `pitch`. With another tuning than 440Hz, all the
+    // pitches are offset by the same amount in volts per octave.
+    fn pitch_cv(&self, pitch: f32) -> f32 {
+        match self.pitch_tracking {
+            PitchTrackingMode::VoltPerOctave => {
+                let offset = (self.tuning_a4 / DEFAULT_TUNING).log2();
+                self.cv_range.normalize(pitch + offset)
+            }
+            PitchTrackingMode::HzPerVolt(hz) => {
+                self.cv_range.output(pitch_frequency(pitch, self.tuning_a4) / hz)
+            }
+        }
+    }
+    // The pitch CV of the note the step played at `raw` on `track` plays, see
+    // `MonoNotePriority`. The step must have a note that isn't muted.
+    fn note_cv(&self, track: usize, raw: usize) -> f32 {
+        let track = &self.tracks[track];
+        let integer_sixteenth = track.step_at(raw, self.direction);
+        let notes = track.steps[integer_sixteenth].notes().iter();
+        let notes = notes.filter(|note| !track.muted(**note));
+        let note = *self.note_priority.note(notes).unwrap();
+        let transpose = self.transpose + self.cv_transpose;
+        self.pitch_cv(transposed_pitch(&track.scale, note, transpose))
+    }
+    // Whether the step played at `raw` on `track` fires.
+    fn fires(&mut self, track: usize, raw: usize) -> bool {
+        self.tracks[track].fires(raw, self.direction, self.dice, &mut self.rng)
+    }
+    // Whether a note sounds during the step played at `raw` on `track`.
+    fn sounds(&mut self, track: usize, raw: usize) -> bool {
+        self.tracks[track].sounds(raw, self.direction, self.dice, &mut self.rng)
+    }
+    // The duration of a step at a clock ratio of 1 in seconds, a sixteenth by default, measured on
+    // the external clock if there is one.
+    fn sixteenth_duration(&self) -> f32 {
+        let external = self.external_clock.as_ref().and_then(|clock| clock.sixteenth_duration());
+        let sixteenth = match external {
+            Some(duration) => duration,
+            None => sixteenth_duration(self.tempo),
+        };
+        sixteenth * 4. / self.steps_per_beat
+    }
+    // The duration of the trigger, in sixteenth, to be compared with the position in a step.
+    fn trigger_window(&self) -> f32 {
+        self.trigger_duration / self.sixteenth_duration()
+    }
+    // The retrigger of the step played at `raw` on `track`, see `Track::ratchet_at`. Steps are
+    // shorter or longer than a sixteenth depending on the clock ratio of the track, the trigger is
+    // the same length in seconds, but at most a step. In gate mode, the gate lasts the whole
+    // retrigger, but for a short time at its end, or a percentage of the retrigger.
+    fn ratchet_at(&self, track: usize, raw: usize, position: f32) -> (usize, f32, f32) {
+        let track = &self.tracks[track];
+        let trigger_duration = (self.trigger_window() * track.clock_ratio).min(1.);
+        let (index, position, trigger_duration) =
+            track.ratchet_at(raw, position, self.direction, self.swing, trigger_duration);
+        match self.gate_mode {
+            GateMode::Trigger => (index, position, trigger_duration),
+            GateMode::Gate | GateMode::Length(_) => {
+                let length = step_length(raw, self.swing) / track.ratchets(raw, self.direction) as f32;
+                let regate = REGATE_DURATION / self.sixteenth_duration() * track.clock_ratio;
+                let gate = (length - regate).max(length / 2.);
+                match self.gate_mode {
+                    GateMode::Length(gate_length_pct) => (index, position, gate.min(length * gate_length_pct / 100.)),
+                    _ => (index, position, gate),
+                }
+            }
+        }
+    }
+    // Whether the note of the step played at `raw` on `track` is held until the next step, during
+    // its last retrigger `retrigger`: the next step is tied, or fires in legato.
+    fn held(&mut self, track: usize, raw: usize, retrigger: usize) -> bool {
+        if retrigger + 1 < self.tracks[track].ratchets(raw, self.direction) {
+            return false;
+        }
+        self.tracks[track].tied(raw + 1, self.direction) || (self.legato && self.fires(track, raw + 1))
+    }
+    // Whether the gate of `track` is high at `position` in the step played at `raw`. The accent gate
+    // is only high for the accented steps. The gate stays high until the end of a step when the note
+    // is held, so that there is no new attack.
+    fn gate(&mut self, track: usize, raw: usize, position: f32, accent: bool) -> bool {
+        let (retrigger, position, trigger_duration) = self.ratchet_at(track, raw, position);
+        if !self.audible() || !self.sounds(track, raw) {
+            return false;
+        }
+        if position >= trigger_duration && !self.held(track, raw, retrigger) {
+            return false;
+        }
+        let track = &self.tracks[track];
+        !accent || track.steps[track.step_at(raw, self.direction)].accent
+    }
+    // Whether the sync pulse is high at `sixteenth`, counted in steps of the first track: during
+    // a trigger at the beginning of each of its loops, not swung nor nudged.
+    fn sync_gate(&self, sixteenth: f32) -> bool {
+        let track = &self.tracks[0];
+        let (start, end) = loop_region(track.loop_start, track.loop_end, track.steps.len());
+        let trigger_duration = (self.trigger_window() * track.clock_ratio).min(1.);
+        let first = sixteenth as usize % (end - start) == 0;
+        self.playing && first && sixteenth.fract() < trigger_duration
+    }
+    fn render_gate<S: AudioSink>(&mut self, track: usize, context: &mut S, beat: f32, port: TriggerPort,
+                                 accent: bool) {
+        let ratio = self.tracks[track].clock_ratio;
+        let step_duration = self.sixteenth_duration() / ratio;
+        let start = self.tracks[track].local(beat * self.steps_per_beat * ratio);
+        self.render_port(context, start, step_duration, port, |renderer, sixteenth| {
+            let (step_index, position) = renderer.position(track, sixteenth);
+            renderer.gate(track, step_index, position, accent)
+        });
+    }
+    fn render_sync<S: AudioSink>(&mut self, context: &mut S, beat: f32, port: TriggerPort) {
+        let ratio = self.tracks[0].clock_ratio;
+        let step_duration = self.sixteenth_duration() / ratio;
+        let start = beat * self.steps_per_beat * ratio;
+        self.render_port(context, start, step_duration, port, |renderer, sixteenth| {
+            renderer.sync_gate(sixteenth)
+        });
+    }
+    // Whether the click of the count in is high at `sixteenth` of the count in: a trigger on each
+    // beat.
+    fn count_in_gate(&self, sixteenth: f32) -> bool {
+        sixteenth < (self.count_in_bars * 16) as f32 && sixteenth % 4. < self.trigger_window()
+    }
+    // The duration of a sixteenth of the count in, counted in sixteenths whatever the resolution
+    // of the steps.
+    fn count_in_step_duration(&self) -> f32 {
+        self.sixteenth_duration() * self.steps_per_beat / 4.
+    }
+    // Whether the trigger of `track` is high `seconds` after the start of the block whatever the
+    // pattern plays: during the click of the count in when there is no sync port, or the trigger
+    // of a note previewed.
+    fn extra_trigger(&self, track: usize, seconds: f32) -> bool {
+        let click = match self.count_in {
+            Some(elapsed) if self.sync_port.is_none() => {
+                let elapsed = elapsed as f32 / CLOCK_RATE as f32 + seconds;
+                self.count_in_gate(elapsed / self.count_in_step_duration())
+            }
+            _ => false,
+        };
+        let preview = match self.tracks[track].preview {
+            Some((_, elapsed)) => {
+                elapsed as f32 / CLOCK_RATE as f32 + seconds < self.trigger_duration
+            }
+            None => false,
+        };
+        click || preview
+    }
+    // Click the count in on the sync port. The notes are muted meanwhile, the gates of the tracks
+    // click instead when there is no sync port.
+    fn render_count_in<S: AudioSink>(&mut self, context: &mut S, port: TriggerPort) {
+        let elapsed = self.count_in.unwrap_or(0);
+        let step_duration = self.count_in_step_duration();
+        let start = frame_sixteenth(0., elapsed, 1. / (CLOCK_RATE as f32 * step_duration));
+        self.render_port(context, start, step_duration, port, |renderer, sixteenth| {
+            renderer.count_in_gate(sixteenth)
+        });
+    }
+    // Move the count in on by `frames`, until the end of its bars.
+    fn advance_count_in(&mut self, frames: usize) {
+        if let Some(elapsed) = self.count_in {
+            let end = elapsed + frames;
+            let length = self.count_in_bars as f32 * 16. * self.count_in_step_duration() *
+                         CLOCK_RATE as f32;
+            self.count_in = if (end as f32) < length { Some(end) } else { None };
+        }
+    }
+    // Play the note previewed on `track`, if any, over what the pattern plays: its pitch, its
+    // trigger being added to the others, or a note on and a note off. MIDI messages that can't be
+    // queued are retried on the next block.
+    fn render_preview<S: AudioSink>(&mut self, track: usize, context: &mut S) {
+        let (pitch, elapsed) = match self.tracks[track].preview {
+            Some(preview) => preview,
+            None => {
+                return;
+            }
+        };
+        let end = elapsed + context.audio_frames();
+        let done = end as f32 >= self.trigger_duration * CLOCK_RATE as f32;
+        match self.tracks[track].output {
+            RendererOutput::CvGate { pitch_channel, .. } => {
+                let value = self.pitch_cv(pitch);
+                let analog_channels = context.analog_out_channels();
+                let analog_frames = context.analog_frames();
+                let analog_out = context.analog_out();
+                for i in 0..analog_frames {
+                    analog_out[i * analog_channels + pitch_channel] = value;
+                }
+            }
+            RendererOutput::Midi(ref mut midi) => {
+                let note = pitch_to_midi(pitch);
+                if elapsed == 0 && midi.sender.try_send([0x90, note, MAX_VELOCITY]).is_err() {
+                    return;
+                }
+                if done && midi.sender.try_send([0x80, note, 0]).is_err() {
+                    self.tracks[track].preview = Some((pitch, end));
+                    return;
+                }
+            }
+        }
+        self.tracks[track].preview = if done { None } else { Some((pitch, end)) };
+    }
+    // Write a gate on `port` for each frame of the block, high when `gate` is true at the position
+    // of the frame, in steps from `start`, a step lasting `step_duration` seconds.
+    fn render_port<S, F>(&mut self, context: &mut S, start: f32, step_duration: f32,
+                         port: TriggerPort, mut gate: F)
+        where S: AudioSink, F: FnMut(&mut MMMSRenderer, f32) -> bool {
+        self.render_levels(context, start, step_duration, port, |renderer, sixteenth| {
+            if gate(renderer, sixteenth) { 1. } else { 0. }
+        });
+    }
+    // Same as `render_port`, but the gate is at `level` of the trigger level on the analog
+    // outputs, from 0 to 1. The digital pins are high when it's above 0.
+    fn render_levels<S, F>(&mut self, context: &mut S, start: f32, step_duration: f32,
+                           port: TriggerPort, mut level: F)
+        where S: AudioSink, F: FnMut(&mut MMMSRenderer, f32) -> f32 {
+        match port {
+            TriggerPort::Analog(n) => {
+                let analog_period = 1. / context.analog_sample_rate();
+                let analog_channels = context.analog_out_channels();
+                let analog_frames = context.analog_frames();
+                let analog_out = context.analog_out();
+                let trigger_level = self.trigger_level;
+                let increment = analog_period / step_duration;
+                let ramp = trigger_level / self.trigger_edge_ramp as f32;
+                let mut value = self.analog_levels.get(n).cloned().unwrap_or(0.);
+                for i in 0..analog_frames {
+                    let target = level(self, frame_sixteenth(start, i, increment)) * trigger_level;
+                    value = if self.trigger_edge_ramp == 0 {
+                        target
+                    } else {
+                        clamp(target, value - ramp, value + ramp)
+                    };
+                    analog_out[i * analog_channels + n] = value;
+                }
+                if let Some(last) = self.analog_levels.get_mut(n) {
+                    *last = value;
+                }
+            }
+            TriggerPort::Digital(n) => {
+                let digital_period = 1. / context.digital_sample_rate();
+                let digital_frames = context.digital_frames();
+                let increment = digital_period / step_duration;
+                for frame in 0..digital_frames {
+                    let high = level(self, frame_sixteenth(start, frame, increment)) > 0.;
+                    context.digital_write_once(frame, n, if high { 1 } else { 0 });
+                }
+            }
+        }
+    }
+    fn render_cv<S: AudioSink>(&mut self, track: usize, context: &mut S, beat: f32) {
+        let analog_period = 1. / context.analog_sample_rate();
+        // `sixteenth` counts the steps of this track, that can be shorter or longer than a
+        // sixteenth
+        let ratio = self.tracks[track].clock_ratio;
+        let step_duration = self.sixteenth_duration() / ratio;
+        let (trigger, pitch_channel) = match self.tracks[track].output {
+            RendererOutput::CvGate { trigger, pitch_channel } => (trigger, pitch_channel),
+            RendererOutput::Midi(_) => {
+                return;
+            }
+        };
+
+        // the trigger, and its repeats, that start with it, with the click of the count in and
+        // the trigger of a note previewed over them
+        let start = self.tracks[track].local(beat * self.steps_per_beat * ratio);
+        let window = (self.trigger_window() * ratio).min(1.);
+        self.render_levels(context, start, step_duration, trigger, |renderer, sixteenth| {
+            let (step_index, position) = renderer.position(track, sixteenth);
+            let level = if renderer.gate(track, step_index, position, false) {
+                if renderer.fires(track, step_index) {
+                    let pitch = renderer.note_cv(track, step_index);
+                    renderer.tracks[track].schedule_echo(step_index, sixteenth, pitch);
+                }
+                1.
+            } else {
+                match renderer.tracks[track].echo_at(sixteenth, window) {
+                    Some((level, _)) if renderer.audible() => level,
+                    _ => 0.,
+                }
+            };
+            if renderer.extra_trigger(track, (sixteenth - start) * step_duration) {
+                1.
+            } else {
+                level
+            }
+        });
+
+        let analog_channels = context.analog_out_channels();
+        let analog_frames = context.analog_frames();
+        let analog_out = context.analog_out();
+        let increment = analog_period / step_duration;
+        for i in 0..analog_frames {
+            let sixteenth = frame_sixteenth(start, i, increment);
+            let (step_index, _) = self.position(track, sixteenth);
+
+            let target = if self.playing && self.fires(track, step_index) {
+                Some(self.note_cv(track, step_index))
+            } else if self.playing {
+                self.tracks[track].echo_at(sixteenth, window).and_then(|(_, pitch)| pitch)
+            } else {
+                None
+            };
+            // the pitch is held after a note, except on a rest
+            if self.playing && self.tracks[track].rests(step_index, self.direction) {
+                self.tracks[track].jump(0.);
+            }
+            // in legato, only the notes that follow another one glide
+            if let Some(value) = target {
+                if self.legato && !(step_index > 0 && self.sounds(track, step_index - 1)) {
+                    self.tracks[track].jump(value);
+                }
+            }
+            let value = self.tracks[track].glide(target, analog_period);
+            let value = self.tracks[track].smooth(value, analog_period);
+            analog_out[i * analog_channels + pitch_channel] = value;
+        }
+        self.tracks[track].expire_echoes(frame_sixteenth(start, analog_frames, increment));
+        if let Some(channel) = self.tracks[track].velocity_channel {
+            for i in 0..analog_frames {
+                let (step_index, _) = self.position(track, frame_sixteenth(start, i, increment));
+                // hold the velocity of the last note played until the next one
+                if self.playing && self.fires(track, step_index) {
+                    let track = &mut self.tracks[track];
+                    let velocity = track.output_velocity(track.step_at(step_index, self.direction));
+                    track.prev_velocity = velocity as f32 / MAX_VELOCITY as f32;
+                }
+                analog_out[i * analog_channels + channel] = self.tracks[track].prev_velocity;
+            }
+        }
+    }
+    // Send note on at the beginning of each step or retrigger, and note off at the end of the
+    // trigger. Only the notes that have been sent are turned off, so that they are turned off even
+    // if the pattern changes in the meantime. Messages are retried on the next frame if the queue
+    // is full.
+    fn render_midi(&mut self, track: usize, frames: usize, period: f32, beat: f32) {
+        let ratio = self.tracks[track].clock_ratio;
+        let increment = period / self.sixteenth_duration() * ratio;
+        let start = self.tracks[track].local(beat * self.steps_per_beat * ratio);
+        for frame in 0..frames {
+            let sixteenth = frame_sixteenth(start, frame, increment);
+            let (step_index, position) = self.position(track, sixteenth);
+            let (retrigger, position, trigger_duration) = self.ratchet_at(track, step_index, position);
+            let current = Some((step_index, retrigger));
+            let audible = self.audible();
+            let fires = audible && self.fires(track, step_index);
+            let sounds = audible && self.sounds(track, step_index);
+            // A tied step continues the note of the previous step, that is held until its end. In
+            // legato, the note is held until the next one starts.
+            let held = sounds && self.held(track, step_index, retrigger);
+            let track = &mut self.tracks[track];
+            let continues = sounds && track.tied(step_index, self.direction);
+            let index = track.step_at(step_index, self.direction);
+            let (muted_rows, note_count) = (track.muted_rows, track.scale.note_count());
+            let velocity = track.output_velocity(index);
+            let midi = match track.output {
+                RendererOutput::Midi(ref mut midi) => midi,
+                _ => {
+                    return;
+                }
+            };
+            if !audible || (position >= trigger_duration && !held) ||
+               (midi.last_step != current && !continues) {
+                midi.notes_off();
+            }
+            if audible && midi.last_step != current && midi.sounding.is_empty() {
+                midi.last_step = current;
+                if fires {
+                    let step = &track.steps[index];
+                    let notes = step.notes().iter();
+                    for index in notes.filter(|note| !row_muted(muted_rows, note_count - 1 - **note as usize)) {
+                        let transpose = self.transpose + self.cv_transpose;
+                        let pitch = transposed_pitch(&track.scale, *index, transpose);
+                        midi.note_on(pitch_to_midi(pitch), velocity);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A command received over OSC. The addresses are:
+///
+/// - `/mmms/tempo f`: the tempo in beats per minute, clamped between 20 and 300
+/// - `/mmms/scale i i`: the root in semitones above C, and the scale type, an index in `SCALE_TYPES`
+/// - `/mmms/start` and `/mmms/stop`: the transport
+///
+/// Integers and floats are accepted for all the arguments, but not infinite floats nor NaN.
+#[cfg(feature = "osc")]
+#[derive(Debug, PartialEq)]
+enum OscCommand {
+    Tempo(f32),
+    Scale(usize, usize),
+    Start,
+    Stop,
+    Reroll,
+}
+
+/// Read the OSC string at the start of `data`, and return it with what follows it, strings being
+/// padded with zeros to a multiple of four bytes.
+#[cfg(feature = "osc")]
+fn osc_string(data: &[u8]) -> Option<(&str, &[u8])> {
+    let end = data.iter().position(|b| *b == 0)?;
+    let string = std::str::from_utf8(&data[..end]).ok()?;
+    let padded = (end + 4) / 4 * 4;
+    if padded > data.len() {
+        return None;
+    }
+    Some((string, &data[padded..]))
+}
+
+/// Parse an OSC message, bundles aren't supported. Returns `None` for anything that isn't a
+/// command, or has the wrong arguments.
+#[cfg(feature = "osc")]
+fn parse_osc(packet: &[u8]) -> Option<OscCommand> {
+    let (address, rest) = osc_string(packet)?;
+    let (tags, mut rest) = match osc_string(rest) {
+        Some((tags, rest)) if tags.starts_with(',') => (&tags[1..], rest),
+        _ => ("", rest),
+    };
+    let mut arguments = SmallVec::<[f32; 2]>::new();
+    for tag in tags.chars() {
+        if rest.len() < 4 {
+            return None;
+        }
+        let bits = u32::from(rest[0]) << 24 | u32::from(rest[1]) << 16 |
+                   u32::from(rest[2]) << 8 | u32::from(rest[3]);
+        arguments.push(match tag {
+            'f' if f32::from_bits(bits).is_finite() => f32::from_bits(bits),
+            'i' => bits as i32 as f32,
+            _ => return None,
+        });
+        rest = &rest[4..];
+    }
+    match (address, arguments.as_slice()) {
+        ("/mmms/tempo", [tempo]) => Some(OscCommand::Tempo(*tempo)),
+        ("/mmms/scale", [root, scale_type]) => {
+            Some(OscCommand::Scale(root.max(0.) as usize, scale_type.max(0.) as usize))
+        }
+        ("/mmms/start", []) => Some(OscCommand::Start),
+        ("/mmms/stop", []) => Some(OscCommand::Stop),
+        ("/mmms/reroll", []) => Some(OscCommand::Reroll),
+        _ => None,
+    }
+}
+
+/// Receives OSC commands on a thread, until dropped.
+#[cfg(feature = "osc")]
+struct OscListener {
+    receiver: Receiver<OscCommand>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "osc")]
+impl OscListener {
+    fn new(address: IpAddr, port: u16) -> io::Result<OscListener> {
+        let socket = UdpSocket::bind((address, port))?;
+        // wake up regularly to check if the listener has been dropped
+        socket.set_read_timeout(Some(time::Duration::from_millis(OSC_POLL_INTERVAL)))?;
+        let (sender, receiver) = channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || {
+            let mut packet = [0; 1024];
+            while thread_running.load(Ordering::Relaxed) {
+                let length = match socket.recv(&mut packet) {
+                    Ok(length) => length,
+                    Err(_) => continue,
+                };
+                if let Some(command) = parse_osc(&packet[..length]) {
+                    if sender.send(command).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(OscListener { receiver, running, thread: Some(thread) })
+    }
+}
+
+#[cfg(feature = "osc")]
+impl Drop for OscListener {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Configures the sequencer before creating it, for the outputs that are optional and the initial
+/// state. The first track is on the trigger and pitch ports when they are set.
+pub struct MmmsBuilder {
+    trigger_port: Option<BelaPort>,
+    pitch_port: Option<BelaPort>,
+    velocity_port: Option<BelaPort>,
+    accent_port: Option<BelaPort>,
+    accent_velocity_boost: u8,
+    trigger_delay: Option<TriggerDelay>,
+    sync_port: Option<BelaPort>,
+    transpose_cv_port: Option<BelaPort>,
+    cv_range: CvRange,
+    pitch_tracking: PitchTrackingMode,
+    tuning_a4: f32,
+    gate_mode: GateMode,
+    note_priority: MonoNotePriority,
+    trigger_level: f32,
+    trigger_edge_ramp: usize,
+    count_in_bars: usize,
+    legato: bool,
+    steps_per_beat: f32,
+    quantize: bool,
+    palette: Palette,
+    view_mode: ViewMode,
+    metronome: bool,
+    offscreen_playhead: bool,
+    follow_playhead: bool,
+    control_map: Option<ControlMap>,
+    autosave_path: Option<PathBuf>,
+    midi_clock: Option<Box<dyn FnMut(&[u8]) + Send>>,
+    #[cfg(feature = "osc")]
+    osc_port: Option<u16>,
+    #[cfg(feature = "osc")]
+    osc_address: IpAddr,
+    // the other tracks
+    outputs: Vec<Output>,
+    scale: TrackScale,
+    tempo: f32,
+    steps: usize,
+    width: usize,
+    height: usize,
+}
+
+impl MmmsBuilder {
+    /// A sequencer for a monome 128, at 120 BPM, in B minor, without any output.
+    pub fn new() -> MmmsBuilder {
+        MmmsBuilder {
+            trigger_port: None,
+            pitch_port: None,
+            velocity_port: None,
+            accent_port: None,
+            accent_velocity_boost: 0,
+            trigger_delay: None,
+            sync_port: None,
+            transpose_cv_port: None,
+            cv_range: CvRange::default(),
+            pitch_tracking: PitchTrackingMode::VoltPerOctave,
+            tuning_a4: DEFAULT_TUNING,
+            gate_mode: GateMode::Trigger,
+            note_priority: MonoNotePriority::Lowest,
+            trigger_level: 1.0,
+            trigger_edge_ramp: 0,
+            count_in_bars: 0,
+            legato: false,
+            steps_per_beat: 4.,
+            quantize: false,
+            palette: Palette::default(),
+            view_mode: ViewMode::Functions,
+            metronome: false,
+            offscreen_playhead: false,
+            follow_playhead: false,
+            control_map: None,
+            autosave_path: None,
+            midi_clock: None,
+            #[cfg(feature = "osc")]
+            osc_port: None,
+            #[cfg(feature = "osc")]
+            osc_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            outputs: Vec::new(),
+            scale: TrackScale::Standard(default_scale()),
+            tempo: 120.,
+            steps: INITIAL_STEPS,
+            width: 16,
+            height: 8,
+        }
+    }
+    /// Output the gates of the first track on `port`, an analog output or a digital pin.
+    pub fn trigger_port(mut self, port: BelaPort) -> MmmsBuilder {
+        self.trigger_port = Some(port);
+        self
+    }
+    /// Output the pitch CV of the first track on `port`, an analog output.
+    pub fn pitch_port(mut self, port: BelaPort) -> MmmsBuilder {
+        self.pitch_port = Some(port);
+        self
+    }
+    /// Output the velocity of the first track on `port`, an analog output.
+    pub fn velocity_port(mut self, port: BelaPort) -> MmmsBuilder {
+        self.velocity_port = Some(port);
+        self
+    }
+    /// Output a gate with the accented steps of the first track on `port`, an analog output or a
+    /// digital pin.
+    pub fn accent_port(mut self, port: BelaPort) -> MmmsBuilder {
+        self.accent_port = Some(port);
+        self
+    }
+    /// Add `boost` to the velocity of the accented steps of the first track, 0 by default.
+    pub fn accent_velocity_boost(mut self, boost: u8) -> MmmsBuilder {
+        self.accent_velocity_boost = boost;
+        self
+    }
+    /// Repeat the triggers of the first track, see `TriggerDelay`. They don't repeat by default.
+    pub fn trigger_delay(mut self, delay: TriggerDelay) -> MmmsBuilder {
+        self.trigger_delay = Some(delay);
+        self
+    }
+    /// Output a trigger at the beginning of each loop of the first track on `port`, an analog
+    /// output or a digital pin.
+    pub fn sync_port(mut self, port: BelaPort) -> MmmsBuilder {
+        self.sync_port = Some(port);
+        self
+    }
+    /// Transpose all the tracks by the CV on `port`, an analog input.
+    pub fn transpose_cv_port(mut self, port: BelaPort) -> MmmsBuilder {
+        self.transpose_cv_port = Some(port);
+        self
+    }
+    /// How the pitch CV of all the tracks maps to the analog outputs.
+    pub fn cv_range(mut self, range: CvRange) -> MmmsBuilder {
+        self.cv_range = range;
+        self
+    }
+    /// Whether the pitch CV of all the tracks is in volts per octave, or in Hz per volt.
+    pub fn pitch_tracking(mut self, mode: PitchTrackingMode) -> MmmsBuilder {
+        self.pitch_tracking = mode;
+        self
+    }
+    /// The frequency of A4 for the pitch CV of all the tracks, in Hz, between 400 and 480.
+    pub fn tuning(mut self, a4: f32) -> MmmsBuilder {
+        self.tuning_a4 = a4;
+        self
+    }
+    /// Output triggers, or gates that last as long as the steps, on all the tracks.
+    pub fn gate_mode(mut self, mode: GateMode) -> MmmsBuilder {
+        self.gate_mode = mode;
+        self
+    }
+    /// Which note of a step with several the pitch CV plays, the lowest by default.
+    pub fn note_priority(mut self, priority: MonoNotePriority) -> MmmsBuilder {
+        self.note_priority = priority;
+        self
+    }
+    /// The value of the gates on the analog outputs when they are high, between 0 and 1.
+    pub fn trigger_level(mut self, level: f32) -> MmmsBuilder {
+        self.trigger_level = level;
+        self
+    }
+    /// The number of analog frames the gates on the analog outputs take to go up or down, 0 for a
+    /// step.
+    pub fn trigger_edge_ramp(mut self, frames: usize) -> MmmsBuilder {
+        self.trigger_edge_ramp = frames;
+        self
+    }
+    /// Click on each beat for this many bars, up to four, when the playback starts.
+    pub fn count_in(mut self, bars: usize) -> MmmsBuilder {
+        self.count_in_bars = bars;
+        self
+    }
+    /// Don't retrigger the gate between the notes of consecutive steps, on all the tracks.
+    pub fn legato(mut self, legato: bool) -> MmmsBuilder {
+        self.legato = legato;
+        self
+    }
+    /// The number of steps per beat at a clock ratio of 1, between 1 and 8, 4 for sixteenths.
+    pub fn steps_per_beat(mut self, steps: f32) -> MmmsBuilder {
+        self.steps_per_beat = steps;
+        self
+    }
+    /// Change the scale, the length or the whole pattern of a track at the next bar, instead of
+    /// immediately.
+    pub fn quantize(mut self, quantize: bool) -> MmmsBuilder {
+        self.quantize = quantize;
+        self
+    }
+    /// The brightness of the keys of the grid, to suit the lighting or the model of the grid.
+    pub fn palette(mut self, palette: Palette) -> MmmsBuilder {
+        self.palette = palette;
+        self
+    }
+    /// Which rows are lit when they have no notes. It can also be changed in the scale picker.
+    pub fn view_mode(mut self, view_mode: ViewMode) -> MmmsBuilder {
+        self.view_mode = view_mode;
+        self
+    }
+    /// Flash the tempo key on each beat, brighter on the first beat of each bar.
+    pub fn metronome(mut self, metronome: bool) -> MmmsBuilder {
+        self.metronome = metronome;
+        self
+    }
+    /// Blink the left or right key toward the playhead when it's scrolled out of view.
+    pub fn offscreen_playhead(mut self, show: bool) -> MmmsBuilder {
+        self.offscreen_playhead = show;
+        self
+    }
+    /// Scroll the view a page at a time to keep the playhead in view while playing.
+    pub fn follow_playhead(mut self, follow: bool) -> MmmsBuilder {
+        self.follow_playhead = follow;
+        self
+    }
+    /// Save the patterns of all the tracks to `path` when they change, and load them from there
+    /// when building, if they have been saved before. `None`, the default, doesn't save nor load
+    /// anything.
+    pub fn autosave(mut self, path: Option<PathBuf>) -> MmmsBuilder {
+        self.autosave_path = path;
+        self
+    }
+    /// Send MIDI clock, 24 messages per beat, and start, continue and stop messages to `callback`,
+    /// on the main thread in `main_thread_work`, whatever the outputs of the tracks.
+    pub fn midi_clock(mut self, callback: Box<dyn FnMut(&[u8]) + Send>) -> MmmsBuilder {
+        self.midi_clock = Some(callback);
+        self
+    }
+    /// Listen for the OSC commands described in `OscCommand` on UDP `port`, or not at all when
+    /// `None`, the default. The commands are applied in `main_thread_work`.
+    #[cfg(feature = "osc")]
+    pub fn osc_port(mut self, port: Option<u16>) -> MmmsBuilder {
+        self.osc_port = port;
+        self
+    }
+    /// The address the OSC port is bound to, 127.0.0.1 by default, so that only this machine can
+    /// send commands. `0.0.0.0` listens on all the network interfaces.
+    #[cfg(feature = "osc")]
+    pub fn osc_address(mut self, address: IpAddr) -> MmmsBuilder {
+        self.osc_address = address;
+        self
+    }
+    /// Add a track on `output`, after the one on the trigger and pitch ports if they are set.
+    pub fn output<O: Into<Output>>(mut self, output: O) -> MmmsBuilder {
+        self.outputs.push(output.into());
+        self
+    }
+    /// The scale of all the tracks.
+    pub fn scale(mut self, scale: Scale) -> MmmsBuilder {
+        self.scale = TrackScale::Standard(scale);
+        self
+    }
+    /// A custom scale for all the tracks, instead of one of `musical_scales`.
+    pub fn custom_scale(mut self, scale: CustomScale) -> MmmsBuilder {
+        self.scale = TrackScale::Custom(scale);
+        self
+    }
+    /// The tempo, in beats per minute, clamped between 20 and 300.
+    pub fn tempo(mut self, tempo: f32) -> MmmsBuilder {
+        self.tempo = tempo;
+        self
+    }
+    /// The number of steps of all the tracks, rounded up to whole bars of sixteen steps.
+    pub fn steps(mut self, steps: usize) -> MmmsBuilder {
+        self.steps = steps;
+        self
+    }
+    /// The size of the grid, 8 or 16 keys wide and high.
+    pub fn grid_size(mut self, width: usize, height: usize) -> MmmsBuilder {
+        self.width = width;
+        self.height = height;
+        self
+    }
+    /// Where the controls are on the control row, instead of the layout of a monome 128 or 64.
+    pub fn control_map(mut self, map: ControlMap) -> MmmsBuilder {
+        self.control_map = Some(map);
+        self
+    }
+    /// The control and renderer sides of the sequencer. Fails if a port can't be used for what it
+    /// has been asked to do, if only one of the trigger and pitch ports is set, or if there are no
+    /// or too many tracks.
+    pub fn build(self) -> Result<(MMMS, MMMSRenderer), MmmsError> {
+        let mut outputs = Vec::new();
+        match (self.trigger_port, self.pitch_port) {
+            (Some(trigger), Some(pitch)) => outputs.push(Output::CvGate(trigger, pitch)),
+            (Some(_), None) => return Err(MmmsError::InvalidPitchPort),
+            (None, Some(_)) => return Err(MmmsError::InvalidTriggerPort),
+            (None, None) => {}
+        }
+        outputs.extend(self.outputs);
+        let (mut mmms, mut renderer) = MMMS::with_tracks(outputs, self.width, self.height, self.tempo,
+                                                       self.scale)?;
+        if let Some(port) = self.velocity_port {
+            renderer.set_velocity_port(0, port)?;
+        }
+        if let Some(port) = self.accent_port {
+            renderer.set_accent_port(0, port)?;
+        }
+        renderer.set_accent_velocity_boost(0, self.accent_velocity_boost)?;
+        renderer.set_trigger_delay(0, self.trigger_delay)?;
+        if let Some(port) = self.sync_port {
+            renderer.set_sync_port(port)?;
+        }
+        if let Some(port) = self.transpose_cv_port {
+            renderer.set_transpose_cv_port(port)?;
+        }
+        renderer.set_cv_range(self.cv_range);
+        renderer.set_pitch_tracking(self.pitch_tracking);
+        renderer.set_tuning(self.tuning_a4);
+        renderer.set_gate_mode(self.gate_mode);
+        renderer.set_note_priority(self.note_priority);
+        renderer.set_trigger_level(self.trigger_level);
+        renderer.set_trigger_edge_ramp(self.trigger_edge_ramp);
+        renderer.set_count_in(self.count_in_bars);
+        mmms.count_in_bars = renderer.count_in_bars;
+        renderer.set_legato(self.legato);
+        renderer.set_steps_per_beat(self.steps_per_beat);
+        mmms.steps_per_beat = renderer.steps_per_beat;
+        renderer.set_quantize(self.quantize);
+        if let Some(callback) = self.midi_clock {
+            let (sender, receiver) = sync_channel::<u8>(MIDI_QUEUE_SIZE);
+            renderer.midi_clock = Some(MidiClock::new(sender));
+            mmms.midi_clock = Some((receiver, callback));
+        }
+        #[cfg(feature = "osc")]
+        {
+            if let Some(port) = self.osc_port {
+                let listener = OscListener::new(self.osc_address, port);
+                mmms.osc = Some(listener.map_err(|e| MmmsError::OscUnavailable(e.kind()))?);
+            }
+        }
+        let steps = clamp((self.steps + 15) / 16, 1, MAX_BARS) * 16;
+        for grid in mmms.virtual_grids.iter_mut() {
+            grid.change_steps_count(steps);
+        }
+        for track in renderer.tracks.iter_mut() {
+            track.resize(steps);
+        }
+        mmms.palette = self.palette;
+        mmms.view_mode = self.view_mode;
+        mmms.metronome = self.metronome;
+        mmms.offscreen_playhead = self.offscreen_playhead;
+        mmms.follow_playhead = self.follow_playhead;
+        if let Some(map) = self.control_map {
+            mmms.set_control_map(map)?;
+        }
+        if let Some(path) = self.autosave_path {
+            // patterns that can't be loaded are replaced on the next edit
+            let _ = mmms.load_autosave(&path);
+            mmms.dirty.set(None);
+            mmms.autosave_path = Some(path);
+        }
+        Ok((mmms, renderer))
+    }
+}
+
+impl Default for MmmsBuilder {
+    fn default() -> MmmsBuilder {
+        MmmsBuilder::new()
+    }
+}
+
+pub struct MMMS {
+    tempo: f32,
+    width: usize,
+    height: usize,
+    sender: Sender<Message>,
+    audio_clock: ClockConsumer,
+    state_tracker: GridStateTracker,
+    // what is drawn on the grid, `width` by `height`
+    leds: Vec<u8>,
+    // one per track
+    virtual_grids: Vec<VirtualGrid>,
+    // the track being edited
+    track: usize,
+    // speed of each track relative to the clock, and the sixteenth of the clock from which the
+    // renderer plays its pattern from the first step
+    clock_ratios: Vec<f32>,
+    origins: Vec<Arc<AtomicUsize>>,
+    #[cfg(feature = "debug-events")]
+    debug_receiver: Receiver<DebugEvent>,
+    midi: Vec<(Receiver<[u8; 3]>, Box<dyn FnMut(&[u8]) + Send>)>,
+    midi_clock: Option<(Receiver<u8>, Box<dyn FnMut(&[u8]) + Send>)>,
+    picking_scale: bool,
+    // The scale being picked, sent when leaving the picker
+    picked_scale: Scale,
+    editing_velocity: bool,
+    editing_probability: bool,
+    editing_tempo: bool,
+    editing_transpose: bool,
+    // the grid keys play their note once instead of editing the pattern
+    preview: bool,
+    // the chord the grid keys add instead of a single note
+    chord: Option<ChordType>,
+    // in scale degrees
+    transpose: i32,
+    // makes the random patterns
+    rng: XorShift,
+    playing: bool,
+    trigger_duration: f32,
+    direction: Direction,
+    // the steps that have just been cleared, lit for `flash` more refreshes
+    flash: usize,
+    flash_steps: (usize, usize),
+    // where the patterns are saved when they change, and why they couldn't be the last time
+    autosave_path: Option<PathBuf>,
+    autosave_error: Option<io::Error>,
+    // the last change that hasn't been saved yet, set when sending a message that changes a
+    // pattern
+    dirty: Cell<Option<time::Instant>>,
+    // the arrow key held to scroll the view, if any
+    held_move: Option<HeldMove>,
+    // the last taps of the tempo, the oldest first
+    taps: Vec<time::Instant>,
+    // the blocks the renderer rendered late or with an unexpected size
+    overruns: Arc<AtomicUsize>,
+    // the edits too long for the renderer to apply them, that it dropped
+    dropped_batches: Arc<AtomicUsize>,
+    // patterns stored to compare them, A and B
+    snapshots: [Option<Pattern>; 2],
+    palette: Palette,
+    view_mode: ViewMode,
+    // flash the tempo key on each beat
+    metronome: bool,
+    // blink the scroll key toward the playhead when it's out of view
+    offscreen_playhead: bool,
+    // scroll the view to the page of the playhead when it leaves the view
+    follow_playhead: bool,
+    // bars of clicks before the playback starts
+    count_in_bars: usize,
+    // the resolution of the steps, see `MMMSRenderer::set_steps_per_beat`
+    steps_per_beat: f32,
+    note_repeat: NoteRepeat,
+    // patterns to arrange in a song, and the order in which they are played
+    bank: [Option<Pattern>; BANK_SIZE],
+    bank_names: [String; BANK_SIZE],
+    song: Vec<usize>,
+    // in song mode, the track playing the song and the position in `song`
+    song_position: Option<(usize, usize)>,
+    // the step during which the song last moved to the next pattern
+    song_step: Option<usize>,
+    // in random walk, the pattern being edited mutates at the end of a loop with a chance of
+    // `walk_rate`, from 0 to 1
+    random_walk: bool,
+    walk_rate: f32,
+    walk_rng: XorShift,
+    // the step during which the pattern last had a chance to mutate
+    walk_step: Option<usize>,
+    #[cfg(feature = "osc")]
+    osc: Option<OscListener>,
+}
+
+impl MMMS {
+    /// The control and renderer sides of the sequencer. Fails if the ports of `output` can't be
+    /// used for what they have been asked to do.
+    pub fn new<O: Into<Output>>(
+        output: O,
+        width: usize,
+        height: usize,
+        tempo: f32,
+    ) -> Result<(MMMS, MMMSRenderer), MmmsError> {
+        MmmsBuilder::new().output(output).grid_size(width, height).tempo(tempo).build()
+    }
+    /// Same as `new`, with a track per output, from one to four, all on the same clock. The grid is
+    /// `width` by `height` keys, 8 or 16 each, and all the tracks start in `initial_scale`.
+    pub fn with_tracks<S: Into<TrackScale>>(
+        outputs: Vec<Output>,
+        width: usize,
+        height: usize,
+        tempo: f32,
+        initial_scale: S,
+    ) -> Result<(MMMS, MMMSRenderer), MmmsError> {
+        if outputs.is_empty() || outputs.len() > MAX_TRACKS {
+            return Err(MmmsError::InvalidTrackCount);
+        }
+        if (width != 8 && width != 16) || (height != 8 && height != 16) {
+            return Err(MmmsError::InvalidGridSize);
+        }
+        let (sender, receiver) = channel::<Message>();
+        let initial_scale = initial_scale.into();
+
+        let tempo = clamp(tempo, MIN_TEMPO, MAX_TEMPO);
+        let (clock_updater, clock_consumer) = audio_clock(tempo, CLOCK_RATE);
+
+        #[cfg(feature = "debug-events")]
+        let (debug_sender, debug_receiver) = sync_channel::<DebugEvent>(DEBUG_QUEUE_SIZE);
+
+        let mut tracks = SmallVec::<[Track; MAX_TRACKS]>::new();
+        let mut midi = Vec::new();
+        for output in outputs {
+            let output = match output {
+                Output::CvGate(trigger_port, pitch_port) => {
+                    let trigger = gate_port(trigger_port, MmmsError::InvalidTriggerPort)?;
+                    let pitch_channel = analog_out_channel(pitch_port, MmmsError::InvalidPitchPort)?;
+                    RendererOutput::CvGate { trigger, pitch_channel }
+                }
+                Output::Midi(callback) => {
+                    let (midi_sender, midi_receiver) = sync_channel::<[u8; 3]>(MIDI_QUEUE_SIZE);
+                    midi.push((midi_receiver, callback));
+                    RendererOutput::Midi(MidiOutput {
+                        sender: midi_sender,
+                        sounding: SmallVec::new(),
+                        last_step: None,
+                    })
+                }
+            };
+            tracks.push(Track::new(output, initial_scale.clone()));
+        }
+
+        // the first row is the control row
+        let virtual_grids: Vec<VirtualGrid> = tracks.iter().map(|_| {
+            VirtualGrid::with_view(width, height - 1, initial_scale.clone())
+        }).collect();
+        let picked_scale = initial_scale.standard();
+        let origins = tracks.iter().map(|track| track.origin.clone()).collect();
+
+        let renderer = MMMSRenderer::new(
+            tempo,
+            clock_updater,
+            clock_consumer.clone(),
+            receiver,
+            tracks,
+            #[cfg(feature = "debug-events")]
+            debug_sender);
+        let overruns = renderer.overruns.clone();
+        let dropped_batches = renderer.dropped_batches.clone();
+        let state_tracker = GridStateTracker::new(width, height);
+
+        Ok((
+            MMMS {
+                tempo,
+                width,
+                height,
+                sender,
+                audio_clock: clock_consumer,
+                state_tracker,
+                leds: vec![0; width * height],
+                clock_ratios: vec![1.; virtual_grids.len()],
+                origins,
+                virtual_grids,
+                track: 0,
+                #[cfg(feature = "debug-events")]
+                debug_receiver,
+                midi,
+                midi_clock: None,
+                picking_scale: false,
+                picked_scale,
+                editing_velocity: false,
+                editing_probability: false,
+                editing_tempo: false,
+                editing_transpose: false,
+                preview: false,
+                chord: None,
+                transpose: 0,
+                rng: XorShift::new(RANDOMIZE_SEED),
+                playing: true,
+                trigger_duration: DEFAULT_TRIGGER_DURATION,
+                direction: Direction::Forward,
+                flash: 0,
+                flash_steps: (0, 0),
+                autosave_path: None,
+                autosave_error: None,
+                dirty: Cell::new(None),
+                held_move: None,
+                taps: Vec::new(),
+                overruns,
+                dropped_batches,
+                snapshots: [None, None],
+                palette: Palette::default(),
+                view_mode: ViewMode::Functions,
+                metronome: false,
+                offscreen_playhead: false,
+                follow_playhead: false,
+                count_in_bars: 0,
+                steps_per_beat: 4.,
+                note_repeat: NoteRepeat::new(),
+                bank: Default::default(),
+                bank_names: Default::default(),
+                song: Vec::new(),
+                song_position: None,
+                song_step: None,
+                random_walk: false,
+                walk_rate: 0.5,
+                walk_rng: XorShift::new(WALK_SEED),
+                walk_step: None,
+                #[cfg(feature = "osc")]
+                osc: None,
+            },
+            renderer,
+        ))
+    }
+    /// Delay odd sixteenth, from 0.0 (straight) to 0.75. 0.5 is a triplet feel.
+    pub fn set_swing(&mut self, swing: f32) {
+        self.sender.send(Message::Swing(clamp(swing, 0.0, MAX_SWING)));
+    }
+    /// Delay each step by a random amount, from 0.0 (on the clock) to 1.0 (up to a fifth of a
+    /// sixteenth).
+    pub fn set_humanize(&mut self, humanize: f32) {
+        self.sender.send(Message::Humanize(clamp(humanize, 0.0, 1.0)));
+    }
+    /// Tune the pitch CV of all the tracks to A4 at `a4` Hz, between 400 and 480Hz.
+    pub fn set_tuning(&mut self, a4: f32) {
+        self.sender.send(Message::Tuning(clamp(a4, MIN_TUNING, MAX_TUNING)));
+    }
+    /// Output triggers, or gates that last as long as the steps, on all the tracks.
+    pub fn set_gate_mode(&mut self, mode: GateMode) {
+        self.sender.send(Message::GateMode(mode));
+    }
+    /// Which note the pitch CV of the tracks plays when a step has several.
+    pub fn set_note_priority(&mut self, priority: MonoNotePriority) {
+        self.sender.send(Message::NotePriority(priority));
+    }
+    /// Don't retrigger the gate between the notes of consecutive steps, on all the tracks.
+    pub fn set_legato(&mut self, legato: bool) {
+        self.sender.send(Message::Legato(legato));
+    }
+    /// The number of steps per beat at a clock ratio of 1, between 1 and 8, 4 for sixteenths.
+    pub fn set_steps_per_beat(&mut self, steps: f32) {
+        self.steps_per_beat = clamp(steps, MIN_STEPS_PER_BEAT, MAX_STEPS_PER_BEAT);
+        self.sender.send(Message::StepsPerBeat(self.steps_per_beat));
+    }
+    /// Click on each beat for `bars` bars when the playback starts, up to four, before playing the
+    /// patterns. 0 starts right away.
+    pub fn set_count_in(&mut self, bars: usize) {
+        self.count_in_bars = cmp::min(bars, MAX_COUNT_IN_BARS);
+        self.sender.send(Message::CountIn(self.count_in_bars));
+    }
+    /// Change the scale, the length or the whole pattern of a track at the next bar, instead of
+    /// immediately.
+    pub fn set_quantize(&mut self, quantize: bool) {
+        self.sender.send(Message::Quantize(quantize));
+    }
+    /// Glide between consecutive pitches on the pitch CV of `track`, in `glide_time` seconds, up to
+    /// two seconds. 0 jumps from a pitch to the next.
+    pub fn set_glide(&mut self, track: usize, glide_time: f32) {
+        let glide_time = clamp(glide_time, 0.0, MAX_GLIDE_TIME);
+        self.sender.send(Message::Track(track, TrackMessage::Glide(glide_time)));
+    }
+    /// Smooth the pitch CV of `track` with a lowpass filter of time constant `time`, in seconds, up
+    /// to 50ms, so that the changes of pitch don't click. 0 leaves it as is.
+    pub fn set_pitch_smoothing(&mut self, track: usize, time: f32) {
+        let time = clamp(time, 0.0, MAX_PITCH_SMOOTHING);
+        self.sender.send(Message::Track(track, TrackMessage::PitchSmoothing(time)));
+    }
+    /// Play `track` at a multiple of the clock: 0.25, 0.5, 1, 2 or 3, the closest is picked.
+    pub fn set_clock_ratio(&mut self, track: usize, ratio: f32) {
+        let ratio = clamp_clock_ratio(ratio);
+        if let Some(r) = self.clock_ratios.get_mut(track) {
+            *r = ratio;
+        }
+        self.sender.send(Message::Track(track, TrackMessage::ClockRatio(ratio)));
+    }
+    /// Transpose all the tracks by `degrees` of their scale, the notes that would be out of the
+    /// scale stay on its lowest or highest note. The patterns are unchanged.
+    pub fn set_transpose(&mut self, degrees: i32) {
+        let max = self.virtual_grids[self.track].current_scale().note_count() as i32 - 1;
+        self.transpose = clamp(degrees, -max, max);
+        self.sender.send(Message::Transpose(self.transpose));
+    }
+    /// The tempo, in beats per minute.
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+    /// The track being edited on the grid.
+    pub fn track(&self) -> usize {
+        self.track
+    }
+    /// The number of steps of the track being edited.
+    pub fn steps_count(&self) -> usize {
+        self.virtual_grids[self.track].steps_count()
+    }
+    /// Play the track being edited in a custom scale, the notes move to the closest pitches.
+    pub fn set_custom_scale(&mut self, scale: CustomScale) {
+        self.change_scale(TrackScale::Custom(scale));
+    }
+    /// The scale of the track being edited, the chromatic scale on its root for a custom scale.
+    pub fn scale(&self) -> &Scale {
+        self.virtual_grids[self.track].scale.as_scale()
+    }
+    /// The scale of the track being edited, standard or custom.
+    pub fn track_scale(&self) -> &TrackScale {
+        &self.virtual_grids[self.track].scale
+    }
+    /// The steps of the track being edited, and the lowest pitch of each of them, transposed,
+    /// `None` when a step has no notes. The pitches of a custom scale are the closest semitones.
+    pub fn steps<'a>(&'a self) -> impl Iterator<Item = (usize, Option<Pitch>)> + 'a {
+        let grid = &self.virtual_grids[self.track];
+        self.lowest_notes().map(move |(x, index)| {
+            (x, index.and_then(|index| grid.scale.idx_to_pitch(index)))
+        })
+    }
+    /// The steps of the track being edited, and the lowest pitch of each of them in volts,
+    /// transposed, `None` when a step has no notes.
+    pub fn steps_cv<'a>(&'a self) -> impl Iterator<Item = (usize, Option<f32>)> + 'a {
+        let grid = &self.virtual_grids[self.track];
+        self.lowest_notes().map(move |(x, index)| {
+            (x, index.and_then(|index| grid.scale.idx_to_cv(index)))
+        })
+    }
+    // The steps of the track being edited, and the index of the lowest note of each of them,
+    // transposed.
+    fn lowest_notes<'a>(&'a self) -> impl Iterator<Item = (usize, Option<usize>)> + 'a {
+        let grid = &self.virtual_grids[self.track];
+        let transpose = self.transpose;
+        let last = grid.scale.note_count() - 1;
+        grid.grid[..grid.width].iter().enumerate().map(move |(x, step)| {
+            // the lowest note is on the highest row
+            let row = step.notes().iter().max();
+            (x, row.map(|row| transposed_idx(&grid.scale, (last - *row as usize) as u8, transpose)))
+        })
+    }
+    /// Play `steps` steps, from 1 to 128, on the track being edited, at the next bar when
+    /// quantizing. The steps added are empty.
+    pub fn set_steps_count(&mut self, steps: usize) {
+        let steps = clamp(steps, 1, MAX_STEPS);
+        self.virtual_grids[self.track].change_steps_count(steps);
+        self.send_quantized(self.track, TrackMessage::Resize(steps));
+    }
+    /// Edit `track` on the grid, if it exists.
+    pub fn select_track(&mut self, track: usize) {
+        if track < self.virtual_grids.len() {
+            self.track = track;
+        }
+    }
+    /// Save the pattern of the track currently being edited, as JSON.
+    pub fn save_to_path(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &self.virtual_grids[self.track].to_pattern())?;
+        Ok(())
+    }
+    /// Store the pattern of the track being edited in snapshot `slot`, A is 0 and B is 1.
+    pub fn store_snapshot(&mut self, slot: usize) {
+        if slot < self.snapshots.len() {
+            self.snapshots[slot] = Some(self.virtual_grids[self.track].to_pattern());
+        }
+    }
+    /// Replace the pattern of the track being edited by snapshot `slot`, if it has been stored.
+    /// When playing, the renderer plays it from the beginning of the next loop of the track.
+    pub fn recall_snapshot(&mut self, slot: usize) {
+        if let Some(Some(ref pattern)) = self.snapshots.get(slot) {
+            self.virtual_grids[self.track].load_pattern(pattern);
+            self.send_pattern_as(self.track, Message::Looped);
+        }
+    }
+    /// Add the pattern in `slot` of the bank at the end of the song. If the slot is empty, the
+    /// pattern of the track being edited is stored there first.
+    pub fn chain(&mut self, slot: usize) {
+        if slot >= self.bank.len() {
+            return;
+        }
+        if self.bank[slot].is_none() {
+            self.bank[slot] = Some(self.virtual_grids[self.track].to_pattern());
+        }
+        self.song.push(slot);
+    }
+    /// Empty the song and the bank, and leave song mode.
+    pub fn clear_song(&mut self) {
+        self.set_song_mode(false);
+        self.song.clear();
+        self.bank = Default::default();
+        self.bank_names = Default::default();
+    }
+    /// Name the pattern in `slot` of the bank, the name is saved with the project.
+    pub fn name_pattern(&mut self, slot: usize, name: &str) {
+        if let Some(slot) = self.bank_names.get_mut(slot) {
+            *slot = name.to_string();
+        }
+    }
+    /// In song mode, the track being edited plays the patterns of the song in order, each one for
+    /// one loop, then starts again from the first one. Leaving song mode keeps the pattern playing.
+    pub fn set_song_mode(&mut self, song: bool) {
+        self.song_position = None;
+        self.song_step = None;
+        if song && !self.song.is_empty() {
+            self.song_position = Some((self.track, 0));
+            self.load_song_pattern();
+        }
+    }
+    // Send the next pattern of the song during the last step of each loop, so that the renderer
+    // plays it from its first step when the loop ends, like a snapshot.
+    fn advance_song(&mut self) {
+        let track = match self.song_position {
+            Some((track, _)) => track,
+            None => {
+                return;
+            }
+        };
+        if self.playing {
+            let ratio = self.steps_per_beat * self.clock_ratios[track];
+            let sixteenth = self.audio_clock.beat() * ratio;
+            self.advance_song_at(sixteenth as usize);
+        }
+    }
+    // Same as `advance_song`, when sixteenth `step` of the clock is being played.
+    fn advance_song_at(&mut self, step: usize) {
+        let (track, position) = match self.song_position {
+            Some(position) => position,
+            None => {
+                return;
+            }
+        };
+        let (start, end) = self.virtual_grids[track].loop_region();
+        let raw = step.saturating_sub(self.origin(track));
+        if (raw + 1) % (end - start) != 0 || self.song_step == Some(step) {
+            return;
+        }
+        self.song_step = Some(step);
+        self.song_position = Some((track, (position + 1) % self.song.len()));
+        self.load_song_pattern();
+    }
+    // Replace the pattern of the song track by the one at the current position of the song, with
+    // its length and scale, at the end of the loop when playing.
+    fn load_song_pattern(&mut self) {
+        if let Some((track, position)) = self.song_position {
+            if let Some(ref pattern) = self.bank[self.song[position]] {
+                self.virtual_grids[track].load_pattern(pattern);
+            }
+            self.send_pattern_as(track, Message::Looped);
+        }
+    }
+    /// In random walk, the pattern being edited slowly changes on its own: at the end of each loop,
+    /// a random step of the loop may have a note moved a degree up or down, or its notes toggled.
+    pub fn set_random_walk(&mut self, random_walk: bool) {
+        self.random_walk = random_walk;
+        self.walk_step = None;
+    }
+    /// The chance that the pattern changes at the end of each loop in random walk, from 0 (never)
+    /// to 1 (every loop).
+    pub fn set_walk_rate(&mut self, rate: f32) {
+        self.walk_rate = clamp(rate, 0.0, 1.0);
+    }
+    // Maybe mutate the pattern during the last step of each loop, in random walk.
+    fn random_walk(&mut self) {
+        if self.playing {
+            let ratio = self.steps_per_beat * self.clock_ratios[self.track];
+            let sixteenth = self.audio_clock.beat() * ratio;
+            self.random_walk_at(sixteenth as usize);
+        }
+    }
+    // Same as `random_walk`, when sixteenth `step` of the clock is being played.
+    fn random_walk_at(&mut self, step: usize) {
+        let (start, end) = self.virtual_grids[self.track].loop_region();
+        let raw = step.saturating_sub(self.origin(self.track));
+        if !self.random_walk || (raw + 1) % (end - start) != 0 || self.walk_step == Some(step) {
+            return;
+        }
+        self.walk_step = Some(step);
+        if self.walk_rng.unit() < self.walk_rate {
+            let ticks = self.virtual_grids[self.track].mutate(&mut self.walk_rng);
+            self.sender.send(Message::BeginBatch);
+            for tick in ticks {
+                self.send_track(TrackMessage::Tick(tick));
+            }
+            self.sender.send(Message::EndBatch);
+        }
+    }
+    /// The number of blocks the renderer took longer to render than they last, or that didn't have
+    /// the size of the first block, since it started.
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
+    }
+    /// The number of edits the renderer dropped since it started, because they had too many
+    /// messages for it to hold them. The renderer plays on with the pattern it had before each of
+    /// them.
+    pub fn dropped_batches(&self) -> usize {
+        self.dropped_batches.load(Ordering::Relaxed)
+    }
+    /// Where the controls are on the control row, see `ControlMap`. Fails if the map is wider than
+    /// the grid. The keys held are released.
+    pub fn set_control_map(&mut self, map: ControlMap) -> Result<(), MmmsError> {
+        if map.controls.len() > self.state_tracker.width {
+            return Err(MmmsError::InvalidControlMap);
+        }
+        let (width, height) = (self.state_tracker.width, self.state_tracker.height);
+        self.state_tracker = GridStateTracker::new(width, height);
+        self.state_tracker.controls = map;
+        Ok(())
+    }
+    /// The chord the grid keys add to the steps instead of a single note, `None` for a single note.
+    /// The pitch CV plays the lowest note of each step.
+    pub fn set_chord(&mut self, chord: Option<ChordType>) {
+        self.chord = chord;
+    }
+    /// Scroll the tracks up and down by octaves rather than by notes, so that the lowest row of
+    /// the view is a tonic, except at the ends of the scale.
+    pub fn set_snap_to_octave(&mut self, snap: bool) {
+        for grid in self.virtual_grids.iter_mut() {
+            grid.snap_to_octave = snap;
+        }
+    }
+    /// The brightness of the keys of the grid.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+    /// Which rows are lit when they have no notes.
+    pub fn set_view_mode(&mut self, view_mode: ViewMode) {
+        self.view_mode = view_mode;
+    }
+    /// Flash the tempo key on each beat, brighter on the first beat of each bar.
+    pub fn set_metronome(&mut self, metronome: bool) {
+        self.metronome = metronome;
+    }
+    /// Blink the left or right key toward the playhead when it's scrolled out of view, on the
+    /// first half of each step.
+    pub fn set_offscreen_playhead(&mut self, show: bool) {
+        self.offscreen_playhead = show;
+    }
+    /// Scroll the view to the page of the playhead when it leaves the view while playing, a page
+    /// at a time rather than following each step. The view can still be scrolled by hand, until
+    /// the playhead leaves it again.
+    pub fn set_follow_playhead(&mut self, follow: bool) {
+        self.follow_playhead = follow;
+    }
+    /// How often the selected note is added to the pattern, while holding scale and velocity.
+    pub fn set_note_repeat_rate(&mut self, rate: RepeatRate) {
+        self.note_repeat.rate = rate;
+        self.note_repeat.stop();
+    }
+    // While the note repeat keys are held, add the selected note to the steps played at each
+    // repeat. The repeats stop as soon as the keys are released.
+    fn repeat_notes(&mut self) {
+        if !self.playing || !self.state_tracker.note_repeat_down() {
+            self.note_repeat.stop();
+            return;
+        }
+        let row = self.virtual_grids[self.track].selected_row();
+        for beat in self.note_repeat.repeats(self.audio_clock.beat()) {
+            let x = self.step_at(self.track, beat);
+            let step = &mut self.virtual_grids[self.track].grid[x];
+            if step.notes().contains(&(row as u8)) {
+                continue;
+            }
+            let rest = step.is_rest();
+            step.toggle(row as u8);
+            self.send_track(TrackMessage::Tick((x, row)));
+            if rest {
+                self.send_track(TrackMessage::Rest((x, false)));
+            }
+        }
+    }
+    // While an arrow key is held, move the view again once the repeat delay has passed, then at a
+    // steady rate.
+    fn repeat_moves(&mut self, now: time::Instant) {
+        let ((x, y), count) = match self.held_move {
+            Some(ref mut held) => {
+                let due = held.due(now);
+                let count = due.saturating_sub(held.repeats);
+                held.repeats = cmp::max(held.repeats, due);
+                (held.mouve, count)
+            }
+            None => return,
+        };
+        for _ in 0..count {
+            self.virtual_grids[self.track].mouve(x, y);
+        }
+    }
+    // Forget the arrow held at `x`, `y` when it's released, returning whether its move has
+    // already been repeated, in which case releasing it doesn't move the view again.
+    fn release_move(&mut self, x: usize, y: usize) -> bool {
+        match self.held_move.take() {
+            Some(ref held) if held.x == x && y == 0 => held.repeats > 0,
+            held => {
+                self.held_move = held;
+                false
+            }
+        }
+    }
+    // The action of the key released at `x`, `y`, in the views open.
+    fn release(&mut self, x: usize, y: usize) -> MMMSAction {
+        self.state_tracker.views = Views {
+            probability: self.editing_probability,
+            tempo: self.editing_tempo,
+            transpose: self.editing_transpose,
+        };
+        self.state_tracker.up(x, y)
+    }
+    /// The step of `track` being played, read from the clock shared with the renderer, without
+    /// locking. Panics if there is no such track.
+    pub fn playhead(&self, track: usize) -> usize {
+        self.step_at(track, self.audio_clock.beat())
+    }
+    // The step of `track` played at `beat`.
+    fn step_at(&self, track: usize, beat: f32) -> usize {
+        let sixteenth = beat * self.steps_per_beat * self.clock_ratios[track];
+        let raw = (sixteenth as usize).saturating_sub(self.origin(track));
+        let (loop_start, loop_end) = self.virtual_grids[track].loop_region();
+        loop_start + direction_step(self.direction, DIRECTION_SEED, raw, loop_end - loop_start)
+    }
+    // The sixteenth of the clock from which the renderer plays the pattern of `track` from its
+    // first step.
+    fn origin(&self, track: usize) -> usize {
+        self.origins[track].load(Ordering::Relaxed)
+    }
+    /// Save the patterns of all the tracks to `path` a little while after they change, or never
+    /// when `None`.
+    pub fn set_autosave_path(&mut self, path: Option<PathBuf>) {
+        self.autosave_path = path;
+    }
+    /// Why the patterns couldn't be saved the last time they changed, `None` if they have been.
+    pub fn autosave_error(&self) -> Option<&io::Error> {
+        self.autosave_error.as_ref()
+    }
+    // Save the patterns if they have changed, and haven't been edited for a little while. This
+    // does file io, it's done on the main thread.
+    fn autosave(&mut self) {
+        let delay = time::Duration::from_millis(AUTOSAVE_DELAY);
+        match self.dirty.get() {
+            Some(edit) if edit.elapsed() >= delay => {}
+            _ => {
+                return;
+            }
+        }
+        self.dirty.set(None);
+        self.autosave_error = match self.autosave_path {
+            Some(ref path) => self.save_autosave(path).err(),
+            None => None,
+        };
+    }
+    // Save the patterns of all the tracks, with their track.
+    fn save_autosave(&self, path: &Path) -> io::Result<()> {
+        let tracks: Vec<TrackPattern> = self.virtual_grids.iter().enumerate().map(|(track, grid)| {
+            TrackPattern { track, pattern: grid.to_pattern() }
+        }).collect();
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &tracks)?;
+        Ok(())
+    }
+    // Load the patterns saved by `save_autosave`, leaving out the tracks that don't exist.
+    fn load_autosave(&mut self, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let tracks: Vec<TrackPattern> = serde_json::from_reader(file)?;
+        for saved in tracks.iter() {
+            check_version(saved.pattern.version, PATTERN_VERSION)?;
+        }
+        let count = self.virtual_grids.len();
+        for saved in tracks.iter().filter(|saved| saved.track < count) {
+            self.virtual_grids[saved.track].load_pattern(&saved.pattern);
+            self.send_pattern(saved.track);
+        }
+        Ok(())
+    }
+    /// Load a pattern saved with `save_to_path`, replacing the one of the track currently being
+    /// edited. Values that are out of range are clamped. Fails with `InvalidData` for a pattern
+    /// saved by a newer version, in a format that isn't known.
+    pub fn load_from_path(&mut self, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let pattern: Pattern = serde_json::from_reader(file)?;
+        check_version(pattern.version, PATTERN_VERSION)?;
+        self.virtual_grids[self.track].load_pattern(&pattern);
+        self.send_pattern(self.track);
+        Ok(())
+    }
+    /// Save the patterns of the bank with their names, the song, the tempo and the palette, as
+    /// JSON.
+    pub fn save_project(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &self.to_project())?;
+        Ok(())
+    }
+    /// Load a project saved with `save_project`, replacing the bank and the song, and entering
+    /// song mode if it was saved in song mode. The patterns in slots that don't exist and the
+    /// slots of the song that don't exist are left out. Fails with `InvalidData` for a project,
+    /// or a pattern of its bank, saved by a newer version, in a format that isn't known.
+    pub fn load_project(&mut self, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        let project: Project = serde_json::from_reader(file)?;
+        check_version(project.version, PROJECT_VERSION)?;
+        for saved in project.bank.iter() {
+            check_version(saved.pattern.version, PATTERN_VERSION)?;
+        }
+        self.load_project_data(project);
+        Ok(())
+    }
+    fn to_project(&self) -> Project {
+        let bank = self.bank.iter().enumerate().filter_map(|(slot, pattern)| {
+            pattern.as_ref().map(|pattern| BankPattern {
+                slot,
+                name: self.bank_names[slot].clone(),
+                pattern: pattern.clone(),
+            })
+        }).collect();
+        Project {
+            version: PROJECT_VERSION,
+            tempo: self.tempo,
+            palette: self.palette,
+            bank,
+            song: self.song.clone(),
+            song_mode: self.song_position.is_some(),
+        }
+    }
+    fn load_project_data(&mut self, project: Project) {
+        self.clear_song();
+        self.set_tempo(project.tempo);
+        self.palette = project.palette;
+        for saved in project.bank.into_iter().filter(|saved| saved.slot < BANK_SIZE) {
+            self.bank_names[saved.slot] = saved.name;
+            self.bank[saved.slot] = Some(saved.pattern);
+        }
+        self.song = project.song.into_iter().filter(|slot| *slot < BANK_SIZE).collect();
+        self.set_song_mode(project.song_mode);
+    }
+    // Send a message about the track being edited.
+    fn send_track(&self, message: TrackMessage) {
+        self.send_to(self.track, message);
+    }
+    // Send a message about `track`, to be saved if it changes its pattern.
+    fn send_to(&self, track: usize, message: TrackMessage) {
+        self.touch(&message);
+        self.sender.send(Message::Track(track, message));
+    }
+    // Same as `send_to`, for a change of the structure of the pattern, that can wait for the
+    // next bar.
+    fn send_quantized(&self, track: usize, message: TrackMessage) {
+        self.touch(&message);
+        self.sender.send(Message::Quantized(track, message));
+    }
+    // Save the patterns a little while after `message`, if it changes one.
+    fn touch(&self, message: &TrackMessage) {
+        if message.changes_pattern() {
+            self.dirty.set(Some(time::Instant::now()));
+        }
+    }
+    // Send the whole pattern of `track` to the renderer, replacing what it was playing at the next
+    // bar when quantizing.
+    fn send_pattern(&self, track: usize) {
+        self.send_pattern_as(track, Message::Quantized);
+    }
+    // Same as `send_pattern`, each change of the track being sent as `message`, to choose when
+    // the renderer applies them.
+    fn send_pattern_as(&self, track: usize, message: fn(usize, TrackMessage) -> Message) {
+        let send = |change: TrackMessage| {
+            self.touch(&change);
+            self.sender.send(message(track, change));
+        };
+        self.sender.send(Message::BeginBatch);
+        send(TrackMessage::Clear);
+        send(TrackMessage::Resize(self.virtual_grids[track].steps_count()));
+        send(TrackMessage::Scale(self.virtual_grids[track].current_scale()));
+        for (x, step) in self.virtual_grids[track].grid.iter().enumerate() {
+            for row in step.notes().iter() {
+                send(TrackMessage::Tick((x, *row as usize)));
+            }
+            if step.velocity != MAX_VELOCITY {
+                send(TrackMessage::Velocity((x, step.velocity)));
+            }
+            if step.probability != MAX_PROBABILITY {
+                send(TrackMessage::Probability((x, step.probability)));
+            }
+            if step.ratchet != 1 {
+                send(TrackMessage::Ratchet((x, step.ratchet)));
+            }
+            if step.accent {
+                send(TrackMessage::Accent((x, true)));
+            }
+            if step.tie {
+                send(TrackMessage::Tie((x, true)));
+            }
+            if step.is_rest() {
+                send(TrackMessage::Rest((x, true)));
+            }
+            if step.nudge != 0 {
+                send(TrackMessage::Nudge((x, step.nudge)));
+            }
+            if step.condition != Condition::Always {
+                send(TrackMessage::Condition((x, step.condition)));
+            }
+        }
+        self.sender.send(Message::EndBatch);
+    }
+    /// Change the tempo, in beats per minute, clamped between 20 and 300. The playback continues
+    /// from the current position.
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = clamp(tempo, MIN_TEMPO, MAX_TEMPO);
+        // The renderer advances the new clock to its own position when it receives it, start from
+        // ours so that the playhead doesn't jump in the meantime.
+        let (mut updater, consumer) = audio_clock(self.tempo, CLOCK_RATE);
+        updater.increment(beats_to_frames(self.audio_clock.beat(), self.tempo));
+        self.audio_clock = consumer.clone();
+        self.trigger_duration = clamp_trigger_duration(self.trigger_duration, self.tempo);
+        self.sender.send(Message::TempoChange(self.tempo, Clock { updater, consumer }));
+    }
+    // Set the tempo from the average interval between the last taps, from the second tap. A tap
+    // long after the previous one starts over.
+    fn tap_tempo(&mut self, now: time::Instant) {
+        let timeout = time::Duration::from_millis(TAP_TEMPO_TIMEOUT);
+        if self.taps.last().map_or(false, |last| now.duration_since(*last) > timeout) {
+            self.taps.clear();
+        }
+        self.taps.push(now);
+        if self.taps.len() > TAP_TEMPO_INTERVALS + 1 {
+            self.taps.remove(0);
+        }
+        if self.taps.len() < 2 {
+            return;
+        }
+        let elapsed = now.duration_since(self.taps[0]);
+        let interval = elapsed.as_secs_f32() / (self.taps.len() - 1) as f32;
+        self.set_tempo(60. / interval);
+    }
+    /// Go back to the first step of all the tracks, on the next sixteenth so that the step being
+    /// played isn't cut. The playhead is at the first step right away. With an external clock,
+    /// the position follows the clock instead.
+    pub fn reset(&mut self) {
+        let (updater, consumer) = audio_clock(self.tempo, CLOCK_RATE);
+        self.audio_clock = consumer.clone();
+        self.note_repeat.stop();
+        self.sender.send(Message::Reset(Clock { updater, consumer }));
+    }
+    // Play the note of the key at `vx`, `vy` in the view once, transposed, without changing the
+    // pattern.
+    fn preview_note(&mut self, vx: usize, vy: usize) {
+        let grid = &self.virtual_grids[self.track];
+        if let Some((_, row)) = grid.vaddress(vx, vy) {
+            let index = grid.scale.note_count() - 1 - row;
+            let pitch = transposed_pitch(&grid.scale, index as u8, self.transpose);
+            self.sender.send(Message::PreviewNote(self.track, pitch));
+        }
+    }
+    // Start or stop the playback.
+    fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+        self.sender.send(if playing { Message::Start } else { Message::Stop });
+    }
+    // Change the scale of the track being edited, the notes move to the new scale.
+    fn change_scale(&mut self, scale: TrackScale) {
+        let current = self.virtual_grids[self.track].current_scale();
+        if !scale.same_as(&current) {
+            // the notes move to the new scale, send the whole pattern again
+            self.virtual_grids[self.track].remap_scale(scale);
+            self.send_pattern(self.track);
+        }
+    }
+    // Apply a command received over OSC, the values are clamped like the ones from the grid.
+    #[cfg(feature = "osc")]
+    fn handle_osc(&mut self, command: OscCommand) {
+        match command {
+            OscCommand::Tempo(tempo) => {
+                self.set_tempo(tempo);
+            }
+            OscCommand::Scale(root, scale_type) => {
+                let scale_type = SCALE_TYPES[clamp(scale_type, 0, SCALE_TYPES.len() - 1)];
+                let scale = Scale::new(pitch_class(root), scale_type);
+                self.picked_scale = scale.clone();
+                self.change_scale(TrackScale::Standard(scale));
+            }
+            OscCommand::Start => {
+                self.set_playing(true);
+            }
+            OscCommand::Stop => {
+                self.set_playing(false);
+            }
+            OscCommand::Reroll => {
+                self.reroll();
+            }
+        }
+    }
+    /// Change the order in which the steps are played.
+    pub fn set_direction(&mut self, direction: Direction) {
+        self.direction = direction;
+        self.sender.send(Message::Direction(direction));
+    }
+    /// When the steps with a probability decide whether they fire, on all the tracks.
+    pub fn set_dice(&mut self, dice: Dice) {
+        self.sender.send(Message::Dice(dice));
+    }
+    /// Decide again whether the steps with a probability fire, with frozen dice.
+    pub fn reroll(&mut self) {
+        self.sender.send(Message::Reroll);
+    }
+    /// Play the next loop of all the tracks as a fill, once.
+    pub fn fill(&mut self) {
+        self.sender.send(Message::Fill);
+    }
+    /// On which loops `step` of the track being edited plays.
+    pub fn set_condition(&mut self, step: usize, condition: Condition) {
+        if step < self.virtual_grids[self.track].steps_count() {
+            let condition = condition.clamped();
+            self.virtual_grids[self.track].grid[step].condition = condition;
+            self.send_track(TrackMessage::Condition((step, condition)));
+        }
+    }
+    /// Only play the steps from `start` to `end`, excluded, on all the tracks. The region is
+    /// clamped to the length of each track, an empty region plays the whole pattern.
+    pub fn set_loop_region(&mut self, start: usize, end: usize) {
+        for grid in self.virtual_grids.iter_mut() {
+            grid.set_loop_region(start, end);
+        }
+        self.sender.send(Message::LoopRegion(start, end));
+    }
+    // Select a fundamental or a scale type from a key press in the picker. The fundamentals are in
+    // fifths order from C, scale types are one per column, to the right. The bottom left key
+    // changes the view mode.
+    fn pick_scale(&mut self, x: usize, y: usize) {
+        if x == 0 && y == self.height - 2 {
+            self.view_mode = match self.view_mode {
+                ViewMode::Functions => ViewMode::Degrees,
+                ViewMode::Degrees => ViewMode::Functions,
+            };
+            return;
+        }
+        let fundamental = self.picked_scale.fundamental();
+        let scale_type = self.picked_scale.scale_type();
+        if x < 4 && y < 3 {
+            let mut pitch = PitchClass::C;
+            for _ in 0..y * 4 + x {
+                pitch = pitch.fifth();
+            }
+            self.picked_scale = Scale::new(pitch, scale_type);
+        } else if x >= 5 && x - 5 < SCALE_TYPES.len() {
+            self.picked_scale = Scale::new(fundamental, SCALE_TYPES[x - 5]);
+        }
+    }
+    /// The lowest pitch of step `x` of `track` in volts, before the transposition. `None` when
+    /// the step has no notes, or when there is no such track or step.
+    pub fn get_step(&self, track: usize, x: usize) -> Option<f32> {
+        let grid = self.virtual_grids.get(track)?;
+        grid.row_to_pitch(grid.get_step(x)?)
+    }
+    /// Make step `x` of `track` play the note of its scale closest to `pitch`, in volts, or
+    /// nothing when `None`, instead of its notes, on the grid and in the renderer.
+    pub fn set_step(&mut self, track: usize, x: usize, pitch: Option<f32>)
+                    -> Result<(), MmmsError> {
+        let grid = self.virtual_grids.get_mut(track).ok_or(MmmsError::InvalidTrack)?;
+        let y = pitch.map(|pitch| grid.pitch_to_row(pitch));
+        let rest = grid.grid.get(x).map_or(false, |step| step.is_rest());
+        let removed = grid.set_step(x, y)?;
+        self.sender.send(Message::BeginBatch);
+        for row in removed.iter().map(|row| *row as usize).chain(y) {
+            self.send_to(track, TrackMessage::Tick((x, row)));
+        }
+        // the step isn't a rest anymore, the tick of a new note already replaces the rest
+        if rest && y.is_none() {
+            self.send_to(track, TrackMessage::Rest((x, false)));
+        }
+        self.sender.send(Message::EndBatch);
+        Ok(())
+    }
+    /// Change the velocity of the steps with notes of the track being edited, from step `from` to
+    /// step `to`, so that it goes from `start` to `end` in a straight line, for a crescendo or a
+    /// decrescendo. Velocities are clamped to 127. In the velocity view, holding a key and pressing
+    /// another one in another column does the same between the two velocities.
+    pub fn velocity_ramp(&mut self, from: usize, to: usize, start: u8, end: u8) {
+        self.sender.send(Message::BeginBatch);
+        for velocity in self.virtual_grids[self.track].velocity_ramp(from, to, start, end) {
+            self.send_track(TrackMessage::Velocity(velocity));
+        }
+        self.sender.send(Message::EndBatch);
+    }
+    // Add a note at `x`, `y` of the view of the track being edited, unless it's already there, and
+    // send the change.
+    fn paint(&mut self, x: usize, y: usize) {
+        let xy = match self.virtual_grids[self.track].vaddress(x, y) {
+            Some(xy) => xy,
+            None => {
+                return;
+            }
+        };
+        if self.virtual_grids[self.track].grid[xy.0].is_rest() {
+            // the first tick only removes the rest
+            self.virtual_grids[self.track].tick(x, y);
+            self.send_track(TrackMessage::Rest((xy.0, false)));
+        }
+        if !self.virtual_grids[self.track].grid[xy.0].notes().contains(&(xy.1 as u8)) {
+            self.virtual_grids[self.track].tick(x, y);
+            self.send_track(TrackMessage::Tick(xy));
+        }
+    }
+    // Replace the notes of the bar in view by random notes, and send the changes.
+    fn randomize(&mut self, density: f32) {
+        let grid = &mut self.virtual_grids[self.track];
+        let start = grid.bar_start();
+        let end = cmp::min(start + 16, grid.steps_count());
+        let before: Vec<_> = grid.grid[start..end].iter().map(|s| s.kind.clone()).collect();
+        grid.randomize(density, &mut self.rng);
+        // a tick toggles a note: tick the notes that are only before or only after, and remove
+        // the rests that have been cleared
+        let mut ticks = Vec::new();
+        let mut rests = Vec::new();
+        for (i, kind) in before.iter().enumerate() {
+            let after = grid.grid[start + i].notes();
+            let notes: &[u8] = match *kind {
+                StepKind::Note(ref notes) => notes,
+                StepKind::Empty | StepKind::Rest => &[],
+            };
+            if *kind == StepKind::Rest && !grid.grid[start + i].is_rest() {
+                rests.push(start + i);
+            }
+            for row in notes.iter().filter(|row| !after.contains(row)) {
+                ticks.push((start + i, *row as usize));
+            }
+            for row in after.iter().filter(|row| !notes.contains(row)) {
+                ticks.push((start + i, *row as usize));
+            }
+        }
+        self.sender.send(Message::BeginBatch);
+        for x in rests {
+            self.send_track(TrackMessage::Rest((x, false)));
+        }
+        for tick in ticks {
+            self.send_track(TrackMessage::Tick(tick));
+        }
+        self.sender.send(Message::EndBatch);
+    }
+    // Light the steps from `start` to `end` for a few refreshes, to confirm they've been cleared.
+    fn flash(&mut self, start: usize, end: usize) {
+        self.flash = CLEAR_FLASH_FRAMES;
+        self.flash_steps = (start, end);
+    }
+    fn scale_picker(&self, current_scale: Scale, grid: &mut [u8]) {
+        let width = self.width;
+        assert!(grid.len() == (self.height - 1) * width);
+        let mut pitch = PitchClass::C;
+        // fundamental picker
+        for i in 0..3 {
+            for j in 0..4 {
+                grid[i * width + j] = if pitch == current_scale.fundamental() { 15 } else { 8 };
+                pitch = pitch.fifth();
+            }
+        }
+
+        // view mode, lit when showing all the degrees
+        grid[(self.height - 2) * width] = if self.view_mode == ViewMode::Degrees { 15 } else { 4 };
+
+        // Scale picker
+        // 4 + 1 of padding for the fundamental picker
+        let mut h_offset = 5;
+        let mut itv = SmallVec::<[u8; 12]>::new();
+        for scale in SCALE_TYPES.iter() {
+            // the types that don't fit aren't shown on narrow grids
+            if h_offset >= width {
+                break;
+            }
+            Scale::type_to_intervals(scale, &mut itv);
+            // draw it on the right hand side. Only the seven first notes.
+            let note_count_clamped = clamp(itv.len(), 0, 7);
+            for i in 0..note_count_clamped {
+                let steps2luminosity = [
+                    5, // 1 semitone
+                    9, // 2 semitones
+                    11, // 3 semitones
+                    13 // 4 semitonees
+                ];
+                let lum_modifier = if *scale == current_scale.scale_type() {
+                    2
+                } else {
+                    0
+                };
+                grid[i * width + h_offset] = lum_modifier + steps2luminosity[(itv[i] - 1) as usize];
+            }
+            h_offset += 1;
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum MMMSIntent {
+    Nothing,
+    Tick,
+    // a modifier that has been used with another key
+    Combo,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum MMMSAction {
+    Nothing,
+    Tick((usize, usize)),
+    Move((isize, isize)),
+    Clear,
+    ClearBar,
+    ToggleScale,
+    ToggleVelocity,
+    ToggleProbability,
+    ToggleTempo,
+    ToggleTranspose,
+    CycleDirection,
+    Transport,
+    Resize(usize), // number is the number of bars
+    Euclid { pulses: usize, steps: usize },
+    TriggerDuration(isize), // halve or double the trigger duration
+    SelectTrack(usize),
+    Randomize(f32), // density
+    Rotate(isize), // steps to the right
+    Reverse,
+    // scale, shift and the left or right arrow: remove or add a step at the end of the pattern
+    Length(isize),
+    // scale, shift and one of the first eight keys: add a pattern of the bank to the song,
+    // storing the current one if the slot is empty
+    Chain(usize),
+    // scale, shift and velocity: empty the song, and direction: enter or leave song mode
+    ClearSong,
+    ToggleSong,
+    // scale and the up or down arrow recall snapshot A or B, with shift they store it
+    StoreSnapshot(usize),
+    RecallSnapshot(usize),
+    // shift, scale and a grid key: reflect the notes across its row
+    Invert(usize),
+    // a grid key held and the left or right arrow: move its step earlier or later
+    Nudge((usize, i8)),
+    // a grid key held and the up or down arrow: move its note an octave up or down
+    Octave((usize, usize, isize)),
+    // shift and a grid key: accent a lit step, change the ratchet of the others
+    StepOption((usize, usize)),
+    // the direction key held and a grid key: mute or unmute its row
+    MuteRow(usize),
+    // shift and the second track in the probability view: decide again which steps fire, and
+    // shift and probability: play the next loop as a fill
+    Reroll,
+    ArmFill,
+    // shift and transport in the tempo view: go through no count in, one bar and two bars, and
+    // shift and tempo: go back to the first step
+    CycleCountIn,
+    Reset,
+    // shift and transpose in the transpose view: toggle the preview of the notes, and shift and
+    // the first track: go through the chords
+    TogglePreview,
+    CycleChord,
+    // shift, a key held and another one to its right on the same row: tie the steps in between,
+    // and the last one, to the first one
+    Tie((usize, usize)),
+    // a key held and another one to its right on the same row with the scale button held: loop
+    // the columns from the first key to the second one, a single key loops the whole pattern again
+    Loop(Option<(usize, usize)>),
+    // a key held and another one in another column: add a note in each column from the first key
+    // to the second one, on the line between them. In the velocity and probability views, ramp
+    // the velocity or the probability of these columns instead
+    Paint(((usize, usize), (usize, usize))),
+}
+
+/// A control of the control row, in the order of the keys of a monome 128. With shift held, the
+/// first eight fill the bar with a euclidean rhythm of one to eight pulses.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Control {
+    /// Start or stop the playback.
+    Transport,
+    /// Show or leave the tempo view. In the tempo view, the grid keys tap the tempo and, with shift
+    /// held, transport goes through the count ins and tempo goes back to the first step.
+    Tempo,
+    /// Show or leave the probability view. In the probability view, with shift held, probability
+    /// arms a fill and the second track decides again which steps with a probability fire.
+    Probability,
+    /// Show or leave the transpose view. In the transpose view, with shift held, transpose toggles
+    /// the preview of the notes and the first track goes through the chords.
+    Transpose,
+    /// Edit one of the four tracks.
+    Track(usize),
+    /// Scroll a page to the left, with shift resize the pattern to one bar.
+    Left,
+    /// Scroll a page to the right, with shift resize the pattern to two bars.
+    Right,
+    /// Scroll a note up, with shift resize the pattern to four bars.
+    Up,
+    /// Scroll a note down, with shift resize the pattern to eight bars.
+    Down,
+    /// Show or leave the velocity view, with shift halve the trigger duration.
+    Velocity,
+    /// Change the direction of the playback, with shift double the trigger duration. Held, the
+    /// keys of the grid mute or unmute their row.
+    Direction,
+    /// Open or close the scale picker, with shift clear the bar in view.
+    Scale,
+    /// Shift.
+    Shift,
+}
+
+impl Control {
+    // The position of the control on a monome 128.
+    fn index(&self) -> usize {
+        match *self {
+            Control::Transport => 0,
+            Control::Tempo => 1,
+            Control::Probability => 2,
+            Control::Transpose => 3,
+            Control::Track(track) => 4 + track,
+            Control::Left => 8,
+            Control::Right => 9,
+            Control::Up => 10,
+            Control::Down => 11,
+            Control::Velocity => 12,
+            Control::Direction => 13,
+            Control::Scale => 14,
+            Control::Shift => 15,
+        }
+    }
+}
+
+/// The controls of a grid 16 keys wide.
+const WIDE_CONTROLS: [Control; 16] = [
+    Control::Transport, Control::Tempo, Control::Probability, Control::Transpose,
+    Control::Track(0), Control::Track(1), Control::Track(2), Control::Track(3),
+    Control::Left, Control::Right, Control::Up, Control::Down,
+    Control::Velocity, Control::Direction, Control::Scale, Control::Shift,
+];
+/// The keys of the control row of a grid 8 keys wide: transport, tempo, the four arrows, scale and
+/// shift.
+const NARROW_CONTROLS: [Control; 8] = [
+    Control::Transport, Control::Tempo, Control::Left, Control::Right, Control::Up, Control::Down,
+    Control::Scale, Control::Shift,
+];
+/// Same with shift held: the first six euclidian rythms, clear bar and shift.
+const NARROW_SHIFT_CONTROLS: [Control; 8] = [
+    Control::Transport, Control::Tempo, Control::Probability, Control::Transpose,
+    Control::Track(0), Control::Track(1), Control::Scale, Control::Shift,
+];
+
+/// The control of each key of the control row, from the left, and with shift held. A grid 16 keys
+/// wide has one key per control, a grid 8 keys wide only has some of them, the keys change with
+/// shift held. The keys past the end of the map have no control. The keys combined with scale
+/// stay where they are: randomize, rotate, the snapshots and the song.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlMap {
+    controls: Vec<Control>,
+    shift_controls: Vec<Control>,
+}
+
+impl ControlMap {
+    /// The controls of `controls`, and `shift_controls` with shift held. Fails if they don't have
+    /// the same length, if scale or shift don't have a key, the same one in both, or if there is a
+    /// track past the fourth one.
+    pub fn new(controls: &[Control], shift_controls: &[Control]) -> Result<ControlMap, MmmsError> {
+        let map = ControlMap {
+            controls: controls.to_vec(),
+            shift_controls: shift_controls.to_vec(),
+        };
+        let modifiers = [Control::Scale, Control::Shift].iter().all(|modifier| {
+            let keys = map.controls.iter().filter(|control| *control == modifier).count();
+            let shifted = map.shift_controls.iter().position(|control| control == modifier);
+            keys == 1 && map.position(modifier.index()) == shifted
+        });
+        let tracks = controls.iter().chain(shift_controls.iter()).all(|control| match *control {
+            Control::Track(track) => track < MAX_TRACKS,
+            _ => true,
+        });
+        if controls.len() != shift_controls.len() || !modifiers || !tracks {
+            return Err(MmmsError::InvalidControlMap);
+        }
+        Ok(map)
+    }
+    /// The controls of a monome 128 for a grid 16 keys wide, or of a monome 64.
+    pub fn for_width(width: usize) -> ControlMap {
+        if width >= 16 {
+            ControlMap { controls: WIDE_CONTROLS.to_vec(), shift_controls: WIDE_CONTROLS.to_vec() }
+        } else {
+            ControlMap {
+                controls: NARROW_CONTROLS.to_vec(),
+                shift_controls: NARROW_SHIFT_CONTROLS.to_vec(),
+            }
+        }
+    }
+    // The control of the key at `x`, in the order of a monome 128.
+    fn control(&self, x: usize, shift: bool) -> Option<usize> {
+        let controls = if shift { &self.shift_controls } else { &self.controls };
+        controls.get(x).map(Control::index)
+    }
+    // Where `control` is when shift isn't held, if the map has it.
+    fn position(&self, control: usize) -> Option<usize> {
+        self.controls.iter().position(|c| c.index() == control)
+    }
+}
+
+// The views open, in which some of the controls do something else with shift held.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct Views {
+    probability: bool,
+    tempo: bool,
+    transpose: bool,
+}
+
+struct GridStateTracker {
+    buttons: Vec<MMMSIntent>,
+    width: usize,
+    height: usize,
+    controls: ControlMap,
+    views: Views,
+}
+
+impl GridStateTracker {
+    fn new(width: usize, height: usize) -> GridStateTracker {
+        GridStateTracker {
+            width,
+            height,
+            buttons: vec![MMMSIntent::Nothing; width * height],
+            controls: ControlMap::for_width(width),
+            views: Views::default(),
+        }
+    }
+
+    // The shift and the scale change buttons, by default the last two keys of the control row.
+    fn shift_key(&self) -> usize {
+        self.controls.position(Control::Shift.index()).unwrap()
+    }
+    fn scale_key(&self) -> usize {
+        self.controls.position(Control::Scale.index()).unwrap()
+    }
+    fn shift_down(&self) -> bool {
+      self.buttons[Self::idx(self.width, self.shift_key(), 0)] != MMMSIntent::Nothing
+    }
+    fn scale_down(&self) -> bool {
+      self.buttons[Self::idx(self.width, self.scale_key(), 0)] != MMMSIntent::Nothing
+    }
+    // The control of the key at `x` on the control row, numbered as on a grid 16 keys wide. Narrow
+    // grids only have some of the controls.
+    fn control(&self, x: usize) -> Option<usize> {
+        self.controls.control(x, self.shift_down())
+    }
+    // Where `control` is on the control row, when shift isn't held, if this grid has it.
+    fn control_position(&self, control: usize) -> Option<usize> {
+        self.controls.position(control)
+    }
+
+    // A key of the grid below the control row that is held, if any.
+    fn held_grid_key(&self) -> Option<(usize, usize)> {
+        (self.width..self.width * self.height).find(|i| {
+            self.buttons[*i] == MMMSIntent::Tick
+        }).map(|i| (i % self.width, i / self.width))
+    }
+
+    // The move of the key at `x`, `y`, if it's an arrow pressed without modifier or held grid
+    // key.
+    fn arrow_move(&self, x: usize, y: usize) -> Option<(isize, isize)> {
+        if y != 0 || self.shift_down() || self.scale_down() || self.held_grid_key().is_some() {
+            return None;
+        }
+        let page = self.width as isize;
+        match self.control(x) {
+            Some(8) => Some((-page, 0)),
+            Some(9) => Some((page, 0)),
+            Some(10) => Some((0, -1)),
+            Some(11) => Some((0, 1)),
+            _ => None,
+        }
+    }
+
+    // Where the direction key is, if it's held to mute rows.
+    fn mute_key_down(&self) -> Option<usize> {
+        self.control_position(13).filter(|x| {
+            self.buttons[Self::idx(self.width, *x, 0)] != MMMSIntent::Nothing
+        })
+    }
+
+    // The action of the control `x` with shift held in the views open, if it has one there.
+    fn view_action(&self, x: usize) -> Option<MMMSAction> {
+        match x {
+            0 if self.views.tempo => Some(MMMSAction::CycleCountIn),
+            1 if self.views.tempo => Some(MMMSAction::Reset),
+            2 if self.views.probability => Some(MMMSAction::ArmFill),
+            3 if self.views.transpose => Some(MMMSAction::TogglePreview),
+            4 if self.views.transpose => Some(MMMSAction::CycleChord),
+            5 if self.views.probability => Some(MMMSAction::Reroll),
+            _ => None,
+        }
+    }
+
+    // Whether scale and velocity are held, to repeat the selected note.
+    fn note_repeat_down(&self) -> bool {
+        match self.control_position(12) {
+            Some(x) => self.buttons[Self::idx(self.width, x, 0)] == MMMSIntent::Combo,
+            None => false,
+        }
+    }
+
+    fn down(&mut self, x: usize, y: usize) {
+        if y == 0 {
+            // control row, only shift and the scale change button are modifiers
+            if x == self.shift_key() || x == self.scale_key() {
+                self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Tick;
+            } else if self.scale_down() && !self.shift_down() && self.control(x) == Some(12) {
+                // note repeat, until the velocity key is released
+                let scale_key = self.scale_key();
+                self.buttons[Self::idx(self.width, scale_key, 0)] = MMMSIntent::Combo;
+                self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Combo;
+            } else if !self.scale_down() && !self.shift_down() && self.control(x) == Some(13) {
+                // the direction key mutes the rows while it's held
+                self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Tick;
+            } else {
+                self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Nothing;
+            }
+        } else {
+            self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Tick;
+        }
+    }
+    fn up(&mut self, x: usize, y: usize) -> MMMSAction {
+        if y == 0 {
+            let intent = self.buttons[Self::idx(self.width, x, y)].clone();
+            self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Nothing;
+            // the scale button is also a modifier, it doesn't open the scale picker when used
+            // like this
+            if x != self.scale_key() && x != self.shift_key() && self.scale_down() {
+                let scale_key = self.scale_key();
+                self.buttons[Self::idx(self.width, scale_key, 0)] = MMMSIntent::Combo;
+                return match x {
+                    0..=7 if self.shift_down() => MMMSAction::Chain(x),
+                    0..=7 => MMMSAction::Randomize((x + 1) as f32 / 8.),
+                    8 if self.shift_down() => MMMSAction::Length(-1),
+                    9 if self.shift_down() => MMMSAction::Length(1),
+                    8 => MMMSAction::Rotate(-1),
+                    9 => MMMSAction::Rotate(1),
+                    10 if self.shift_down() => MMMSAction::StoreSnapshot(0),
+                    11 if self.shift_down() => MMMSAction::StoreSnapshot(1),
+                    10 => MMMSAction::RecallSnapshot(0),
+                    11 => MMMSAction::RecallSnapshot(1),
+                    12 if self.shift_down() => MMMSAction::ClearSong,
+                    13 if self.shift_down() => MMMSAction::ToggleSong,
+                    13 => MMMSAction::Reverse,
+                    _ => MMMSAction::Nothing,
+                };
+            }
+            let x = match self.control(x) {
+                Some(x) => x,
+                None => {
+                    return MMMSAction::Nothing
+                }
+            };
+            if !self.shift_down() && (x == 8 || x == 9) {
+                if let Some((column, row)) = self.held_grid_key() {
+                    self.buttons[Self::idx(self.width, column, row)] = MMMSIntent::Combo;
+                    return MMMSAction::Nudge((column, if x == 8 { -1 } else { 1 }));
+                }
+            }
+            if !self.shift_down() && (x == 10 || x == 11) {
+                if let Some((column, row)) = self.held_grid_key() {
+                    self.buttons[Self::idx(self.width, column, row)] = MMMSIntent::Combo;
+                    return MMMSAction::Octave((column, row - 1, if x == 10 { 1 } else { -1 }));
+                }
+            }
+            // a page of the view to the left or to the right
+            let page = self.width as isize;
+            if !self.shift_down() {
+                match x {
+                    0 => {
+                        return MMMSAction::Transport
+                    }
+                    1 => {
+                        return MMMSAction::ToggleTempo
+                    }
+                    2 => {
+                        return MMMSAction::ToggleProbability
+                    }
+                    3 => {
+                        return MMMSAction::ToggleTranspose
+                    }
+                    4..=7 => {
+                        return MMMSAction::SelectTrack(x - 4)
+                    }
+                    8 => {
+                        return MMMSAction::Move((-page, 0))
+                    }
+                    9 => {
+                        return MMMSAction::Move((page, 0))
+                    }
+                    10 => {
+                        return MMMSAction::Move((0, -1))
+                    }
+                    11 => {
+                        return MMMSAction::Move((0, 1))
+                    }
+                    12 if intent == MMMSIntent::Combo => {
+                        return MMMSAction::Nothing
+                    }
+                    12 => {
+                        return MMMSAction::ToggleVelocity
+                    }
+                    13 if intent == MMMSIntent::Combo => {
+                        return MMMSAction::Nothing
+                    }
+                    13 => {
+                        return MMMSAction::CycleDirection
+                    }
+                    14 if intent == MMMSIntent::Combo => {
+                        return MMMSAction::Nothing
+                    }
+                    14 => {
+                        return MMMSAction::ToggleScale
+                    }
+                    _ => {
+                        return MMMSAction::Nothing
+                    }
+                }
+            } else {
+                if let Some(action) = self.view_action(x) {
+                    return action;
+                }
                 match x {
                     8 => {
-                        return MMMSAction::Move((-16, 0))
+                        return MMMSAction::Resize(1)
+                    }
+                    9 => {
+                        return MMMSAction::Resize(2)
+                    }
+                    10 => {
+                        return MMMSAction::Resize(4)
+                    }
+                    11 => {
+                        return MMMSAction::Resize(8)
+                    }
+                    12 => {
+                        return MMMSAction::TriggerDuration(-1)
+                    }
+                    13 => {
+                        return MMMSAction::TriggerDuration(1)
+                    }
+                    14 if intent == MMMSIntent::Combo => {
+                        return MMMSAction::Nothing
+                    }
+                    14 => {
+                        return MMMSAction::ClearBar
+                    }
+                    0..=7 => {
+                        return MMMSAction::Euclid { pulses: x + 1, steps: 8 }
+                    }
+                    _ => {
+                        return MMMSAction::Nothing
+                    }
+                }
+            }
+        } else {
+            let but = self.buttons[Self::idx(self.width, x, y)].clone();
+            self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Nothing;
+            match but {
+                MMMSIntent::Nothing | MMMSIntent::Combo => {
+                    // !? pressed a key during startup
+                    MMMSAction::Nothing
+                }
+                MMMSIntent::Tick => {
+                    if let Some(mute_key) = self.mute_key_down() {
+                        self.buttons[Self::idx(self.width, mute_key, 0)] = MMMSIntent::Combo;
+                        return MMMSAction::MuteRow(y - 1);
+                    }
+                    let held = (0..x).find(|x| {
+                        self.buttons[Self::idx(self.width, *x, y)] == MMMSIntent::Tick
+                    });
+                    if self.scale_down() {
+                        let scale_key = self.scale_key();
+                        self.buttons[Self::idx(self.width, scale_key, 0)] = MMMSIntent::Combo;
+                        if self.shift_down() {
+                            return MMMSAction::Invert(y - 1);
+                        }
+                        if let Some(held) = held {
+                            self.buttons[Self::idx(self.width, held, y)] = MMMSIntent::Combo;
+                        }
+                        return MMMSAction::Loop(held.map(|held| (held, x)));
+                    }
+                    if let Some(held) = held.filter(|_| self.shift_down()) {
+                        self.buttons[Self::idx(self.width, held, y)] = MMMSIntent::Combo;
+                        return MMMSAction::Tie((held, x));
+                    }
+                    // keys held in the same column make a chord
+                    if let Some((column, row)) = self.held_grid_key().filter(|&(column, _)| column != x) {
+                        if !self.shift_down() {
+                            self.buttons[Self::idx(self.width, column, row)] = MMMSIntent::Combo;
+                            return MMMSAction::Paint(((column, row - 1), (x, y - 1)));
+                        }
+                    }
+                    if self.shift_down() && x == 0 && y == self.height - 1 {
+                        return MMMSAction::Clear;
+                    }
+                    if self.shift_down() {
+                        return MMMSAction::StepOption((x, y - 1));
+                    }
+                    MMMSAction::Tick((x, y - 1))
+                }
+            }
+        }
+    }
+    fn idx(width: usize, x: usize, y: usize) -> usize {
+        y * width + x
+    }
+
+}
+
+impl MMMS {
+    /// Draw the sequencer on a grid of the size passed to `new`, one brightness per key, row by
+    /// row. `InstrumentControl::render` only has room for 128 keys, a grid with more keys is drawn
+    /// with this instead.
+    pub fn render_leds(&mut self, grid: &mut [u8]) {
+        assert!(grid.len() == self.width * self.height);
+        let width = self.width;
+        let now = self.audio_clock.beat();
+        let pos_in_pattern = self.step_at(self.track, now);
+
+        grid.iter_mut().map(|x| *x = 0).count();
+
+        if self.follow_playhead && self.playing {
+            self.virtual_grids[self.track].follow(pos_in_pattern);
+        }
+
+        // transport button, lit when playing
+        self.light(grid, 0, if self.playing { 15 } else { 0 });
+        // direction button, brighter for the less predictable directions
+        self.light(grid, 13, match self.direction {
+            Direction::Forward => 0,
+            Direction::Reverse => 6,
+            Direction::PingPong => 10,
+            Direction::Random => 15,
+        });
+
+        // track buttons, dim for the tracks that exist, lit for the one being edited
+        for track in 0..self.virtual_grids.len() {
+            self.light(grid, 4 + track, if track == self.track { 15 } else { 4 });
+        }
+
+        if !self.picking_scale {
+            if self.editing_velocity {
+                self.virtual_grids[self.track].velocity_viewport(&mut grid[width..]);
+                self.light(grid, 12, 15);
+            } else if self.editing_probability {
+                self.virtual_grids[self.track].probability_viewport(&mut grid[width..]);
+                self.light(grid, 2, 15);
+            } else {
+                self.virtual_grids[self.track].viewport(&mut grid[width..], &self.palette, self.view_mode);
+            }
+
+            // draw octave indicator if shift is not pressed. Otherwise, draw the amount of bars
+            if !self.state_tracker.shift_down() {
+                let current_octave = self.virtual_grids[self.track].current_octave();
+                self.light(grid, 8 + cmp::min(current_octave, INDICATOR_KEYS - 1), 15);
+            } else {
+                // a bar that isn't complete is shown as one, the longest patterns light all the
+                // keys of the indicator
+                let bars = (self.virtual_grids[self.track].steps_count() + 15) / 16;
+                let bars = cmp::min(bars, INDICATOR_KEYS);
+                for i in 0..bars {
+                    self.light(grid, 8 + i, 15);
+                }
+            }
+
+            // confirm a clear
+            if self.flash > 0 {
+                self.flash -= 1;
+                let offset_x = self.virtual_grids[self.track].offset_x;
+                for j in 0..width {
+                    if offset_x + j >= self.flash_steps.0 && offset_x + j < self.flash_steps.1 {
+                        for i in 1..self.height {
+                            grid[i * width + j] = 8;
+                        }
+                    }
+                }
+            }
+
+            // draw playhead if visible
+            if self.virtual_grids[self.track].x_in_view(pos_in_pattern) {
+                let column = pos_in_pattern - self.virtual_grids[self.track].offset_x;
+                for i in 1..self.height {
+                    let idx = i * width + column;
+                    grid[idx] = cmp::max(grid[idx], self.palette.playhead);
+                }
+            } else if self.offscreen_playhead && self.playing {
+                let step = now * self.steps_per_beat * self.clock_ratios[self.track];
+                if step.fract() < 0.5 {
+                    let edge = if pos_in_pattern < self.virtual_grids[self.track].offset_x {
+                        Control::Left
+                    } else {
+                        Control::Right
+                    };
+                    self.light(grid, edge.index(), 15);
+                }
+            }
+        } else {
+            self.scale_picker(self.picked_scale.clone(), &mut grid[width..]);
+        }
+
+        // when transposing, the first row shows the offset from its middle, in degrees
+        if self.editing_transpose {
+            let (from, to) = transpose_bar(self.transpose, width);
+            for i in 0..width {
+                grid[width + i] = if i >= from && i <= to { 15 } else { 0 };
+            }
+            grid[width + width / 2] = if self.transpose == 0 { 8 } else { 15 };
+            self.light(grid, 3, 15);
+        } else if self.preview {
+            self.light(grid, 3, 8);
+        }
+
+        // the metronome follows the clock, like the playhead
+        if self.metronome && self.playing {
+            match metronome_flash(now) {
+                Some(true) => self.light(grid, 1, self.palette.bar),
+                Some(false) => self.light(grid, 1, self.palette.beat),
+                None => {}
+            }
+        }
+
+        // when editing the tempo, the control row is a bar graph of the tempo
+        if self.editing_tempo {
+            let lit = tempo_bar_length(self.tempo, width);
+            for i in 0..width {
+                grid[i] = if i < lit { 15 } else { 0 };
+            }
+        }
+    }
+    // Set the key of `control` on the control row to `brightness`, if this grid has it.
+    fn light(&self, grid: &mut [u8], control: usize, brightness: u8) {
+        if let Some(x) = self.state_tracker.control_position(control) {
+            if x < self.width {
+                grid[x] = brightness;
+            }
+        }
+    }
+}
+
+impl InstrumentControl for MMMS {
+    // Only the first 128 keys are drawn, that is the first eight rows of a grid 16 keys wide.
+    fn render(&mut self, grid: &mut [u8; 128]) {
+        let mut leds = mem::replace(&mut self.leds, Vec::new());
+        self.render_leds(&mut leds);
+        let count = cmp::min(grid.len(), leds.len());
+        grid.iter_mut().map(|x| *x = 0).count();
+        grid[..count].copy_from_slice(&leds[..count]);
+        self.leds = leds;
+    }
+    fn main_thread_work(&mut self) {
+        for &mut (ref receiver, ref mut callback) in self.midi.iter_mut() {
+            while let Ok(message) = receiver.try_recv() {
+                callback(&message);
+            }
+        }
+        if let Some((ref receiver, ref mut callback)) = self.midi_clock {
+            while let Ok(message) = receiver.try_recv() {
+                callback(&[message]);
+            }
+        }
+        #[cfg(feature = "debug-events")]
+        while let Ok(event) = self.debug_receiver.try_recv() {
+            println!("{:?}", event);
+        }
+        #[cfg(feature = "osc")]
+        loop {
+            let command = match self.osc.as_ref().and_then(|osc| osc.receiver.try_recv().ok()) {
+                Some(command) => command,
+                None => break,
+            };
+            self.handle_osc(command);
+        }
+        self.repeat_notes();
+        self.repeat_moves(time::Instant::now());
+        self.advance_song();
+        self.random_walk();
+        self.autosave();
+    }
+    fn input(&mut self, event: MonomeEvent) {
+        match event {
+            MonomeEvent::GridKey { x, y, direction } => match direction {
+                KeyDirection::Down => {
+                    // in the tempo and transpose views, the arrows don't move the view
+                    let (x, y) = (x as usize, y as usize);
+                    let mouve = match self.state_tracker.arrow_move(x, y) {
+                        Some(_) if self.editing_tempo || self.editing_transpose => None,
+                        mouve => mouve,
+                    };
+                    self.held_move = mouve.map(|mouve| {
+                        HeldMove { x, mouve, since: time::Instant::now(), repeats: 0 }
+                    });
+                    // taps are on the press, to be on time
+                    if self.editing_tempo && y != 0 && !self.state_tracker.shift_down() {
+                        self.tap_tempo(time::Instant::now());
                     }
-                    9 => {
-                        return MMMSAction::Move((16, 0))
+                    self.state_tracker.down(x, y);
+                }
+                KeyDirection::Up => match self.release(x as usize, y as usize) {
+                    MMMSAction::Move(_) if self.release_move(x as usize, y as usize) => {}
+                    MMMSAction::Tick((x, y)) if self.picking_scale => {
+                        self.pick_scale(x, y);
                     }
-                    10 => {
-                        return MMMSAction::Move((0, -1))
+                    MMMSAction::Tick(_) if self.editing_tempo => {}
+                    MMMSAction::Tick((x, y)) if self.preview => {
+                        self.preview_note(x, y);
                     }
-                    11 => {
-                        return MMMSAction::Move((0, 1))
+                    // the keys past the end of a short pattern have no step
+                    MMMSAction::Tick((x, _)) | MMMSAction::StepOption((x, _)) |
+                    MMMSAction::Nudge((x, _)) | MMMSAction::Octave((x, _, _)) |
+                    MMMSAction::Tie((_, x))
+                        if !self.virtual_grids[self.track].x_in_pattern(x) => {}
+                    MMMSAction::Tick((x, y)) if self.editing_velocity => {
+                        let velocity = self.virtual_grids[self.track].set_velocity(x, y);
+                        self.send_track(TrackMessage::Velocity(velocity));
                     }
-                    14 => {
-                        return MMMSAction::ToggleScale
+                    MMMSAction::Tick((x, y)) if self.editing_probability => {
+                        let probability = self.virtual_grids[self.track].set_probability(x, y);
+                        self.send_track(TrackMessage::Probability(probability));
                     }
-                    _ => {
-                        return MMMSAction::Nothing
+                    // the rows below the lowest note of a short scale have no note
+                    MMMSAction::Tick((_, y)) | MMMSAction::StepOption((_, y)) |
+                    MMMSAction::Invert(y) | MMMSAction::MuteRow(y) | MMMSAction::Octave((_, y, _))
+                        if !self.virtual_grids[self.track].y_in_scale(y) => {}
+                    MMMSAction::Paint(((_, y), _)) | MMMSAction::Paint((_, (_, y)))
+                        if !self.editing_velocity && !self.editing_probability &&
+                           !self.virtual_grids[self.track].y_in_scale(y) => {}
+                    MMMSAction::Tick((x, y)) if self.chord.is_some() => {
+                        if let Some((column, _)) = self.virtual_grids[self.track].vaddress(x, y) {
+                            let chord = self.chord.unwrap();
+                            let rest = self.virtual_grids[self.track].grid[column].is_rest();
+                            let ticks = self.virtual_grids[self.track].tick_chord(x, y, chord);
+                            let step = &self.virtual_grids[self.track].grid[column];
+                            self.sender.send(Message::BeginBatch);
+                            for tick in ticks {
+                                self.send_track(TrackMessage::Tick(tick));
+                            }
+                            if step.is_rest() != rest {
+                                self.send_track(TrackMessage::Rest((column, step.is_rest())));
+                            }
+                            self.sender.send(Message::EndBatch);
+                        }
+                    }
+                    MMMSAction::Tick((x, y)) => {
+                        if let Some(xy) = self.virtual_grids[self.track].vaddress(x, y) {
+                            let rest = self.virtual_grids[self.track].grid[xy.0].is_rest();
+                            self.virtual_grids[self.track].tick(x, y);
+                            let step = &self.virtual_grids[self.track].grid[xy.0];
+                            // a tick on a rest only removes the rest
+                            if !rest || !step.is_empty() {
+                                self.send_track(TrackMessage::Tick(xy));
+                            }
+                            if step.is_rest() != rest {
+                                self.send_track(TrackMessage::Rest((xy.0, step.is_rest())));
+                            }
+                        }
+                        #[cfg(feature = "debug-events")]
+                        print!("{}", self.virtual_grids[self.track].render_ascii());
+                    }
+                    MMMSAction::Move((x, y)) if self.editing_tempo => {
+                        // left and right are -10 and +10 BPM, up and down are +1 and -1 BPM
+                        let nudge = x.signum() * 10 - y;
+                        let tempo = self.tempo + nudge as f32;
+                        self.set_tempo(tempo);
+                    }
+                    MMMSAction::Move((x, y)) if self.editing_transpose => {
+                        // left and right are an octave, up and down a degree
+                        let octave = self.virtual_grids[self.track].current_scale().octave_note_count();
+                        let nudge = x.signum() * octave as isize - y;
+                        let transpose = self.transpose + nudge as i32;
+                        self.set_transpose(transpose);
+                    }
+                    MMMSAction::Move((x, y)) => {
+                        self.virtual_grids[self.track].mouve(x, y);
+                    }
+                    MMMSAction::Resize(bars) => {
+                        self.set_steps_count(cmp::min(bars, MAX_BARS) * 16);
+                    }
+                    MMMSAction::Length(steps) => {
+                        let count = self.virtual_grids[self.track].steps_count() as isize + steps;
+                        self.set_steps_count(clamp(count, 1, MAX_STEPS as isize) as usize);
+                    }
+                    // in the probability view, shift changes the condition of a step
+                    MMMSAction::StepOption((x, _)) if self.editing_probability => {
+                        let condition = self.virtual_grids[self.track].cycle_condition(x);
+                        self.send_track(TrackMessage::Condition(condition));
+                    }
+                    MMMSAction::ArmFill => {
+                        self.fill();
+                    }
+                    MMMSAction::CycleCountIn => {
+                        let bars = (self.count_in_bars + 1) % 3;
+                        self.set_count_in(bars);
+                    }
+                    MMMSAction::Reset => {
+                        self.reset();
+                    }
+                    MMMSAction::TogglePreview => {
+                        self.preview = !self.preview;
+                    }
+                    MMMSAction::CycleChord => {
+                        self.chord = ChordType::next(self.chord);
+                    }
+                    MMMSAction::Euclid { pulses, steps } => {
+                        let y = self.virtual_grids[self.track].selected_row();
+                        self.virtual_grids[self.track].fill_euclid(pulses, steps, y as u8);
+                        self.send_track(TrackMessage::Euclid {
+                            start: self.virtual_grids[self.track].bar_start(),
+                            pulses,
+                            steps,
+                            y
+                        });
+                    }
+                    MMMSAction::StepOption((x, y)) => {
+                        match self.virtual_grids[self.track].toggle_accent(x, y) {
+                            Some(accent) => {
+                                self.send_track(TrackMessage::Accent(accent));
+                            }
+                            None => {
+                                let ratchet = self.virtual_grids[self.track].cycle_ratchet(x);
+                                self.send_track(TrackMessage::Ratchet(ratchet));
+                            }
+                        }
+                    }
+                    MMMSAction::Tie((from, to))
+                        if !self.picking_scale && !self.editing_velocity &&
+                           !self.editing_probability => {
+                        self.sender.send(Message::BeginBatch);
+                        for tie in self.virtual_grids[self.track].tie(from, to) {
+                            self.send_track(TrackMessage::Tie(tie));
+                        }
+                        self.sender.send(Message::EndBatch);
+                    }
+                    MMMSAction::Paint((from, to)) if self.editing_velocity => {
+                        let grid = &self.virtual_grids[self.track];
+                        let (start, end) = (grid.velocity_at(from.1), grid.velocity_at(to.1));
+                        let offset_x = grid.offset_x;
+                        self.velocity_ramp(from.0 + offset_x, to.0 + offset_x, start, end);
+                    }
+                    MMMSAction::Paint((from, to)) if self.editing_probability => {
+                        let grid = &mut self.virtual_grids[self.track];
+                        let (start, end) = (grid.probability_at(from.1), grid.probability_at(to.1));
+                        let (from, to) = (from.0 + grid.offset_x, to.0 + grid.offset_x);
+                        let ramp = grid.probability_ramp(from, to, start, end);
+                        self.sender.send(Message::BeginBatch);
+                        for probability in ramp {
+                            self.send_track(TrackMessage::Probability(probability));
+                        }
+                        self.sender.send(Message::EndBatch);
+                    }
+                    MMMSAction::Paint((from, to)) if !self.picking_scale => {
+                        self.sender.send(Message::BeginBatch);
+                        for (x, y) in self.virtual_grids[self.track].line(from, to) {
+                            self.paint(x, y);
+                        }
+                        self.sender.send(Message::EndBatch);
+                    }
+                    MMMSAction::Loop(columns) if !self.picking_scale => {
+                        let (start, end) = match columns {
+                            Some((from, to)) => {
+                                let offset_x = self.virtual_grids[self.track].offset_x;
+                                (offset_x + from, offset_x + to + 1)
+                            }
+                            None => (0, MAX_STEPS),
+                        };
+                        self.set_loop_region(start, end);
+                    }
+                    MMMSAction::Clear => {
+                        self.virtual_grids[self.track].clear_all();
+                        self.send_track(TrackMessage::Clear);
+                        self.flash(0, self.virtual_grids[self.track].steps_count());
+                    }
+                    MMMSAction::ClearBar => {
+                        let bar = self.virtual_grids[self.track].offset_x / 16;
+                        self.virtual_grids[self.track].clear_bar(bar);
+                        self.send_track(TrackMessage::ClearBar(bar));
+                        self.flash(bar * 16, bar * 16 + 16);
+                    }
+                    MMMSAction::ToggleScale => {
+                        if !self.picking_scale {
+                            self.picked_scale = self.virtual_grids[self.track].current_scale().standard();
+                        } else {
+                            let scale = self.picked_scale.clone();
+                            self.change_scale(TrackScale::Standard(scale));
+                        }
+                        self.picking_scale = !self.picking_scale;
+                    }
+                    MMMSAction::ToggleVelocity => {
+                        self.editing_velocity = !self.editing_velocity;
+                        self.editing_probability = false;
+                    }
+                    MMMSAction::ToggleProbability => {
+                        self.editing_probability = !self.editing_probability;
+                        self.editing_velocity = false;
+                    }
+                    MMMSAction::ToggleTempo => {
+                        self.editing_tempo = !self.editing_tempo;
+                        self.editing_transpose = false;
+                    }
+                    MMMSAction::ToggleTranspose => {
+                        self.editing_transpose = !self.editing_transpose;
+                        self.editing_tempo = false;
+                    }
+                    MMMSAction::CycleDirection => {
+                        let direction = self.direction.next();
+                        self.set_direction(direction);
+                    }
+                    MMMSAction::TriggerDuration(direction) => {
+                        let duration = self.trigger_duration * (2. as f32).powi(direction as i32);
+                        self.trigger_duration = clamp_trigger_duration(duration, self.tempo);
+                        self.sender.send(Message::TriggerDuration(self.trigger_duration));
+                    }
+                    // in the probability view, the random keys set the rate of the random walk, and
+                    // reverse starts or ends it
+                    MMMSAction::Randomize(rate) if self.editing_probability => {
+                        self.set_walk_rate(rate);
+                    }
+                    MMMSAction::Reverse if self.editing_probability => {
+                        let random_walk = !self.random_walk;
+                        self.set_random_walk(random_walk);
+                    }
+                    MMMSAction::Randomize(density) => {
+                        self.randomize(density);
+                    }
+                    MMMSAction::Reroll => {
+                        self.reroll();
+                    }
+                    MMMSAction::Chain(slot) => {
+                        self.chain(slot);
+                    }
+                    MMMSAction::ClearSong => {
+                        self.clear_song();
+                    }
+                    MMMSAction::ToggleSong => {
+                        let song = self.song_position.is_none();
+                        self.set_song_mode(song);
+                    }
+                    MMMSAction::Invert(y) if !self.picking_scale => {
+                        if let Some((_, row)) = self.virtual_grids[self.track].vaddress(0, y) {
+                            self.virtual_grids[self.track].invert(row as u8);
+                            self.send_track(TrackMessage::Invert(row));
+                        }
+                    }
+                    MMMSAction::MuteRow(y) if !self.picking_scale => {
+                        if let Some((_, row)) = self.virtual_grids[self.track].vaddress(0, y) {
+                            let muted = self.virtual_grids[self.track].toggle_mute(row);
+                            self.sender.send(Message::MuteRow(self.track, row, muted));
+                        }
+                    }
+                    MMMSAction::Nudge((x, amount)) if !self.picking_scale => {
+                        let nudge = self.virtual_grids[self.track].nudge(x, amount);
+                        self.send_track(TrackMessage::Nudge(nudge));
+                    }
+                    MMMSAction::Octave((x, y, octaves)) if !self.picking_scale => {
+                        let ticks = self.virtual_grids[self.track].shift_octave(x, y, octaves);
+                        self.sender.send(Message::BeginBatch);
+                        for tick in ticks {
+                            self.send_track(TrackMessage::Tick(tick));
+                        }
+                        self.sender.send(Message::EndBatch);
+                    }
+                    MMMSAction::StoreSnapshot(slot) => {
+                        self.store_snapshot(slot);
+                    }
+                    MMMSAction::RecallSnapshot(slot) => {
+                        self.recall_snapshot(slot);
+                    }
+                    MMMSAction::Reverse => {
+                        self.virtual_grids[self.track].reverse();
+                        self.send_track(TrackMessage::Reverse);
+                    }
+                    MMMSAction::Rotate(steps) => {
+                        self.virtual_grids[self.track].rotate(steps);
+                        self.send_track(TrackMessage::Rotate(steps));
+                    }
+                    MMMSAction::SelectTrack(track) => {
+                        self.select_track(track);
+                    }
+                    MMMSAction::Transport => {
+                        let playing = !self.playing;
+                        self.set_playing(playing);
+                    }
+                    _ => {}
+                },
+            },
+            _ => {}
+        }
+    }
+}
+
+/// A step of a pattern, as saved on disk.
+#[derive(Clone, Serialize, Deserialize)]
+struct PatternStep {
+    notes: Vec<u8>,
+    velocity: u8,
+    #[serde(default = "max_probability")]
+    probability: u8,
+    #[serde(default = "single_trigger")]
+    ratchet: u8,
+    #[serde(default)]
+    accent: bool,
+    #[serde(default)]
+    tie: bool,
+    #[serde(default)]
+    rest: bool,
+    #[serde(default)]
+    nudge: i8,
+    #[serde(default)]
+    condition: Condition,
+}
+
+fn max_probability() -> u8 {
+    MAX_PROBABILITY
+}
+
+fn single_trigger() -> u8 {
+    1
+}
+
+/// A pattern, as saved on disk. The root is in semitones above C, the scale type is an index in
+/// `SCALE_TYPES`. A custom scale has its offsets in `cents`, empty otherwise, and repeats every
+/// `period` cents. Missing fields take their default value, unknown fields are ignored.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Pattern {
+    version: u32,
+    width: usize,
+    root: usize,
+    scale_type: usize,
+    cents: Vec<f32>,
+    period: f32,
+    offset_x: usize,
+    offset_y: usize,
+    steps: Vec<PatternStep>,
+}
+
+impl Default for Pattern {
+    fn default() -> Pattern {
+        VirtualGrid::new().to_pattern()
+    }
+}
+
+/// Fail with `InvalidData` for a pattern or a project saved in `version` of its format, when it's
+/// newer than `supported`, the version this one saves in, whose format isn't known.
+fn check_version(version: u32, supported: u32) -> io::Result<()> {
+    if version > supported {
+        let message = format!("saved in version {} of the format, after {}", version, supported);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    Ok(())
+}
+
+/// The pattern of a track, as autosaved, with its track.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct TrackPattern {
+    track: usize,
+    pattern: Pattern,
+}
+
+/// A pattern of the bank, as saved in a project, with its slot and its name.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct BankPattern {
+    slot: usize,
+    name: String,
+    pattern: Pattern,
+}
+
+/// A project, as saved on disk: the patterns of the bank, the song, as the slots of its patterns
+/// in order, and the settings that aren't part of a pattern. Missing fields take their default
+/// value, unknown fields are ignored, so that projects saved by other versions can be loaded.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct Project {
+    version: u32,
+    tempo: f32,
+    palette: Palette,
+    bank: Vec<BankPattern>,
+    song: Vec<usize>,
+    song_mode: bool,
+}
+
+impl Default for Project {
+    fn default() -> Project {
+        Project {
+            version: PROJECT_VERSION,
+            tempo: 120.,
+            palette: Palette::default(),
+            bank: Vec::new(),
+            song: Vec::new(),
+            song_mode: false,
+        }
+    }
+}
+
+/// Handle a grid much larger than a monome 128, and allow inputing and displaying on a monome 128,
+/// and scrolling through bars (left/right) and notes (up/down). It is aware of the scale it's
+/// representing.
+/// 0x0 is top left, 64x128 is bottom right
+/// the offset_x and offset_y are the position of the top left corner of the viewport
+/// selected_row is the row of the last note ticked, used when generating notes
+/// Each step can hold up to MAX_POLYPHONY notes, played at the velocity of the step.
+/// The view is `view_width` steps and `view_height` notes, the size of the grid without its control
+/// row.
+struct VirtualGrid {
+    width: usize,
+    height: usize,
+    view_width: usize,
+    view_height: usize,
+    offset_x: usize,
+    offset_y: usize,
+    selected_row: usize,
+    // the steps played, from `loop_start` to `loop_end`, excluded
+    loop_start: usize,
+    loop_end: usize,
+    // one bit per row, the notes of the muted rows are kept but don't play
+    muted_rows: u128,
+    scale: TrackScale,
+    grid: SmallVec<[Step<u8>; MAX_STEPS]>,
+    // scroll vertically by octaves, with a tonic on the lowest row of the view
+    snap_to_octave: bool,
+}
+
+impl VirtualGrid {
+    fn new() -> VirtualGrid {
+        VirtualGrid::with_view(16, 7, TrackScale::Standard(default_scale()))
+    }
+    fn with_view(view_width: usize, view_height: usize, scale: TrackScale) -> VirtualGrid {
+         // This is a lie: the grid is in fact just a vector with the position of the notes that
+         // are ticked, for each step (empty if nothing has been ticked).
+         let mut grid = SmallVec::<[Step<u8>; MAX_STEPS]>::new();
+         // third octave
+         let start_offset =
+             scale.note_count().saturating_sub(scale.octave_note_count() * 3 + view_height);
+         grid.resize(INITIAL_STEPS, Step::new());
+         VirtualGrid {
+             width: INITIAL_STEPS,
+             height: scale.note_count(),
+             view_width,
+             view_height,
+             offset_x: 0,
+             offset_y: start_offset,
+             // lowest note of the view
+             selected_row: cmp::min(start_offset + view_height, scale.note_count()) - 1,
+             loop_start: 0,
+             loop_end: MAX_STEPS,
+             muted_rows: 0,
+             scale,
+             grid,
+             snap_to_octave: false,
+         }
+    }
+    fn steps_count(&self) -> usize {
+        self.width
+    }
+    // Any number of steps up to `MAX_STEPS`, the view can be wider than the pattern.
+    fn change_steps_count(&mut self, count: usize) {
+      let count = clamp(count, 1, MAX_STEPS);
+      self.width = count;
+      self.offset_x = clamp((self.offset_x as isize) as isize, 0 as isize, self.width.saturating_sub(self.view_width) as isize) as usize;
+      self.grid.resize(count, Step::new());
+    }
+    fn mouve(&mut self, x: isize, y: isize) {
+        self.offset_x = clamp((self.offset_x as isize + x as isize) as isize, 0 as isize, self.width.saturating_sub(self.view_width) as isize) as usize;
+        if self.snap_to_octave && y != 0 && self.height > self.view_height {
+            self.snap_octave(y);
+            return;
+        }
+        self.offset_y = clamp((self.offset_y as isize + y as isize) as isize, 0 as isize, self.max_offset_y() as isize) as usize;
+    }
+    // Move the view `octaves` octaves down, or up when negative, to the next tonic. At the ends of
+    // the scale, the view stops at the highest or lowest note.
+    fn snap_octave(&mut self, octaves: isize) {
+        let octave = self.scale.octave_note_count() as isize;
+        // the note of the lowest row of the view, 0 being the lowest note
+        let bottom = (self.height - self.offset_y - self.view_height) as isize;
+        let next = if octaves < 0 {
+            (bottom / octave + 1) * octave
+        } else {
+            ((bottom + octave - 1) / octave - 1) * octave
+        };
+        let bottom = next - (octaves - octaves.signum()) * octave;
+        let offset = (self.height - self.view_height) as isize - bottom;
+        self.offset_y = clamp(offset, 0, self.max_offset_y() as isize) as usize;
+    }
+    fn clear_all(&mut self) {
+        for i in self.grid.iter_mut() {
+            *i = Step::new();
+        }
+    }
+    fn set_loop_region(&mut self, start: usize, end: usize) {
+        self.loop_start = start;
+        self.loop_end = end;
+    }
+    // Mute or unmute row `y`. Returns whether it's now muted.
+    fn toggle_mute(&mut self, y: usize) -> bool {
+        if y >= 128 {
+            return false;
+        }
+        self.muted_rows ^= 1 << y;
+        self.muted(y)
+    }
+    fn muted(&self, y: usize) -> bool {
+        row_muted(self.muted_rows, y)
+    }
+    // The steps played, clamped to the pattern.
+    fn loop_region(&self) -> (usize, usize) {
+        loop_region(self.loop_start, self.loop_end, self.width)
+    }
+    // The first step of the bar in view, the view can be narrower than a bar.
+    fn bar_start(&self) -> usize {
+        self.offset_x - self.offset_x % 16
+    }
+    // Clear the steps of bar `bar`, the first one being 0.
+    fn clear_bar(&mut self, bar: usize) {
+        for i in self.grid.iter_mut().skip(bar * 16).take(16) {
+            *i = Step::new();
+        }
+    }
+    // Reflect the notes of the pattern across row `pivot_row`, a rising line becomes a falling one.
+    // Notes that would be out of the scale stay on its lowest or highest note.
+    fn invert(&mut self, pivot_row: u8) {
+        let height = self.height;
+        for step in self.grid.iter_mut() {
+            step.map_notes(|row| reflect(*row, pivot_row, height));
+        }
+    }
+    // Reverse the order of the steps of the pattern, the first one becomes the last one.
+    fn reverse(&mut self) {
+        let width = self.width;
+        self.grid[..width].reverse();
+    }
+    // Move all the steps of the pattern by `steps`, to the right when positive. The steps moved
+    // past the end come back at the start.
+    fn rotate(&mut self, steps: isize) {
+        let width = self.width;
+        rotate(&mut self.grid[..width], steps);
+    }
+    fn set_scale(&mut self, scale: TrackScale) {
+        self.height = scale.note_count();
+        self.scale = scale;
+        self.offset_y = clamp(self.offset_y, 0, self.max_offset_y());
+        self.selected_row = clamp(self.selected_row, 0, self.height - 1);
+    }
+    // The highest offset of the view in the scale. A scale with fewer notes than the view has rows
+    // starts at its top.
+    fn max_offset_y(&self) -> usize {
+        self.height.saturating_sub(self.view_height)
+    }
+    // Whether row `vy` of the view has a note, it doesn't below the lowest note of a short scale.
+    fn y_in_scale(&self, vy: usize) -> bool {
+        vy + self.offset_y < self.height
+    }
+    // Change the scale, moving each note to the closest pitch in the new scale, so that the pattern
+    // keeps its shape.
+    fn remap_scale(&mut self, scale: TrackScale) {
+        let rows: Vec<u8> = (0..self.height).map(|row| {
+            nearest_row(&self.scale, &scale, row) as u8
+        }).collect();
+        for step in self.grid.iter_mut() {
+            step.map_notes(|row| rows[*row as usize]);
+        }
+        // keep the same part of the keyboard in view
+        let bottom = rows[cmp::min(self.offset_y + self.view_height, self.height) - 1] as usize;
+        self.selected_row = rows[self.selected_row] as usize;
+        self.set_scale(scale);
+        self.offset_y = clamp(bottom.saturating_sub(self.view_height - 1), 0, self.max_offset_y());
+    }
+    fn to_pattern(&self) -> Pattern {
+        Pattern {
+            version: PATTERN_VERSION,
+            width: self.width,
+            root: semitones_above_c(self.scale.fundamental()),
+            scale_type: SCALE_TYPES.iter().position(|t| Some(*t) == self.scale.scale_type()).unwrap_or(0),
+            cents: match self.scale {
+                TrackScale::Custom(ref scale) => scale.cents().to_vec(),
+                TrackScale::Standard(_) => Vec::new(),
+            },
+            period: match self.scale {
+                TrackScale::Custom(ref scale) => scale.period(),
+                TrackScale::Standard(_) => 1200.,
+            },
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            steps: self.grid[..self.width].iter().map(|step| {
+                PatternStep {
+                    notes: step.notes().to_vec(),
+                    velocity: step.velocity,
+                    probability: step.probability,
+                    ratchet: step.ratchet,
+                    accent: step.accent,
+                    tie: step.tie,
+                    rest: step.is_rest(),
+                    nudge: step.nudge,
+                    condition: step.condition,
+                }
+            }).collect(),
+        }
+    }
+    // Replace the current pattern. Anything out of range is clamped, so that patterns saved by
+    // other versions can be loaded.
+    fn load_pattern(&mut self, pattern: &Pattern) {
+        let root = pitch_class(pattern.root);
+        let scale_type = SCALE_TYPES[clamp(pattern.scale_type, 0, SCALE_TYPES.len() - 1)];
+        // a custom scale that can't be used falls back on the scale type
+        self.set_scale(match CustomScale::with_period(root, &pattern.cents, pattern.period) {
+            Ok(scale) => TrackScale::Custom(scale),
+            Err(_) => TrackScale::Standard(Scale::new(root, scale_type)),
+        });
+        self.change_steps_count(clamp(pattern.width, 1, MAX_STEPS));
+        self.clear_all();
+        for (x, step) in pattern.steps.iter().take(self.width).enumerate() {
+            for row in step.notes.iter() {
+                let row = clamp(*row as usize, 0, self.height - 1) as u8;
+                self.grid[x].toggle(row);
+            }
+            self.grid[x].velocity = clamp(step.velocity, 0, MAX_VELOCITY);
+            self.grid[x].probability = clamp(step.probability, 0, MAX_PROBABILITY);
+            self.grid[x].ratchet = clamp(step.ratchet, 1, MAX_RATCHET);
+            self.grid[x].accent = step.accent;
+            self.grid[x].tie = step.tie;
+            self.grid[x].set_rest(step.rest);
+            self.grid[x].nudge = clamp(step.nudge, -MAX_NUDGE, MAX_NUDGE);
+            self.grid[x].condition = step.condition.clamped();
+        }
+        self.offset_x = clamp(pattern.offset_x, 0, self.width.saturating_sub(self.view_width));
+        self.offset_y = clamp(pattern.offset_y, 0, self.max_offset_y());
+    }
+    fn selected_row(&self) -> usize {
+        self.selected_row
+    }
+    // Replace the notes of the bar currently in view by a euclidian rythm of `pulses` in `steps`,
+    // repeated until the end of the bar, at row `pitch_row`.
+    fn fill_euclid(&mut self, pulses: usize, steps: usize, pitch_row: u8) {
+        let bar = euclid_bar(pulses, steps);
+        for i in 0..16 {
+            let x = self.bar_start() + i;
+            if x >= self.width {
+                break;
+            }
+            self.grid[x].clear_notes();
+            if bar[i] {
+                self.grid[x].toggle(pitch_row);
+            }
+        }
+    }
+    // The step and the row of the key at `vx`, `vy` in the view, `None` past the end of the
+    // pattern or below the lowest note of the scale.
+    fn vaddress(&self, vx: usize, vy: usize) -> Option<(usize, usize)> {
+        let x = vx + self.offset_x;
+        let y = vy + self.offset_y;
+        if x < self.width && y < self.height {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+    // return a number between 0 and 8 that represents the octave currently in the view
+    fn current_octave(&self) -> usize {
+        let below = self.scale.note_count().saturating_sub(self.offset_y + self.view_height);
+        clamp(below / self.scale.octave_note_count(), 0, 8)
+    }
+    fn current_scale(&self) -> TrackScale {
+        self.scale.clone()
+    }
+    #[cfg(any(test, feature = "debug-events"))]
+    fn in_view(&self, x: usize, y: usize) -> bool {
+        y >= self.offset_y && y < self.offset_y + self.view_height &&
+        x >= self.offset_x && x < self.offset_x + self.view_width
+    }
+    fn x_in_view(&self, x: usize) -> bool {
+        x >= self.offset_x && x < self.offset_x + self.view_width
+    }
+    // Scroll to the page of step `x` if it's out of view.
+    fn follow(&mut self, x: usize) {
+        if !self.x_in_view(x) {
+            let page = x / self.view_width * self.view_width;
+            self.offset_x = cmp::min(page, self.width.saturating_sub(self.view_width));
+        }
+    }
+    // Whether column `vx` of the view has a step, it doesn't past the end of a short pattern.
+    fn x_in_pattern(&self, vx: usize) -> bool {
+        vx + self.offset_x < self.width
+    }
+    fn viewport(&self, grid: &mut [u8], palette: &Palette, view_mode: ViewMode) {
+        assert!(grid.len() == self.view_height * self.view_width);
+        for i in 0..self.view_height {
+            for j in 0..self.view_width {
+                let local_idx = i * self.view_width + j;
+                if !self.x_in_pattern(j) || !self.y_in_scale(i) {
+                    grid[local_idx] = 0;
+                    continue;
+                }
+                // flip verticaly so that lower notes are at the bottom
+                let degree = self.scale.idx_to_degree(self.scale.note_count() - 1 - (self.offset_y + i));
+                grid[local_idx] = match (view_mode, degree) {
+                    (ViewMode::Degrees, Some(degree)) => { palette.degrees[degree_index(degree)] }
+                    (ViewMode::Functions, Some(Degrees::Tonic)) => { palette.tonic }
+                    (ViewMode::Functions, Some(Degrees::Dominant)) => { palette.dominant }
+                    (ViewMode::Functions, Some(Degrees::Leading)) => { palette.leading }
+                    _ => { 0 }
+                };
+                let step = &self.grid[self.offset_x + j];
+                if step.notes().contains(&((self.offset_y + i) as u8)) {
+                    grid[local_idx] = if step.accent {
+                        palette.accent
+                    } else if step.nudge != 0 {
+                        palette.nudged
+                    } else {
+                        palette.note
+                    };
+                } else if self.held_notes(self.offset_x + j).contains(&((self.offset_y + i) as u8)) {
+                    // the note continues from the left
+                    grid[local_idx] = palette.held;
+                } else if step.is_rest() {
+                    grid[local_idx] = cmp::max(grid[local_idx], palette.rest);
+                }
+                // the steps out of the loop region and the muted rows are dimmed
+                let (start, end) = self.loop_region();
+                if self.offset_x + j < start || self.offset_x + j >= end {
+                    grid[local_idx] /= 2;
+                }
+                if self.muted(self.offset_y + i) {
+                    grid[local_idx] /= 2;
+                }
+            }
+        }
+    }
+    // The notes held during step `x` because it's tied, none if it isn't.
+    fn held_notes(&self, mut x: usize) -> &[u8] {
+        if !self.grid[x].tie {
+            return &[];
+        }
+        while x > 0 && self.grid[x].tie {
+            x -= 1;
+        }
+        self.grid[x].notes()
+    }
+    // Tie the steps after column `from` of the view, up to column `to`, to the step at `from`, or
+    // untie them if the last one already is. Returns the steps and their new tie.
+    fn tie(&mut self, from: usize, to: usize) -> Vec<(usize, bool)> {
+        let (from, to) = (from + self.offset_x, to + self.offset_x);
+        let tie = !self.grid[to].tie;
+        (from + 1..to + 1).map(|x| {
+            self.grid[x].tie = tie;
+            (x, tie)
+        }).collect()
+    }
+    // The pitch of row `y` in volts, the highest note being 0, `None` below the lowest note.
+    fn row_to_pitch(&self, y: usize) -> Option<f32> {
+        self.scale.idx_to_cv(self.height.checked_sub(y + 1)?)
+    }
+    // The row of the note closest to `pitch`, in volts.
+    fn pitch_to_row(&self, pitch: f32) -> usize {
+        self.height - 1 - self.scale.nearest_idx(pitch)
+    }
+    // The row of the lowest note of step `x`, `None` when it has no notes or isn't in the pattern.
+    fn get_step(&self, x: usize) -> Option<usize> {
+        if x >= self.steps_count() {
+            return None;
+        }
+        self.grid[x].notes().iter().max().map(|y| *y as usize)
+    }
+    // Replace the notes of step `x` by the note of row `y`, or by nothing when `None`. Returns the
+    // notes removed, and fails if there is no such step or row.
+    fn set_step(&mut self, x: usize, y: Option<usize>)
+                -> Result<SmallVec<[u8; MAX_POLYPHONY]>, MmmsError> {
+        if x >= self.steps_count() || y.map_or(false, |y| y >= self.height) {
+            return Err(MmmsError::InvalidStep);
+        }
+        let removed = SmallVec::from_slice(self.grid[x].notes());
+        self.grid[x].clear_notes();
+        if let Some(y) = y {
+            self.grid[x].toggle(y as u8);
+        }
+        Ok(removed)
+    }
+    // Cycle a key through its states: nothing, a note, and a rest when it was the last note of its
+    // step. Pressing a rest removes it. Other notes of the step are kept.
+    fn tick(&mut self, vx: usize, vy: usize) {
+        let (x, y) = match self.vaddress(vx, vy) {
+            Some(xy) => xy,
+            None => {
+                return;
+            }
+        };
+        let step = &mut self.grid[x];
+        if step.is_rest() {
+            step.set_rest(false);
+            return;
+        }
+        self.selected_row = y;
+        step.toggle(y as u8);
+        step.set_rest(step.is_empty());
+    }
+    // Same as `tick`, with the notes of `chord` rooted at this key rather than a single note, but
+    // the ones past the highest note of the scale. When the root is already there, the notes of
+    // the chord are removed instead. Returns the notes toggled.
+    fn tick_chord(&mut self, vx: usize, vy: usize, chord: ChordType) -> Vec<(usize, usize)> {
+        let (x, y) = match self.vaddress(vx, vy) {
+            Some(xy) => xy,
+            None => {
+                return Vec::new();
+            }
+        };
+        let step = &mut self.grid[x];
+        if step.is_rest() {
+            step.set_rest(false);
+            return Vec::new();
+        }
+        self.selected_row = y;
+        let remove = step.notes().contains(&(y as u8));
+        let mut ticks = Vec::new();
+        for row in chord.degrees().iter().filter(|degree| **degree <= y).map(|degree| y - degree) {
+            let note = row as u8;
+            if step.notes().contains(&note) == remove {
+                step.toggle(note);
+                if step.notes().contains(&note) != remove {
+                    ticks.push((x, row));
+                }
+            }
+        }
+        step.set_rest(step.is_empty());
+        ticks
+    }
+    // The keys of the view on the line from `from` to `to`, one per column, in this order. The
+    // columns past the end of the pattern have no key.
+    fn line(&self, from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+        let columns: Vec<usize> = if from.0 <= to.0 {
+            (from.0..to.0 + 1).collect()
+        } else {
+            (to.0..from.0 + 1).rev().collect()
+        };
+        let span = cmp::max((to.0 as isize - from.0 as isize).abs(), 1) as f32;
+        columns.into_iter().filter(|x| self.x_in_pattern(*x)).map(|x| {
+            let along = (x as isize - from.0 as isize).abs() as f32 / span;
+            let y = from.1 as f32 + (to.1 as f32 - from.1 as f32) * along;
+            (x, y.round() as usize)
+        }).collect()
+    }
+    // Display the velocity of each step in the view as a vertical bar, brighter for the steps that
+    // have notes.
+    fn velocity_viewport(&self, grid: &mut [u8]) {
+        assert!(grid.len() == self.view_height * self.view_width);
+        let rows = self.view_height;
+        for j in 0..self.view_width {
+            if !self.x_in_pattern(j) {
+                for i in 0..rows {
+                    grid[i * self.view_width + j] = 0;
+                }
+                continue;
+            }
+            let step = &self.grid[self.offset_x + j];
+            let level = (step.velocity as usize * rows + MAX_VELOCITY as usize - 1) / MAX_VELOCITY as usize;
+            for i in 0..rows {
+                grid[i * self.view_width + j] = if rows - i > level {
+                    0
+                } else if step.is_empty() {
+                    4
+                } else {
+                    15
+                };
+            }
+        }
+    }
+    // Set the velocity of a step from a press in the velocity view, the top row being the maximum.
+    // Returns the step and its new velocity.
+    fn set_velocity(&mut self, vx: usize, vy: usize) -> (usize, u8) {
+        let x = vx + self.offset_x;
+        let velocity = self.velocity_at(vy);
+        self.grid[x].velocity = velocity;
+        (x, velocity)
+    }
+    // The velocity shown by row `vy` of the velocity view, the top row being the loudest.
+    fn velocity_at(&self, vy: usize) -> u8 {
+        ((self.view_height - vy) * MAX_VELOCITY as usize / self.view_height) as u8
+    }
+    // Change the velocity of the steps with notes from column `from` to column `to` of the
+    // pattern, so that it goes from `start` to `end` in a straight line. Returns the steps changed
+    // and their new velocity.
+    fn velocity_ramp(&mut self, from: usize, to: usize, start: u8, end: u8) -> Vec<(usize, u8)> {
+        let changed = self.ramp(from, to, start, end, MAX_VELOCITY);
+        for &(x, velocity) in changed.iter() {
+            self.grid[x].velocity = velocity;
+        }
+        changed
+    }
+    // Same as `velocity_ramp`, for the probability.
+    fn probability_ramp(&mut self, from: usize, to: usize, start: u8, end: u8) -> Vec<(usize, u8)> {
+        let changed = self.ramp(from, to, start, end, MAX_PROBABILITY);
+        for &(x, probability) in changed.iter() {
+            self.grid[x].probability = probability;
+        }
+        changed
+    }
+    // The values on a straight line from `start` at column `from` to `end` at column `to`, of the
+    // steps with notes in between, clamped to `max`.
+    fn ramp(&self, from: usize, to: usize, start: u8, end: u8, max: u8) -> Vec<(usize, u8)> {
+        let (first, last) = (cmp::min(from, to), cmp::min(cmp::max(from, to), self.width - 1));
+        let span = cmp::max((to as isize - from as isize).abs(), 1) as f32;
+        let (start, end) = (clamp(start, 0, max) as f32, clamp(end, 0, max) as f32);
+        (first..last + 1).filter(|x| !self.grid[*x].is_empty()).map(|x| {
+            let along = (x as isize - from as isize).abs() as f32 / span;
+            (x, clamp((start + (end - start) * along).round(), 0., max as f32) as u8)
+        }).collect()
+    }
+    // Same as the velocity view, for the probability of each step.
+    fn probability_viewport(&self, grid: &mut [u8]) {
+        assert!(grid.len() == self.view_height * self.view_width);
+        let rows = self.view_height;
+        for j in 0..self.view_width {
+            if !self.x_in_pattern(j) {
+                for i in 0..rows {
+                    grid[i * self.view_width + j] = 0;
+                }
+                continue;
+            }
+            let step = &self.grid[self.offset_x + j];
+            let level = (step.probability as usize * rows + MAX_PROBABILITY as usize - 1) / MAX_PROBABILITY as usize;
+            for i in 0..rows {
+                grid[i * self.view_width + j] = if rows - i > level {
+                    0
+                } else if step.is_empty() {
+                    4
+                } else {
+                    15
+                };
+            }
+        }
+    }
+    // Set the probability of a step from a press in the probability view, the top row being
+    // always. Returns the step and its new probability.
+    fn set_probability(&mut self, vx: usize, vy: usize) -> (usize, u8) {
+        let x = vx + self.offset_x;
+        let probability = self.probability_at(vy);
+        self.grid[x].probability = probability;
+        (x, probability)
+    }
+    // The probability shown by row `vy` of the probability view, the top row being always.
+    fn probability_at(&self, vy: usize) -> u8 {
+        ((self.view_height - vy) * MAX_PROBABILITY as usize / self.view_height) as u8
+    }
+    // For each step of the bar in view, place a note on a random row in view with a probability of
+    // `density`, clear the step otherwise.
+    fn randomize(&mut self, density: f32, rng: &mut impl Rng) {
+        for x in self.bar_start()..cmp::min(self.bar_start() + 16, self.width) {
+            self.grid[x].clear_notes();
+            if rng.unit() < density {
+                let rows = cmp::min(self.view_height, self.height - self.offset_y);
+                let row = self.offset_y + rng.below(rows);
+                self.grid[x].toggle(row as u8);
+            }
+        }
+    }
+    // Change a random step of the loop: one of its notes goes a degree up or down, or its notes are
+    // removed, or a note is added at the selected row if it has none. Returns the notes toggled.
+    fn mutate(&mut self, rng: &mut impl Rng) -> Vec<(usize, usize)> {
+        let (start, end) = self.loop_region();
+        let x = start + rng.below(end - start);
+        let last = self.scale.note_count() - 1;
+        let notes: Vec<usize> = self.grid[x].notes().iter().map(|row| *row as usize).collect();
+        let ticks = match (notes.first(), rng.below(3)) {
+            (None, _) => vec![(x, self.selected_row)],
+            (Some(_), 2) => notes.iter().map(|row| (x, *row)).collect(),
+            (Some(&row), direction) => {
+                // the rows go down in pitch
+                let up = (direction == 0 && row > 0) || row == last;
+                let moved = if up { row - 1 } else { row + 1 };
+                if notes.contains(&moved) {
+                    vec![(x, row)]
+                } else {
+                    vec![(x, row), (x, moved)]
+                }
+            }
+        };
+        for &(x, row) in ticks.iter() {
+            self.grid[x].toggle(row as u8);
+        }
+        ticks
+    }
+    // Toggle the accent of the step in column `vx`, if it has a note at `vy`. Returns the step and
+    // its new accent.
+    fn toggle_accent(&mut self, vx: usize, vy: usize) -> Option<(usize, bool)> {
+        let (x, y) = self.vaddress(vx, vy)?;
+        if !self.grid[x].notes().contains(&(y as u8)) {
+            return None;
+        }
+        self.grid[x].accent = !self.grid[x].accent;
+        Some((x, self.grid[x].accent))
+    }
+    // Go to the next number of triggers for the step in column `vx` of the view, back to one after
+    // the maximum. Returns the step and its new number of triggers.
+    fn cycle_ratchet(&mut self, vx: usize) -> (usize, u8) {
+        let x = vx + self.offset_x;
+        let ratchet = self.grid[x].ratchet % MAX_RATCHET + 1;
+        self.grid[x].ratchet = ratchet;
+        (x, ratchet)
+    }
+    // Change the condition of the step in column `vx` of the view to the next one. Returns the step
+    // and its new condition.
+    fn cycle_condition(&mut self, vx: usize) -> (usize, Condition) {
+        let x = vx + self.offset_x;
+        let condition = self.grid[x].condition.next();
+        self.grid[x].condition = condition;
+        (x, condition)
+    }
+    // Move the step in column `vx` of the view `amount` eighth of a sixteenth later, earlier when
+    // negative. Returns the step and its new timing offset.
+    fn nudge(&mut self, vx: usize, amount: i8) -> (usize, i8) {
+        let x = vx + self.offset_x;
+        let nudge = clamp(self.grid[x].nudge + amount, -MAX_NUDGE, MAX_NUDGE);
+        self.grid[x].nudge = nudge;
+        (x, nudge)
+    }
+    // Move the note at `vx`, `vy` in the view `octaves` octaves up, down when negative, without
+    // moving the view. The note goes to the highest or the lowest note of the scale when it would
+    // go past it. Returns the notes toggled, none when there is no note at this key.
+    fn shift_octave(&mut self, vx: usize, vy: usize, octaves: isize) -> Vec<(usize, usize)> {
+        let (x, y) = match self.vaddress(vx, vy) {
+            Some(xy) => xy,
+            None => {
+                return Vec::new();
+            }
+        };
+        let shift = octaves * self.scale.octave_note_count() as isize;
+        let row = clamp(y as isize - shift, 0, self.height as isize - 1) as usize;
+        if row == y || !self.grid[x].notes().contains(&(y as u8)) {
+            return Vec::new();
+        }
+        self.selected_row = row;
+        self.grid[x].toggle(y as u8);
+        if self.grid[x].notes().contains(&(row as u8)) {
+            return vec![(x, y)];
+        }
+        self.grid[x].toggle(row as u8);
+        vec![(x, y), (x, row)]
+    }
+    // The name of the note of row `y`, the highest being 0. A custom scale has no note names, its
+    // pitches are in volts.
+    fn note_name(&self, y: usize) -> String {
+        let idx = self.scale.note_count() - 1 - y;
+        match self.scale {
+            TrackScale::Standard(ref scale) => format!("{}", scale.idx_to_pitch(idx).unwrap()),
+            TrackScale::Custom(ref scale) => format!("{:.3}V", scale.cv(idx)),
+        }
+    }
+    // Draw the grid, one line per note, the highest first, after the pitch of the note. The notes
+    // in the view are circled. 1 is a ticked note.
+    #[cfg(any(test, feature = "debug-events"))]
+    fn render_ascii(&self) -> String {
+        let mut ascii = String::from("######### begin #######\n");
+        for i in 0..self.scale.note_count() {
+            for j in 0..self.width + 1 {
+                if j == 0 {
+                    ascii.push_str(&format!("{}\t", self.note_name(i)));
+                    continue;
+                }
+                let ticked = if self.grid[j - 1].notes().contains(&(i as u8)) { 1 } else { 0 };
+                if self.in_view(j - 1, i) {
+                   ascii.push_str(&format!("|{}|", ticked));
+                } else  {
+                   ascii.push_str(&format!(" {} ", ticked));
+                }
+            }
+            ascii.push('\n');
+        }
+        ascii.push_str("#########  end  #######\n");
+        ascii
+    }
+}
+
+// The rows with notes, from the highest note, one character per step: `x` for a step that plays
+// the note, `.` otherwise. Shorter than `render_ascii`, for logging.
+impl fmt::Display for VirtualGrid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} steps in {}", self.steps_count(), self.scale)?;
+        let steps = &self.grid[..self.steps_count()];
+        for y in 0..self.scale.note_count() {
+            if !steps.iter().any(|step| step.notes().contains(&(y as u8))) {
+                continue;
+            }
+            write!(f, "\n{}\t", self.note_name(y))?;
+            for step in steps.iter() {
+                write!(f, "{}", if step.notes().contains(&(y as u8)) { 'x' } else { '.' })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn it_works() { }
+
+    // Euclidian rythms are only defined up to a rotation.
+    fn is_rotation_of(pattern: &[bool], expected: &[bool]) -> bool {
+        (0..expected.len()).any(|r| {
+            (0..expected.len()).all(|i| pattern[i] == expected[(i + r) % expected.len()])
+        })
+    }
+
+    #[test]
+    fn fill_euclid() {
+        let x = true;
+        let o = false;
+        let e38 = [x, o, o, x, o, o, x, o];
+        let e58 = [x, o, x, x, o, x, x, o];
+        for &(pulses, expected) in [(3, &e38), (5, &e58)].iter() {
+            let mut grid = VirtualGrid::new();
+            grid.fill_euclid(pulses, 8, 10);
+            let pattern: Vec<bool> = grid.grid[..16].iter().map(|n| !n.is_empty()).collect();
+            assert!(is_rotation_of(&pattern[..8], expected));
+            // repeated until the end of the bar
+            assert_eq!(pattern[..8], pattern[8..]);
+            assert!(grid.grid[..16].iter().all(|n| n.is_empty() || n.notes()[..] == [10]));
+            assert!(grid.grid[16..].iter().all(|n| n.is_empty()));
+        }
+    }
+
+    #[test]
+    fn swing() {
+        // straight
+        assert_eq!(swing_position(1.25, 0.0), (1, 0.25));
+        // even steps are not delayed
+        assert_eq!(swing_position(2.25, 0.5), (2, 0.25));
+        // the second sixteenth starts a third of the way into the step
+        let third = 1. / 3.;
+        let (step, position) = swing_position(1.0 + third - 0.01, 0.5);
+        assert_eq!(step, 0);
+        assert!(position > 1.0);
+        let (step, position) = swing_position(1.0 + third + 0.01, 0.5);
+        assert_eq!(step, 1);
+        assert!((position - 0.01).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pattern_round_trip() {
+        let mut grid = VirtualGrid::new();
+        grid.tick(0, 0);
+        grid.tick(3, 2);
+        grid.set_velocity(3, 4);
+        let mut pattern = grid.to_pattern();
+        assert_eq!(semitones_above_c(pitch_class(pattern.root)), pattern.root);
+
+        // out of range values are clamped
+        pattern.steps[5].notes.push(255);
+        pattern.width = 1000;
+        let mut loaded = VirtualGrid::new();
+        loaded.load_pattern(&pattern);
+        assert_eq!(loaded.steps_count(), MAX_STEPS);
+        assert_eq!(loaded.grid[0].notes(), grid.grid[0].notes());
+        assert_eq!(loaded.grid[3].notes(), grid.grid[3].notes());
+        assert_eq!(loaded.grid[3].velocity, grid.grid[3].velocity);
+        assert_eq!(loaded.grid[5].notes()[..], [(loaded.height - 1) as u8]);
+    }
+
+    #[test]
+    fn midi_note_off_after_pattern_change() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let (receiver, _) = mmms.midi.remove(0);
+        let note = pitch_to_midi(renderer.tracks[0].scale.idx_to_cv(20).unwrap());
+        renderer.tracks[0].steps[0].toggle(20);
+        let period = 1. / 44100.;
+
+        renderer.render_midi(0, 16, period, 0.);
+        assert_eq!(receiver.try_recv().unwrap(), [0x90, note, MAX_VELOCITY]);
+        assert!(receiver.try_recv().is_err());
+
+        // the note is turned off at the end of the trigger, even if the step has been cleared
+        renderer.tracks[0].clear();
+        renderer.render_midi(0, 16, period, 0.025);
+        assert_eq!(receiver.try_recv().unwrap(), [0x80, note, 0]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn direction() {
+        let order = |direction| -> Vec<usize> {
+            (0..8).map(|raw| direction_step(direction, 1, raw, 4)).collect()
+        };
+        assert_eq!(order(Direction::Forward), [0, 1, 2, 3, 0, 1, 2, 3]);
+        assert_eq!(order(Direction::Reverse), [3, 2, 1, 0, 3, 2, 1, 0]);
+        assert_eq!(order(Direction::PingPong), [0, 1, 2, 3, 2, 1, 0, 1]);
+        assert_eq!(direction_step(Direction::PingPong, 1, 5, 1), 0);
+        let random = order(Direction::Random);
+        assert_eq!(random, order(Direction::Random));
+        assert!(random.iter().all(|step| *step < 4));
+        let other_seed: Vec<usize> = (0..8).map(|raw| direction_step(Direction::Random, 2, raw, 4)).collect();
+        assert_ne!(random, other_seed);
+    }
+
+    #[test]
+    fn tempo_change() {
+        let (mut mmms, mut renderer) = MMMS::new((BelaPort::Digital(0), BelaPort::AnalogOut(0)),
+                                                 16, 8, 120.).unwrap();
+        // one second at 120 BPM
+        renderer.clock_updater.increment(CLOCK_RATE as usize);
+        assert_eq!(mmms.audio_clock.beat(), 2.);
+        mmms.set_tempo(1000.);
+        assert_eq!(mmms.tempo, MAX_TEMPO);
+        assert!((mmms.audio_clock.beat() - 2.).abs() < 0.01);
+        // the renderer moves a bit further before receiving the message
+        renderer.clock_updater.increment(CLOCK_RATE as usize / 2);
+        let msg = renderer.receiver.try_recv().unwrap();
+        renderer.handle_message(msg);
+        assert_eq!(renderer.tempo, MAX_TEMPO);
+        assert!((renderer.clock_consumer.beat() - 3.).abs() < 0.01);
+        assert!((mmms.audio_clock.beat() - 3.).abs() < 0.01);
+
+        assert_eq!(tempo_bar_length(MIN_TEMPO, 16), 1);
+        assert_eq!(tempo_bar_length(MAX_TEMPO, 16), 16);
+        assert_eq!(tempo_bar_length(MAX_TEMPO, 8), 8);
+    }
+
+    #[test]
+    fn polyphonic_tick() {
+        let mut grid = VirtualGrid::new();
+        for &y in [1, 3, 5].iter() {
+            grid.tick(2, y);
+        }
+        let (x, _) = grid.vaddress(2, 0).unwrap();
+        for &y in [1, 3, 5].iter() {
+            let (_, row) = grid.vaddress(2, y).unwrap();
+            assert!(grid.grid[x].notes().contains(&(row as u8)));
+        }
+        assert_eq!(grid.grid[x].notes().len(), 3);
+
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        for y in 0..7 {
+            let lit = leds[y * 16 + 2] >= 12;
+            assert_eq!(lit, y == 1 || y == 3 || y == 5);
+        }
+
+        // ticking again removes a single note
+        grid.tick(2, 3);
+        assert_eq!(grid.grid[x].notes().len(), 2);
+    }
+
+    #[test]
+    fn velocity() {
+        let mut grid = VirtualGrid::new();
+        assert_eq!(grid.grid[0].velocity, MAX_VELOCITY);
+        grid.tick(0, 0);
+        assert_eq!(grid.set_velocity(0, 0), (0, MAX_VELOCITY));
+        let (x, velocity) = grid.set_velocity(0, 3);
+        assert_eq!(x, 0);
+        assert_eq!(velocity, 72);
+
+        let mut leds = [0 as u8; 7 * 16];
+        grid.velocity_viewport(&mut leds);
+        for i in 0..7 {
+            assert_eq!(leds[i * 16], if i >= 3 { 15 } else { 0 });
+            // no notes, but full velocity
+            assert_eq!(leds[i * 16 + 1], 4);
+        }
+    }
+
+    #[test]
+    fn scale_remap() {
+        let mut grid = VirtualGrid::new();
+        let minor_count = grid.height;
+        let row = |count: usize, idx: usize| (count - 1 - idx) as u8;
+        // G and C# in B minor
+        grid.grid[0].toggle(row(minor_count, 5));
+        grid.grid[1].toggle(row(minor_count, 1));
+        // B and F#, in both scales
+        grid.grid[2].toggle(row(minor_count, 7));
+        grid.grid[2].toggle(row(minor_count, 4));
+
+        let pentatonic = Scale::new(PitchClass::B, ScaleType::MinorPentatonic);
+        let pentatonic_count = pentatonic.note_count();
+        grid.remap_scale(pentatonic.into());
+        assert_eq!(grid.height, pentatonic_count);
+        // G goes down to F#, C# up to D
+        assert_eq!(grid.grid[0].notes().to_vec(), vec![row(pentatonic_count, 3)]);
+        assert_eq!(grid.grid[1].notes().to_vec(), vec![row(pentatonic_count, 1)]);
+        assert_eq!(grid.grid[2].notes().to_vec(),
+                   vec![row(pentatonic_count, 5), row(pentatonic_count, 3)]);
+        assert!(grid.grid[3].is_empty());
+
+        // notes that end up on the same pitch are merged
+        grid.grid[4].toggle(row(pentatonic_count, 1));
+        grid.grid[4].toggle(row(pentatonic_count, 2));
+        grid.remap_scale(Scale::new(PitchClass::B, ScaleType::Chromatic).into());
+        grid.remap_scale(Scale::new(PitchClass::B, ScaleType::MinorPentatonic).into());
+        assert_eq!(grid.grid[4].notes().len(), 2);
+        assert!(grid.offset_y + 7 <= grid.height);
+    }
+
+    #[test]
+    fn probability() {
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(8);
+        let probabilities = [100, 0, 50, 50, 50, 50, 50, 50];
+        for (x, probability) in probabilities.iter().enumerate() {
+            renderer.tracks[0].steps[x].toggle(20);
+            renderer.tracks[0].set_probability(x, *probability);
+        }
+        let mut sequence = |seed| -> Vec<bool> {
+            renderer.rng = XorShift::new(seed);
+            renderer.tracks[0].rolls = [None, None];
+            (0..64).map(|raw| {
+                let fires = renderer.fires(0, raw);
+                // rolled once per step played
+                assert_eq!(renderer.fires(0, raw), fires);
+                fires
+            }).collect()
+        };
+        let fired = sequence(1);
+        assert_eq!(fired, sequence(1));
+        assert_ne!(fired, sequence(2));
+        for (raw, fires) in fired.iter().enumerate() {
+            match raw % 8 {
+                0 => assert!(*fires),
+                1 => assert!(!*fires),
+                _ => {}
+            }
+        }
+        let half = fired.iter().enumerate().filter(|&(raw, fires)| raw % 8 >= 2 && *fires).count();
+        assert!(half > 12 && half < 36);
+    }
+
+    #[test]
+    fn ratchet() {
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].set_ratchet(0, 4);
+        assert_eq!(renderer.ratchet_at(0, 0, 0.3), (1, 0.3 - 0.25, renderer.trigger_window()));
+        assert_eq!(renderer.ratchet_at(0, 1, 0.3), (0, 0.3, renderer.trigger_window()));
+
+        // at the maximum tempo and trigger duration, the triggers stay shorter than the retriggers
+        renderer.set_tempo(MAX_TEMPO);
+        renderer.set_trigger_duration(1.);
+        for &swing in [0., MAX_SWING].iter() {
+            renderer.set_swing(swing);
+            for &raw in [0, 1].iter() {
+                renderer.tracks[0].set_ratchet(raw, 3);
+                let length = step_length(raw, swing) / 3.;
+                let (index, position, trigger) = renderer.ratchet_at(0, raw, step_length(raw, swing) - 0.001);
+                assert_eq!(index, 2);
+                // the gate is off before the next step
+                assert!(position >= trigger);
+                assert!(trigger <= length / 2.);
+            }
+        }
+    }
+
+    #[test]
+    fn midi_ratchet() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let (receiver, _) = mmms.midi.remove(0);
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.tracks[0].set_ratchet(0, 3);
+        // one sixteenth at 120 BPM is 5512.5 frames
+        renderer.render_midi(0, 5500, 1. / 44100., 0.);
+        let note_ons = receiver.try_iter().filter(|message| message[0] == 0x90).count();
+        assert_eq!(note_ons, 3);
+    }
+
+    #[test]
+    fn glide() {
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let period = 1. / 22050.;
+        // no glide, the pitch jumps and is held during rests
+        assert_eq!(renderer.tracks[0].glide(Some(0.5), period), 0.5);
+        assert_eq!(renderer.tracks[0].glide(None, period), 0.5);
+
+        // 10ms is 220.5 frames
+        renderer.tracks[0].set_glide(0.01);
+        let first = renderer.tracks[0].glide(Some(0.1), period);
+        assert!(first < 0.5 && first > 0.49);
+        let mut value = first;
+        for _ in 0..200 {
+            let next = renderer.tracks[0].glide(None, period);
+            assert!(next < value);
+            value = next;
+        }
+        assert!(value > 0.1);
+        for _ in 0..21 {
+            value = renderer.tracks[0].glide(Some(0.1), period);
+        }
+        assert_eq!(value, 0.1);
+    }
+
+    #[test]
+    fn pitch_smoothing() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let period = 1. / 22050.;
+        // no smoothing, the pitch is left as is
+        for &value in [0.3, 0.1 + 0.2, -1. / 3., 0.0].iter() {
+            assert_eq!(renderer.tracks[0].smooth(value, period).to_bits(), (value as f32).to_bits());
+        }
+
+        // 1ms is 22 frames, two thirds of the way after as many frames
+        mmms.set_pitch_smoothing(0, 0.001);
+        renderer.receive();
+        let first = renderer.tracks[0].smooth(0.5, period);
+        assert!(first > 0. && first < 0.05);
+        let mut value = first;
+        for _ in 1..22 {
+            let next = renderer.tracks[0].smooth(0.5, period);
+            assert!(next > value && next < 0.5);
+            value = next;
+        }
+        assert!((value - 0.5 * (1. - (-1f32).exp())).abs() < 0.01);
+        for _ in 0..1000 {
+            value = renderer.tracks[0].smooth(0.5, period);
+        }
+        assert!((value - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn invalid_ports() {
+        let error = |result: Result<(MMMS, MMMSRenderer), MmmsError>| result.err();
+        assert_eq!(error(MMMS::new((BelaPort::Digital(0), BelaPort::Digital(1)), 16, 8, 120.)),
+                   Some(MmmsError::InvalidPitchPort));
+        assert_eq!(error(MMMS::new((BelaPort::AnalogIn(0), BelaPort::AnalogOut(0)), 16, 8, 120.)),
+                   Some(MmmsError::InvalidTriggerPort));
+        let (_, mut renderer) = MMMS::new((BelaPort::AnalogOut(1), BelaPort::AnalogOut(0)),
+                                          16, 8, 120.).unwrap();
+        assert_eq!(renderer.set_velocity_port(0, BelaPort::Digital(2)),
+                   Err(MmmsError::InvalidVelocityPort));
+        assert_eq!(renderer.set_velocity_port(0, BelaPort::AnalogOut(2)), Ok(()));
+    }
+
+    #[test]
+    fn clear_bar() {
+        let mut grid = VirtualGrid::new();
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        for &x in [3, 19].iter() {
+            grid.grid[x].toggle(2);
+            renderer.tracks[0].press(x, 2);
+        }
+        grid.clear_bar(1);
+        renderer.handle_message(Message::Track(0, TrackMessage::ClearBar(1)));
+        assert!(!grid.grid[3].is_empty() && grid.grid[19].is_empty());
+        assert!(!renderer.tracks[0].steps[3].is_empty() && renderer.tracks[0].steps[19].is_empty());
+
+        grid.clear_all();
+        assert!(grid.grid.iter().all(|step| step.is_empty()));
+    }
+
+    #[test]
+    fn tracks() {
+        assert_eq!(MMMS::with_tracks(vec![], 16, 8, 120., default_scale()).err(), Some(MmmsError::InvalidTrackCount));
+        let outputs = vec![
+            Output::from((BelaPort::Digital(0), BelaPort::AnalogOut(0))),
+            Output::from((BelaPort::Digital(1), BelaPort::AnalogOut(1))),
+        ];
+        let (mut mmms, mut renderer) = MMMS::with_tracks(outputs, 16, 8, 120., default_scale()).unwrap();
+        assert_eq!(renderer.tracks.len(), 2);
+        assert_eq!(renderer.set_velocity_port(2, BelaPort::AnalogOut(2)), Err(MmmsError::InvalidTrack));
+
+        mmms.select_track(1);
+        mmms.virtual_grids[1].tick(0, 0);
+        let xy = mmms.virtual_grids[1].vaddress(0, 0).unwrap();
+        mmms.send_track(TrackMessage::Tick(xy));
+        // each track has its own pattern
+        assert!(mmms.virtual_grids[0].grid[xy.0].is_empty());
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        assert!(renderer.tracks[0].steps[xy.0].is_empty());
+        assert!(!renderer.tracks[1].steps[xy.0].is_empty());
+
+        // there is no third track
+        mmms.select_track(2);
+        assert_eq!(mmms.track, 1);
+    }
+
+    #[test]
+    fn clock_ratio() {
+        // note ons in four sixteenths, at 120 BPM, when all steps have a note
+        let note_ons = |ratio: f32| -> usize {
+            let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+            let (receiver, _) = mmms.midi.remove(0);
+            for step in renderer.tracks[0].steps.iter_mut() {
+                step.toggle(20);
+            }
+            renderer.tracks[0].set_clock_ratio(ratio);
+            let mut count = 0;
+            for block in 0..(4 * 5512 / 16) {
+                renderer.render_midi(0, 16, 1. / 44100., block as f32 * 16. / 22050.);
+                count += receiver.try_iter().filter(|message| message[0] == 0x90).count();
+            }
+            count
+        };
+        assert_eq!(note_ons(1.), 4);
+        assert_eq!(note_ons(0.5), 2);
+        assert_eq!(note_ons(3.), 12);
+        assert_eq!(clamp_clock_ratio(0.3), 0.25);
+        assert_eq!(clamp_clock_ratio(10.), 3.);
+
+        // the trigger lasts as long in seconds
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let trigger = renderer.trigger_window();
+        renderer.tracks[0].set_clock_ratio(0.5);
+        assert_eq!(renderer.ratchet_at(0, 0, 0.).2, trigger * 0.5);
+    }
+
+    #[test]
+    fn external_clock() {
+        let mut clock = ExternalClock::new(0);
+        // a sixteenth every 100 frames, high for 10 frames
+        let pulses = |clock: &mut ExternalClock, count: usize| {
+            for _ in 0..count {
+                for frame in 0..100 {
+                    clock.input(frame < 10);
+                }
+            }
+        };
+        for _ in 0..50 {
+            clock.input(false);
+        }
+        assert_eq!(clock.sixteenth(), 0.);
+        pulses(&mut clock, 1);
+        // the length of a sixteenth is not known yet
+        assert_eq!(clock.sixteenth(), 0.);
+        pulses(&mut clock, 2);
+        assert_eq!(clock.sixteenth_duration(), Some(100. / 44100.));
+        clock.input(true);
+        for _ in 0..49 {
+            clock.input(false);
+        }
+        assert!((clock.sixteenth() - 3.5).abs() < 0.02);
+        // the clock stops: the position stays in the current sixteenth
+        for _ in 0..1000 {
+            clock.input(false);
+        }
+        assert!(clock.sixteenth() < 4.);
+
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        assert_eq!(renderer.set_clock_source(ClockSource::External(BelaPort::AnalogIn(0))),
+                   Err(MmmsError::InvalidClockPort));
+        assert_eq!(renderer.set_clock_source(ClockSource::External(BelaPort::Digital(3))), Ok(()));
+    }
+
+    #[test]
+    fn transpose() {
+        let scale = TrackScale::Standard(Scale::new(PitchClass::B, ScaleType::Minor));
+        let last = scale.note_count() as u8 - 1;
+        assert_eq!(transposed_pitch(&scale, 10, 2), scale.idx_to_cv(12).unwrap());
+        assert_eq!(transposed_pitch(&scale, 10, -3), scale.idx_to_cv(7).unwrap());
+        // clamped to the scale
+        assert_eq!(transposed_pitch(&scale, 1, -5), scale.idx_to_cv(0).unwrap());
+        assert_eq!(transposed_pitch(&scale, last, 3), scale.idx_to_cv(last as usize).unwrap());
+
+        // the pattern is untouched, the note played is transposed
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let (receiver, _) = mmms.midi.remove(0);
+        renderer.tracks[0].steps[0].toggle(20);
+        mmms.set_transpose(2);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        renderer.render_midi(0, 16, 1. / 44100., 0.);
+        let note = pitch_to_midi(renderer.tracks[0].scale.idx_to_cv(22).unwrap());
+        assert_eq!(receiver.try_recv().unwrap(), [0x90, note, MAX_VELOCITY]);
+        assert_eq!(renderer.tracks[0].steps[0].notes().to_vec(), vec![20]);
+
+        assert_eq!(transpose_bar(0, 16), (8, 8));
+        assert_eq!(transpose_bar(-3, 16), (5, 8));
+        assert_eq!(transpose_bar(20, 16), (8, 15));
+        assert_eq!(transpose_bar(-20, 8), (0, 4));
+    }
+
+    #[test]
+    fn randomize() {
+        let mut grid = VirtualGrid::new();
+        let mut rng = XorShift::new(RANDOMIZE_SEED);
+        grid.randomize(1., &mut rng);
+        let rows = grid.offset_y..grid.offset_y + 7;
+        assert!(grid.grid[..16].iter().all(|step| {
+            step.notes().len() == 1 && rows.contains(&(step.notes()[0] as usize))
+        }));
+        assert!(grid.grid[16].is_empty());
+        grid.randomize(0., &mut rng);
+        assert!(grid.grid.iter().all(|step| step.is_empty()));
+
+        // the renderer gets the same pattern, rests cleared
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        for x in 0..16 {
+            mmms.virtual_grids[0].grid[x].set_rest(true);
+            renderer.tracks[0].steps[x].set_rest(true);
+        }
+        mmms.randomize(0.5);
+        while let Ok(message) = renderer.receiver.try_recv() {
+            renderer.handle_message(message);
+        }
+        for x in 0..16 {
+            let step = &mmms.virtual_grids[0].grid[x];
+            assert_eq!(step.is_empty(), renderer.tracks[0].steps[x].is_empty());
+            assert!(!step.is_rest() && !renderer.tracks[0].steps[x].is_rest());
+        }
+        assert!(mmms.virtual_grids[0].grid[..16].iter().any(|step| step.is_empty()));
+        assert!(mmms.virtual_grids[0].grid[..16].iter().any(|step| !step.is_empty()));
+    }
+
+    #[test]
+    fn accent() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        // only lit steps can be accented
+        assert_eq!(mmms.virtual_grids[0].toggle_accent(2, 3), None);
+        mmms.virtual_grids[0].tick(2, 3);
+        let accent = mmms.virtual_grids[0].toggle_accent(2, 3);
+        assert_eq!(accent, Some((2, true)));
+        mmms.send_track(TrackMessage::Accent(accent.unwrap()));
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        assert!(renderer.tracks[0].steps[2].accent);
+
+        let mut leds = [0 as u8; 7 * 16];
+        mmms.virtual_grids[0].viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[3 * 16 + 2], 15);
+        mmms.virtual_grids[0].toggle_accent(2, 3);
+        mmms.virtual_grids[0].viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[3 * 16 + 2], 12);
+
+        assert_eq!(renderer.set_accent_port(0, BelaPort::AnalogIn(0)), Err(MmmsError::InvalidAccentPort));
+        assert_eq!(renderer.set_accent_port(1, BelaPort::Digital(2)), Err(MmmsError::InvalidTrack));
+        assert_eq!(renderer.set_accent_port(0, BelaPort::Digital(2)), Ok(()));
+    }
+
+    #[test]
+    fn view_controls() {
+        let mut tracker = GridStateTracker::new(16, 8);
+        let shifted = |tracker: &mut GridStateTracker, x: usize| {
+            tracker.down(15, 0);
+            tracker.down(x, 0);
+            let action = tracker.up(x, 0);
+            tracker.up(15, 0);
+            action
+        };
+        match shifted(&mut tracker, 0) {
+            MMMSAction::Euclid { pulses: 1, steps: 8 } => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.views.tempo = true;
+        match shifted(&mut tracker, 0) {
+            MMMSAction::CycleCountIn => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        // the other keys still fill a euclidean rhythm
+        match shifted(&mut tracker, 3) {
+            MMMSAction::Euclid { pulses: 4, steps: 8 } => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.views = Views { probability: true, tempo: false, transpose: true };
+        match shifted(&mut tracker, 3) {
+            MMMSAction::TogglePreview => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        match shifted(&mut tracker, 2) {
+            MMMSAction::ArmFill => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        // the views follow the control map: the chords are on the first track on a monome 64
+        let mut tracker = GridStateTracker::new(8, 8);
+        tracker.views.transpose = true;
+        tracker.down(7, 0);
+        tracker.down(4, 0);
+        match tracker.up(4, 0) {
+            MMMSAction::CycleChord => {}
+            action => panic!("unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    fn grid_sizes() {
+        assert_eq!(GridStateTracker::idx(16, 3, 2), 35);
+        assert_eq!(GridStateTracker::idx(8, 3, 2), 19);
+        assert_eq!(GridStateTracker::idx(8, 7, 7), 63);
+        assert_eq!(MMMS::new(Output::Midi(Box::new(|_| {})), 12, 8, 120.).err(),
+                   Some(MmmsError::InvalidGridSize));
+
+        // a monome 64: the arrows are next to transport and tempo, shift is the last key
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 8, 8, 120.).unwrap();
+        let mut tracker = GridStateTracker::new(8, 8);
+        tracker.down(3, 0);
+        match tracker.up(3, 0) {
+            MMMSAction::Move((8, 0)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.down(7, 0);
+        tracker.down(0, 7);
+        match tracker.up(0, 7) {
+            MMMSAction::Clear => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        let mut leds = [0 as u8; 128];
+        mmms.render(&mut leds);
+        // nothing is drawn past the 64 keys
+        assert_eq!(leds[0], 15);
+        assert!(leds[64..].iter().all(|led| *led == 0));
+        mmms.virtual_grids[0].mouve(8, 0);
+        assert_eq!(mmms.virtual_grids[0].offset_x, 8);
+        assert_eq!(mmms.virtual_grids[0].bar_start(), 0);
+
+        // a monome 256 has fifteen rows of notes
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 16, 120.).unwrap();
+        assert_eq!(mmms.virtual_grids[0].view_height, 15);
+        mmms.virtual_grids[0].tick(0, 14);
+        let mut leds = vec![0 as u8; 256];
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[15 * 16], 12);
+    }
+
+    #[test]
+    fn tie() {
+        // with shift, hold a key, press another one to its right
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(15, 0);
+        tracker.down(2, 3);
+        tracker.down(5, 3);
+        match tracker.up(5, 3) {
+            MMMSAction::Tie((2, 5)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        match tracker.up(2, 3) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.up(15, 0);
+
+        let mut grid = VirtualGrid::new();
+        grid.tick(0, 2);
+        assert_eq!(grid.tie(0, 2), vec![(1, true), (2, true)]);
+        let (_, row) = grid.vaddress(0, 2).unwrap();
+        assert_eq!(grid.held_notes(2), &[row as u8]);
+        assert!(grid.held_notes(3).is_empty());
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[2 * 16 + 2], 7);
+        assert_eq!(grid.tie(0, 2), vec![(1, false), (2, false)]);
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let (receiver, _) = mmms.midi.remove(0);
+        for &x in [0, 3].iter() {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        for &x in [1, 2].iter() {
+            renderer.handle_message(Message::Track(0, TrackMessage::Tie((x, true))));
+        }
+        // held until the end of the last tied step, without a new attack
+        assert!(renderer.gate(0, 0, 0.9, false));
+        assert!(renderer.gate(0, 1, 0.05, false));
+        assert!(renderer.gate(0, 2, 0.05, false));
+        assert!(!renderer.gate(0, 2, 0.9, false));
+        assert!(!renderer.gate(0, 3, 0.9, false));
+
+        // two sixteenths at 120 BPM, then two more
+        renderer.render_midi(0, 11000, 1. / 44100., 0.);
+        let messages: Vec<_> = receiver.try_iter().map(|message| message[0]).collect();
+        assert_eq!(messages, vec![0x90]);
+        renderer.render_midi(0, 11000, 1. / 44100., 11000. / 22050.);
+        let messages: Vec<_> = receiver.try_iter().map(|message| message[0]).collect();
+        assert_eq!(messages, vec![0x80, 0x90, 0x80]);
+    }
+
+    #[test]
+    fn rest() {
+        // empty, note, rest, empty
+        let mut grid = VirtualGrid::new();
+        let (x, _) = grid.vaddress(4, 1).unwrap();
+        grid.tick(4, 1);
+        assert!(!grid.grid[x].is_empty() && !grid.grid[x].is_rest());
+        grid.tick(4, 1);
+        assert!(grid.grid[x].is_empty() && grid.grid[x].is_rest());
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert!((0..7).all(|y| leds[y * 16 + 4] >= 2));
+        grid.tick(4, 3);
+        assert!(grid.grid[x].is_empty() && !grid.grid[x].is_rest());
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert!((0..7).any(|y| leds[y * 16 + 4] == 0));
+
+        // a note replaces a rest, only the last note leaves a rest
+        grid.grid[x].set_rest(true);
+        grid.grid[x].toggle(2);
+        assert!(!grid.grid[x].is_rest());
+        grid.tick(4, 1);
+        grid.tick(4, 1);
+        assert!(!grid.grid[x].is_rest());
+
+        // the renderer cuts the pitch on a rest, and holds it on an empty step
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.handle_message(Message::Track(0, TrackMessage::Rest((1, true))));
+        assert!(renderer.tracks[0].rests(1, Direction::Forward));
+        assert!(!renderer.tracks[0].rests(2, Direction::Forward));
+        renderer.tracks[0].glide(Some(0.5), 1. / 22050.);
+        renderer.tracks[0].jump(0.);
+        assert_eq!(renderer.tracks[0].glide(None, 1. / 22050.), 0.);
+    }
+
+    #[test]
+    fn scroll_to_edges() {
+        let mut grid = VirtualGrid::new();
+        grid.mouve(-1000, -1000);
+        assert_eq!((grid.offset_x, grid.offset_y), (0, 0));
+        grid.mouve(1000, 1000);
+        assert_eq!((grid.offset_x, grid.offset_y), (INITIAL_STEPS - 16, grid.height - 7));
+        // the last row and column of the view are the last ones of the grid
+        assert_eq!(grid.vaddress(15, 6).unwrap(), (INITIAL_STEPS - 1, grid.height - 1));
+        // and there is nothing past them
+        assert_eq!(grid.vaddress(16, 6), None);
+        assert_eq!(grid.vaddress(15, 7), None);
+        grid.tick(16, 7);
+        assert!(grid.grid.iter().all(|step| step.notes().is_empty() && !step.is_rest()));
+        assert!(grid.in_view(INITIAL_STEPS - 1, grid.height - 1));
+        assert!(!grid.in_view(INITIAL_STEPS - 17, grid.height - 1));
+        assert!(!grid.in_view(INITIAL_STEPS - 1, grid.height - 8));
+
+        // the view stays in the grid when it shrinks
+        grid.change_steps_count(64);
+        grid.mouve(1000, 0);
+        assert_eq!(grid.offset_x, 48);
+        grid.change_steps_count(16);
+        assert_eq!(grid.offset_x, 0);
+        assert!(grid.x_in_view(15) && !grid.x_in_view(16));
+    }
+
+    #[test]
+    fn octave_indicator() {
+        let mut grid = VirtualGrid::new();
+        // the view starts three octaves above the lowest note
+        assert_eq!(grid.current_octave(), 3);
+        grid.mouve(0, 1000);
+        assert_eq!(grid.current_octave(), 0);
+        let octave = grid.scale.octave_note_count() as isize;
+        grid.mouve(0, -octave);
+        assert_eq!(grid.current_octave(), 1);
+        grid.mouve(0, -1000);
+        let expected = (grid.scale.note_count() - 7) / grid.scale.octave_note_count();
+        assert_eq!(grid.current_octave(), clamp(expected, 0, 8));
+    }
+
+    #[test]
+    fn viewport_degrees() {
+        let grid = VirtualGrid::new();
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        // the row at the bottom of the view is a tonic, then up to the leading tone at the top
+        let column: Vec<u8> = (0..7).rev().map(|row| leds[row * 16]).collect();
+        assert_eq!(column, vec![10, 0, 0, 0, 6, 0, 4]);
+        assert!((0..16).all(|x| leds[6 * 16 + x] == 10));
+    }
+
+    #[test]
+    fn tick_and_ascii() {
+        let mut grid = VirtualGrid::new();
+        let empty = grid.render_ascii();
+        assert_eq!(empty.lines().count(), grid.scale.note_count() + 2);
+        assert!(!empty.contains("|1|"));
+
+        grid.tick(3, 2);
+        let (x, y) = grid.vaddress(3, 2).unwrap();
+        assert_eq!(grid.selected_row(), y);
+        assert_eq!(&grid.grid[x].notes()[..], &[y as u8]);
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[2 * 16 + 3], 12);
+        let ascii = grid.render_ascii();
+        let line = ascii.lines().nth(y + 1).unwrap();
+        let cells: Vec<&str> = line.split('\t').nth(1).unwrap().as_bytes().chunks(3)
+            .map(|cell| std::str::from_utf8(cell).unwrap()).collect();
+        assert_eq!(cells[x], "|1|");
+        assert_eq!(cells.iter().filter(|cell| cell.contains('1')).count(), 1);
+        // out of the view
+        assert_eq!(cells[16], " 0 ");
+    }
+
+    #[test]
+    fn display() {
+        let mut grid = VirtualGrid::new();
+        assert_eq!(grid.to_string(), format!("32 steps in {}", grid.scale));
+        grid.tick(3, 2);
+        grid.tick(5, 2);
+        grid.tick(5, 6);
+        let name = |grid: &VirtualGrid, vy: usize| {
+            let idx = grid.scale.note_count() - 1 - (grid.offset_y + vy);
+            match grid.scale {
+                TrackScale::Standard(ref scale) => scale.idx_to_pitch(idx).unwrap().to_string(),
+                TrackScale::Custom(_) => unreachable!(),
+            }
+        };
+        let display = grid.to_string();
+        let lines: Vec<&str> = display.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let rest = ".".repeat(26);
+        assert_eq!(lines[1], format!("{}\t...x.x{}", name(&grid, 2), rest));
+        assert_eq!(lines[2], format!("{}\t.....x{}", name(&grid, 6), rest));
+
+        // the renderer sums up its tracks
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].press(3, 2);
+        let debug = format!("{:?}", renderer);
+        assert!(debug.starts_with("MMMSRenderer { tempo: 120.0"));
+        assert!(debug.contains("Track { steps: 32, active_steps: 1"));
+        assert!(debug.len() < 300);
+    }
+
+    #[test]
+    fn builder() {
+        let (mmms, renderer) = MmmsBuilder::new()
+            .trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0))
+            .velocity_port(BelaPort::AnalogOut(1))
+            .accent_port(BelaPort::Digital(1))
+            .output(Output::Midi(Box::new(|_| {})))
+            .scale(Scale::new(PitchClass::D, ScaleType::Major))
+            .tempo(90.)
+            .steps(40)
+            .build()
+            .unwrap();
+        assert_eq!(renderer.tracks.len(), 2);
+        assert_eq!(renderer.tracks[0].velocity_channel, Some(1));
+        assert!(renderer.tracks[0].accent_port.is_some() && renderer.tracks[1].accent_port.is_none());
+        assert_eq!(mmms.tempo, 90.);
+        for grid in mmms.virtual_grids.iter() {
+            assert_eq!(grid.steps_count(), 48);
+            assert!(grid.current_scale().fundamental() == PitchClass::D);
+        }
+        assert!(renderer.tracks.iter().all(|track| track.steps.len() == 48));
+
+        let missing_pitch = MmmsBuilder::new().trigger_port(BelaPort::Digital(0)).build();
+        assert_eq!(missing_pitch.err(), Some(MmmsError::InvalidPitchPort));
+        assert_eq!(MmmsBuilder::new().build().err(), Some(MmmsError::InvalidTrackCount));
+        let wrong_velocity = MmmsBuilder::new()
+            .output(Output::Midi(Box::new(|_| {})))
+            .velocity_port(BelaPort::Digital(3))
+            .build();
+        assert_eq!(wrong_velocity.err(), Some(MmmsError::InvalidVelocityPort));
+    }
+
+    #[test]
+    fn cv_range() {
+        // the default is the Bela with an analog gain of two: 10V, at one volt per octave
+        let range = CvRange::default();
+        assert_eq!(range.normalize(2.5), 0.25);
+        let scale = TrackScale::Standard(Scale::new(PitchClass::B, ScaleType::Minor));
+        let pitch = transposed_pitch(&scale, 14, 0);
+        assert_eq!(range.normalize(pitch), pitch / 10.);
+
+        // 1.2V per octave on a 5V output
+        let range = CvRange { volts_per_octave: 1.2, max_volts: 5. };
+        assert!((range.normalize(2.) - 0.48).abs() < 1e-6);
+        // out of range pitches are clamped instead of crashing
+        assert_eq!(range.normalize(10.), 1.);
+        assert_eq!(range.normalize(-1.), 0.);
+    }
+
+    #[test]
+    fn pitch_tracking() {
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let scale = TrackScale::Standard(Scale::new(PitchClass::B, ScaleType::Minor));
+        let octave = scale.octave_note_count() as u8;
+        let low = transposed_pitch(&scale, 14, 0);
+        let high = transposed_pitch(&scale, 14 + octave, 0);
+
+        // an octave is a volt higher
+        let (low_cv, high_cv) = (renderer.pitch_cv(low), renderer.pitch_cv(high));
+        assert!((high_cv - low_cv - 0.1).abs() < 1e-6);
+
+        // an octave is twice the voltage
+        renderer.set_pitch_tracking(PitchTrackingMode::HzPerVolt(100.));
+        let (low_cv, high_cv) = (renderer.pitch_cv(low), renderer.pitch_cv(high));
+        assert!((high_cv / low_cv - 2.).abs() < 1e-4);
+        assert!((low_cv - pitch_frequency(low, 440.) / 100. / 10.).abs() < 1e-6);
+        assert!((pitch_frequency(transposed_pitch(&scale, 0, 0), 440.) * 2. -
+                 pitch_frequency(transposed_pitch(&scale, octave, 0), 440.)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn tuning() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let scale = TrackScale::Standard(Scale::new(PitchClass::B, ScaleType::Minor));
+        let pitch = transposed_pitch(&scale, 20, 0);
+        let ratio: f32 = 432. / 440.;
+
+        renderer.set_pitch_tracking(PitchTrackingMode::HzPerVolt(100.));
+        let at_440 = renderer.pitch_cv(pitch);
+        mmms.set_tuning(432.);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        assert!((renderer.pitch_cv(pitch) / at_440 - ratio).abs() < 1e-5);
+
+        // the same offset for all the pitches in volts per octave
+        renderer.set_pitch_tracking(PitchTrackingMode::VoltPerOctave);
+        let offset = renderer.pitch_cv(pitch) - pitch / 10.;
+        assert!((offset - ratio.log2() / 10.).abs() < 1e-6);
+
+        renderer.set_tuning(1000.);
+        assert_eq!(renderer.tuning_a4, MAX_TUNING);
+    }
+
+    #[test]
+    fn gate_mode() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(2);
+        for x in 0..2 {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        // a trigger of 10ms, in a step of 125ms at 120 BPM
+        assert!(renderer.gate(0, 0, 0.05, false));
+        assert!(!renderer.gate(0, 0, 0.5, false));
+
+        mmms.set_gate_mode(GateMode::Gate);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        for raw in 0..2 {
+            assert!(renderer.gate(0, raw, 0.05, false));
+            assert!(renderer.gate(0, raw, 0.5, false));
+            // low for 2ms before the next step
+            assert!(renderer.gate(0, raw, 0.98, false));
+            assert!(!renderer.gate(0, raw, 0.99, false));
+        }
+        // each retrigger is a gate
+        renderer.tracks[0].set_ratchet(0, 2);
+        assert!(renderer.gate(0, 0, 0.4, false));
+        assert!(!renderer.gate(0, 0, 0.495, false));
+        assert!(renderer.gate(0, 0, 0.55, false));
+    }
+
+    #[test]
+    fn legato() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(4);
+        for &x in [0, 1, 3].iter() {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        renderer.tracks[0].steps[2].set_rest(true);
+        // without legato, each note is a trigger
+        assert!(!renderer.gate(0, 0, 0.5, false));
+
+        mmms.set_legato(true);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        // active to active: the gate stays high
+        assert!(renderer.gate(0, 0, 0.5, false));
+        assert!(renderer.gate(0, 0, 0.999, false));
+        assert!(renderer.gate(0, 1, 0.0, false));
+        // active to rest to active: the gate goes low, then retriggers
+        assert!(!renderer.gate(0, 1, 0.5, false));
+        assert!(!renderer.gate(0, 2, 0.05, false));
+        assert!(renderer.gate(0, 3, 0.0, false));
+
+        // in gate mode, there is no gap before a legato note either
+        renderer.set_gate_mode(GateMode::Gate);
+        assert!(renderer.gate(0, 0, 0.999, false));
+        assert!(!renderer.gate(0, 1, 0.999, false));
+    }
+
+    #[test]
+    fn loop_region() {
+        // scale held, a key held and another one to its right
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(14, 0);
+        tracker.down(2, 3);
+        tracker.down(5, 3);
+        match tracker.up(5, 3) {
+            MMMSAction::Loop(Some((2, 5))) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        match tracker.up(2, 3) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        // and a single key to loop the whole pattern
+        tracker.down(7, 1);
+        match tracker.up(7, 1) {
+            MMMSAction::Loop(None) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        // the scale picker doesn't open
+        match tracker.up(14, 0) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let order = |renderer: &MMMSRenderer, direction| -> Vec<usize> {
+            (0..6).map(|raw| renderer.tracks[0].step_at(raw, direction)).collect()
+        };
+        assert_eq!(order(&renderer, Direction::Forward), [0, 1, 2, 3, 4, 5]);
+        mmms.set_loop_region(4, 8);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        assert_eq!(order(&renderer, Direction::Forward), [4, 5, 6, 7, 4, 5]);
+        assert_eq!(order(&renderer, Direction::Reverse), [7, 6, 5, 4, 7, 6]);
+        // the steps out of the region are dimmed
+        let mut leds = [0 as u8; 7 * 16];
+        mmms.virtual_grids[0].viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[6 * 16 + 3], 5);
+        assert_eq!(leds[6 * 16 + 4], 10);
+        assert_eq!(leds[6 * 16 + 8], 5);
+        // a region past the end of the pattern plays all of it
+        renderer.set_loop_region(40, 48);
+        assert_eq!(order(&renderer, Direction::Forward), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(mmms.virtual_grids[0].loop_region(), (4, 8));
+    }
+
+    #[test]
+    fn rotate() {
+        // scale held, and the left and right arrows
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(14, 0);
+        tracker.down(9, 0);
+        match tracker.up(9, 0) {
+            MMMSAction::Rotate(1) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.down(8, 0);
+        match tracker.up(8, 0) {
+            MMMSAction::Rotate(-1) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let mut grid = VirtualGrid::new();
+        grid.change_steps_count(16);
+        grid.tick(0, 1);
+        grid.tick(5, 2);
+        grid.tick(15, 3);
+        let lit = |grid: &VirtualGrid| -> Vec<usize> {
+            (0..grid.steps_count()).filter(|x| !grid.grid[*x].is_empty()).collect()
+        };
+        grid.rotate(1);
+        assert_eq!(lit(&grid), [0, 1, 6]);
+        assert_eq!(grid.grid[0].notes().len(), 1);
+        grid.rotate(-1);
+        assert_eq!(lit(&grid), [0, 5, 15]);
+        grid.rotate(-1);
+        assert_eq!(lit(&grid), [4, 14, 15]);
+        assert_eq!(grid.grid.len(), 16);
+
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].steps[31].toggle(3);
+        renderer.handle_message(Message::Track(0, TrackMessage::Rotate(1)));
+        assert!(renderer.tracks[0].steps[0].notes().contains(&3));
+        renderer.handle_message(Message::Track(0, TrackMessage::Rotate(-2)));
+        assert!(renderer.tracks[0].steps[30].notes().contains(&3));
+        assert_eq!(renderer.tracks[0].steps.len(), 32);
+    }
+
+    #[test]
+    fn invert() {
+        // shift, scale and a grid key
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(15, 0);
+        tracker.down(14, 0);
+        tracker.down(3, 4);
+        match tracker.up(3, 4) {
+            MMMSAction::Invert(3) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        // releasing scale doesn't clear the bar
+        match tracker.up(14, 0) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        // a rising motif, two rows apart, becomes a falling one
+        let mut grid = VirtualGrid::new();
+        grid.grid[0].toggle(20);
+        grid.grid[1].toggle(18);
+        grid.invert(19);
+        assert_eq!(grid.grid[0].notes(), &[18]);
+        assert_eq!(grid.grid[1].notes(), &[20]);
+        // out of the scale, the notes stay on its edges and are merged
+        let top = (grid.height - 1) as u8;
+        grid.grid[2].toggle(0);
+        grid.grid[2].toggle(1);
+        grid.invert(top);
+        assert_eq!(grid.grid[2].notes(), &[top]);
+
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let count = renderer.tracks[0].scale.note_count();
+        renderer.tracks[0].press(0, 20);
+        renderer.tracks[0].press(1, 18);
+        renderer.handle_message(Message::Track(0, TrackMessage::Invert(19)));
+        assert_eq!(renderer.tracks[0].steps[0].notes(), &[(count - 1 - 18) as u8]);
+        assert_eq!(renderer.tracks[0].steps[1].notes(), &[(count - 1 - 20) as u8]);
+    }
+
+    #[test]
+    fn reverse() {
+        // scale held, and the direction button
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(14, 0);
+        tracker.down(13, 0);
+        match tracker.up(13, 0) {
+            MMMSAction::Reverse => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let mut grid = VirtualGrid::new();
+        grid.change_steps_count(16);
+        grid.grid[0].toggle(10);
+        grid.grid[1].toggle(11);
+        grid.grid[1].toggle(12);
+        grid.grid[3].toggle(13);
+        grid.reverse();
+        let notes: Vec<Vec<u8>> = (12..16).map(|x| grid.grid[x].notes().to_vec()).collect();
+        assert_eq!(notes, vec![vec![13], vec![], vec![11, 12], vec![10]]);
+        assert!((0..12).all(|x| grid.grid[x].is_empty()));
+
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(4);
+        renderer.tracks[0].steps[0].toggle(3);
+        renderer.tracks[0].steps[1].toggle(4);
+        renderer.tracks[0].steps[1].toggle(5);
+        renderer.tracks[0].steps[3].toggle(6);
+        renderer.handle_message(Message::Track(0, TrackMessage::Reverse));
+        let notes: Vec<Vec<u8>> =
+            renderer.tracks[0].steps.iter().map(|step| step.notes().to_vec()).collect();
+        assert_eq!(notes, vec![vec![6], vec![], vec![4, 5], vec![3]]);
+    }
+
+    #[test]
+    fn humanize() {
+        let humanized = |sixteenth| {
+            delayed_position(sixteenth, 0.0, |step| humanize_delay(HUMANIZE_SEED, step, 1.0))
+        };
+        assert_eq!(delayed_position(5.25, 0.0, |_| 0.), (5, 0.25));
+        // the delays are stable, bounded, and the steps don't overlap
+        for step in 1..64 {
+            let delay = humanize_delay(HUMANIZE_SEED, step, 1.0);
+            assert_eq!(delay, humanize_delay(HUMANIZE_SEED, step, 1.0));
+            assert!(delay >= 0. && delay <= MAX_HUMANIZE_DELAY);
+            assert_eq!(humanize_delay(HUMANIZE_SEED, step, 0.5), delay / 2.);
+            let onset = step as f32 + delay;
+            assert_eq!(humanized(onset - 0.001).0, step - 1);
+            let (index, position) = humanized(onset + 0.001);
+            assert_eq!(index, step);
+            assert!(position < 0.002);
+        }
+
+        // the trigger follows the delay
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_humanize(1.0);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        renderer.tracks[0].steps[3].toggle(20);
+        renderer.set_playing(true);
+        let delay = humanize_delay(HUMANIZE_SEED, 3, 1.0);
+        let (step, position) = renderer.position(0, 3.0 + delay - 0.001);
+        assert!(!renderer.gate(0, step, position, false));
+        let (step, position) = renderer.position(0, 3.0 + delay + 0.001);
+        assert!(renderer.gate(0, step, position, false));
+    }
+
+    #[test]
+    fn nudge() {
+        // a step held, and the right arrow
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(3, 2);
+        tracker.down(9, 0);
+        match tracker.up(9, 0) {
+            MMMSAction::Nudge((3, 1)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        match tracker.up(3, 2) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let mut grid = VirtualGrid::new();
+        grid.tick(2, 3);
+        assert_eq!(grid.nudge(2, -1), (2, -1));
+        assert_eq!(grid.nudge(2, -5), (2, -MAX_NUDGE));
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[3 * 16 + 2], 13);
+
+        // two eighth of a sixteenth later
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].steps[2].toggle(20);
+        renderer.handle_message(Message::Track(0, TrackMessage::Nudge((2, 2))));
+        renderer.set_playing(true);
+        let (step, position) = renderer.position(0, 2.2);
+        assert_eq!(step, 1);
+        assert!(!renderer.gate(0, step, position, false));
+        let (step, position) = renderer.position(0, 2.26);
+        assert_eq!(step, 2);
+        assert!((position - 0.01).abs() < 0.001);
+        assert!(renderer.gate(0, step, position, false));
+        // out of range, and not past the swung step before it
+        renderer.handle_message(Message::Track(0, TrackMessage::Nudge((2, -8))));
+        assert_eq!(renderer.tracks[0].steps[2].nudge, -MAX_NUDGE);
+        renderer.set_swing(MAX_SWING);
+        let onset = renderer.tracks[0].onset(2, Direction::Forward, renderer.swing, 0.);
+        assert!(onset > 1.75 && onset < 1.8);
+        // remembered until the nudge or the timing changes
+        assert_eq!(renderer.tracks[0].onsets.get()[2].map(|onset| onset.at), Some(onset));
+        renderer.handle_message(Message::Track(0, TrackMessage::Nudge((2, 0))));
+        assert_eq!(renderer.tracks[0].onset(2, Direction::Forward, renderer.swing, 0.), 2.);
+    }
+
+    #[test]
+    fn shift_octave() {
+        // a step held, and the up arrow
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(3, 3);
+        tracker.down(10, 0);
+        match tracker.up(10, 0) {
+            MMMSAction::Octave((3, 2, 1)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        // near the top of the range, the note stops at the highest note
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let note_count = mmms.virtual_grids[0].scale.note_count();
+        let octave = mmms.virtual_grids[0].scale.octave_note_count();
+        mmms.virtual_grids[0].offset_y = 0;
+        let (down, up) = (KeyDirection::Down, KeyDirection::Up);
+        let keys = [(3, 3, down), (3, 3, up), (3, 3, down), (10, 0, down), (10, 0, up), (3, 3, up)];
+        for &(x, y, direction) in keys.iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        assert_eq!(mmms.virtual_grids[0].grid[3].notes()[..], [0]);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 0);
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps[3].notes()[..], [note_count as u8 - 1]);
+        // no higher, then an octave down
+        assert!(mmms.virtual_grids[0].shift_octave(3, 0, 1).is_empty());
+        assert_eq!(mmms.virtual_grids[0].shift_octave(3, 0, -1), vec![(3, 0), (3, octave)]);
+        assert_eq!(mmms.virtual_grids[0].grid[3].notes()[..], [octave as u8]);
+        // onto a note of the step, and where there is no note
+        mmms.virtual_grids[0].tick(3, 0);
+        assert_eq!(mmms.virtual_grids[0].shift_octave(3, 0, -1), vec![(3, 0)]);
+        assert_eq!(mmms.virtual_grids[0].grid[3].notes()[..], [octave as u8]);
+        assert!(mmms.virtual_grids[0].shift_octave(4, 0, -1).is_empty());
+    }
+
+    #[test]
+    fn preview() {
+        let (mut mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+        mmms.set_playing(false);
+        // shift and transpose in the transpose view, then a grid key
+        let (down, up) = (KeyDirection::Down, KeyDirection::Up);
+        let keys = [(3, 0, down), (3, 0, up), (15, 0, down), (3, 0, down), (3, 0, up),
+                    (15, 0, up), (3, 0, down), (3, 0, up), (2, 3, down), (2, 3, up)];
+        for &(x, y, direction) in keys.iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        assert!(mmms.preview);
+        assert!(mmms.virtual_grids[0].grid.iter().all(|step| step.is_empty() && !step.is_rest()));
+        let row = mmms.virtual_grids[0].vaddress(2, 2).unwrap().1;
+        let index = mmms.virtual_grids[0].scale.note_count() - 1 - row;
+        let pitch = transposed_pitch(&mmms.virtual_grids[0].scale, index as u8, 0);
+        let mut previews = Vec::new();
+        while let Ok(message) = renderer.receiver.try_recv() {
+            match message {
+                Message::PreviewNote(track, pitch) => previews.push((track, pitch)),
+                Message::Track(..) => panic!("unexpected {:?}", message),
+                _ => renderer.handle_message(message),
+            }
+        }
+        assert_eq!(previews, vec![(0, pitch)]);
+
+        // the note plays for a trigger, while stopped
+        renderer.preview(0, pitch);
+        let mut sink = MockSink::new(16);
+        renderer.render_to(&mut sink);
+        assert!(sink.digital(0, 0));
+        assert_eq!(sink.analog(0, 0), renderer.pitch_cv(pitch));
+        let blocks = (renderer.trigger_duration * CLOCK_RATE as f32) as usize / 16 + 1;
+        for _ in 0..blocks {
+            renderer.render_to(&mut sink);
+        }
+        assert!(!sink.digital(0, 0));
+        assert!(renderer.tracks[0].preview.is_none());
+        assert!(renderer.tracks[0].steps.iter().all(|step| step.is_empty()));
+
+        // while playing, the end of a preview and the trigger of the second step, that starts at
+        // frame 5512.5, are both in the block from frame 5504
+        let (_, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+        renderer.tracks[0].steps[1].toggle(20);
+        for _ in 0..5504 / 16 {
+            renderer.render_to(&mut sink);
+        }
+        let elapsed = (renderer.trigger_duration * CLOCK_RATE as f32) as usize - 8;
+        renderer.tracks[0].preview = Some((pitch, elapsed));
+        renderer.render_to(&mut sink);
+        assert!((0..8).chain(9..16).all(|frame| sink.digital(frame, 0)));
+        assert!(!sink.digital(8, 0));
+    }
+
+    #[test]
+    fn chord_mode() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        // shift and the first track in the transpose view
+        let (down, up) = (KeyDirection::Down, KeyDirection::Up);
+        let keys = [(3, 0, down), (3, 0, up), (15, 0, down), (4, 0, down), (4, 0, up),
+                    (15, 0, up), (3, 0, down), (3, 0, up), (2, 7, down), (2, 7, up)];
+        for &(x, y, direction) in keys.iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        assert_eq!(mmms.chord, Some(ChordType::Triad));
+        // the root, the third and the fifth of the scale, from the lowest row of the view
+        let grid = &mmms.virtual_grids[0];
+        let root = grid.vaddress(2, 6).unwrap().1;
+        let degrees = |root: usize, notes: &[u8]| -> Vec<usize> {
+            let mut degrees: Vec<usize> = notes.iter().map(|note| root - *note as usize).collect();
+            degrees.sort();
+            degrees
+        };
+        assert_eq!(degrees(root, &grid.grid[2].notes()), vec![0, 2, 4]);
+        renderer.receive();
+        let note_count = grid.scale.note_count();
+        let rows: Vec<u8> = renderer.tracks[0].steps[2].notes().iter().map(|index| {
+            (note_count - 1 - *index as usize) as u8
+        }).collect();
+        assert_eq!(degrees(root, &rows), vec![0, 2, 4]);
+
+        // a seventh and a sus, the notes past the highest are left out
+        let mut grid = VirtualGrid::new();
+        let y = grid.vaddress(0, 6).unwrap().1;
+        assert_eq!(grid.tick_chord(0, 6, ChordType::Seventh).len(), 4);
+        assert_eq!(degrees(y, &grid.grid[0].notes()), vec![0, 2, 4, 6]);
+        assert_eq!(grid.tick_chord(1, 6, ChordType::Sus).len(), 3);
+        assert_eq!(degrees(y, &grid.grid[1].notes()), vec![0, 3, 4]);
+        // the root is there, the chord is removed, and the step is a rest
+        assert_eq!(grid.tick_chord(0, 6, ChordType::Triad).len(), 3);
+        assert_eq!(grid.grid[0].notes()[..], [(y - 6) as u8]);
+        grid.tick_chord(1, 6, ChordType::Sus);
+        assert!(grid.grid[1].is_rest());
+        grid.offset_y = 0;
+        assert_eq!(grid.tick_chord(2, 1, ChordType::Triad), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn move_repeat() {
+        let (mut mmms, _renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.virtual_grids[0].offset_y = 0;
+        let key = |mmms: &mut MMMS, direction| {
+            mmms.input(MonomeEvent::GridKey { x: 11, y: 0, direction });
+        };
+
+        // a tap moves once, on release
+        key(&mut mmms, KeyDirection::Down);
+        mmms.main_thread_work();
+        assert_eq!(mmms.virtual_grids[0].offset_y, 0);
+        key(&mut mmms, KeyDirection::Up);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 1);
+
+        // held, the move repeats after a delay, then at a steady rate, and releasing the key
+        // doesn't move again
+        key(&mut mmms, KeyDirection::Down);
+        let since = mmms.held_move.as_ref().unwrap().since;
+        let delay = time::Duration::from_millis(MOVE_REPEAT_DELAY);
+        let interval = time::Duration::from_millis(MOVE_REPEAT_INTERVAL);
+        mmms.repeat_moves(since + delay / 2);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 1);
+        mmms.repeat_moves(since + delay);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 2);
+        mmms.repeat_moves(since + delay + interval / 2);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 2);
+        mmms.repeat_moves(since + delay + interval * 3);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 5);
+        key(&mut mmms, KeyDirection::Up);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 5);
+        assert!(mmms.held_move.is_none());
+        mmms.repeat_moves(since + delay + interval * 10);
+        assert_eq!(mmms.virtual_grids[0].offset_y, 5);
+
+        // the arrows nudging the tempo don't repeat
+        mmms.editing_tempo = true;
+        key(&mut mmms, KeyDirection::Down);
+        assert!(mmms.held_move.is_none());
+    }
+
+    #[test]
+    fn accent_velocity_boost() {
+        let (_mmms, mut renderer) = MmmsBuilder::new()
+            .trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0))
+            .velocity_port(BelaPort::AnalogOut(1))
+            .accent_velocity_boost(40)
+            .autosave(None)
+            .build()
+            .unwrap();
+        // an accented step and one that isn't, both at 80, and a loud accented one
+        for &(x, velocity, accent) in [(0, 80, true), (1, 80, false), (2, 100, true)].iter() {
+            renderer.tracks[0].press(x, 2);
+            renderer.tracks[0].set_velocity(x, velocity);
+            renderer.tracks[0].steps[x].accent = accent;
+        }
+        renderer.set_playing(true);
+        let mut sink = MockSink::new(16);
+        let mut velocities = Vec::new();
+        // the middle of each of the first three sixteenths
+        for block in 0..(5512 * 3 / 16) {
+            renderer.render_to(&mut sink);
+            if block % (5512 / 16) == 5512 / 32 {
+                velocities.push(sink.analog(0, 1) * MAX_VELOCITY as f32);
+            }
+        }
+        assert_eq!(velocities.len(), 3);
+        assert!((velocities[0] - 120.).abs() < 1e-3);
+        assert!((velocities[1] - 80.).abs() < 1e-3);
+        assert!((velocities[2] - 127.).abs() < 1e-3);
+
+        // the MIDI export has the same velocities
+        let velocities: Vec<u8> = renderer.to_midi_events().iter().filter_map(|event| {
+            match event.1 {
+                MidiEvent::NoteOn { velocity, .. } => Some(velocity),
+                MidiEvent::NoteOff { .. } => None,
+            }
+        }).collect();
+        assert_eq!(velocities, vec![120, 80, 127]);
+        assert_eq!(renderer.set_accent_velocity_boost(1, 40), Err(MmmsError::InvalidTrack));
+    }
+
+    #[test]
+    fn snap_to_octave() {
+        let pentatonic = Scale::new(PitchClass::C, ScaleType::MajorPentatonic);
+        for scale in [default_scale(), pentatonic].iter() {
+            let mut grid = VirtualGrid::with_view(16, 7, scale.clone().into());
+            let octave = grid.scale.octave_note_count();
+            let bottom = |grid: &VirtualGrid| grid.height - grid.offset_y - grid.view_height;
+            grid.offset_y = 30;
+            grid.snap_to_octave = true;
+            // the first move goes to the next tonic, then each one is an octave
+            grid.mouve(0, -1);
+            assert!(grid.offset_y < 30 && bottom(&grid) % octave == 0);
+            for &y in [-1, 1, 1].iter() {
+                let offset = grid.offset_y as isize;
+                grid.mouve(0, y);
+                assert_eq!(grid.offset_y as isize - offset, y * octave as isize);
+                assert_eq!(bottom(&grid) % octave, 0);
+            }
+            let offset = grid.offset_y;
+            grid.mouve(0, 2);
+            assert_eq!(grid.offset_y, offset + 2 * octave);
+            // the view stops at the ends of the scale
+            grid.mouve(0, -1000);
+            assert_eq!(grid.offset_y, 0);
+            grid.mouve(0, 1000);
+            assert_eq!(grid.offset_y, grid.max_offset_y());
+            grid.mouve(0, -1);
+            assert_eq!(grid.offset_y, grid.max_offset_y() - octave);
+        }
+
+        // from the arrows, only when it's on
+        let (mut mmms, _renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let arrow = |mmms: &mut MMMS| {
+            let offset = mmms.virtual_grids[0].offset_y;
+            for &direction in [KeyDirection::Down, KeyDirection::Up].iter() {
+                mmms.input(MonomeEvent::GridKey { x: 10, y: 0, direction });
+            }
+            offset - mmms.virtual_grids[0].offset_y
+        };
+        assert_eq!(arrow(&mut mmms), 1);
+        mmms.set_snap_to_octave(true);
+        arrow(&mut mmms);
+        assert_eq!(arrow(&mut mmms), 7);
+    }
+
+    #[test]
+    fn set_and_get_steps() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let scale = renderer.track_scale(0).clone();
+        let lowest = scale.idx_to_cv(0).unwrap();
+        let highest = scale.idx_to_cv(scale.note_count() - 1).unwrap();
+        let last = renderer.steps_count(0) - 1;
+
+        // on the renderer, the first and last steps, the lowest and highest notes
+        for &(index, pitch) in [(0, lowest), (last, highest), (0, highest), (last, lowest)].iter() {
+            assert_eq!(renderer.set_step(0, index, Some(pitch)), Ok(()));
+            assert_eq!(renderer.get_step(0, index), Some(pitch));
+        }
+        // pitches out of the scale go to the closest note
+        renderer.set_step(0, 1, Some(lowest - 1.)).unwrap();
+        assert_eq!(renderer.get_step(0, 1), Some(lowest));
+        renderer.set_step(0, 1, Some(highest + 1.)).unwrap();
+        assert_eq!(renderer.get_step(0, 1), Some(highest));
+        renderer.set_step(0, 1, None).unwrap();
+        assert_eq!(renderer.get_step(0, 1), None);
+        assert_eq!(renderer.set_step(0, last + 1, Some(lowest)), Err(MmmsError::InvalidStep));
+        assert_eq!(renderer.get_step(0, last + 1), None);
+        assert_eq!(renderer.set_step(1, 0, Some(lowest)), Err(MmmsError::InvalidTrack));
+        assert_eq!(renderer.get_step(1, 0), None);
+
+        // on the grid, in rows, and sent to the renderer
+        renderer.set_step(0, last, None).unwrap();
+        let grid = &mmms.virtual_grids[0];
+        assert_eq!(grid.pitch_to_row(lowest), grid.height - 1);
+        assert_eq!(grid.pitch_to_row(highest), 0);
+        assert_eq!(grid.row_to_pitch(0), Some(highest));
+        assert_eq!(grid.row_to_pitch(grid.height), None);
+        let last = mmms.steps_count() - 1;
+        mmms.virtual_grids[0].grid[last].toggle(3);
+        mmms.send_to(0, TrackMessage::Tick((last, 3)));
+        for &(index, pitch) in [(0, lowest), (last, highest)].iter() {
+            assert_eq!(mmms.set_step(0, index, Some(pitch)), Ok(()));
+            assert_eq!(mmms.get_step(0, index), Some(pitch));
+        }
+        assert_eq!(mmms.virtual_grids[0].get_step(last), Some(0));
+        assert_eq!(mmms.virtual_grids[0].grid[last].notes().len(), 1);
+        renderer.receive();
+        assert_eq!(renderer.get_step(0, 0), Some(lowest));
+        assert_eq!(renderer.tracks[0].steps[last].notes().len(), 1);
+        assert_eq!(mmms.set_step(0, last, None), Ok(()));
+        renderer.receive();
+        assert_eq!(renderer.get_step(0, last), None);
+        // a rest is cleared in the renderer too
+        mmms.virtual_grids[0].grid[1].set_rest(true);
+        mmms.send_to(0, TrackMessage::Rest((1, true)));
+        renderer.receive();
+        assert!(renderer.tracks[0].steps[1].is_rest());
+        assert_eq!(mmms.set_step(0, 1, None), Ok(()));
+        renderer.receive();
+        assert!(!mmms.virtual_grids[0].grid[1].is_rest());
+        assert!(!renderer.tracks[0].steps[1].is_rest());
+        mmms.virtual_grids[0].grid[1].set_rest(true);
+        mmms.send_to(0, TrackMessage::Rest((1, true)));
+        assert_eq!(mmms.set_step(0, 1, Some(lowest)), Ok(()));
+        renderer.receive();
+        assert!(!renderer.tracks[0].steps[1].is_rest());
+        assert_eq!(renderer.get_step(0, 1), Some(lowest));
+        let height = mmms.virtual_grids[0].height;
+        assert_eq!(mmms.virtual_grids[0].set_step(0, Some(height)), Err(MmmsError::InvalidStep));
+        assert_eq!(mmms.set_step(0, last + 1, None), Err(MmmsError::InvalidStep));
+        assert_eq!(mmms.get_step(0, last + 1), None);
+        assert_eq!(mmms.set_step(1, 0, None), Err(MmmsError::InvalidTrack));
+    }
+
+    #[test]
+    fn tap_tempo() {
+        let (mut mmms, _renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 90.).unwrap();
+        for &direction in [KeyDirection::Down, KeyDirection::Up].iter() {
+            mmms.input(MonomeEvent::GridKey { x: 1, y: 0, direction });
+        }
+        assert!(mmms.editing_tempo);
+        // in the tempo view, the grid keys tap the tempo instead of adding notes
+        for &direction in [KeyDirection::Down, KeyDirection::Up].iter() {
+            mmms.input(MonomeEvent::GridKey { x: 3, y: 4, direction });
+        }
+        assert_eq!(mmms.taps.len(), 1);
+        assert!(mmms.virtual_grids[0].grid.iter().all(|step| step.is_empty()));
+        assert_eq!(mmms.tempo, 90.);
+
+        // four taps half a second apart
+        let start = time::Instant::now();
+        mmms.taps.clear();
+        for i in 0..4 {
+            mmms.tap_tempo(start + time::Duration::from_millis(500 * i));
+        }
+        assert!((mmms.tempo - 120.).abs() < 0.01);
+        // a tap long after starts over
+        let late = start + time::Duration::from_millis(1500 + TAP_TEMPO_TIMEOUT + 1);
+        mmms.tap_tempo(late);
+        assert!((mmms.tempo - 120.).abs() < 0.01);
+        assert_eq!(mmms.taps.len(), 1);
+        mmms.tap_tempo(late + time::Duration::from_millis(400));
+        assert!((mmms.tempo - 150.).abs() < 0.01);
+        // only the last intervals count
+        for i in 2..10 {
+            mmms.tap_tempo(late + time::Duration::from_millis(400 + 250 * i));
+        }
+        assert_eq!(mmms.taps.len(), TAP_TEMPO_INTERVALS + 1);
+        assert!((mmms.tempo - 240.).abs() < 0.01);
+    }
+
+    #[test]
+    fn note_priority() {
+        let build = |priority: Option<MonoNotePriority>| {
+            let builder = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+                .pitch_port(BelaPort::AnalogOut(0)).autosave(None);
+            let builder = match priority {
+                Some(priority) => builder.note_priority(priority),
+                None => builder,
+            };
+            builder.build().unwrap()
+        };
+        let pitch = |renderer: &mut MMMSRenderer| {
+            // three notes, the middle one added last
+            for &note in [10, 30, 20].iter() {
+                renderer.tracks[0].steps[0].toggle(note);
+            }
+            renderer.set_playing(true);
+            let mut sink = MockSink::new(16);
+            renderer.render_to(&mut sink);
+            sink.analog(0, 0)
+        };
+        let note_pitch = |renderer: &MMMSRenderer, note: u8| {
+            renderer.pitch_cv(transposed_pitch(&renderer.tracks[0].scale, note, 0))
+        };
+
+        let (_, mut renderer) = build(None);
+        assert_eq!(renderer.note_priority, MonoNotePriority::Lowest);
+        assert_eq!(pitch(&mut renderer), note_pitch(&renderer, 10));
+        let (_, mut renderer) = build(Some(MonoNotePriority::Highest));
+        assert_eq!(pitch(&mut renderer), note_pitch(&renderer, 30));
+        let (mut mmms, mut renderer) = build(None);
+        mmms.set_note_priority(MonoNotePriority::Last);
+        renderer.receive();
+        assert_eq!(pitch(&mut renderer), note_pitch(&renderer, 20));
+    }
+
+    #[test]
+    fn transpose_cv() {
+        let (mut mmms, mut renderer) = MmmsBuilder::new()
+            .trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0))
+            .transpose_cv_port(BelaPort::AnalogIn(2))
+            .autosave(None)
+            .build()
+            .unwrap();
+        assert_eq!(renderer.set_transpose_cv_port(BelaPort::AnalogOut(2)),
+                   Err(MmmsError::InvalidTransposePort));
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.set_playing(true);
+        let mut sink = MockSink::new(16);
+        let mut play = |renderer: &mut MMMSRenderer, volts: f32| {
+            for i in 0..sink.analog_frames() {
+                sink.analog_in[i * sink.analog_in_channels + 2] = volts / ANALOG_IN_MAX_VOLTS;
+            }
+            renderer.render_to(&mut sink);
+            (renderer.cv_transpose, sink.analog(0, 0))
+        };
+        let octave = renderer.tracks[0].scale.octave_note_count() as i32;
+        assert_eq!(play(&mut renderer, 0.).0, 0);
+        // a volt is an octave
+        let (transpose, cv) = play(&mut renderer, 1.);
+        assert_eq!(transpose, octave);
+        let pitch = transposed_pitch(&renderer.tracks[0].scale, 20, octave);
+        assert_eq!(cv, renderer.pitch_cv(pitch));
+        // a little wiggle doesn't change the transposition, between two degrees it stays on the
+        // last one until the CV is clearly closer to the next one
+        assert_eq!(play(&mut renderer, 1.01).0, octave);
+        assert_eq!(play(&mut renderer, 0.99).0, octave);
+        let degree = |degree: usize| {
+            let scale = &renderer.tracks[0].scale;
+            scale.idx_to_cv(degree).unwrap() - scale.idx_to_cv(0).unwrap()
+        };
+        let (low, high) = (degree(octave as usize), degree(octave as usize + 1));
+        let middle = (low + high) / 2.;
+        assert_eq!(play(&mut renderer, middle + 0.001).0, octave);
+        assert_eq!(play(&mut renderer, high - 0.001).0, octave + 1);
+        assert_eq!(play(&mut renderer, middle - 0.001).0, octave + 1);
+        assert_eq!(play(&mut renderer, low).0, octave);
+
+        // it adds to the transposition of the grid
+        mmms.set_transpose(2);
+        renderer.receive();
+        let cv = play(&mut renderer, 1.).1;
+        let pitch = transposed_pitch(&renderer.tracks[0].scale, 20, octave + 2);
+        assert_eq!(cv, renderer.pitch_cv(pitch));
+    }
+
+    #[test]
+    fn offscreen_playhead() {
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        // the playhead is on the first step, the view on the second bar, and an octave that isn't
+        // shown on the left key
+        mmms.virtual_grids[0].mouve(16, 0);
+        mmms.virtual_grids[0].mouve(0, 2);
+        let mut leds = [0 as u8; 128];
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[8], 0);
+        mmms.set_offscreen_playhead(true);
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[8], 15);
+        assert_eq!(leds[9], 0);
+        // nothing when the playhead is in view
+        mmms.virtual_grids[0].mouve(-16, 0);
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[8], 0);
+        mmms.playing = false;
+        mmms.virtual_grids[0].mouve(16, 0);
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[8], 0);
+    }
+
+    #[test]
+    fn trigger_delay() {
+        let build = |port: BelaPort, feedback: f32| {
+            MmmsBuilder::new()
+                .trigger_port(port)
+                .pitch_port(BelaPort::AnalogOut(0))
+                .trigger_delay(TriggerDelay { delay_time: 3., feedback, pitch: true })
+                .autosave(None)
+                .build()
+                .unwrap()
+        };
+        // the frames at which the trigger goes up during the first bar, one sixteenth at 120 BPM
+        // being 5512.5 frames, and the pitch CV at the end of each block
+        let play = |renderer: &mut MMMSRenderer| {
+            renderer.set_playing(true);
+            let mut sink = MockSink::new(16);
+            let mut edges = vec![];
+            let mut pitches = vec![];
+            let mut high = false;
+            for block in 0..22050 * 2 / 16 {
+                renderer.render_to(&mut sink);
+                for frame in 0..16 {
+                    if sink.digital(frame, 0) && !high {
+                        edges.push(block * 16 + frame);
                     }
+                    high = sink.digital(frame, 0);
                 }
-            } else {
-                match x {
-                    8 => {
-                        return MMMSAction::Resize(1)
-                    }
-                    9 => {
-                        return MMMSAction::Resize(2)
-                    }
-                    10 => {
-                        return MMMSAction::Resize(4)
-                    }
-                    11 => {
-                        return MMMSAction::Resize(8)
+                pitches.push(sink.analog(7, 0));
+            }
+            (edges, pitches)
+        };
+
+        let (_, mut renderer) = build(BelaPort::Digital(0), 0.3);
+        renderer.tracks[0].steps[0].toggle(20);
+        let (edges, _) = play(&mut renderer);
+        // a dotted eighth later
+        assert_eq!(edges, vec![0, 16538]);
+        // the repeat of the first step plays its pitch again, after the second step
+        let (_, mut renderer) = build(BelaPort::Digital(0), 0.3);
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.tracks[0].steps[1].toggle(30);
+        let (_, pitches) = play(&mut renderer);
+        assert_eq!(pitches[13781 / 16], renderer.note_cv(0, 1));
+        assert_eq!(pitches[19293 / 16], renderer.note_cv(0, 0));
+
+        // the repeats fade out on an analog output
+        let (_, mut renderer) = build(BelaPort::AnalogOut(1), 0.5);
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.set_playing(true);
+        let mut sink = MockSink::new(16);
+        let mut levels = vec![];
+        for _ in 0..22050 * 4 / 16 {
+            renderer.render_to(&mut sink);
+            let level = sink.analog(0, 1);
+            if level > 0. && levels.last().map_or(true, |last| *last != level) {
+                levels.push(level);
+            }
+        }
+        assert_eq!(levels, vec![1., 0.5, 0.25, 0.125]);
+
+        // without a delay a trigger plays once
+        let (_, mut renderer) = build(BelaPort::Digital(0), 0.3);
+        renderer.set_trigger_delay(0, None).unwrap();
+        renderer.tracks[0].steps[0].toggle(20);
+        assert_eq!(play(&mut renderer).0, vec![0]);
+        assert_eq!(renderer.set_trigger_delay(1, None), Err(MmmsError::InvalidTrack));
+    }
+
+    #[test]
+    fn scroll_covers_scale() {
+        let scales: Vec<TrackScale> = vec![
+            TrackScale::Standard(default_scale()),
+            Scale::new(PitchClass::C, ScaleType::Chromatic).into(),
+            Scale::new(PitchClass::E, ScaleType::MajorPentatonic).into(),
+            CustomScale::with_period(PitchClass::C, &[0., 100.], 9600.).unwrap().into(),
+        ];
+        // the note indices in view, 0 being the lowest note
+        let visible = |grid: &VirtualGrid| -> Vec<usize> {
+            (0..grid.view_height).filter_map(|vy| grid.vaddress(0, vy))
+                .map(|(_, row)| grid.scale.note_count() - 1 - row).collect()
+        };
+        for scale in scales.iter() {
+            for &view_height in [7, 15].iter() {
+                for &snap in [false, true].iter() {
+                    let mut grid = VirtualGrid::with_view(16, view_height, scale.clone());
+                    grid.snap_to_octave = snap;
+                    let highest = scale.note_count() - 1;
+                    // one row or one octave at a time, as with the arrow keys
+                    for _ in 0..scale.note_count() {
+                        grid.mouve(0, -1);
                     }
-                    _ => {
-                        return MMMSAction::Nothing
+                    assert!(visible(&grid).contains(&highest));
+                    for _ in 0..scale.note_count() {
+                        grid.mouve(0, 1);
                     }
+                    assert!(visible(&grid).contains(&0));
+                    // and all the way there at once
+                    grid.mouve(0, -1000);
+                    assert!(visible(&grid).contains(&highest));
+                    grid.mouve(0, 1000);
+                    assert!(visible(&grid).contains(&0));
                 }
             }
-        } else {
-            let but = self.buttons[Self::idx(self.width, x, y)].clone();
-            self.buttons[Self::idx(self.width, x, y)] = MMMSIntent::Nothing;
-            match but {
-                MMMSIntent::Nothing => {
-                    // !? pressed a key during startup
-                    MMMSAction::Nothing
-                }
-                MMMSIntent::Tick => {
-                    if self.shift_down() && x == 0 && y == 7 {
-                        return MMMSAction::Clear;
-                    }
-                    MMMSAction::Tick((x, y - 1))
+        }
+    }
+
+    #[test]
+    fn follow_playhead() {
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let mut leds = [0 as u8; 128];
+        // the playhead is on the first step, the view on the second bar
+        mmms.virtual_grids[0].mouve(16, 0);
+        mmms.render_leds(&mut leds);
+        assert_eq!(mmms.virtual_grids[0].offset_x, 16);
+        mmms.set_follow_playhead(true);
+        mmms.render_leds(&mut leds);
+        assert_eq!(mmms.virtual_grids[0].offset_x, 0);
+        // steps in view don't move it
+        for x in 0..16 {
+            mmms.virtual_grids[0].follow(x);
+            assert_eq!(mmms.virtual_grids[0].offset_x, 0);
+        }
+        // past the view, it jumps to the page of the playhead, the last one ending the pattern
+        mmms.virtual_grids[0].follow(20);
+        assert_eq!(mmms.virtual_grids[0].offset_x, 16);
+        mmms.set_steps_count(40);
+        mmms.virtual_grids[0].follow(35);
+        assert_eq!(mmms.virtual_grids[0].offset_x, 24);
+        // not while stopped
+        mmms.playing = false;
+        mmms.render_leds(&mut leds);
+        assert_eq!(mmms.virtual_grids[0].offset_x, 24);
+    }
+
+    #[test]
+    fn project() {
+        let path = std::env::temp_dir().join("mmms-project-test.json");
+        let (mut mmms, _renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_steps_count(16);
+        mmms.virtual_grids[0].tick(0, 3);
+        mmms.chain(0);
+        mmms.set_steps_count(32);
+        mmms.change_scale(Scale::new(PitchClass::D, ScaleType::Major).into());
+        mmms.virtual_grids[0].tick(1, 3);
+        mmms.chain(1);
+        mmms.chain(0);
+        mmms.name_pattern(1, "chorus");
+        mmms.set_tempo(100.);
+        let palette = Palette { playhead: 9, ..Palette::default() };
+        mmms.set_palette(palette);
+        mmms.save_project(&path).unwrap();
+
+        let (mut loaded, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        loaded.load_project(&path).unwrap();
+        assert_eq!(loaded.song, vec![0, 1, 0]);
+        assert_eq!(loaded.bank_names[1], "chorus");
+        assert_eq!(loaded.bank_names[0], "");
+        assert_eq!(loaded.tempo, 100.);
+        assert_eq!(loaded.palette, palette);
+        let json = |pattern: &Option<Pattern>| {
+            pattern.as_ref().map(|pattern| serde_json::to_string(pattern).unwrap())
+        };
+        for slot in 0..BANK_SIZE {
+            assert_eq!(json(&loaded.bank[slot]), json(&mmms.bank[slot]));
+        }
+        assert!(loaded.bank[1].is_some() && loaded.bank[2].is_none());
+        assert_eq!(loaded.song_position, None);
+
+        // saved in song mode, the song plays when loaded
+        mmms.set_song_mode(true);
+        mmms.save_project(&path).unwrap();
+        loaded.load_project(&path).unwrap();
+        assert_eq!(loaded.song_position, Some((0, 0)));
+        assert_eq!(loaded.virtual_grids[0].steps_count(), 16);
+
+        // unknown fields are ignored and missing ones take their default value, a slot that
+        // doesn't exist is left out
+        std::fs::write(&path, r#"{
+            "version": 1,
+            "tempo": 90,
+            "swing": 0.3,
+            "bank": [{ "slot": 2, "name": "intro", "colour": "red" }, { "slot": 12 }],
+            "song": [2, 12]
+        }"#).unwrap();
+        loaded.load_project(&path).unwrap();
+        assert_eq!(loaded.tempo, 90.);
+        assert_eq!(loaded.palette, Palette::default());
+        assert_eq!(loaded.song, vec![2]);
+        assert_eq!(loaded.bank_names[2], "intro");
+        assert!(loaded.bank[2].is_some() && loaded.bank[0].is_none());
+        assert_eq!(loaded.song_position, None);
+
+        // a project or a pattern of a newer version isn't loaded
+        let newer = [r#"{ "version": 2, "tempo": 100 }"#,
+                     r#"{ "tempo": 100, "bank": [{ "slot": 1, "pattern": { "version": 2 } }] }"#];
+        for json in newer.iter() {
+            std::fs::write(&path, json).unwrap();
+            let error = loaded.load_project(&path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(loaded.tempo, 90.);
+        }
+        let steps = loaded.virtual_grids[0].steps_count();
+        std::fs::write(&path, r#"{ "version": 2, "width": 4 }"#).unwrap();
+        let error = loaded.load_from_path(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(loaded.virtual_grids[0].steps_count(), steps);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn indicator_keys() {
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let mut leds = [0 as u8; 128];
+        // the highest octave stops before the scale key
+        mmms.virtual_grids[0].mouve(0, -1000);
+        assert!(mmms.virtual_grids[0].current_octave() >= INDICATOR_KEYS);
+        mmms.render_leds(&mut leds);
+        assert_eq!(&leds[8..16], &[0, 0, 0, 0, 0, 15, 0, 0]);
+        // so do the bars of the longest pattern
+        mmms.set_steps_count(MAX_STEPS);
+        mmms.state_tracker.down(15, 0);
+        mmms.render_leds(&mut leds);
+        assert_eq!(&leds[8..16], &[15, 15, 15, 15, 15, 15, 0, 0]);
+    }
+
+    #[test]
+    fn autosave() {
+        let path = std::env::temp_dir().join("mmms-autosave-test.json");
+        let _ = std::fs::remove_file(&path);
+        // a file that isn't a pattern isn't loaded
+        std::fs::write(&path, "not a pattern").unwrap();
+        let build = |path: &Path| {
+            MmmsBuilder::new()
+                .output(Output::Midi(Box::new(|_| {})))
+                .output(Output::Midi(Box::new(|_| {})))
+                .autosave(Some(path.to_path_buf()))
+                .build()
+                .unwrap()
+        };
+        let (mut mmms, _renderer) = build(&path);
+        assert!(mmms.virtual_grids[0].grid.iter().all(|step| step.is_empty()));
+        assert!(mmms.dirty.get().is_none());
+
+        // the tempo and how a track plays aren't part of the pattern
+        mmms.set_tempo(140.);
+        mmms.set_glide(0, 0.1);
+        assert!(mmms.dirty.get().is_none());
+
+        // an edit is saved a little while after it's made
+        for &direction in [KeyDirection::Down, KeyDirection::Up].iter() {
+            mmms.input(MonomeEvent::GridKey { x: 2, y: 3, direction });
+        }
+        mmms.select_track(1);
+        for &direction in [KeyDirection::Down, KeyDirection::Up].iter() {
+            mmms.input(MonomeEvent::GridKey { x: 5, y: 4, direction });
+        }
+        assert!(mmms.dirty.get().is_some());
+        std::fs::remove_file(&path).unwrap();
+        mmms.main_thread_work();
+        assert!(!path.exists());
+        let delay = time::Duration::from_millis(AUTOSAVE_DELAY);
+        mmms.dirty.set(Some(time::Instant::now() - delay));
+        mmms.main_thread_work();
+        assert!(path.exists());
+        assert!(mmms.dirty.get().is_none());
+        assert!(mmms.autosave_error().is_none());
+
+        // all the tracks are loaded back
+        let (saved, _renderer) = build(&path);
+        for (track, x) in [(0, 2), (1, 5)].iter() {
+            let grid = &saved.virtual_grids[*track];
+            assert_eq!(grid.grid[*x].notes(), mmms.virtual_grids[*track].grid[*x].notes());
+            assert_eq!(grid.grid.iter().filter(|step| !step.is_empty()).count(), 1);
+        }
+        let _ = std::fs::remove_file(&path);
+
+        // a pattern that can't be saved is reported
+        mmms.set_autosave_path(Some(std::env::temp_dir().join("mmms-missing").join("a.json")));
+        mmms.dirty.set(Some(time::Instant::now() - delay));
+        mmms.main_thread_work();
+        assert!(mmms.autosave_error().is_some());
+    }
+
+    #[test]
+    fn snapshots() {
+        // shift and scale held to store, scale held to recall
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(15, 0);
+        tracker.down(14, 0);
+        tracker.down(11, 0);
+        match tracker.up(11, 0) {
+            MMMSAction::StoreSnapshot(1) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.up(15, 0);
+        tracker.down(10, 0);
+        match tracker.up(10, 0) {
+            MMMSAction::RecallSnapshot(0) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.virtual_grids[0].tick(2, 3);
+        mmms.virtual_grids[0].nudge(2, 1);
+        mmms.store_snapshot(0);
+        // recalling an empty slot does nothing
+        mmms.recall_snapshot(1);
+        mmms.virtual_grids[0].tick(2, 3);
+        mmms.virtual_grids[0].tick(5, 1);
+        mmms.send_pattern(0);
+        renderer.receive();
+        mmms.recall_snapshot(0);
+        let lit: Vec<usize> = (0..32).filter(|x| !mmms.virtual_grids[0].grid[*x].is_empty()).collect();
+        assert_eq!(lit, [2]);
+        assert_eq!(mmms.virtual_grids[0].grid[2].nudge, 1);
+        // when playing, the renderer gets the whole pattern, and plays it from the next loop
+        renderer.set_playing(true);
+        renderer.receive();
+        let length = renderer.tracks[0].steps.len() as f32;
+        renderer.tracks[0].loop_wrap_at(length - 0.1, true);
+        assert!(!renderer.tracks[0].steps[5].is_empty());
+        // an edit made in the meantime waits too
+        mmms.send_track(TrackMessage::Tick((7, 1)));
+        renderer.receive();
+        assert!(renderer.tracks[0].steps[7].is_empty());
+        renderer.tracks[0].loop_wrap_at(length + 0.1, true);
+        let lit: Vec<usize> = (0..32).filter(|x| !renderer.tracks[0].steps[*x].is_empty()).collect();
+        assert_eq!(lit, [2, 7]);
+        assert_eq!(renderer.tracks[0].steps[2].nudge, 1);
+    }
+
+    #[test]
+    fn quantize() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        // immediately by default
+        renderer.handle_message(Message::Quantized(0, TrackMessage::Resize(48)));
+        assert_eq!(renderer.tracks[0].steps.len(), 48);
+
+        mmms.set_quantize(true);
+        renderer.handle_message(renderer.receiver.try_recv().unwrap());
+        renderer.quantize_at(2.5);
+        renderer.handle_message(Message::Quantized(0, TrackMessage::Resize(16)));
+        // an edit made after waits too, and is applied after the resize
+        renderer.handle_message(Message::Track(0, TrackMessage::Tick((2, 3))));
+        renderer.quantize_at(3.99);
+        assert_eq!(renderer.tracks[0].steps.len(), 48);
+        assert!(renderer.tracks[0].steps[2].is_empty());
+        // the second bar starts at the fourth beat
+        renderer.quantize_at(4.0);
+        assert_eq!(renderer.tracks[0].steps.len(), 16);
+        assert!(!renderer.tracks[0].steps[2].is_empty());
+        assert!(renderer.quantized.is_empty());
+    }
+
+    #[test]
+    fn midi_clock() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let sent = messages.clone();
+        let (mut mmms, mut renderer) = MmmsBuilder::new()
+            .output(Output::Midi(Box::new(|_| {})))
+            .midi_clock(Box::new(move |message| sent.lock().unwrap().extend_from_slice(message)))
+            .autosave(None)
+            .build()
+            .unwrap();
+        // a beat in blocks of 16 frames
+        let block = 16. / beats_to_frames(1., 120.) as f32;
+        let mut beat = 0.;
+        while beat < 1. {
+            renderer.midi_clock.as_mut().unwrap().process(beat, true);
+            beat += block;
+        }
+        mmms.main_thread_work();
+        {
+            let messages = messages.lock().unwrap();
+            assert_eq!(messages[0], 0xfa);
+            assert_eq!(messages.iter().filter(|m| **m == 0xf8).count(), MIDI_CLOCK_PPQN);
+        }
+
+        // stop, and continue from where it stopped
+        messages.lock().unwrap().clear();
+        renderer.handle_message(Message::Stop);
+        renderer.midi_clock.as_mut().unwrap().process(1., false);
+        renderer.midi_clock.as_mut().unwrap().set_playing(true, 1.);
+        renderer.midi_clock.as_mut().unwrap().process(1., true);
+        mmms.main_thread_work();
+        assert_eq!(*messages.lock().unwrap(), vec![0xfc, 0xfb, 0xf8]);
+    }
+
+    #[cfg(feature = "osc")]
+    #[test]
+    fn osc() {
+        let tempo = [
+            b'/', b'm', b'm', b'm', b's', b'/', b't', b'e', b'm', b'p', b'o', 0, b',', b'f', 0, 0,
+            0x43, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(parse_osc(&tempo), Some(OscCommand::Tempo(128.)));
+        assert_eq!(parse_osc(&tempo[..18]), None);
+        let mut nan = tempo;
+        nan[16..].copy_from_slice(&f32::NAN.to_bits().to_be_bytes());
+        assert_eq!(parse_osc(&nan), None);
+        let start = [b'/', b'm', b'm', b'm', b's', b'/', b's', b't', b'a', b'r', b't', 0, b',', 0, 0, 0];
+        assert_eq!(parse_osc(&start), Some(OscCommand::Start));
+        assert_eq!(parse_osc(&start[..12]), Some(OscCommand::Start));
+        let scale = [
+            b'/', b'm', b'm', b'm', b's', b'/', b's', b'c', b'a', b'l', b'e', 0, b',', b'i', b'i', 0,
+            0, 0, 0, 2, 0, 0, 0, 1,
+        ];
+        assert_eq!(parse_osc(&scale), Some(OscCommand::Scale(2, 1)));
+        assert_eq!(parse_osc(b"/mmms/nope\0\0,\0\0\0"), None);
+
+        let (mut mmms, renderer) = MmmsBuilder::new()
+            .output(Output::Midi(Box::new(|_| {})))
+            .autosave(None)
+            .osc_port(None)
+            .build()
+            .unwrap();
+        mmms.handle_osc(parse_osc(&tempo).unwrap());
+        match renderer.receiver.try_recv() {
+            Ok(Message::TempoChange(tempo, _)) => assert_eq!(tempo, 128.),
+            message => panic!("unexpected {:?}", message),
+        }
+        // clamped
+        mmms.handle_osc(OscCommand::Tempo(1000.));
+        match renderer.receiver.try_recv() {
+            Ok(Message::TempoChange(tempo, _)) => assert_eq!(tempo, MAX_TEMPO),
+            message => panic!("unexpected {:?}", message),
+        }
+
+        // on this machine only, and not on a port that is taken
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let listen = || {
+            MmmsBuilder::new().output(Output::Midi(Box::new(|_| {}))).osc_port(Some(port)).build()
+        };
+        match listen() {
+            Err(MmmsError::OscUnavailable(io::ErrorKind::AddrInUse)) => {}
+            result => panic!("unexpected {:?}", result.map(|_| ())),
+        }
+        drop(socket);
+        let (mmms, _renderer) = listen().unwrap();
+        assert!(mmms.osc.is_some());
+        assert!(UdpSocket::bind(("127.0.0.1", port)).is_err());
+    }
+
+    #[test]
+    fn getters() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        assert_eq!(mmms.tempo(), 120.);
+        assert_eq!(mmms.track(), 0);
+        assert_eq!(mmms.steps_count(), INITIAL_STEPS);
+        assert!(mmms.scale().scale_type() == ScaleType::Minor);
+        assert!(mmms.track_scale().scale_type() == Some(ScaleType::Minor));
+        assert!(mmms.steps().all(|(_, pitch)| pitch.is_none()));
+        assert!(mmms.steps_cv().all(|(_, pitch)| pitch.is_none()));
+
+        mmms.virtual_grids[0].tick(2, 3);
+        mmms.virtual_grids[0].tick(2, 5);
+        let (_, row) = mmms.virtual_grids[0].vaddress(2, 5).unwrap();
+        let index = mmms.scale().note_count() - 1 - row;
+        renderer.tracks[0].press(2, row);
+        let expected = transposed_pitch(mmms.track_scale(), index as u8, 0);
+        let lit: Vec<(usize, Option<f32>)> =
+            mmms.steps_cv().filter(|step| step.1.is_some()).collect();
+        assert_eq!(lit, vec![(2, Some(expected))]);
+        let lit: Vec<(usize, Option<f32>)> = mmms.steps().filter(|step| step.1.is_some())
+            .map(|(x, pitch)| (x, pitch.map(|pitch| pitch.to_cv()))).collect();
+        assert_eq!(lit, vec![(2, Some(expected))]);
+
+        assert_eq!(renderer.tempo(), 120.);
+        assert_eq!(renderer.steps_count(0), INITIAL_STEPS);
+        assert!(renderer.scale(0).scale_type() == ScaleType::Minor);
+        assert!(renderer.track_scale(0).scale_type() == Some(ScaleType::Minor));
+        let pitches: Vec<Option<f32>> = renderer.steps_cv(0).map(|(_, pitch)| pitch).collect();
+        assert_eq!(pitches[2], Some(expected));
+        let pitches: Vec<Option<f32>> =
+            renderer.steps(0).map(|(_, pitch)| pitch.map(|pitch| pitch.to_cv())).collect();
+        assert_eq!(pitches[2], Some(expected));
+        assert!(pitches.iter().enumerate().all(|(x, pitch)| x == 2 || pitch.is_none()));
+    }
+
+    #[test]
+    fn playhead() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_steps_count(12);
+        // a sixteenth is 5512.5 frames at 120 BPM
+        let mut sink = MockSink::new(64);
+        for _ in 0..3000 {
+            renderer.render_to(&mut sink);
+            let sixteenth = renderer.clock_consumer.beat() * 4.;
+            assert_eq!(renderer.playhead(0), sixteenth as usize % 12);
+            assert_eq!(mmms.playhead(0), renderer.playhead(0));
+        }
+        assert!(renderer.clock_consumer.beat() * 4. > 24.);
+    }
+
+    #[test]
+    fn frame_sixteenth() {
+        // a frame of the analog outputs is 1/2756th of a sixteenth at 120 BPM, and after ten hours
+        // the clock is at sixteenth 288000, where the precision of an `f32` is about 1/32th
+        let increment = 1. / 22050. / sixteenth_duration(120.);
+        for &start in [0., 1000.25, 288000.].iter() {
+            for frame in 0..2756 {
+                let exact = start as f64 + frame as f64 * increment as f64;
+                let sixteenth = super::frame_sixteenth(start, frame, increment) as f64;
+                assert!((sixteenth - exact).abs() <= exact.max(1.) * std::f32::EPSILON as f64);
+            }
+            // a whole sixteenth later
+            let next = super::frame_sixteenth(start, 2756, increment);
+            assert!((next - (start + 1.)).abs() < 0.03);
+        }
+    }
+
+    #[test]
+    fn quantize_cv() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let cv = |renderer: &MMMSRenderer, idx: usize| {
+            renderer.track_scale(0).idx_to_cv(idx).unwrap()
+        };
+        let count = renderer.track_scale(0).note_count();
+        for idx in 0..count - 1 {
+            let (low, high) = (cv(&renderer, idx), cv(&renderer, idx + 1));
+            assert_eq!(renderer.quantize_cv(0, low + (high - low) * 0.4), low);
+            assert_eq!(renderer.quantize_cv(0, low + (high - low) * 0.6), high);
+        }
+        // the ends of the scale
+        assert_eq!(renderer.quantize_cv(0, -3.), cv(&renderer, 0));
+        assert_eq!(renderer.quantize_cv(0, 20.), cv(&renderer, count - 1));
+
+        // the semitones of the notes an octave of input snaps to, 0V being a C
+        let semitones = |renderer: &MMMSRenderer| -> Vec<usize> {
+            let mut semitones: Vec<usize> = (0..48).map(|i| {
+                let cv = renderer.quantize_cv(0, 4. + i as f32 / 48.);
+                (cv * 12.).round() as usize % 12
+            }).collect();
+            semitones.dedup();
+            semitones.sort();
+            semitones.dedup();
+            semitones
+        };
+        assert_eq!(semitones(&renderer), vec![1, 2, 4, 6, 7, 9, 11]);
+        mmms.change_scale(TrackScale::Standard(Scale::new(PitchClass::C, ScaleType::Major)));
+        renderer.receive();
+        assert_eq!(semitones(&renderer), vec![0, 2, 4, 5, 7, 9, 11]);
+    }
+
+    #[test]
+    fn ascii_small_grid() {
+        let mut grid = VirtualGrid::with_view(2, 2, default_scale().into());
+        grid.tick(1, 0);
+        let ascii = grid.render_ascii();
+        let lines: Vec<&str> = ascii.lines().collect();
+        assert_eq!(lines.len(), grid.scale.note_count() + 2);
+        assert_eq!(lines[0], "######### begin #######");
+        assert_eq!(lines[lines.len() - 1], "#########  end  #######");
+        let outside = " 0 ".repeat(grid.steps_count() - 2);
+        for (row, line) in lines[1..lines.len() - 1].iter().enumerate() {
+            let cells = line.split('\t').nth(1).unwrap();
+            let expected = if row == grid.offset_y {
+                format!("|0||1|{}", outside)
+            } else if row == grid.offset_y + 1 {
+                format!("|0||0|{}", outside)
+            } else {
+                " 0 ".repeat(grid.steps_count())
+            };
+            assert_eq!(cells, expected);
+        }
+    }
+
+    #[test]
+    fn batched_edits() {
+        let (mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        // a render never sees half of an edit: all the steps have the same note
+        let consistent = |renderer: &MMMSRenderer| {
+            let steps = &renderer.tracks[0].steps;
+            steps[..16].iter().all(|step| step.notes() == steps[0].notes())
+        };
+        let sender = mmms.sender.clone();
+        sender.send(Message::BeginBatch).unwrap();
+        for x in 0..8 {
+            sender.send(Message::Track(0, TrackMessage::Tick((x, 0)))).unwrap();
+        }
+        renderer.receive();
+        assert!(renderer.tracks[0].steps.iter().all(|step| step.notes().is_empty()));
+        for x in 8..16 {
+            sender.send(Message::Track(0, TrackMessage::Tick((x, 0)))).unwrap();
+        }
+        sender.send(Message::EndBatch).unwrap();
+        renderer.receive();
+        assert!(consistent(&renderer));
+        assert!(!renderer.tracks[0].steps[0].notes().is_empty());
+
+        // an edit that doesn't fit is dropped whole
+        sender.send(Message::BeginBatch).unwrap();
+        sender.send(Message::Track(0, TrackMessage::Clear)).unwrap();
+        for _ in 0..BATCH_QUEUE_SIZE {
+            sender.send(Message::Track(0, TrackMessage::Tick((0, 1)))).unwrap();
+        }
+        sender.send(Message::EndBatch).unwrap();
+        renderer.receive();
+        assert!(consistent(&renderer));
+        assert!(!renderer.tracks[0].steps[0].notes().is_empty());
+        assert_eq!(mmms.dropped_batches(), 1);
+        assert!(renderer.batch.is_empty());
+
+        let editor = std::thread::spawn(move || {
+            for i in 0..100 {
+                sender.send(Message::BeginBatch).unwrap();
+                sender.send(Message::Track(0, TrackMessage::Clear)).unwrap();
+                for x in 0..16 {
+                    sender.send(Message::Track(0, TrackMessage::Tick((x, i % 2)))).unwrap();
+                    std::thread::yield_now();
                 }
+                sender.send(Message::EndBatch).unwrap();
             }
+        });
+        loop {
+            let finished = editor.is_finished();
+            renderer.receive();
+            assert!(consistent(&renderer));
+            if finished {
+                break;
+            }
+        }
+        assert!(renderer.receiver.try_recv().is_err());
+        assert!(!renderer.batching);
+        assert!(!renderer.tracks[0].steps[0].notes().is_empty());
+    }
+
+    #[test]
+    fn control_disconnected() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.virtual_grids[0].tick(2, 3);
+        mmms.send_track(TrackMessage::Tick(mmms.virtual_grids[0].vaddress(2, 3).unwrap()));
+        renderer.receive();
+        assert!(renderer.control_connected());
+        drop(mmms);
+        renderer.receive();
+        assert!(!renderer.control_connected());
+        renderer.receive();
+        assert!(!renderer.tracks[0].steps[2].notes().is_empty());
+    }
+
+    #[test]
+    fn palette() {
+        let draw = |palette: Palette| {
+            let (mut mmms, _) = MmmsBuilder::new().output(Output::Midi(Box::new(|_| {})))
+                .autosave(None).palette(palette).build().unwrap();
+            mmms.virtual_grids[0].tick(5, 3);
+            let mut leds = [0 as u8; 128];
+            mmms.render_leds(&mut leds);
+            leds
+        };
+        let default = draw(Palette::default());
+        assert_eq!(default[4 * 16 + 5], 12);
+        let custom = draw(Palette { note: 9, playhead: 1, ..Palette::default() });
+        assert_eq!(custom[4 * 16 + 5], 9);
+        // the playhead is on the first column, over the unlit keys
+        let column = |leds: &[u8; 128]| (1..8).map(|row| leds[row * 16]).collect::<Vec<u8>>();
+        assert!(column(&default).iter().all(|led| *led >= 4));
+        assert!(column(&custom).contains(&1));
+    }
+
+    #[test]
+    fn metronome() {
+        let flashes: Vec<Option<bool>> = (0..800).map(|i| metronome_flash(i as f32 / 100.)).collect();
+        // lit for the first quarter of each beat
+        assert_eq!(flashes.iter().filter(|flash| flash.is_some()).count(), 200);
+        assert!(flashes[..25].iter().all(|flash| *flash == Some(true)));
+        assert_eq!(flashes[25], None);
+        assert!(flashes[100..125].iter().all(|flash| *flash == Some(false)));
+        assert_eq!(flashes[400], Some(true));
+        assert_eq!(flashes.iter().filter(|flash| **flash == Some(true)).count(), 50);
+
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let mut leds = [0 as u8; 128];
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[1], 0);
+        mmms.set_metronome(true);
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[1], 15);
+        mmms.playing = false;
+        mmms.render_leds(&mut leds);
+        assert_eq!(leds[1], 0);
+    }
+
+    #[test]
+    fn any_length() {
+        for &steps in [7, 12, 20].iter() {
+            let mut grid = VirtualGrid::new();
+            grid.change_steps_count(steps);
+            grid.mouve(16, 0);
+            assert_eq!(grid.offset_x, steps.saturating_sub(16));
+            grid.mouve(-16, 0);
+            assert_eq!(grid.offset_x, 0);
+            let last = cmp::min(steps, 16) - 1;
+            grid.tick(last, 0);
+            let mut leds = [0 as u8; 7 * 16];
+            grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+            assert_eq!(leds[last], 12);
+            // nothing is drawn past the end of the pattern
+            assert!((0..7).all(|row| (steps..16).all(|x| leds[row * 16 + x] == 0)));
+            grid.velocity_viewport(&mut leds);
+            assert!((steps..16).all(|x| leds[6 * 16 + x] == 0));
+            grid.probability_viewport(&mut leds);
+            assert!((steps..16).all(|x| leds[6 * 16 + x] == 0));
+            grid.randomize(1., &mut XorShift::new(RANDOMIZE_SEED));
+            assert_eq!(grid.to_pattern().steps.len(), steps);
+            // saved and loaded again, scrolled to the end
+            grid.mouve(16, 0);
+            let mut loaded = VirtualGrid::new();
+            loaded.load_pattern(&grid.to_pattern());
+            assert_eq!(loaded.steps_count(), steps);
+            assert_eq!(loaded.offset_x, grid.offset_x);
+            assert_eq!(loaded.grid[last].notes(), grid.grid[last].notes());
+        }
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_steps_count(20);
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps.len(), 20);
+        mmms.set_steps_count(7);
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps.len(), 7);
+        // a key past the end does nothing
+        mmms.state_tracker.down(10, 3);
+        match mmms.state_tracker.up(10, 3) {
+            MMMSAction::Tick((10, 2)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        assert!(!mmms.virtual_grids[0].x_in_pattern(10));
+        let mut leds = [0 as u8; 128];
+        mmms.render_leds(&mut leds);
+        // a snapshot of a pattern shorter than the view is recalled
+        mmms.set_steps_count(12);
+        mmms.store_snapshot(0);
+        mmms.set_steps_count(20);
+        mmms.recall_snapshot(0);
+        assert_eq!(mmms.steps_count(), 12);
+
+        // scale, shift and an arrow shorten or lengthen the pattern by a step
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(14, 0);
+        tracker.down(15, 0);
+        tracker.down(9, 0);
+        match tracker.up(9, 0) {
+            MMMSAction::Length(1) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.down(8, 0);
+        match tracker.up(8, 0) {
+            MMMSAction::Length(-1) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    fn note_repeat() {
+        let count = |rate: RepeatRate, from: usize| {
+            let mut repeat = NoteRepeat::new();
+            repeat.rate = rate;
+            (from..100).map(|i| repeat.repeats(i as f32 / 100.).len()).sum::<usize>()
+        };
+        // over one beat
+        assert_eq!(count(RepeatRate::Sixteenth, 0), 4);
+        assert_eq!(count(RepeatRate::Eighth, 0), 2);
+        assert_eq!(count(RepeatRate::EighthTriplet, 0), 3);
+        assert_eq!(count(RepeatRate::SixteenthTriplet, 0), 6);
+        // the first repeat waits for the next sixteenth
+        assert_eq!(count(RepeatRate::Sixteenth, 10), 3);
+        let mut repeat = NoteRepeat::new();
+        assert!(repeat.repeats(0.3).is_empty());
+        assert_eq!(repeat.repeats(0.6), vec![0.5]);
+        repeat.stop();
+        assert_eq!(repeat.repeats(0.75), vec![0.75]);
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.state_tracker.down(14, 0);
+        mmms.state_tracker.down(12, 0);
+        assert!(mmms.state_tracker.note_repeat_down());
+        let row = mmms.virtual_grids[0].selected_row();
+        mmms.main_thread_work();
+        assert_eq!(&mmms.virtual_grids[0].grid[0].notes()[..], &[row as u8]);
+        // the note is only added once
+        mmms.main_thread_work();
+        assert_eq!(mmms.virtual_grids[0].grid[0].notes().len(), 1);
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps[0].notes().len(), 1);
+        match mmms.state_tracker.up(12, 0) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        assert!(!mmms.state_tracker.note_repeat_down());
+        match mmms.state_tracker.up(14, 0) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        mmms.main_thread_work();
+        assert!(mmms.note_repeat.next.is_none());
+    }
+
+    #[test]
+    fn song() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_steps_count(16);
+        mmms.virtual_grids[0].tick(0, 3);
+        mmms.chain(0);
+        mmms.set_steps_count(32);
+        mmms.change_scale(Scale::new(PitchClass::D, ScaleType::Major).into());
+        mmms.virtual_grids[0].tick(1, 3);
+        mmms.chain(1);
+        // a slot already stored isn't replaced
+        mmms.chain(0);
+        assert_eq!(mmms.song, vec![0, 1, 0]);
+        mmms.song.pop();
+
+        // stopped, the renderer changes the pattern right away
+        renderer.set_playing(false);
+        mmms.set_song_mode(true);
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps.len(), 16);
+        assert!(mmms.virtual_grids[0].current_scale().scale_type() == Some(ScaleType::Minor));
+        // the next pattern comes during the last step of the loop, once
+        mmms.advance_song_at(14);
+        assert_eq!(mmms.song_position, Some((0, 0)));
+        mmms.advance_song_at(15);
+        assert_eq!(mmms.song_position, Some((0, 1)));
+        mmms.advance_song_at(15);
+        assert_eq!(mmms.song_position, Some((0, 1)));
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps.len(), 32);
+        assert!(mmms.virtual_grids[0].current_scale().scale_type() == Some(ScaleType::Major));
+        assert!(!mmms.virtual_grids[0].grid[1].notes().is_empty());
+        mmms.advance_song_at(31);
+        assert_eq!(mmms.song_position, Some((0, 0)));
+        assert_eq!(mmms.virtual_grids[0].steps_count(), 16);
+
+        mmms.set_song_mode(false);
+        mmms.advance_song_at(15);
+        assert_eq!(mmms.song_position, None);
+
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(14, 0);
+        tracker.down(15, 0);
+        for &(x, expected) in [(3, "Chain(3)"), (12, "ClearSong"), (13, "ToggleSong")].iter() {
+            tracker.down(x, 0);
+            assert_eq!(format!("{:?}", tracker.up(x, 0)), expected);
         }
     }
-    fn idx(width: usize, x: usize, y: usize) -> usize {
-        y * width + x
+
+    #[test]
+    fn song_loops() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_steps_count(16);
+        mmms.virtual_grids[0].tick(0, 3);
+        mmms.chain(0);
+        mmms.set_steps_count(32);
+        mmms.virtual_grids[0].tick(1, 3);
+        mmms.chain(1);
+        renderer.set_playing(false);
+        mmms.set_song_mode(true);
+        renderer.receive();
+        renderer.set_playing(true);
+
+        // the renderer at `sixteenth`, and the control side just before
+        let mut frames = 0;
+        let mut play_to = |mmms: &mut MMMS, renderer: &mut MMMSRenderer, sixteenth: f32| {
+            let to = beats_to_frames(sixteenth / 4., 120.);
+            renderer.clock_updater.increment(to - frames);
+            frames = to;
+            mmms.main_thread_work();
+            renderer.render_to(&mut MockSink::new(16));
+            frames += 16;
+        };
+        // the 32 steps pattern is sent during the last step of the first one, and plays from its
+        // first step when the renderer ends the loop
+        play_to(&mut mmms, &mut renderer, 15.5);
+        assert_eq!(renderer.tracks[0].steps.len(), 16);
+        assert_eq!(renderer.playhead(0), 15);
+        play_to(&mut mmms, &mut renderer, 16.5);
+        assert_eq!(renderer.tracks[0].steps.len(), 32);
+        assert!(!renderer.tracks[0].steps[1].is_empty());
+        assert_eq!((renderer.playhead(0), mmms.playhead(0)), (0, 0));
+        // all of it is played before the first pattern comes back
+        play_to(&mut mmms, &mut renderer, 32.5);
+        assert_eq!((renderer.playhead(0), mmms.playhead(0)), (16, 16));
+        assert_eq!(mmms.song_position, Some((0, 1)));
+        play_to(&mut mmms, &mut renderer, 47.5);
+        assert_eq!(renderer.tracks[0].steps.len(), 32);
+        play_to(&mut mmms, &mut renderer, 48.5);
+        assert_eq!(renderer.tracks[0].steps.len(), 16);
+        assert!(!renderer.tracks[0].steps[0].is_empty());
+        assert_eq!((renderer.playhead(0), mmms.playhead(0)), (0, 0));
     }
 
-}
+    #[test]
+    fn mute_row() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let (receiver, _) = mmms.midi.remove(0);
+        let count = renderer.tracks[0].scale.note_count();
+        let note = |index: usize| pitch_to_midi(renderer.tracks[0].scale.idx_to_cv(index).unwrap());
+        let played = note(20);
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.tracks[0].steps[0].toggle(22);
+        renderer.tracks[0].steps[1].toggle(22);
+        renderer.tracks[0].steps[2].toggle(21);
+        renderer.handle_message(Message::MuteRow(0, count - 1 - 22, true));
 
-impl InstrumentControl for MMMS {
-    fn render(&mut self, grid: &mut [u8; 128]) {
-        let now = self.audio_clock.beat();
-        let sixteenth = now * 4.;
-        let pos_in_pattern = (sixteenth as usize) % self.virtual_grid.steps_count();
+        // the step with only muted notes doesn't fire, the others still do
+        assert!(renderer.fires(0, 0));
+        assert!(!renderer.fires(0, 1));
+        assert!(renderer.fires(0, 2));
+        renderer.render_midi(0, 16, 1. / 44100., 0.);
+        assert_eq!(receiver.try_recv().unwrap(), [0x90, played, MAX_VELOCITY]);
+        assert!(receiver.try_recv().is_err());
 
-        grid.iter_mut().map(|x| *x = 0).count();
+        renderer.handle_message(Message::MuteRow(0, count - 1 - 22, false));
+        assert!(renderer.fires(0, 1));
 
-        if !self.picking_scale {
-            self.virtual_grid.viewport(&mut grid[16..]);
+        // a key of the row with the direction key held mutes it, the row is dimmed
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(13, 0);
+        tracker.down(5, 3);
+        match tracker.up(5, 3) {
+            MMMSAction::MuteRow(2) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        match tracker.up(13, 0) {
+            MMMSAction::Nothing => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        // shift and the first column still open the options of the step
+        tracker.down(15, 0);
+        tracker.down(0, 3);
+        match tracker.up(0, 3) {
+            MMMSAction::StepOption((0, 2)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.up(15, 0);
+        tracker.down(13, 0);
+        match tracker.up(13, 0) {
+            MMMSAction::CycleDirection => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        let mut grid = VirtualGrid::new();
+        grid.tick(4, 2);
+        let (_, row) = grid.vaddress(4, 2).unwrap();
+        assert!(grid.toggle_mute(row));
+        let mut leds = [0 as u8; 7 * 16];
+        grid.viewport(&mut leds, &Palette::default(), ViewMode::Functions);
+        assert_eq!(leds[2 * 16 + 4], 6);
+        assert!(!grid.toggle_mute(row));
+    }
 
-            // draw octave indicator if shift is not pressed. Otherwise, draw the amount of bars
-            if !self.state_tracker.shift_down() {
-                let current_octave = self.virtual_grid.current_octave();
-                grid[8 + current_octave] = 15;
-            } else {
-                let bars = self.virtual_grid.steps_count() / 16;
-                for i in 0..bars {
-                    grid[8 + i] = 15;
+    #[test]
+    fn gate_length() {
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.set_gate_mode(GateMode::Length(50.));
+        for &tempo in [60., 120., 240.].iter() {
+            renderer.set_tempo(tempo);
+            let (_, _, window) = renderer.ratchet_at(0, 0, 0.);
+            assert_eq!(window, 0.5);
+            assert!(renderer.gate(0, 0, 0.49, false));
+            assert!(!renderer.gate(0, 0, 0.51, false));
+        }
+        // the gate still goes low before the next step
+        renderer.set_gate_mode(GateMode::Length(150.));
+        assert_eq!(renderer.gate_mode, GateMode::Length(100.));
+        let (_, _, window) = renderer.ratchet_at(0, 0, 0.);
+        assert!(window < 1.);
+    }
+
+    #[test]
+    fn mock_sink() {
+        let (_, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+        renderer.tracks[0].steps[0].toggle(20);
+        renderer.tracks[0].steps[2].toggle(27);
+        let cv = |renderer: &MMMSRenderer, index: u8| {
+            renderer.pitch_cv(transposed_pitch(&renderer.tracks[0].scale, index, 0))
+        };
+        let (low, high) = (cv(&renderer, 20), cv(&renderer, 27));
+
+        // the gate and the pitch at the beginning and in the middle of the first four steps, a
+        // sixteenth being 5512.5 frames at 120 BPM
+        let mut sink = MockSink::new(16);
+        let mut gates = Vec::new();
+        let mut pitches = Vec::new();
+        for block in 0..22050 / 16 {
+            renderer.render_to(&mut sink);
+            let frame = block * 16;
+            if frame % 5512 < 16 || (frame + 2756) % 5512 < 16 {
+                gates.push(sink.digital(15, 0));
+                pitches.push(sink.analog(7, 0));
+            }
+        }
+        assert_eq!(gates, [true, false, false, false, true, false, false, false]);
+        assert_eq!(pitches[1], low);
+        // the pitch is held after a note
+        assert_eq!(pitches[3], low);
+        assert_eq!(pitches[5], high);
+        assert_eq!(pitches[7], high);
+    }
+
+    #[test]
+    fn trigger_level() {
+        let (_, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::AnalogOut(1))
+            .pitch_port(BelaPort::AnalogOut(0)).trigger_level(0.5).autosave(None).build().unwrap();
+        renderer.tracks[0].steps[0].toggle(20);
+        // the trigger lasts 10ms, 220 analog frames
+        let mut sink = MockSink::new(16);
+        let mut levels = Vec::new();
+        for _ in 0..60 {
+            renderer.render_to(&mut sink);
+            levels.extend((0..8).map(|frame| sink.analog(frame, 1)));
+        }
+        assert!(levels[..200].iter().all(|level| *level == 0.5));
+        assert!(levels[240..].iter().all(|level| *level == 0.));
+
+        renderer.set_trigger_level(2.);
+        assert_eq!(renderer.trigger_level, 1.);
+    }
+
+    #[test]
+    fn trigger_edge_ramp() {
+        let (_, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::AnalogOut(1))
+            .pitch_port(BelaPort::AnalogOut(0)).trigger_edge_ramp(4).autosave(None).build()
+            .unwrap();
+        renderer.tracks[0].steps[0].toggle(20);
+        let mut sink = MockSink::new(16);
+        let mut levels = Vec::new();
+        for _ in 0..60 {
+            renderer.render_to(&mut sink);
+            levels.extend((0..8).map(|frame| sink.analog(frame, 1)));
+        }
+        // up over four frames, and down over four frames after 220 frames
+        assert_eq!(levels[..5], [0.25, 0.5, 0.75, 1., 1.]);
+        let fall = 4 + levels[4..].iter().position(|level| *level < 1.).unwrap();
+        assert!(fall > 200 && fall < 240);
+        assert_eq!(levels[fall..fall + 5], [0.75, 0.5, 0.25, 0., 0.]);
+    }
+
+    #[test]
+    fn sync_port() {
+        let (_, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).sync_port(BelaPort::Digital(2)).autosave(None)
+            .build().unwrap();
+        renderer.tracks[0].resize(4);
+        for x in 0..4 {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        // three loops of four steps, a sixteenth being 5512.5 frames at 120 BPM
+        let mut sink = MockSink::new(16);
+        let mut pulses = Vec::new();
+        let mut high = false;
+        for block in 0..3 * 4 * 5512 / 16 {
+            renderer.render_to(&mut sink);
+            for frame in 0..16 {
+                if sink.digital(frame, 2) && !high {
+                    pulses.push(block * 16 + frame);
                 }
+                high = sink.digital(frame, 2);
             }
+        }
+        assert_eq!(pulses.len(), 3);
+        for (pulse, frame) in pulses.iter().enumerate() {
+            assert!((*frame as f32 - pulse as f32 * 4. * 5512.5).abs() < 2.);
+        }
+        assert_eq!(renderer.set_sync_port(BelaPort::AnalogIn(0)), Err(MmmsError::InvalidSyncPort));
+    }
 
-            // draw playhead if visible
-            if self.virtual_grid.x_in_view(pos_in_pattern) {
-                for i in 1..self.height + 1 {
-                    let idx = i * 16 + pos_in_pattern % 16;
-                    if grid[idx] < 4 {
-                        grid[idx] = 4;
-                    }
+    #[test]
+    fn count_in() {
+        let (mut mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).count_in(1).autosave(None).build().unwrap();
+        renderer.tracks[0].resize(4);
+        renderer.tracks[0].steps[0].toggle(20);
+        mmms.set_playing(false);
+        mmms.set_playing(true);
+        // a click on each beat, then the pattern, a beat being 22050 frames at 120 BPM
+        let mut sink = MockSink::new(16);
+        let mut edges = Vec::new();
+        let mut high = false;
+        for block in 0..6 * 22050 / 16 {
+            renderer.render_to(&mut sink);
+            if block * 16 < 4 * 22050 {
+                assert_eq!(renderer.clock_consumer.beat(), 0.);
+            }
+            for frame in 0..16 {
+                if sink.digital(frame, 0) && !high {
+                    edges.push(block * 16 + frame);
                 }
+                high = sink.digital(frame, 0);
             }
-        } else {
-            self.scale_picker(self.virtual_grid.current_scale(), &mut grid[16..]);
         }
+        // the pattern starts with the block after the end of the count in
+        assert_eq!(edges.len(), 6);
+        for (beat, frame) in edges.iter().enumerate() {
+            assert!((*frame as isize - beat as isize * 22050).abs() <= 16);
+        }
+        assert!(renderer.clock_consumer.beat() > 1.9);
 
-        self.virtual_grid.draw();
+        // shift and transport in the tempo view go through the lengths
+        let keys = [(1, 0), (15, 0), (0, 0)];
+        for &(x, y) in keys.iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Down });
+            if x != 15 {
+                mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Up });
+            }
+        }
+        renderer.receive();
+        assert_eq!((mmms.count_in_bars, renderer.count_in_bars), (2, 2));
+        assert!(mmms.playing);
+
+        // slaved, the position follows the external clock during the count in, only the notes are
+        // muted
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        let (receiver, _) = mmms.midi.remove(0);
+        renderer.set_clock_source(ClockSource::External(BelaPort::Digital(1))).unwrap();
+        renderer.set_count_in(1);
+        renderer.tracks[0].resize(16);
+        for x in 0..16 {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        renderer.set_playing(false);
+        renderer.set_playing(true);
+        let mut sink = MockSink::new(16);
+        // a sixteenth every 256 frames, the count in lasts 16 of them
+        let mut pulses = |renderer: &mut MMMSRenderer, blocks: std::ops::Range<usize>| {
+            for block in blocks {
+                for (frame, bits) in sink.digital_in.iter_mut().enumerate() {
+                    *bits = if (block * 16 + frame) % 256 < 8 { 1 << 1 } else { 0 };
+                }
+                renderer.render_to(&mut sink);
+            }
+        };
+        pulses(&mut renderer, 0..128);
+        assert!(renderer.count_in.is_some());
+        assert!(renderer.clock_consumer.beat() > 1.5);
+        assert!(receiver.try_recv().is_err());
+        pulses(&mut renderer, 128..320);
+        assert!(renderer.count_in.is_none());
+        assert_eq!(receiver.try_recv().unwrap()[0], 0x90);
     }
-    fn main_thread_work(&mut self) {
-        // noop
+
+    #[test]
+    fn reset() {
+        let (mut mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+        let mut sink = MockSink::new(16);
+        // ten steps and a half
+        for _ in 0..(10.5 * 5512.5) as usize / 16 {
+            renderer.render_to(&mut sink);
+        }
+        assert_eq!(renderer.playhead(0), 10);
+        // shift and tempo in the tempo view
+        let keys = [(1, 0, true), (15, 0, false), (1, 0, true)];
+        for &(x, y, release) in keys.iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Down });
+            if release {
+                mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Up });
+            }
+        }
+        assert_eq!(mmms.playhead(0), 0);
+        // the step being played finishes, then the playback goes back to the first step
+        renderer.render_to(&mut sink);
+        assert_eq!(renderer.playhead(0), 10);
+        for _ in 0..5512 / 2 / 16 + 2 {
+            renderer.render_to(&mut sink);
+        }
+        assert_eq!((mmms.playhead(0), renderer.playhead(0)), (0, 0));
+        assert!(renderer.clock_consumer.beat() < 0.01);
+        for _ in 0..5512 / 16 + 1 {
+            renderer.render_to(&mut sink);
+        }
+        assert_eq!((mmms.playhead(0), renderer.playhead(0)), (1, 1));
+
+        // while stopped, right away
+        mmms.set_playing(false);
+        renderer.render_to(&mut sink);
+        mmms.reset();
+        renderer.render_to(&mut sink);
+        assert_eq!(renderer.clock_consumer.beat(), 0.);
     }
-    fn input(&mut self, event: MonomeEvent) {
-        match event {
-            MonomeEvent::GridKey { x, y, direction } => match direction {
-                KeyDirection::Down => {
-                    self.state_tracker.down(x as usize, y as usize);
+
+    #[test]
+    fn overruns() {
+        let (mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+        renderer.render_to(&mut MockSink::new(16));
+        // a block of another size, then a block that took longer than its duration
+        let overruns = mmms.overruns();
+        renderer.render_to(&mut MockSink::new(32));
+        assert!(mmms.overruns() > overruns);
+        let overruns = mmms.overruns();
+        renderer.count_overrun(16, time::Duration::from_millis(0));
+        assert_eq!(mmms.overruns(), overruns);
+        renderer.count_overrun(16, time::Duration::from_millis(1));
+        assert_eq!(mmms.overruns(), overruns + 1);
+    }
+
+    #[test]
+    fn to_midi_events() {
+        let (_, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(16);
+        // a note on each beat, the last one tied to the step after it
+        for x in [0, 4, 8, 12].iter() {
+            renderer.tracks[0].steps[*x].toggle(20);
+        }
+        renderer.tracks[0].steps[13].tie = true;
+        let note = pitch_to_midi(transposed_pitch(&renderer.tracks[0].scale, 20, 0));
+        // the trigger lasts 10ms, 1/50th of a beat at 120 BPM
+        let events = renderer.to_midi_events();
+        assert_eq!(events.len(), 8);
+        for (i, &(beat, event)) in events.iter().enumerate() {
+            let expected = match (i / 2, i % 2) {
+                (3, 1) => 3.5,
+                (on, 0) => on as f64,
+                (on, _) => on as f64 + 0.02,
+            };
+            assert!((beat - expected).abs() < 0.0001, "{} at {}", i, beat);
+            match (i % 2, event) {
+                (0, MidiEvent::NoteOn { track: 0, note: n, velocity }) if n == note => {
+                    assert_eq!(velocity, MAX_VELOCITY);
                 }
-                KeyDirection::Up => match self.state_tracker.up(x as usize, y as usize) {
-                    MMMSAction::Tick((x, y)) => {
-                        self.virtual_grid.tick(x, y);
-                        let xy = self.virtual_grid.vaddress(x, y);
-                        self.sender.send(Message::Tick(xy));
-                    }
-                    MMMSAction::Move((x, y)) => {
-                        self.virtual_grid.mouve(x, y);
-                    }
-                    MMMSAction::Resize(bars) => {
-                        self.virtual_grid.change_steps_count(bars * 16);
-                        self.sender.send(Message::Resize(bars * 16));
-                    }
-                    MMMSAction::Clear => {
-                        self.virtual_grid.clear();
-                        self.sender.send(Message::Clear);
-                    }
-                    MMMSAction::ToggleScale => {
-                        self.picking_scale = !self.picking_scale;
-                    }
-                    _ => {
-                        println!("nothing");
-                    }
-                },
-            },
-            _ => {}
+                (1, MidiEvent::NoteOff { track: 0, note: n }) if n == note => {}
+                _ => panic!("unexpected {:?}", event),
+            }
         }
     }
-}
 
-/// Handle a grid much larger than a monome 128, and allow inputing and displaying on a monome 128,
-/// and scrolling through bars (left/right) and notes (up/down). It is aware of the scale it's
-/// representing.
-/// 0x0 is top left, 64x128 is bottom right
-/// the offset_x and offset_y are the position of the top left corner of the viewport
-struct VirtualGrid {
-    width: usize,
-    height: usize,
-    offset_x: usize,
-    offset_y: usize,
-    scale: Scale,
-    grid: SmallVec<[Option<u8>; MAX_STEPS]>,
-}
+    #[test]
+    fn steps_per_beat() {
+        let (mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).steps_per_beat(3.).autosave(None).build().unwrap();
+        for x in 0..8 {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        // a beat is 22050 frames at 120 BPM, a trigger on each third of it
+        let mut sink = MockSink::new(16);
+        let mut edges = Vec::new();
+        let mut high = false;
+        let mut playheads = Vec::new();
+        let mut first = 0;
+        for block in 0..22050 / 16 {
+            renderer.render_to(&mut sink);
+            for frame in 0..16 {
+                if sink.digital(frame, 0) && !high {
+                    edges.push(block * 16 + frame);
+                }
+                high = sink.digital(frame, 0);
+                if high && block * 16 + frame < 7350 {
+                    first += 1;
+                }
+            }
+            if playheads.last() != Some(&mmms.playhead(0)) {
+                playheads.push(mmms.playhead(0));
+            }
+        }
+        assert_eq!(edges.len(), 3);
+        for (step, frame) in edges.iter().enumerate() {
+            assert!((*frame as f32 - step as f32 * 7350.).abs() <= 1.);
+        }
+        assert_eq!(playheads, vec![0, 1, 2]);
+        renderer.render_to(&mut sink);
+        assert_eq!((mmms.playhead(0), renderer.playhead(0)), (3, 3));
+        // the trigger still lasts 10ms
+        assert!((first as isize - 441).abs() <= 1);
+    }
 
-impl VirtualGrid {
-    fn new() -> VirtualGrid {
-         // This is a lie: the grid is in fact just a vector with the position of the notes that
-         // are ticked (or none if it's not been ticked).
-         let mut grid = SmallVec::<[Option<u8>; MAX_STEPS]>::new();
-         // TODO: pick a scale when starting? random?
-         let scale = Scale::new(PitchClass::B, ScaleType::Minor);
-         // third octave
-         let start_offset = scale.note_count() - scale.octave_note_count() * 3 - 7;
-         grid.resize(INITIAL_STEPS, None);
-         VirtualGrid {
-             width: INITIAL_STEPS,
-             height: scale.note_count(),
-             offset_x: 0,
-             offset_y: start_offset,
-             scale,
-             grid,
-         }
+    #[test]
+    fn mock_sink_external_clock() {
+        let (_, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+        renderer.set_clock_source(ClockSource::External(BelaPort::Digital(1))).unwrap();
+        renderer.tracks[0].steps[1].toggle(20);
+        // an edge every 64 frames, that is four blocks
+        let mut sink = MockSink::new(16);
+        let mut gates = Vec::new();
+        for block in 0..12 {
+            for (frame, bits) in sink.digital_in.iter_mut().enumerate() {
+                *bits = if (block * 16 + frame) % 64 < 8 { 1 << 1 } else { 0 };
+            }
+            renderer.render_to(&mut sink);
+            gates.push(sink.digital(15, 0));
+        }
+        // the second step starts with the second edge, and ends with the third one
+        assert!(gates[..4].iter().all(|gate| !gate));
+        assert!(gates[4]);
+        assert!(gates[8..].iter().all(|gate| !gate));
     }
-    fn steps_count(&self) -> usize {
-        self.width
+
+    #[test]
+    fn external_clock_ignores_tempo() {
+        let play = |tempo: Option<f32>| {
+            let (mut mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+                .pitch_port(BelaPort::AnalogOut(0)).autosave(None).build().unwrap();
+            renderer.set_clock_source(ClockSource::External(BelaPort::Digital(1))).unwrap();
+            for x in 0..4 {
+                renderer.tracks[0].steps[x].toggle(20);
+            }
+            let mut sink = MockSink::new(16);
+            let mut gates = Vec::new();
+            for block in 0..24 {
+                if block == 6 {
+                    if let Some(tempo) = tempo {
+                        mmms.set_tempo(tempo);
+                    }
+                }
+                for (frame, bits) in sink.digital_in.iter_mut().enumerate() {
+                    *bits = if (block * 16 + frame) % 64 < 8 { 1 << 1 } else { 0 };
+                }
+                renderer.render_to(&mut sink);
+                gates.extend((0..16).map(|frame| sink.digital(frame, 0)));
+            }
+            (gates, renderer.tempo(), mmms.playhead(0))
+        };
+        // slaved, the steps follow the edges whatever the tempo
+        let (gates, tempo, playhead) = play(None);
+        for &new_tempo in [60., 240.].iter() {
+            assert_eq!(play(Some(new_tempo)), (gates.clone(), tempo, playhead));
+        }
     }
-    fn change_steps_count(&mut self, count: usize) {
-      assert!(count % 16 == 0);
-      self.width = count;
-      self.offset_x = clamp((self.offset_x as isize) as isize, 0 as isize, (self.width - 16) as isize) as usize;
-      self.grid.resize(count, None);
+
+    #[test]
+    fn initial_scale() {
+        let (mmms, renderer) = MmmsBuilder::new()
+            .output(Output::Midi(Box::new(|_| {})))
+            .output(Output::Midi(Box::new(|_| {})))
+            .scale(Scale::new(PitchClass::E, ScaleType::MajorPentatonic))
+            .autosave(None)
+            .build()
+            .unwrap();
+        let scale = Scale::new(PitchClass::E, ScaleType::MajorPentatonic);
+        assert!(mmms.picked_scale.fundamental() == PitchClass::E);
+        for (grid, track) in mmms.virtual_grids.iter().zip(renderer.tracks.iter()) {
+            assert!(grid.current_scale().fundamental() == PitchClass::E);
+            assert!(track.scale.fundamental() == PitchClass::E);
+            assert_eq!(track.scale.note_count(), scale.note_count());
+            // the view starts on the third octave of the scale, whatever its number of notes
+            assert_eq!(grid.height, scale.note_count());
+            assert_eq!(grid.offset_y, scale.note_count() - scale.octave_note_count() * 3 - 7);
+            assert_eq!(grid.selected_row, grid.offset_y + 6);
+        }
+        // the pentatonic scale has fewer rows than the default one
+        assert!(mmms.virtual_grids[0].height < VirtualGrid::new().height);
     }
-    fn mouve(&mut self, x: isize, y: isize) {
-        self.offset_x = clamp((self.offset_x as isize + x as isize) as isize, 0 as isize, (self.width - 16) as isize) as usize;
-        self.offset_y = clamp((self.offset_y as isize + y as isize) as isize, 0 as isize, (self.height - 7) as isize) as usize;
+
+    #[test]
+    fn custom_scale() {
+        assert_eq!(CustomScale::new(PitchClass::C, &[]).err(), Some(MmmsError::InvalidScale));
+        assert_eq!(CustomScale::new(PitchClass::C, &[100., 300.]).err(), Some(MmmsError::InvalidScale));
+        assert_eq!(CustomScale::new(PitchClass::C, &[0., 500., 400.]).err(), Some(MmmsError::InvalidScale));
+        assert_eq!(CustomScale::new(PitchClass::C, &[0., 1200.]).err(), Some(MmmsError::InvalidScale));
+        let invalid = Some(MmmsError::InvalidScale);
+        for &period in [0., -1200., std::f32::NAN, std::f32::INFINITY].iter() {
+            assert_eq!(CustomScale::with_period(PitchClass::C, &[0.], period).err(), invalid);
+        }
+        // one note per cent gives more notes than rows
+        let cents: Vec<f32> = (0..1200).map(|cents| cents as f32).collect();
+        assert_eq!(CustomScale::new(PitchClass::C, &cents).err(), invalid);
+        // a period above MIDI note 127 gives a single note
+        assert_eq!(CustomScale::with_period(PitchClass::C, &[0.], 12000.).err(), invalid);
+        let scale = CustomScale::with_period(PitchClass::C, &[0.], 100.).unwrap();
+        assert!(scale.note_count() <= MAX_SCALE_NOTES);
+        // fewer notes than rows on the grid are fine
+        assert_eq!(CustomScale::new(PitchClass::C, &[0.]).unwrap().note_count(), 10);
+
+        // a root, a neutral third and a fifth
+        let scale = CustomScale::new(PitchClass::D, &[0., 350., 700.]).unwrap();
+        assert_eq!(scale.octave_note_count(), 3);
+        let (mut mmms, mut renderer) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).custom_scale(scale.clone()).autosave(None).build().unwrap();
+        assert_eq!(mmms.virtual_grids[0].height, scale.note_count());
+        assert_eq!(renderer.track_scale(0).note_count(), scale.note_count());
+        renderer.tracks[0].steps[0].toggle(3);
+        renderer.tracks[0].steps[1].toggle(4);
+        renderer.tracks[0].steps[2].toggle(8);
+        let mut sink = MockSink::new(16);
+        let mut pitches = Vec::new();
+        for block in 0..16538 / 16 {
+            renderer.render_to(&mut sink);
+            if (block * 16 + 2756) % 5512 < 16 {
+                pitches.push(sink.analog(7, 0));
+            }
+        }
+        // D1, D1 350 cents and D2 700 cents, in volts above C0, on a 10V output
+        let expected = [1. + 200. / 1200., 1. + 550. / 1200., 2. + 900. / 1200.];
+        assert_eq!(pitches.len(), 3);
+        for (pitch, expected) in pitches.iter().zip(expected.iter()) {
+            assert!((pitch - expected / 10.).abs() < 1e-6);
+        }
+        // MIDI plays the closest semitone
+        assert_eq!(pitch_to_midi(transposed_pitch(renderer.track_scale(0), 4, 0)), 30);
+        // the steps are the closest semitones, on the chromatic scale on the root
+        assert!(renderer.scale(0).scale_type() == ScaleType::Chromatic);
+        assert!(renderer.scale(0).fundamental() == PitchClass::D);
+        let pitches: Vec<Option<u8>> = renderer.steps(0).take(3)
+            .map(|(_, pitch)| pitch.map(|pitch| pitch_to_midi(pitch.to_cv()))).collect();
+        let midi = |idx| Some(pitch_to_midi(renderer.track_scale(0).idx_to_cv(idx).unwrap()));
+        assert_eq!(pitches, vec![midi(3), midi(4), midi(8)]);
+
+        // the scale is saved with the pattern
+        let mut loaded = VirtualGrid::new();
+        loaded.load_pattern(&mmms.virtual_grids[0].to_pattern());
+        assert!(loaded.current_scale().same_as(&TrackScale::Custom(scale)));
+        mmms.change_scale(default_scale().into());
+        assert!(mmms.track_scale().scale_type() == Some(ScaleType::Minor));
     }
-    fn clear(&mut self) {
-        for i in self.grid.iter_mut() {
-            *i = None;
+
+    #[test]
+    fn degrees_view() {
+        let mut grid = VirtualGrid::new();
+        grid.set_scale(Scale::new(PitchClass::C, ScaleType::Major).into());
+        let palette = Palette::default();
+        let mut leds = vec![0; 16 * 7];
+        grid.viewport(&mut leds, &palette, ViewMode::Degrees);
+        // seven rows, one per degree
+        let mut lit = Vec::new();
+        for i in 0..7 {
+            let idx = grid.height - 1 - (grid.offset_y + i);
+            assert_eq!(leds[i * 16], palette.degrees[idx % 7]);
+            lit.push(leds[i * 16]);
+        }
+        lit.sort();
+        let mut degrees = palette.degrees.to_vec();
+        degrees.sort();
+        assert_eq!(lit, degrees);
+        // only the tonics, dominants and leading tones otherwise
+        grid.viewport(&mut leds, &palette, ViewMode::Functions);
+        for i in 0..7 {
+            let idx = grid.height - 1 - (grid.offset_y + i);
+            let expected = match idx % 7 {
+                0 => palette.tonic,
+                4 => palette.dominant,
+                6 => palette.leading,
+                _ => 0,
+            };
+            assert_eq!(leds[i * 16], expected);
         }
+
+        let (mut mmms, _) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        assert_eq!(mmms.view_mode, ViewMode::Functions);
+        mmms.pick_scale(0, 6);
+        assert_eq!(mmms.view_mode, ViewMode::Degrees);
+        assert!(mmms.picked_scale.fundamental() == PitchClass::B);
     }
-    fn vaddress(&self, vx: usize, vy: usize) -> (usize, usize) {
-        let x = vx + self.offset_x;
-        let y = vy + self.offset_y;
 
-        assert!(x < self.width);
-        assert!(y < self.height);
+    #[test]
+    fn paint() {
+        let mut tracker = GridStateTracker::new(16, 8);
+        tracker.down(2, 3);
+        tracker.down(6, 5);
+        assert_eq!(format!("{:?}", tracker.up(6, 5)), "Paint(((2, 2), (6, 4)))");
+        // the first key doesn't tick when released
+        assert_eq!(format!("{:?}", tracker.up(2, 3)), "Nothing");
+        // from right to left, and a chord in a single column
+        tracker.down(9, 1);
+        tracker.down(5, 1);
+        assert_eq!(format!("{:?}", tracker.up(5, 1)), "Paint(((9, 0), (5, 0)))");
+        tracker.up(9, 1);
+        tracker.down(4, 2);
+        tracker.down(4, 6);
+        assert_eq!(format!("{:?}", tracker.up(4, 6)), "Tick((4, 5))");
+        assert_eq!(format!("{:?}", tracker.up(4, 2)), "Tick((4, 1))");
+        // a flat line, from left to right
+        tracker.down(2, 3);
+        tracker.down(6, 3);
+        assert_eq!(format!("{:?}", tracker.up(6, 3)), "Paint(((2, 2), (6, 2)))");
+        assert_eq!(format!("{:?}", tracker.up(2, 3)), "Nothing");
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.virtual_grids[0].tick(5, 2);
+        renderer.tracks[0].press(5, mmms.virtual_grids[0].vaddress(5, 2).unwrap().1);
+        for &(x, y, direction) in [(7, 5, KeyDirection::Down), (3, 1, KeyDirection::Down),
+                                   (3, 1, KeyDirection::Up), (7, 5, KeyDirection::Up)].iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        renderer.receive();
+        // five columns, from the bottom right to the top left, the note already there is kept
+        let rows: Vec<Vec<u8>> =
+            (0..9).map(|x| mmms.virtual_grids[0].grid[x].notes().to_vec()).collect();
+        let row = |vy: usize| mmms.virtual_grids[0].vaddress(0, vy).unwrap().1 as u8;
+        let expected = [vec![], vec![], vec![], vec![row(0)], vec![row(1)], vec![row(2)],
+                        vec![row(3)], vec![row(4)], vec![]];
+        assert_eq!(rows, expected);
+        // the renderer numbers the notes from the lowest
+        let last = renderer.tracks[0].scale.note_count() as u8 - 1;
+        for x in 0..9 {
+            let notes: Vec<u8> = rows[x].iter().map(|row| last - row).collect();
+            assert_eq!(renderer.tracks[0].steps[x].notes().to_vec(), notes);
+        }
 
-        (x, y)
+        // a flat line from left to right paints notes, without ties
+        for &(x, y, direction) in [(10, 6, KeyDirection::Down), (14, 6, KeyDirection::Down),
+                                   (14, 6, KeyDirection::Up), (10, 6, KeyDirection::Up)].iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        renderer.receive();
+        let row = mmms.virtual_grids[0].vaddress(0, 5).unwrap().1 as u8;
+        for x in 10..15 {
+            assert_eq!(mmms.virtual_grids[0].grid[x].notes(), &[row]);
+            assert_eq!(renderer.tracks[0].steps[x].notes(), &[last - row]);
+            assert!(!mmms.virtual_grids[0].grid[x].tie && !renderer.tracks[0].steps[x].tie);
+        }
     }
-    // return a number between 0 and 8 that represents the octave currently in the view
-    fn current_octave(&self) -> usize {
-        clamp((self.scale.note_count() - (self.offset_y + 7)) / self.scale.octave_note_count(), 0, 8)
+
+    #[test]
+    fn velocity_ramp() {
+        let mut grid = VirtualGrid::new();
+        for x in 0..8 {
+            grid.tick(x, 3);
+        }
+        grid.tick(10, 3);
+        // from 0 to 127 over eight steps
+        let ramp = grid.velocity_ramp(0, 7, 0, 127);
+        let velocities: Vec<u8> = ramp.iter().map(|&(_, velocity)| velocity).collect();
+        assert_eq!(velocities, [0, 18, 36, 54, 73, 91, 109, 127]);
+        assert!((0..8).all(|x| grid.grid[x].velocity == velocities[x]));
+        // the steps without notes are skipped, the values keep their place on the line
+        grid.tick(4, 3);
+        let ramp = grid.velocity_ramp(10, 3, 250, 30);
+        assert_eq!(ramp, vec![(3, 30), (5, 58), (6, 72), (7, 85), (10, 127)]);
+        assert_eq!(grid.grid[4].velocity, 73);
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        for x in 0..8 {
+            mmms.virtual_grids[0].tick(x, 3);
+        }
+        mmms.velocity_ramp(0, 7, 0, 127);
+        renderer.receive();
+        let played: Vec<u8> = renderer.tracks[0].steps[..8].iter().map(|step| step.velocity).collect();
+        assert_eq!(played, velocities);
+
+        // in the velocity view, a flat line from left to right is a flat ramp, not a tie
+        mmms.editing_velocity = true;
+        for &(x, y, direction) in [(2, 5, KeyDirection::Down), (6, 5, KeyDirection::Down),
+                                   (6, 5, KeyDirection::Up), (2, 5, KeyDirection::Up)].iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        renderer.receive();
+        let flat = mmms.virtual_grids[0].velocity_at(4);
+        for x in 2..7 {
+            assert_eq!(mmms.virtual_grids[0].grid[x].velocity, flat);
+            assert_eq!(renderer.tracks[0].steps[x].velocity, flat);
+            assert!(!mmms.virtual_grids[0].grid[x].tie && !renderer.tracks[0].steps[x].tie);
+        }
+        // the same in the probability view
+        mmms.editing_velocity = false;
+        mmms.editing_probability = true;
+        for &(x, y, direction) in [(1, 3, KeyDirection::Down), (5, 3, KeyDirection::Down),
+                                   (5, 3, KeyDirection::Up), (1, 3, KeyDirection::Up)].iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction });
+        }
+        renderer.receive();
+        let flat = mmms.virtual_grids[0].probability_at(2);
+        assert!(flat < MAX_PROBABILITY);
+        for x in 1..6 {
+            assert_eq!(mmms.virtual_grids[0].grid[x].probability, flat);
+            assert_eq!(renderer.tracks[0].steps[x].probability, flat);
+            assert!(!mmms.virtual_grids[0].grid[x].tie && !renderer.tracks[0].steps[x].tie);
+        }
     }
-    fn current_scale(&self) -> Scale {
-        self.scale.clone()
+
+    #[test]
+    fn resize_in_place() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        // the steps are never moved to the heap
+        for &count in [64, 65, MAX_STEPS, 1, MAX_STEPS].iter() {
+            mmms.set_steps_count(count);
+            renderer.receive();
+            assert_eq!(renderer.tracks[0].steps.len(), count);
+            assert!(!renderer.tracks[0].steps.spilled() && !mmms.virtual_grids[0].grid.spilled());
+        }
     }
-    fn in_view(&self, x: usize, y: usize) -> bool {
-        y >= self.offset_y && y < self.offset_y + 7 &&
-        x >= self.offset_x && x < self.offset_x + 16
+
+    #[test]
+    fn max_steps() {
+        let mut grid = VirtualGrid::new();
+        grid.change_steps_count(10 * 16);
+        assert_eq!(grid.steps_count(), MAX_STEPS);
+        assert_eq!(grid.grid.len(), MAX_STEPS);
+        grid.change_steps_count(0);
+        assert_eq!(grid.steps_count(), 1);
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(10 * 16);
+        assert_eq!(renderer.tracks[0].steps.len(), MAX_STEPS);
+        mmms.set_steps_count(10 * 16);
+        renderer.receive();
+        assert_eq!(mmms.steps_count(), MAX_STEPS);
+        assert_eq!(renderer.steps_count(0), MAX_STEPS);
+        // all the keys of the bar indicator are lit, up to the scale key
+        mmms.state_tracker.down(15, 0);
+        let mut leds = [0; 128];
+        mmms.render_leds(&mut leds);
+        assert!((8..8 + INDICATOR_KEYS).all(|x| leds[x] == 15));
     }
-    fn x_in_view(&self, x: usize) -> bool {
-        x >= self.offset_x && x < self.offset_x + 16
+
+    #[test]
+    fn frozen_dice() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(16);
+        for x in 0..16 {
+            renderer.tracks[0].steps[x].toggle(20);
+            renderer.tracks[0].set_probability(x, 50);
+        }
+        let play = |renderer: &mut MMMSRenderer, pattern: usize| -> Vec<bool> {
+            (pattern * 16..pattern * 16 + 16).map(|raw| renderer.fires(0, raw)).collect()
+        };
+        // a new roll for each step
+        let (first, second) = (play(&mut renderer, 0), play(&mut renderer, 1));
+        assert_ne!(first, second);
+
+        mmms.set_dice(Dice::Frozen(0));
+        renderer.receive();
+        let frozen = play(&mut renderer, 2);
+        assert!(frozen.iter().any(|fires| *fires) && !frozen.iter().all(|fires| *fires));
+        for pattern in 3..6 {
+            assert_eq!(play(&mut renderer, pattern), frozen);
+        }
+        mmms.reroll();
+        renderer.receive();
+        let rerolled = play(&mut renderer, 6);
+        assert_ne!(rerolled, frozen);
+        assert_eq!(play(&mut renderer, 7), rerolled);
+        // the probabilities still apply to the frozen rolls
+        renderer.tracks[0].set_probability(0, 0);
+        renderer.tracks[0].set_probability(1, 100);
+        let changed = play(&mut renderer, 8);
+        assert!(!changed[0] && changed[1] && changed[2..] == rerolled[2..]);
+
+        // shift and the second track in the probability view roll again, elsewhere they fill a
+        // euclidean rhythm
+        let keys = |mmms: &mut MMMS, keys: &[(i32, i32, KeyDirection)]| {
+            for &(x, y, direction) in keys.iter() {
+                mmms.input(MonomeEvent::GridKey { x, y, direction });
+            }
+        };
+        let reroll = [(15, 0, KeyDirection::Down), (5, 0, KeyDirection::Down),
+                      (5, 0, KeyDirection::Up), (15, 0, KeyDirection::Up)];
+        let probability = [(2, 0, KeyDirection::Down), (2, 0, KeyDirection::Up)];
+        keys(&mut mmms, &probability);
+        keys(&mut mmms, &reroll);
+        renderer.receive();
+        let frozen = play(&mut renderer, 9);
+        assert_ne!(frozen, changed);
+        assert_eq!(play(&mut renderer, 10), frozen);
+        assert!(mmms.virtual_grids[0].grid[..16].iter().all(|step| step.is_empty()));
+        keys(&mut mmms, &probability);
+        keys(&mut mmms, &reroll);
+        assert!(mmms.virtual_grids[0].grid[..16].iter().any(|step| !step.is_empty()));
+
+        // rolled again every two loops
+        renderer.set_dice(Dice::Frozen(2));
+        let loops: Vec<Vec<bool>> = (10..14).map(|pattern| play(&mut renderer, pattern)).collect();
+        assert_eq!(loops[0], loops[1]);
+        assert_ne!(loops[1], loops[2]);
+        assert_eq!(loops[2], loops[3]);
     }
-    fn viewport(&self, grid: &mut [u8]) {
-        assert!(grid.len() == 7 * 16);
-        for i in 0..7 {
-            for j in 0..16 {
-                let local_idx = i * 16 + j;
-                // flip verticaly so that lower notes are at the bottom
-                grid[local_idx] = match self.scale.idx_to_degree(self.scale.note_count() - 1 - (self.offset_y + i)) {
-                    Ok(Degrees::Tonic) => { 10 }
-                    Ok(Degrees::Dominant) => { 6 }
-                    Ok(Degrees::Leading) => { 4 }
-                    _ => { 0 }
-                };
-                if self.grid[self.offset_x + j].is_some() &&
-                   self.grid[self.offset_x + j].unwrap() == (self.offset_y + i) as u8 {
-                    grid[local_idx] = 15;
-                }
+
+    #[test]
+    fn conditions() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        renderer.tracks[0].resize(16);
+        for x in 0..3 {
+            renderer.tracks[0].steps[x].toggle(20);
+        }
+        mmms.set_condition(0, Condition::Every(1, 2));
+        mmms.set_condition(1, Condition::Fill);
+        mmms.set_condition(2, Condition::NotFill);
+        renderer.receive();
+        let play = |renderer: &mut MMMSRenderer, x: usize| -> Vec<bool> {
+            (0..5).map(|pattern| renderer.fires(0, pattern * 16 + x)).collect()
+        };
+        assert_eq!(play(&mut renderer, 0), vec![true, false, true, false, true]);
+        assert_eq!(play(&mut renderer, 1), vec![false; 5]);
+        assert_eq!(play(&mut renderer, 2), vec![true; 5]);
+
+        // the loop after the one during which the fill is asked for is a fill, once
+        mmms.fill();
+        renderer.receive();
+        let fills: Vec<bool> = (5..8).map(|pattern| renderer.fires(0, pattern * 16 + 1)).collect();
+        assert_eq!(fills, vec![false, true, false]);
+        assert!(!renderer.fires(0, 6 * 16 + 2));
+
+        // shift and a step in the probability view go through the conditions
+        let tap = |mmms: &mut MMMS, x: i32, y: i32, shift: bool| {
+            let keys = [(15, 0, KeyDirection::Down), (x, y, KeyDirection::Down),
+                        (x, y, KeyDirection::Up), (15, 0, KeyDirection::Up)];
+            for &(x, y, direction) in keys.iter().filter(|key| shift || key.0 != 15) {
+                mmms.input(MonomeEvent::GridKey { x, y, direction });
             }
+        };
+        tap(&mut mmms, 2, 0, false);
+        let mut cycle = vec![];
+        for _ in 0..9 {
+            tap(&mut mmms, 4, 3, true);
+            cycle.push(mmms.virtual_grids[0].grid[4].condition);
         }
+        assert_eq!(cycle, vec![Condition::Every(1, 2), Condition::Every(2, 2), Condition::Every(1, 4),
+                               Condition::Every(2, 4), Condition::Every(3, 4), Condition::Every(4, 4),
+                               Condition::Fill, Condition::NotFill, Condition::Always]);
+        renderer.receive();
+        assert_eq!(renderer.tracks[0].steps[4].condition, Condition::Always);
+        // and shift with the probability key arms a fill
+        tap(&mut mmms, 2, 0, true);
+        assert!(mmms.editing_probability);
+        renderer.receive();
+        assert!(renderer.tracks[0].fill_armed);
     }
-    fn tick(&mut self, vx: usize, vy: usize) {
-        let (x, y) = self.vaddress(vx, vy);
-        if self.grid[x].is_some() {
-            if self.grid[x].unwrap() == y as u8 {
-                self.grid[x] = None;
-            } else {
-                self.grid[x] = Some(y as u8);
+
+    #[test]
+    fn random_walk() {
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.set_steps_count(16);
+        for x in (0..16).step_by(2) {
+            mmms.virtual_grids[0].tick(x, 3);
+        }
+        mmms.send_pattern(0);
+        renderer.receive();
+        let notes = |mmms: &MMMS| -> Vec<Vec<u8>> {
+            mmms.virtual_grids[0].grid[..16].iter().map(|step| step.notes().to_vec()).collect()
+        };
+        let play_loop = |mmms: &mut MMMS, pattern: usize| {
+            for step in pattern * 16..pattern * 16 + 16 {
+                mmms.random_walk_at(step);
+                mmms.random_walk_at(step);
+            }
+        };
+        let before = notes(&mmms);
+        mmms.set_random_walk(true);
+        mmms.set_walk_rate(0.);
+        for pattern in 0..8 {
+            play_loop(&mut mmms, pattern);
+        }
+        assert_eq!(notes(&mmms), before);
+
+        mmms.set_walk_rate(1.);
+        let mut previous = before;
+        for pattern in 8..16 {
+            play_loop(&mut mmms, pattern);
+            let current = notes(&mmms);
+            assert_eq!((0..16).filter(|x| current[*x] != previous[*x]).count(), 1);
+            previous = current;
+        }
+        // the renderer follows, it numbers the notes from the lowest
+        renderer.receive();
+        let last = renderer.tracks[0].scale.note_count() as u8 - 1;
+        for x in 0..16 {
+            let mut expected: Vec<u8> = previous[x].iter().map(|row| last - row).collect();
+            let mut notes = renderer.tracks[0].steps[x].notes().to_vec();
+            expected.sort();
+            notes.sort();
+            assert_eq!(notes, expected);
+        }
+
+        // nothing changes when it's off
+        mmms.set_random_walk(false);
+        play_loop(&mut mmms, 16);
+        assert_eq!(notes(&mmms), previous);
+
+        // in the probability view, scale and reverse toggle it, scale and a random key set the rate
+        let keys = [(2, 0), (14, 0), (13, 0), (3, 0)];
+        for &(x, y) in keys.iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Down });
+            if x != 14 {
+                mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Up });
             }
-        } else {
-            self.grid[x] = Some(y as u8);
         }
+        mmms.input(MonomeEvent::GridKey { x: 14, y: 0, direction: KeyDirection::Up });
+        assert!(mmms.random_walk);
+        assert_eq!(mmms.walk_rate, 0.5);
+        assert_eq!(notes(&mmms), previous);
     }
-    // Draw the grid. The notes in the view are circled. 1 is a ticked note.
-    fn draw(&self) {
-        println!("######### begin #######");
-        for i in 0..self.scale.note_count() {
-            for j in 0..self.width + 1 {
-                if j == 0 {
-                    print!("{}\t", self.scale.idx_to_pitch(self.scale.note_count() - 1 - i).unwrap());
-                    continue;
-                }
-                if self.in_view(j, i) {
-                   if self.grid[j - 1].is_some() {
-                     print!("|{}|", if self.grid[j - 1].unwrap() == i as u8 { 1 } else { 0 });
-                   } else {
-                     print!("|0|");
-                   }
-                } else  {
-                   if self.grid[j - 1].is_some() {
-                     print!(" {} ", if self.grid[j - 1].unwrap() == i as u8 { 1 } else { 0 });
-                   } else {
-                     print!(" 0 ");
-                   }
-                }
+
+    #[test]
+    fn grid_key_release() {
+        // the intent of a key is read before it's released, the rows below the control row are
+        // numbered from 0
+        let mut tracker = GridStateTracker::new(16, 8);
+        for &(x, y) in [(0, 1), (7, 4), (15, 7)].iter() {
+            tracker.down(x, y);
+            match tracker.up(x, y) {
+                MMMSAction::Tick((tick_x, tick_y)) if tick_x == x && tick_y == y - 1 => {}
+                action => panic!("unexpected {:?}", action),
+            }
+            // released twice, the second time it wasn't held
+            match tracker.up(x, y) {
+                MMMSAction::Nothing => {}
+                action => panic!("unexpected {:?}", action),
             }
-            print!("\n");
         }
-        println!("#########  end  #######");
     }
-}
 
-#[cfg(test)]
-mod tests {
     #[test]
-    fn it_works() { }
+    fn control_map() {
+        // the defaults are the layouts of a monome 128 and 64
+        for &width in [8, 16].iter() {
+            let tracker = GridStateTracker::new(width, 8);
+            assert_eq!((tracker.scale_key(), tracker.shift_key()), (width - 2, width - 1));
+        }
+        // shift moved to the first key, and transport to the last one
+        let mut controls = WIDE_CONTROLS.to_vec();
+        controls.swap(0, 15);
+        let map = ControlMap::new(&controls, &controls).unwrap();
+        let (mut mmms, _) = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).control_map(map.clone()).autosave(None)
+            .build().unwrap();
+        assert_eq!(mmms.state_tracker.shift_key(), 0);
+        let tracker = &mut mmms.state_tracker;
+        tracker.down(0, 0);
+        assert!(tracker.shift_down());
+        tracker.down(15, 0);
+        match tracker.up(15, 0) {
+            MMMSAction::Euclid { pulses: 1, steps: 8 } => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.down(2, 3);
+        match tracker.up(2, 3) {
+            MMMSAction::StepOption((2, 2)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.up(0, 0);
+        tracker.down(15, 0);
+        match tracker.up(15, 0) {
+            MMMSAction::Transport => {}
+            action => panic!("unexpected {:?}", action),
+        }
+
+        // no shift, shift elsewhere with shift held, a fifth track, or wider than the grid
+        controls[0] = Control::Transport;
+        let mut shift_controls = WIDE_CONTROLS.to_vec();
+        assert_eq!(ControlMap::new(&controls, &shift_controls), Err(MmmsError::InvalidControlMap));
+        shift_controls.swap(0, 15);
+        controls = shift_controls.clone();
+        shift_controls.swap(0, 1);
+        assert_eq!(ControlMap::new(&controls, &shift_controls), Err(MmmsError::InvalidControlMap));
+        controls[4] = Control::Track(4);
+        assert_eq!(ControlMap::new(&controls, &controls), Err(MmmsError::InvalidControlMap));
+        let wide = MmmsBuilder::new().trigger_port(BelaPort::Digital(0))
+            .pitch_port(BelaPort::AnalogOut(0)).grid_size(8, 8).control_map(map).autosave(None)
+            .build();
+        assert_eq!(wide.err(), Some(MmmsError::InvalidControlMap));
+    }
+
+    #[test]
+    fn narrow_shift() {
+        // on a monome 64, shift is the last key of the control row: the key at index 15 is a note
+        let mut tracker = GridStateTracker::new(8, 8);
+        assert_eq!(tracker.shift_key(), 7);
+        assert_eq!(GridStateTracker::idx(8, 7, 1), 15);
+        tracker.down(7, 1);
+        assert!(!tracker.shift_down());
+        tracker.up(7, 1);
+        tracker.down(7, 0);
+        assert!(tracker.shift_down() && !tracker.scale_down());
+        tracker.down(2, 3);
+        match tracker.up(2, 3) {
+            MMMSAction::StepOption((2, 2)) => {}
+            action => panic!("unexpected {:?}", action),
+        }
+        tracker.up(7, 0);
+        assert!(!tracker.shift_down());
+    }
+
+    #[test]
+    fn short_scale() {
+        // a semitone repeated every eight octaves, four notes in all
+        let scale = CustomScale::with_period(PitchClass::C, &[0., 100.], 9600.).unwrap();
+        assert_eq!(scale.note_count(), 4);
+        let mut grid = VirtualGrid::with_view(16, 7, TrackScale::Custom(scale.clone()));
+        assert_eq!((grid.offset_y, grid.selected_row), (0, 3));
+        grid.mouve(0, 3);
+        assert_eq!(grid.offset_y, 0);
+        grid.mouve(0, -3);
+        assert_eq!(grid.offset_y, 0);
+        assert_eq!(grid.current_octave(), 0);
+        assert!(grid.y_in_scale(3) && !grid.y_in_scale(4));
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.virtual_grids[0].tick(2, 3);
+        mmms.set_custom_scale(scale);
+        renderer.receive();
+        // the note moves to the closest pitch, in view
+        let grid = &mmms.virtual_grids[0];
+        assert_eq!(grid.offset_y, 0);
+        assert_eq!(grid.grid[2].notes().len(), 1);
+        assert!((grid.grid[2].notes()[0] as usize) < 4);
+        // the keys below the scale do nothing, the ones in it play
+        for &(x, y) in [(5, 6), (6, 2)].iter() {
+            mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Down });
+            mmms.input(MonomeEvent::GridKey { x, y, direction: KeyDirection::Up });
+        }
+        renderer.receive();
+        assert!(mmms.virtual_grids[0].grid[5].notes().is_empty());
+        assert!(renderer.tracks[0].steps[5].notes().is_empty());
+        assert_eq!(mmms.virtual_grids[0].grid[6].notes().to_vec(), vec![1]);
+        assert_eq!(renderer.tracks[0].steps[6].notes().to_vec(), vec![2]);
+        let mut leds = [0 as u8; 128];
+        mmms.render(&mut leds);
+        // the playhead is on the first column
+        assert!((5..8).all(|y| leds[y * 16 + 1..y * 16 + 16].iter().all(|led| *led == 0)));
+        assert_eq!(leds[2 * 16 + 6], mmms.palette.note);
+    }
+
+    #[test]
+    fn scale_change_to_short_scale() {
+        // from the highest notes of a chromatic scale several octaves long, to a scale of four
+        // notes: the view goes back to the top of the scale, with all its notes visible
+        let short = CustomScale::with_period(PitchClass::C, &[0., 100.], 9600.).unwrap();
+        let chromatic = Scale::new(PitchClass::C, ScaleType::Chromatic);
+        let mut grid = VirtualGrid::with_view(16, 7, chromatic.clone().into());
+        assert!(grid.height > 7 * 12);
+        grid.mouve(0, -1000);
+        grid.selected_row = grid.height - 1;
+        grid.set_scale(TrackScale::Custom(short.clone()));
+        assert_eq!((grid.offset_y, grid.selected_row), (0, 3));
+        assert!((0..4).all(|vy| grid.y_in_scale(vy)));
+
+        let (mut mmms, mut renderer) = MMMS::new(Output::Midi(Box::new(|_| {})), 16, 8, 120.).unwrap();
+        mmms.change_scale(chromatic.into());
+        mmms.virtual_grids[0].mouve(0, 1000);
+        for y in 1..8 {
+            mmms.input(MonomeEvent::GridKey { x: 1, y, direction: KeyDirection::Down });
+            mmms.input(MonomeEvent::GridKey { x: 1, y, direction: KeyDirection::Up });
+        }
+        mmms.set_custom_scale(short);
+        renderer.receive();
+        let grid = &mmms.virtual_grids[0];
+        assert_eq!(grid.offset_y, 0);
+        assert!(grid.selected_row < 4);
+        assert!(grid.grid[1].notes().iter().all(|row| (*row as usize) < 4));
+        // the notes are drawn and still play
+        let mut leds = [0 as u8; 128];
+        mmms.render(&mut leds);
+        assert!((1..5).any(|y| leds[y * 16 + 1] == mmms.palette.note));
+        assert!(!renderer.tracks[0].steps[1].notes().is_empty());
+    }
 }